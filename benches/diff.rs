@@ -0,0 +1,101 @@
+// Benchmarks of the diff hot path between the two fixture feeds, run with `cargo bench --bench
+// diff`. To check a change, save a baseline before it with `-- --save-baseline before` and compare
+// after it with `-- --baseline before`
+
+use std::{hint::black_box, io::Cursor};
+
+use chrono::NaiveDate;
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use gtfs_parsing::schedule::Schedule;
+use transit_server::diff::{core::ScheduleUpdate, ir::ScheduleIR};
+use zip::ZipArchive;
+
+// Both fixtures have service on it, same as `long_running_tests`
+const START_DATE: (i32, u32, u32) = (2025, 4, 28);
+// Full window the server keeps
+const DAYS: u8 = 32;
+
+fn load_schedule(dir: &str) -> Schedule {
+    let bytes = std::fs::read(format!("./gtfs_data/{}/gtfs_supplemented.zip", dir)).unwrap();
+
+    Schedule::from_zip(ZipArchive::new(Cursor::new(bytes)).unwrap(), None).unwrap()
+}
+
+fn build_ir(schedule: Schedule) -> ScheduleIR {
+    let (year, month, day) = START_DATE;
+
+    ScheduleIR::try_from_schedule_with_dates(
+        schedule,
+        NaiveDate::from_ymd_opt(year, month, day).unwrap(),
+        DAYS,
+    )
+}
+
+// Parsed once and shared by every benchmark, parsing takes far longer than anything measured
+struct Fixtures {
+    parsed: Schedule,
+    schedule: ScheduleIR,
+    schedule_alt: ScheduleIR,
+    // Turns `schedule` into `schedule_alt`, and back
+    forward: ScheduleUpdate,
+    backward: ScheduleUpdate,
+}
+
+impl Fixtures {
+    fn load() -> Self {
+        let parsed = load_schedule("schedule");
+        let schedule = build_ir(parsed.clone());
+        let schedule_alt = build_ir(load_schedule("schedule_alt"));
+        let forward = schedule_alt.get_diff(&schedule);
+        let backward = schedule.get_diff(&schedule_alt);
+
+        Self {
+            parsed,
+            schedule,
+            schedule_alt,
+            forward,
+            backward,
+        }
+    }
+}
+
+fn diff_benches(c: &mut Criterion) {
+    let fixtures = Fixtures::load();
+    let mut group = c.benchmark_group("diff");
+
+    group.bench_function("get_diff", |b| {
+        b.iter(|| black_box(&fixtures.schedule_alt).get_diff(black_box(&fixtures.schedule)))
+    });
+
+    // What each history entry goes through on every update
+    group.bench_function("combine", |b| {
+        b.iter(|| black_box(&fixtures.forward).combine(black_box(&fixtures.backward)))
+    });
+
+    // The schedule is consumed, so each iteration gets its own copy outside the measurement
+    group.bench_function("apply_to_schedule", |b| {
+        b.iter_batched(
+            || fixtures.schedule.clone(),
+            |schedule| fixtures.forward.apply_to_schedule(schedule),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+
+    // Building the IR from a parsed feed, which resolves each trip's date mask from its service
+    let mut group = c.benchmark_group("ir");
+    group.bench_function("try_from_schedule", |b| {
+        b.iter_batched(|| fixtures.parsed.clone(), build_ir, BatchSize::LargeInput)
+    });
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // Each iteration works on a whole city's schedule, the default 100 samples take minutes
+    config = Criterion::default().sample_size(10);
+    targets = diff_benches
+}
+criterion_main!(benches);
@@ -2,7 +2,12 @@ use std::{fs::OpenOptions, io::stdout, time::Duration};
 
 use logge_rs::{error, setup_logger};
 use tokio::time::sleep;
-use transit_server::{cacher::cacher_serve_loop, error::ScheduleError};
+use transit_server::{
+    cacher::{cacher_serve_loop, render_metrics},
+    config::{CACHER_CONFIG, CacherConfig},
+    error::ScheduleError,
+    exporter::metrics_loop,
+};
 
 const LOGGER_FILE: &'static str = "cacher.log";
 
@@ -22,6 +27,16 @@ async fn main() -> Result<(), ScheduleError> {
     )
     .unwrap();
 
+    *CACHER_CONFIG.write().await = CacherConfig::from_env();
+
+    if let Some(addr) = CACHER_CONFIG.read().await.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(e) = metrics_loop(addr, render_metrics).await {
+                error!("Metrics server failed: {}", e);
+            }
+        });
+    }
+
     loop {
         if let Err(e) = cacher_serve_loop().await {
             error!("Cacher server failed: {e}");
@@ -0,0 +1,64 @@
+use std::{env, path::PathBuf, process::exit};
+
+use transit_server::{error::ScheduleError, server::archive::replay};
+
+const USAGE: &str = "Usage: replay --timestamp <timestamp> <archive dir>";
+
+fn parse_args() -> Option<(u32, PathBuf)> {
+    let mut timestamp = None;
+    let mut dir = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timestamp" => timestamp = Some(args.next()?.parse().ok()?),
+            _ if dir.is_none() => dir = Some(PathBuf::from(arg)),
+            _ => return None,
+        }
+    }
+
+    Some((timestamp?, dir?))
+}
+
+fn main() -> Result<(), ScheduleError> {
+    let Some((timestamp, dir)) = parse_args() else {
+        eprintln!("{}", USAGE);
+        exit(2);
+    };
+
+    let archived = replay(&dir, timestamp)?;
+    let schedule = archived.schedule.unwrap_or_default();
+
+    println!(
+        "State at {} (archived at {}, newest was {})",
+        timestamp,
+        archived.timestamp.unwrap_or_default(),
+        archived.latest_timestamp.unwrap_or_default()
+    );
+    println!(
+        "{} routes, {} trips, {} stops, {} shapes, {} agencies",
+        schedule.routes.len(),
+        schedule.routes.iter().map(|r| r.trips.len()).sum::<usize>(),
+        schedule.stops.len(),
+        schedule.shapes.len(),
+        schedule.agencies.len()
+    );
+
+    for route in schedule.routes.iter() {
+        println!(
+            "  {}: {} trips",
+            route.route_id.as_deref().unwrap_or_default(),
+            route.trips.len()
+        );
+    }
+
+    if let Some(to_latest) = archived.to_latest {
+        println!(
+            "Until the newest state: {} trips added, {} trips removed",
+            to_latest.added_trips.len(),
+            to_latest.removed_trip_ids.len()
+        );
+    }
+
+    Ok(())
+}
@@ -2,7 +2,11 @@ use std::{fs::OpenOptions, io::stdout, time::Duration};
 
 use logge_rs::{error, info, setup_logger};
 use tokio::time::sleep;
-use transit_server::server::{server_loop, update_loop};
+use transit_server::{
+    config::{SERVER_CONFIG, ServerConfig},
+    exporter::metrics_loop,
+    server::{feed_update_loop, render_metrics, server_loop, update_loop},
+};
 
 const LOGGER_FILE: &'static str = "server.log";
 
@@ -22,6 +26,22 @@ async fn main() {
     )
     .unwrap();
 
+    *SERVER_CONFIG.write().await = ServerConfig::from_env();
+    info!("Using config {:?}", SERVER_CONFIG.read().await);
+
+    // These never return, and don't need restarting along with the main feed
+    for feed in SERVER_CONFIG.read().await.extra_feeds.clone() {
+        tokio::spawn(feed_update_loop(feed));
+    }
+
+    if let Some(addr) = SERVER_CONFIG.read().await.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(e) = metrics_loop(addr, render_metrics).await {
+                error!("Metrics server failed: {}", e);
+            }
+        });
+    }
+
     loop {
         info!("Starting new server instance");
 
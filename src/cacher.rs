@@ -3,30 +3,49 @@ use std::{
     convert::Infallible,
     future::{Ready, ready},
     io::Write,
-    net::SocketAddr,
-    sync::LazyLock,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
     time::Duration,
 };
 
 use crate::{
+    cacher::breaker::{CircuitBreaker, guarded},
+    config::CACHER_CONFIG,
     error::ScheduleError,
-    server::db_transit::{LastUpdateRequest, schedule_client::ScheduleClient},
+    exporter::MetricsText,
+    server::db_transit::{LastUpdateRequest, LastUpdateResponse, schedule_client::ScheduleClient},
 };
+use futures_core::future::BoxFuture;
 use http_body_util::{BodyExt, Full, combinators::WithTrailers};
-use hyper::{HeaderMap, Request, Response, body::Bytes, server::conn::http2, service::service_fn};
+use hyper::{
+    HeaderMap, Request, Response,
+    body::{Body, Bytes},
+    header::{CONTENT_TYPE, HeaderValue},
+    server::conn::http2,
+    service::service_fn,
+};
 use hyper_util::{
     client::legacy::{Client, connect::HttpConnector},
     rt::{TokioExecutor, TokioIo, TokioTimer},
 };
 use logge_rs::{error, info, warn};
-use tokio::{net::TcpListener, sync::RwLock};
-use tonic::transport::Channel;
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, RwLock, Semaphore},
+};
+use tonic::{Code, Status, transport::Channel};
 
-const GRPC_BASE_URL: &'static str = "http://localhost:50052";
 const GRPC_URL_PATH: &'static str = "/db_transit.Schedule/GetSchedule";
-const GRPC_FULL_URL: &'static str = "http://localhost:50052/db_transit.Schedule/GetSchedule";
 
 const MAX_CACHE_ENTRIES: u32 = 20;
+// gRPC's equivalent of a 413
+const GRPC_RESOURCE_EXHAUSTED: u32 = 8;
+const GRPC_UNAVAILABLE: u32 = 14;
+// Upstream failures in a row before requests stop being forwarded, and for how long
+const BREAKER_FAILURE_THRESHOLD: u32 = 5;
+const BREAKER_COOL_DOWN: Duration = Duration::from_secs(30);
 
 static GRPC_CLIENT: RwLock<Option<ScheduleClient<Channel>>> = RwLock::const_new(None);
 static HTTP_CLIENT: LazyLock<Client<HttpConnector, Full<Bytes>>> = LazyLock::new(|| {
@@ -36,66 +55,155 @@ static HTTP_CLIENT: LazyLock<Client<HttpConnector, Full<Bytes>>> = LazyLock::new
         .http2_only(true)
         .build_http()
 });
-static LAST_UPDATE: RwLock<u32> = RwLock::const_new(0);
+// Hash of the feed the cached responses were built from
+static LAST_HASH: RwLock<Option<Vec<u8>>> = RwLock::const_new(None);
 static CACHED_SCHEDULE: LazyLock<RwLock<HashMap<Vec<u8>, (Vec<u8>, HeaderMap, HeaderMap)>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
+// Shared by every upstream call, both update checks and forwarded requests
+static UPSTREAM_BREAKER: Mutex<CircuitBreaker> = Mutex::const_new(CircuitBreaker::new(
+    BREAKER_FAILURE_THRESHOLD,
+    BREAKER_COOL_DOWN,
+));
+// Total connections accepted by `cacher_serve_loop`
+static ACCEPTED_CONNECTIONS: LazyLock<Arc<AtomicU64>> = LazyLock::new(Default::default);
+// Requests answered from the cache, and ones that had to go upstream
+pub(crate) static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+pub mod breaker;
+pub mod tests;
 
 type BodyType = WithTrailers<Full<Bytes>, Ready<Option<Result<HeaderMap, Infallible>>>>;
 
-async fn check_cache_validity() {
-    // Send request
-    let req = LastUpdateRequest {};
-
-    let mut mclient = GRPC_CLIENT.write().await;
-
-    let mut clearing = false;
-
-    if mclient.is_some() {
-        let client = mclient.as_mut().unwrap();
-        let resp = client.get_last_update(req).await;
-
-        match resp {
-            Ok(rsp) => {
-                let timestamp = rsp.into_inner().timestamp;
-                match timestamp {
-                    Some(time) => {
-                        if *LAST_UPDATE.read().await != time {
-                            // Server reporting new timestamp, update timestamp and clear the cache
-                            clearing = true;
-                            *LAST_UPDATE.write().await = time;
-                        }
-                    }
-                    None => {
-                        // Server not reporting a timestamp, something has gone wrong. Reset
-                        // everything
-                        clearing = true;
-                        *LAST_UPDATE.write().await = 0
-                    }
-                }
+/// Records the server's latest feed hash, returning whether it changed. The timestamp isn't used
+/// since an identical feed re-fetched later still gets a new one
+async fn is_cache_stale(resp: LastUpdateResponse) -> bool {
+    let mut last_hash = LAST_HASH.write().await;
+
+    match resp.hash {
+        Some(hash) => {
+            if last_hash.as_ref() != Some(&hash) {
+                // Server reporting new content, update hash and clear the cache
+                *last_hash = Some(hash);
+                true
+            } else {
+                false
             }
-            Err(_) => clearing = true,
         }
-    } else {
-        // GRPC Client not set up, clear the cache and move on
-        clearing = true;
+        None => {
+            // Server not reporting a hash, something has gone wrong. Reset everything
+            *last_hash = None;
+            true
+        }
+    }
+}
+
+async fn connect_upstream(url: &str) -> Result<ScheduleClient<Channel>, ScheduleError> {
+    let max_message_bytes = CACHER_CONFIG.read().await.max_message_bytes;
+
+    Ok(ScheduleClient::connect(url.to_owned())
+        .await?
+        .max_decoding_message_size(max_message_bytes))
+}
+
+/// Whether the call failed because the connection to upstream is gone, rather than upstream
+/// answering with an error
+fn is_broken_channel(status: &Status) -> bool {
+    status.code() == Code::Unavailable
+}
+
+/// Asks upstream for its latest update. A broken channel is dropped and replaced with a new
+/// connection to `url`, leaving `client` unset if none could be made. Connecting goes through the
+/// breaker like any other upstream call, so while upstream is down it's retried once per update
+/// check until the circuit opens, rather than with a backoff each check has to wait through. Also
+/// `None` if the circuit is open
+async fn fetch_last_update(
+    client: &RwLock<Option<ScheduleClient<Channel>>>,
+    url: &str,
+) -> Option<Result<LastUpdateResponse, Status>> {
+    // Copies share the same channel, calls are made on one so the lock isn't held while waiting
+    let current = client.read().await.clone();
+    if let Some(mut current) = current {
+        match guarded(&UPSTREAM_BREAKER, || {
+            current.get_last_update(LastUpdateRequest {})
+        })
+        .await?
+        {
+            Err(e) if is_broken_channel(&e) => {
+                warn!("Channel to upstream broke, reconnecting: {}", e);
+                *client.write().await = None;
+            }
+            res => return Some(res.map(|rsp| rsp.into_inner())),
+        }
     }
 
+    let mut current = match guarded(&UPSTREAM_BREAKER, || connect_upstream(url)).await? {
+        Ok(new_client) => {
+            info!("Connected to upstream");
+            client.write().await.insert(new_client).clone()
+        }
+        Err(e) => {
+            warn!("Unable to connect to upstream: {}", e);
+            return None;
+        }
+    };
+
+    guarded(&UPSTREAM_BREAKER, || {
+        current.get_last_update(LastUpdateRequest {})
+    })
+    .await
+    .map(|res| res.map(|rsp| rsp.into_inner()))
+}
+
+/// Clears the cache if upstream's feed changed or it can't be reached at all
+async fn refresh_cache(client: &RwLock<Option<ScheduleClient<Channel>>>, url: &str) {
+    let clearing = match fetch_last_update(client, url).await {
+        Some(Ok(rsp)) => is_cache_stale(rsp).await,
+        // Upstream is down, the cache is still the newest schedule we know of. If it changed
+        // the hash check clears it once upstream is back
+        Some(Err(e)) => {
+            warn!("Unable to check for updates, keeping cache: {}", e);
+            false
+        }
+        // Circuit is open, same as above
+        None if client.read().await.is_some() => false,
+        // Unable to reconnect, clear the cache and move on
+        None => true,
+    };
+
     if clearing {
         warn!("Found an issue, clearing cache");
         CACHED_SCHEDULE.write().await.clear();
     }
 }
 
-/// Converts request body into a vector of raw bytes
-async fn decode_body(
-    mut body: hyper::body::Incoming,
-) -> Result<(Vec<u8>, HeaderMap), ScheduleError> {
+async fn check_cache_validity() {
+    let upstream_url = CACHER_CONFIG.read().await.upstream_url.clone();
+
+    refresh_cache(&GRPC_CLIENT, &upstream_url).await;
+}
+
+/// Converts request body into a vector of raw bytes, failing as soon as it goes over `limit` bytes
+async fn decode_body<B>(
+    mut body: B,
+    limit: Option<usize>,
+) -> Result<(Vec<u8>, HeaderMap), ScheduleError>
+where
+    B: Body<Data = Bytes> + Unpin,
+    ScheduleError: From<B::Error>,
+{
     let mut v = Vec::new();
     let mut t = HeaderMap::new();
 
     while let Some(next) = body.frame().await {
         let frame = next?;
         if let Some(chunk) = frame.data_ref() {
+            if let Some(limit) = limit
+                && v.len() + chunk.len() > limit
+            {
+                return Err(ScheduleError::RequestTooLarge(limit));
+            }
+
             v.write_all(chunk)?;
         } else if let Some(trailer) = frame.trailers_ref() {
             for (hn, hv) in trailer.iter() {
@@ -107,16 +215,53 @@ async fn decode_body(
     Ok((v, t))
 }
 
+/// Replays a stored upstream response. The body is stored as sent, still compressed if upstream
+/// compressed it, so all headers including `grpc-encoding` must be kept for clients to decode it
 fn form_response(bvec: &Vec<u8>, headers: HeaderMap, trailers: HeaderMap) -> Response<BodyType> {
     let body = Full::new(Bytes::from_iter(bvec.into_iter().cloned()))
         .with_trailers(ready(Some(Ok(trailers))));
     let mut resp = Response::new(body);
+    *resp.headers_mut() = headers;
 
-    for (hn, hv) in headers.iter() {
-        resp.headers_mut().insert(hn, hv.clone());
+    resp
+}
+
+/// Key for a request in the cache. Upstream picks the response compression from the accepted
+/// encodings, so requests only match if they accept the same ones
+fn cache_key(req_headers: &HeaderMap, req_body: &[u8]) -> Vec<u8> {
+    let mut key = Vec::new();
+
+    for accepted in req_headers.get_all("grpc-accept-encoding") {
+        key.extend_from_slice(accepted.as_bytes());
+        key.push(b',');
     }
+    // Header values can't contain a NUL, so it can't be confused with part of the encodings
+    key.push(0);
+    key.extend_from_slice(req_body);
 
-    resp
+    key
+}
+
+async fn cached_response(key: &[u8]) -> Option<Response<BodyType>> {
+    CACHED_SCHEDULE
+        .read()
+        .await
+        .get(key)
+        .map(|(bvec, headers, trailers)| form_response(bvec, headers.clone(), trailers.clone()))
+}
+
+/// Response with no messages carrying a gRPC error status, as sent by a gRPC server
+fn grpc_error_response(code: u32, message: &str) -> Response<BodyType> {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(code));
+    if let Ok(message) = HeaderValue::from_str(message) {
+        trailers.insert("grpc-message", message);
+    }
+
+    form_response(&Vec::new(), headers, trailers)
 }
 
 async fn add_cached_value(key: Vec<u8>, bvec: Vec<u8>, headers: HeaderMap, trailers: HeaderMap) {
@@ -132,6 +277,29 @@ async fn add_cached_value(key: Vec<u8>, bvec: Vec<u8>, headers: HeaderMap, trail
     cache.insert(key, (bvec, headers, trailers));
 }
 
+/// Caches an upstream response for next time unless it's over `max_cached_entry_bytes`, replaying
+/// it to the client either way
+async fn cache_and_respond(
+    key: Vec<u8>,
+    bvec: Vec<u8>,
+    headers: HeaderMap,
+    trailers: HeaderMap,
+) -> Response<BodyType> {
+    let max_cached_entry_bytes = CACHER_CONFIG.read().await.max_cached_entry_bytes;
+
+    if bvec.len() > max_cached_entry_bytes {
+        info!(
+            "Response of {} bytes too large to cache, limit is {}",
+            bvec.len(),
+            max_cached_entry_bytes
+        );
+    } else {
+        add_cached_value(key, bvec.clone(), headers.clone(), trailers.clone()).await;
+    }
+
+    form_response(&bvec, headers, trailers)
+}
+
 async fn serve_schedule(
     req: Request<hyper::body::Incoming>,
 ) -> Result<Response<BodyType>, ScheduleError> {
@@ -143,15 +311,36 @@ async fn serve_schedule(
     check_cache_validity().await;
 
     let req_headers = req.headers().clone();
-    let (req_body, _) = decode_body(req.into_body()).await?;
+    let max_request_bytes = CACHER_CONFIG.read().await.max_request_bytes;
+    let (req_body, _) = match decode_body(req.into_body(), Some(max_request_bytes)).await {
+        Ok(decoded) => decoded,
+        Err(ScheduleError::RequestTooLarge(limit)) => {
+            warn!("Rejecting request larger than {} bytes", limit);
+            return Ok(grpc_error_response(
+                GRPC_RESOURCE_EXHAUSTED,
+                &format!("Request body larger than {} bytes", limit),
+            ));
+        }
+        Err(e) => return Err(e),
+    };
 
-    if let Some((bvec, headers, trailers)) = CACHED_SCHEDULE.read().await.get(&req_body) {
+    let key = cache_key(&req_headers, &req_body);
+
+    if let Some(resp) = cached_response(&key).await {
         info!("Cache hit found");
+        CACHE_HITS.fetch_add(1, Ordering::Relaxed);
 
-        Ok(form_response(bvec, headers.clone(), trailers.clone()))
+        Ok(resp)
     } else {
+        CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+
         // Upstream response
-        let mut upstream_req = hyper::Request::builder().method("POST").uri(GRPC_FULL_URL);
+        let upstream_url = format!(
+            "{}{}",
+            CACHER_CONFIG.read().await.upstream_url,
+            GRPC_URL_PATH
+        );
+        let mut upstream_req = hyper::Request::builder().method("POST").uri(upstream_url);
 
         for (hn, hv) in req_headers.iter() {
             upstream_req = upstream_req.header(hn, hv);
@@ -162,31 +351,113 @@ async fn serve_schedule(
 
         info!("Forwarding request upstream: {:?}", upstream_req);
 
-        let upstream_resp = HTTP_CLIENT.request(upstream_req).await?;
+        let Some(upstream_resp) =
+            guarded(&UPSTREAM_BREAKER, || HTTP_CLIENT.request(upstream_req)).await
+        else {
+            warn!("Circuit open, not forwarding request upstream");
+            return Ok(grpc_error_response(
+                GRPC_UNAVAILABLE,
+                "Upstream unavailable, retry later",
+            ));
+        };
+        let upstream_resp = upstream_resp?;
 
         let headers = upstream_resp.headers().clone();
-        let (bvec, trailers) = decode_body(upstream_resp.into_body()).await?;
-
-        // Cache value for next time
-        add_cached_value(req_body, bvec.clone(), headers.clone(), trailers.clone()).await;
+        let (bvec, trailers) = decode_body(upstream_resp.into_body(), None).await?;
 
-        Ok(form_response(&bvec, headers, trailers))
+        Ok(cache_and_respond(key, bvec, headers, trailers).await)
     }
 }
 
-pub async fn cacher_serve_loop() -> Result<(), ScheduleError> {
-    let grpc_client = ScheduleClient::connect(GRPC_BASE_URL).await?;
-    *(GRPC_CLIENT.write().await) = Some(grpc_client);
+/// Current cacher metrics in the Prometheus text format, see `exporter::metrics_loop`
+pub fn render_metrics() -> BoxFuture<'static, String> {
+    Box::pin(async {
+        let hits = CACHE_HITS.load(Ordering::Relaxed);
+        let misses = CACHE_MISSES.load(Ordering::Relaxed);
+        // No requests yet counts as no hits
+        let hit_ratio = if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        };
+
+        let mut text = MetricsText::new();
+        text.counter(
+            "transit_cacher_connections_total",
+            "Connections accepted",
+            ACCEPTED_CONNECTIONS.load(Ordering::Relaxed),
+        )
+        .counter(
+            "transit_cache_hits_total",
+            "Requests answered from the cache",
+            hits,
+        )
+        .counter(
+            "transit_cache_misses_total",
+            "Requests forwarded upstream",
+            misses,
+        )
+        .gauge(
+            "transit_cache_hit_ratio",
+            "Share of requests answered from the cache",
+            hit_ratio,
+        )
+        .gauge(
+            "transit_cache_entries",
+            "Responses currently cached",
+            CACHED_SCHEDULE.read().await.len() as f64,
+        );
+
+        text.into_string()
+    })
+}
 
-    // Set up server
-    let addr: SocketAddr = "[::1]:50051".parse()?;
+pub async fn cacher_serve_loop() -> Result<(), ScheduleError> {
+    let (addr, upstream_url, max_connections) = {
+        let config = CACHER_CONFIG.read().await;
+        (
+            config.listen_addr,
+            config.upstream_url.clone(),
+            config.max_connections,
+        )
+    };
+
+    // Upstream being down at startup isn't fatal, update checks keep trying to connect
+    match connect_upstream(&upstream_url).await {
+        Ok(grpc_client) => *(GRPC_CLIENT.write().await) = Some(grpc_client),
+        Err(e) => warn!(
+            "Unable to connect to upstream, retrying on first request: {}",
+            e
+        ),
+    }
 
-    // We create a TcpListener and bind it to [::1]:50051
     let listener = TcpListener::bind(addr).await?;
 
-    // We start a loop to continuously accept incoming connections
+    serve_connections(
+        listener,
+        Arc::new(Semaphore::new(max_connections)),
+        ACCEPTED_CONNECTIONS.clone(),
+    )
+    .await
+}
+
+/// Serves every connection on `listener`, at most as many at once as `limit` has permits. Once
+/// they're used up no more connections are accepted until one closes, so the excess waits in the
+/// listen backlog instead of each getting a task. Each accepted connection is counted in
+/// `accepted`
+pub(crate) async fn serve_connections(
+    listener: TcpListener,
+    limit: Arc<Semaphore>,
+    accepted: Arc<AtomicU64>,
+) -> Result<(), ScheduleError> {
     loop {
+        let permit = limit
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Connection limit semaphore is never closed");
         let (stream, _) = listener.accept().await?;
+        accepted.fetch_add(1, Ordering::Relaxed);
 
         // Use an adapter to access something implementing `tokio::io` traits as if they implement
         // `hyper::rt` IO traits.
@@ -202,6 +473,8 @@ pub async fn cacher_serve_loop() -> Result<(), ScheduleError> {
             {
                 error!("Error serving connection: {}", err);
             }
+
+            drop(permit);
         });
     }
 }
@@ -0,0 +1,123 @@
+use std::time::{Duration, Instant};
+
+use logge_rs::{info, warn};
+use tokio::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests go upstream as usual
+    Closed,
+    /// Upstream failed too many times in a row, requests aren't attempted until the cool-down ends
+    Open,
+    /// Cool-down is over, a single request at a time probes whether upstream has recovered
+    HalfOpen,
+}
+
+/// Stops calling upstream after `failure_threshold` failures in a row, for `cool_down`
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cool_down: Duration,
+
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    // When the probe in flight while half-open started. One that hasn't finished within a cool-down
+    // is taken to have been dropped, so another can go
+    probe_started: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub const fn new(failure_threshold: u32, cool_down: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cool_down,
+            consecutive_failures: 0,
+            opened_at: None,
+            probe_started: None,
+        }
+    }
+
+    pub fn state(&self, now: Instant) -> CircuitState {
+        match self.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if now.duration_since(opened_at) < self.cool_down => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    pub fn allows_request(&self, now: Instant) -> bool {
+        match self.state(now) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => self
+                .probe_started
+                .is_none_or(|started| now.duration_since(started) >= self.cool_down),
+        }
+    }
+
+    /// Whether a request may go upstream, like `allows_request`. While half-open the request
+    /// becomes the probe, and the rest are turned away until it's recorded
+    pub fn start_request(&mut self, now: Instant) -> bool {
+        if !self.allows_request(now) {
+            return false;
+        }
+
+        if self.state(now) == CircuitState::HalfOpen {
+            self.probe_started = Some(now);
+        }
+
+        true
+    }
+
+    pub fn record_success(&mut self) {
+        if self.opened_at.is_some() {
+            info!("Upstream recovered, closing circuit");
+        }
+
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+        self.probe_started = None;
+    }
+
+    /// A failed probe while half-open opens the circuit again for another cool-down
+    pub fn record_failure(&mut self, now: Instant) {
+        self.consecutive_failures += 1;
+        self.probe_started = None;
+
+        if self.consecutive_failures >= self.failure_threshold {
+            if self.opened_at.is_none() {
+                warn!(
+                    "Upstream failed {} times in a row, opening circuit",
+                    self.consecutive_failures
+                );
+            }
+
+            self.opened_at = Some(now);
+        }
+    }
+}
+
+/// Runs `request` unless the circuit is open, recording whether it succeeded. Returns `None`
+/// without running it if the circuit is open, or half-open with a probe already in flight
+pub async fn guarded<T, E, F>(
+    breaker: &Mutex<CircuitBreaker>,
+    request: impl FnOnce() -> F,
+) -> Option<Result<T, E>>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    if !breaker.lock().await.start_request(Instant::now()) {
+        return None;
+    }
+
+    // Lock isn't held during the request so requests still run concurrently
+    let res = request().await;
+
+    let mut breaker = breaker.lock().await;
+    match res {
+        Ok(_) => breaker.record_success(),
+        Err(_) => breaker.record_failure(Instant::now()),
+    }
+
+    Some(res)
+}
@@ -0,0 +1,402 @@
+#![cfg(test)]
+
+use std::{
+    convert::Infallible,
+    io::{Read, Write},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{
+    HeaderMap,
+    body::{Bytes, Frame},
+    header::HeaderValue,
+    server::conn::http2,
+    service::service_fn,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use prost::Message;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, MutexGuard, RwLock, Semaphore},
+    task::JoinSet,
+    time::sleep,
+};
+
+use crate::{
+    config::{CACHER_CONFIG, CacherConfig},
+    error::ScheduleError,
+    server::db_transit::{LastUpdateResponse, ScheduleResponse},
+};
+
+use super::breaker::{CircuitBreaker, CircuitState, guarded};
+use super::{
+    add_cached_value, cache_and_respond, cache_key, cached_response, decode_body, form_response,
+    is_cache_stale, refresh_cache, serve_connections,
+};
+
+// Held by tests that go through the shared cache or feed hash
+static CACHE_STATE: Mutex<()> = Mutex::const_new(());
+
+// Holds `CACHE_STATE`, and puts the cacher config back the way it was once dropped, even if the
+// test panicked
+pub(crate) struct CacheStateGuard {
+    config: CacherConfig,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl CacheStateGuard {
+    pub(crate) async fn lock() -> Self {
+        let lock = CACHE_STATE.lock().await;
+
+        Self {
+            config: CACHER_CONFIG.read().await.clone(),
+            _lock: lock,
+        }
+    }
+}
+
+impl Drop for CacheStateGuard {
+    fn drop(&mut self) {
+        // Only fails if a task the test spawned is still holding the config
+        if let Ok(mut config) = CACHER_CONFIG.try_write() {
+            *config = self.config.clone();
+        }
+    }
+}
+
+fn resp(timestamp: u32, hash: &[u8]) -> LastUpdateResponse {
+    LastUpdateResponse {
+        timestamp: Some(timestamp),
+        hash: Some(hash.to_vec()),
+        diff_timestamps: vec![],
+    }
+}
+
+#[tokio::test]
+async fn test_cache_keyed_on_hash() {
+    let _guard = CacheStateGuard::lock().await;
+
+    // First response always invalidates, nothing has been recorded yet
+    assert!(is_cache_stale(resp(100, b"aaaa")).await);
+
+    // Same feed re-fetched later, cache should survive
+    assert!(!is_cache_stale(resp(200, b"aaaa")).await);
+
+    // New feed content
+    assert!(is_cache_stale(resp(200, b"bbbb")).await);
+    assert!(!is_cache_stale(resp(300, b"bbbb")).await);
+
+    // Missing hash resets, so the next real hash invalidates again
+    assert!(
+        is_cache_stale(LastUpdateResponse {
+            timestamp: Some(300),
+            hash: None,
+            diff_timestamps: vec![],
+        })
+        .await
+    );
+    assert!(is_cache_stale(resp(300, b"bbbb")).await);
+}
+
+#[tokio::test]
+async fn test_gzip_response_round_trip() {
+    let _guard = CacheStateGuard::lock().await;
+
+    let message = ScheduleResponse {
+        timestamp: Some(1234),
+        ..Default::default()
+    };
+
+    // Length-prefixed gRPC frame with the compressed flag set
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&message.encode_to_vec()).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let mut frame = vec![1];
+    frame.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&compressed);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/grpc"));
+    headers.insert("grpc-encoding", HeaderValue::from_static("gzip"));
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from_static("0"));
+
+    let mut req_headers = HeaderMap::new();
+    req_headers.insert("grpc-accept-encoding", HeaderValue::from_static("gzip"));
+    let key = cache_key(&req_headers, b"request");
+
+    add_cached_value(key.clone(), frame, headers, trailers).await;
+
+    // A client that can't decode gzip mustn't be given the stored response
+    assert!(
+        cached_response(&cache_key(&HeaderMap::new(), b"request"))
+            .await
+            .is_none()
+    );
+
+    let resp = cached_response(&key).await.unwrap();
+    assert_eq!(resp.headers().get("grpc-encoding").unwrap(), "gzip");
+
+    let collected = resp.into_body().collect().await.unwrap();
+    assert_eq!(
+        collected.trailers().unwrap().get("grpc-status").unwrap(),
+        "0"
+    );
+
+    let body = collected.to_bytes();
+    assert_eq!(body[0], 1);
+    let len = u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize;
+
+    let mut decoded = Vec::new();
+    GzDecoder::new(&body[5..5 + len])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(ScheduleResponse::decode(&decoded[..]).unwrap(), message);
+}
+
+#[tokio::test]
+async fn test_oversized_request_rejected() {
+    // 100 chunks of 1 KiB, counting how many get read
+    let polled = Arc::new(AtomicUsize::new(0));
+    let counter = polled.clone();
+    let chunks = tokio_stream::iter((0..100).map(move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Ok::<_, Infallible>(Frame::data(Bytes::from(vec![0u8; 1024])))
+    }));
+
+    let res = decode_body(StreamBody::new(chunks), Some(10 * 1024)).await;
+
+    assert!(matches!(res, Err(ScheduleError::RequestTooLarge(10240))));
+    // Stopped at the first chunk over the limit
+    assert_eq!(polled.load(Ordering::SeqCst), 11);
+
+    let chunks = tokio_stream::iter(
+        (0..10).map(|_| Ok::<_, Infallible>(Frame::data(Bytes::from(vec![0u8; 1024])))),
+    );
+    let (body, _) = decode_body(StreamBody::new(chunks), Some(10 * 1024))
+        .await
+        .unwrap();
+    assert_eq!(body.len(), 10 * 1024);
+}
+
+#[tokio::test]
+async fn test_connection_limit() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let limit = Arc::new(Semaphore::new(2));
+    let accepted = Arc::new(AtomicU64::new(0));
+    tokio::spawn(serve_connections(listener, limit.clone(), accepted.clone()));
+    // Lets the accept loop catch up
+    let settle = || sleep(Duration::from_millis(100));
+
+    let mut streams = Vec::new();
+    for _ in 0..3 {
+        streams.push(TcpStream::connect(addr).await.unwrap());
+    }
+    settle().await;
+
+    // The third connection waits until one of the first two closes
+    assert_eq!(accepted.load(Ordering::Relaxed), 2);
+    assert_eq!(limit.available_permits(), 0);
+
+    drop(streams.remove(0));
+    settle().await;
+    assert_eq!(accepted.load(Ordering::Relaxed), 3);
+    assert_eq!(limit.available_permits(), 0);
+
+    drop(streams);
+    settle().await;
+    assert_eq!(limit.available_permits(), 2);
+}
+
+#[test]
+fn test_circuit_breaker_states() {
+    let start = Instant::now();
+    let mut breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+
+    breaker.record_failure(start);
+    breaker.record_failure(start);
+    assert_eq!(breaker.state(start), CircuitState::Closed);
+    breaker.record_failure(start);
+    assert_eq!(breaker.state(start), CircuitState::Open);
+    assert!(!breaker.allows_request(start + Duration::from_secs(29)));
+
+    // Failed probe, back to waiting out a full cool-down
+    let probe = start + Duration::from_secs(31);
+    assert_eq!(breaker.state(probe), CircuitState::HalfOpen);
+    breaker.record_failure(probe);
+    assert_eq!(
+        breaker.state(probe + Duration::from_secs(29)),
+        CircuitState::Open
+    );
+
+    breaker.record_success();
+    assert_eq!(breaker.state(probe), CircuitState::Closed);
+}
+
+#[test]
+fn test_circuit_breaker_single_probe() {
+    let start = Instant::now();
+    let mut breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+    breaker.record_failure(start);
+
+    let probe = start + Duration::from_secs(31);
+    assert!(breaker.start_request(probe));
+    assert!(!breaker.start_request(probe));
+    assert!(!breaker.allows_request(probe + Duration::from_secs(29)));
+
+    // A probe that never finished doesn't keep the circuit shut for good
+    let retry = probe + Duration::from_secs(30);
+    assert!(breaker.start_request(retry));
+    breaker.record_success();
+    assert!(breaker.start_request(retry));
+    assert!(breaker.start_request(retry));
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_skips_upstream() {
+    let breaker = Mutex::new(CircuitBreaker::new(2, Duration::from_millis(100)));
+    let calls = AtomicUsize::new(0);
+    let upstream = |ok: bool| {
+        calls.fetch_add(1, Ordering::Relaxed);
+        async move { if ok { Ok(()) } else { Err(()) } }
+    };
+
+    assert_eq!(guarded(&breaker, || upstream(false)).await, Some(Err(())));
+    assert_eq!(guarded(&breaker, || upstream(false)).await, Some(Err(())));
+
+    // Open, upstream isn't called at all
+    for _ in 0..5 {
+        assert_eq!(guarded(&breaker, || upstream(true)).await, None);
+    }
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+    sleep(Duration::from_millis(150)).await;
+    // Only the first request after the cool-down probes upstream, the rest wait for its result
+    let slow_upstream = || {
+        calls.fetch_add(1, Ordering::Relaxed);
+        async {
+            sleep(Duration::from_millis(50)).await;
+            Ok::<(), ()>(())
+        }
+    };
+    let (probe, waiting) = tokio::join!(guarded(&breaker, slow_upstream), async {
+        sleep(Duration::from_millis(10)).await;
+        guarded(&breaker, || upstream(true)).await
+    });
+    assert_eq!(probe, Some(Ok(())));
+    assert_eq!(waiting, None);
+    assert_eq!(calls.load(Ordering::Relaxed), 3);
+    assert_eq!(
+        breaker.lock().await.state(Instant::now()),
+        CircuitState::Closed
+    );
+}
+
+// Answers every request with a `LastUpdateResponse` for `hash`, like the server would. Aborting it
+// drops every open connection along with the listener
+async fn fake_upstream(listener: TcpListener, hash: &'static [u8]) {
+    let mut connections = JoinSet::new();
+
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let service = service_fn(move |_| async move {
+            let message = resp(100, hash).encode_to_vec();
+            let mut frame = vec![0];
+            frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&message);
+
+            let mut headers = HeaderMap::new();
+            headers.insert("content-type", HeaderValue::from_static("application/grpc"));
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+
+            Ok::<_, Infallible>(form_response(&frame, headers, trailers))
+        });
+
+        connections.spawn(
+            http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service),
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_reconnects_after_upstream_restart() {
+    let _guard = CacheStateGuard::lock().await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let url = format!("http://{}", addr);
+    let upstream = tokio::spawn(fake_upstream(listener, b"restart"));
+
+    let client = RwLock::new(None);
+    let key = cache_key(&HeaderMap::new(), b"reconnect");
+    // Records the hash, clearing anything cached before it
+    refresh_cache(&client, &url).await;
+    assert!(client.read().await.is_some());
+    add_cached_value(
+        key.clone(),
+        b"cached".to_vec(),
+        HeaderMap::new(),
+        HeaderMap::new(),
+    )
+    .await;
+
+    // Upstream restarts on the same address, the client's connection is gone
+    upstream.abort();
+    let _ = upstream.await;
+    let upstream = tokio::spawn(fake_upstream(
+        TcpListener::bind(addr).await.unwrap(),
+        b"restart",
+    ));
+
+    refresh_cache(&client, &url).await;
+    assert!(client.read().await.is_some());
+    assert!(cached_response(&key).await.is_some());
+
+    // Nothing left to reconnect to, the cacher clears instead of waiting for it
+    upstream.abort();
+    let _ = upstream.await;
+
+    refresh_cache(&client, &url).await;
+    assert!(client.read().await.is_none());
+    assert!(cached_response(&key).await.is_none());
+}
+
+#[tokio::test]
+async fn test_oversized_entry_not_cached() {
+    let _guard = CacheStateGuard::lock().await;
+    CACHER_CONFIG.write().await.max_cached_entry_bytes = 1024;
+
+    let small = cache_key(&HeaderMap::new(), b"small entry");
+    let large = cache_key(&HeaderMap::new(), b"large entry");
+    let small_resp = cache_and_respond(
+        small.clone(),
+        vec![1; 1024],
+        HeaderMap::new(),
+        HeaderMap::new(),
+    )
+    .await;
+    let large_resp = cache_and_respond(
+        large.clone(),
+        vec![2; 1025],
+        HeaderMap::new(),
+        HeaderMap::new(),
+    )
+    .await;
+
+    // Both are passed on, only the one within the limit is kept
+    let small_body = small_resp.into_body().collect().await.unwrap().to_bytes();
+    let large_body = large_resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(small_body, vec![1; 1024]);
+    assert_eq!(large_body, vec![2; 1025]);
+    assert!(cached_response(&small).await.is_some());
+    assert!(cached_response(&large).await.is_none());
+}
@@ -0,0 +1,348 @@
+use std::{
+    borrow::Cow,
+    env,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use logge_rs::warn;
+use tokio::sync::RwLock;
+
+use crate::{
+    diff::ir::{DEFAULT_SERVICE_DAY_CUTOFF_HOUR, ScheduleOptions},
+    server::HistoryLimits,
+};
+
+const DEFAULT_FEED_URL: &str = "https://rrgtfsfeeds.s3.amazonaws.com/gtfs_supplemented.zip";
+// Regular scheduled feed, without the supplemented feed's service changes
+const DEFAULT_FALLBACK_FEED_URL: &str = "https://rrgtfsfeeds.s3.amazonaws.com/gtfs_subway.zip";
+const DEFAULT_LISTEN_ADDR: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 50052);
+const DEFAULT_CACHER_LISTEN_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 50051);
+// The server's default address
+const DEFAULT_UPSTREAM_URL: &str = "http://localhost:50052";
+const DEFAULT_MAX_HISTORY_LEN: usize = 10;
+const DEFAULT_MAX_RETAINED_HISTORY_LEN: usize = 20;
+// About how often the slowest clients poll
+const DEFAULT_RETAIN_REQUESTED_SECS: u32 = 3600;
+const DEFAULT_MAX_TRIP_CHURN: f64 = 0.5;
+const DEFAULT_MAX_CONCURRENT_PARSES: usize = 1;
+const DEFAULT_STALE_CONTENT_HOURS: u32 = 6;
+const DEFAULT_MIN_COMPRESS_BYTES: usize = 1024;
+const DEFAULT_MAX_DIFF_AGAINST_BYTES: usize = 16 * 1024 * 1024;
+const DEFAULT_MAX_CONCURRENT_DIFFS: usize = 2;
+const DEFAULT_MAX_REQUEST_BYTES: usize = 4 * 1024 * 1024;
+const DEFAULT_MAX_CONNECTIONS: usize = 256;
+// Fits a full schedule, anything bigger would crowd out the diffs most clients ask for
+const DEFAULT_MAX_CACHED_ENTRY_BYTES: usize = 8 * 1024 * 1024;
+// Full schedules run past tonic's 4 MiB default. Server and cacher read the same
+// `TRANSIT_MAX_MESSAGE_BYTES` so they agree on it
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024 * 1024;
+
+// Settings for the schedule server, read by the update loop on every update so they can be changed
+// while running
+pub static SERVER_CONFIG: RwLock<ServerConfig> = RwLock::const_new(ServerConfig::new());
+
+// Settings for the caching proxy
+pub static CACHER_CONFIG: RwLock<CacherConfig> = RwLock::const_new(CacherConfig::new());
+
+/// Feed served as its own `<name>.Schedule` service next to the main one
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedConfig {
+    pub name: String,
+    pub feed_url: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// Supplemented feed the schedule is built from
+    pub feed_url: Cow<'static, str>,
+    /// Feed used when the supplemented one can't be fetched, unset disables the fallback
+    pub fallback_feed_url: Option<Cow<'static, str>>,
+    /// Number of past schedule states clients can receive a diff from
+    pub max_history_len: usize,
+    /// Number of past states kept at most, holding on to ones clients still ask for past
+    /// `max_history_len`
+    pub max_retained_history_len: usize,
+    /// The oldest state isn't evicted while a client reported being on it within this many seconds,
+    /// so clients polling less often than the history turns over still get diffs. 0 disables it
+    pub retain_requested_secs: u32,
+    /// Merge shapes with identical points when building the schedule
+    pub dedup_shapes: bool,
+    /// Clean up headsigns that only differ in whitespace or case when building the schedule
+    pub normalize_headsigns: bool,
+    /// Turn the feed's frequencies.txt into one trip per departure when building the schedule
+    pub expand_frequencies: bool,
+    /// Fill in stop times missing an arrival or departure, and interpolate ones missing both
+    pub fill_stop_times: bool,
+    /// Hours the feed contents can go unchanged before the schedule is reported as stale by the
+    /// `transit_content_stale` metric
+    pub stale_content_hours: u32,
+    /// Trips not active within this many days are kept without stop times, 32 keeps everything
+    pub min_active_within_days: u8,
+    /// Where to fetch the expected blake3 checksum of the main feed from, unset skips the check.
+    /// Extra feeds are never checked
+    pub feed_manifest_url: Option<String>,
+    /// Responses smaller than this are sent uncompressed, where compression costs more than it
+    /// saves. The compression level itself isn't configurable, tonic doesn't expose it
+    pub min_compress_bytes: usize,
+    /// Largest gRPC message encoded or decoded, before compression
+    pub max_message_bytes: usize,
+    /// Directory evicted history entries are written to, unset drops them
+    pub archive_dir: Option<PathBuf>,
+    /// Feeds updated and served alongside the main one, set as `name=url` pairs separated by
+    /// commas. They have no fallback, history archive or health reporting
+    pub extra_feeds: Vec<FeedConfig>,
+    /// Share of trips a single update can change before it's reported as suspicious, e.g. every
+    /// trip id being regenerated
+    pub max_trip_churn: f64,
+    /// Don't publish updates over `max_trip_churn`, keeping the previous schedule. They don't count
+    /// as successful updates, so the previous schedule still goes stale
+    pub suppress_trip_churn: bool,
+    /// Hour the service day starts, before it the previous day's trips are still served
+    pub service_day_cutoff_hour: u32,
+    /// Address Prometheus metrics are served on, unset disables them
+    pub metrics_addr: Option<SocketAddr>,
+    /// Feeds parsed at once, each holding a whole parsed feed in memory. Only read on the first
+    /// parse
+    pub max_concurrent_parses: usize,
+    /// Largest schedule a client can upload to `DiffAgainst`, encoded. Diffing one costs about as
+    /// much as an update, so this is well under `max_message_bytes`. It's the limit on every
+    /// request the service decodes, the others are all tiny. Only read on startup
+    pub max_diff_against_bytes: usize,
+    /// `DiffAgainst` requests diffed at once, further ones are rejected until one finishes. Only
+    /// read on the first of them
+    pub max_concurrent_diffs: usize,
+    /// Seconds after an update during which the next one replaces it in the history rather than
+    /// being added, so a feed republished several times in a row doesn't push out older states. 0
+    /// keeps every update
+    pub min_update_gap_secs: u32,
+    /// Address the gRPC service is served on. Only read on startup
+    pub listen_addr: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerConfig {
+    pub const fn new() -> Self {
+        Self {
+            feed_url: Cow::Borrowed(DEFAULT_FEED_URL),
+            fallback_feed_url: Some(Cow::Borrowed(DEFAULT_FALLBACK_FEED_URL)),
+            max_history_len: DEFAULT_MAX_HISTORY_LEN,
+            max_retained_history_len: DEFAULT_MAX_RETAINED_HISTORY_LEN,
+            retain_requested_secs: DEFAULT_RETAIN_REQUESTED_SECS,
+            dedup_shapes: false,
+            normalize_headsigns: false,
+            expand_frequencies: false,
+            fill_stop_times: false,
+            stale_content_hours: DEFAULT_STALE_CONTENT_HOURS,
+            min_active_within_days: 32,
+            feed_manifest_url: None,
+            min_compress_bytes: DEFAULT_MIN_COMPRESS_BYTES,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            archive_dir: None,
+            extra_feeds: Vec::new(),
+            max_trip_churn: DEFAULT_MAX_TRIP_CHURN,
+            suppress_trip_churn: false,
+            service_day_cutoff_hour: DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+            metrics_addr: None,
+            max_concurrent_parses: DEFAULT_MAX_CONCURRENT_PARSES,
+            max_diff_against_bytes: DEFAULT_MAX_DIFF_AGAINST_BYTES,
+            max_concurrent_diffs: DEFAULT_MAX_CONCURRENT_DIFFS,
+            min_update_gap_secs: 0,
+            listen_addr: DEFAULT_LISTEN_ADDR,
+        }
+    }
+
+    /// Defaults overridden by any `TRANSIT_*` environment variables that are set
+    pub fn from_env() -> Self {
+        let default = Self::new();
+
+        Self {
+            feed_url: env::var("TRANSIT_FEED_URL")
+                .map(Cow::Owned)
+                .unwrap_or(default.feed_url),
+            fallback_feed_url: env::var("TRANSIT_FALLBACK_FEED_URL")
+                .ok()
+                .map(Cow::Owned)
+                .or(default.fallback_feed_url),
+            max_history_len: env_or("TRANSIT_MAX_HISTORY_LEN", default.max_history_len),
+            max_retained_history_len: env_or(
+                "TRANSIT_MAX_RETAINED_HISTORY_LEN",
+                default.max_retained_history_len,
+            ),
+            retain_requested_secs: env_or(
+                "TRANSIT_RETAIN_REQUESTED_SECS",
+                default.retain_requested_secs,
+            ),
+            dedup_shapes: env_or("TRANSIT_DEDUP_SHAPES", default.dedup_shapes),
+            normalize_headsigns: env_or("TRANSIT_NORMALIZE_HEADSIGNS", default.normalize_headsigns),
+            expand_frequencies: env_or("TRANSIT_EXPAND_FREQUENCIES", default.expand_frequencies),
+            fill_stop_times: env_or("TRANSIT_FILL_STOP_TIMES", default.fill_stop_times),
+            stale_content_hours: env_or("TRANSIT_STALE_CONTENT_HOURS", default.stale_content_hours),
+            min_active_within_days: env_or(
+                "TRANSIT_MIN_ACTIVE_WITHIN_DAYS",
+                default.min_active_within_days,
+            ),
+            feed_manifest_url: env::var("TRANSIT_FEED_MANIFEST_URL")
+                .ok()
+                .or(default.feed_manifest_url),
+            min_compress_bytes: env_or("TRANSIT_MIN_COMPRESS_BYTES", default.min_compress_bytes),
+            max_message_bytes: env_or("TRANSIT_MAX_MESSAGE_BYTES", default.max_message_bytes),
+            archive_dir: env::var_os("TRANSIT_ARCHIVE_DIR")
+                .map(PathBuf::from)
+                .or(default.archive_dir),
+            extra_feeds: env::var("TRANSIT_EXTRA_FEEDS")
+                .map(|v| parse_feeds(&v))
+                .unwrap_or(default.extra_feeds),
+            max_trip_churn: env_or("TRANSIT_MAX_TRIP_CHURN", default.max_trip_churn),
+            suppress_trip_churn: env_or("TRANSIT_SUPPRESS_TRIP_CHURN", default.suppress_trip_churn),
+            service_day_cutoff_hour: env_or(
+                "TRANSIT_SERVICE_DAY_CUTOFF_HOUR",
+                default.service_day_cutoff_hour,
+            ),
+            metrics_addr: env_opt("TRANSIT_METRICS_ADDR").or(default.metrics_addr),
+            max_concurrent_parses: env_or(
+                "TRANSIT_MAX_CONCURRENT_PARSES",
+                default.max_concurrent_parses,
+            ),
+            max_diff_against_bytes: env_or(
+                "TRANSIT_MAX_DIFF_AGAINST_BYTES",
+                default.max_diff_against_bytes,
+            ),
+            max_concurrent_diffs: env_or(
+                "TRANSIT_MAX_CONCURRENT_DIFFS",
+                default.max_concurrent_diffs,
+            ),
+            min_update_gap_secs: env_or("TRANSIT_MIN_UPDATE_GAP_SECS", default.min_update_gap_secs),
+            listen_addr: env_or("TRANSIT_LISTEN_ADDR", default.listen_addr),
+        }
+    }
+
+    pub fn schedule_options(&self) -> ScheduleOptions {
+        ScheduleOptions {
+            dedup_shapes: self.dedup_shapes,
+            normalize_headsigns: self.normalize_headsigns,
+            expand_frequencies: self.expand_frequencies,
+            fill_stop_times: self.fill_stop_times,
+            min_active_within_days: Some(self.min_active_within_days),
+            service_day_cutoff_hour: self.service_day_cutoff_hour,
+            ..ScheduleOptions::default()
+        }
+    }
+
+    pub fn history_limits(&self) -> HistoryLimits {
+        HistoryLimits {
+            max_len: self.max_history_len,
+            max_retained_len: self.max_retained_history_len,
+            retain_requested_secs: self.retain_requested_secs,
+            min_update_gap: self.min_update_gap_secs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacherConfig {
+    /// Largest request body accepted from clients, larger ones are rejected while being read
+    pub max_request_bytes: usize,
+    /// Connections served at once, further ones wait to be accepted until one closes
+    pub max_connections: usize,
+    /// Largest gRPC message decoded from the server, should match the server's
+    pub max_message_bytes: usize,
+    /// Upstream responses larger than this are passed on to the client but not cached
+    pub max_cached_entry_bytes: usize,
+    /// Address Prometheus metrics are served on, unset disables them
+    pub metrics_addr: Option<SocketAddr>,
+    /// Address clients connect to. Only read on startup
+    pub listen_addr: SocketAddr,
+    /// Base URL of the server requests are forwarded to
+    pub upstream_url: Cow<'static, str>,
+}
+
+impl Default for CacherConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CacherConfig {
+    pub const fn new() -> Self {
+        Self {
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            max_cached_entry_bytes: DEFAULT_MAX_CACHED_ENTRY_BYTES,
+            metrics_addr: None,
+            listen_addr: DEFAULT_CACHER_LISTEN_ADDR,
+            upstream_url: Cow::Borrowed(DEFAULT_UPSTREAM_URL),
+        }
+    }
+
+    /// Defaults overridden by any `TRANSIT_*` environment variables that are set
+    pub fn from_env() -> Self {
+        let default = Self::new();
+
+        Self {
+            max_request_bytes: env_or("TRANSIT_MAX_REQUEST_BYTES", default.max_request_bytes),
+            max_connections: env_or("TRANSIT_MAX_CONNECTIONS", default.max_connections),
+            max_message_bytes: env_or("TRANSIT_MAX_MESSAGE_BYTES", default.max_message_bytes),
+            max_cached_entry_bytes: env_or(
+                "TRANSIT_MAX_CACHED_ENTRY_BYTES",
+                default.max_cached_entry_bytes,
+            ),
+            metrics_addr: env_opt("TRANSIT_CACHER_METRICS_ADDR").or(default.metrics_addr),
+            listen_addr: env_or("TRANSIT_CACHER_LISTEN_ADDR", default.listen_addr),
+            upstream_url: env::var("TRANSIT_UPSTREAM_URL")
+                .map(Cow::Owned)
+                .unwrap_or(default.upstream_url),
+        }
+    }
+}
+
+/// `name=url` pairs separated by commas, malformed pairs are skipped
+fn parse_feeds(value: &str) -> Vec<FeedConfig> {
+    value
+        .split(',')
+        .filter_map(|pair| match pair.trim().split_once('=') {
+            Some((name, feed_url)) if !name.is_empty() && !feed_url.is_empty() => {
+                Some(FeedConfig {
+                    name: name.to_owned(),
+                    feed_url: feed_url.to_owned(),
+                })
+            }
+            _ => {
+                warn!("Ignoring invalid feed {:?} in TRANSIT_EXTRA_FEEDS", pair);
+                None
+            }
+        })
+        .collect()
+}
+
+// Same as `env_or` for settings that are unset by default
+fn env_opt<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            warn!("Ignoring invalid value {:?} for {}", value, name);
+            None
+        }
+    }
+}
+
+// Reads and parses an environment variable, falling back to `default` if it's unset or invalid
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!("Ignoring invalid value {:?} for {}", value, name);
+            default
+        }),
+        Err(_) => default,
+    }
+}
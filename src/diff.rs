@@ -1,3 +1,10 @@
+pub mod builder;
+pub mod compact;
 pub mod core;
+pub mod frequencies;
+pub mod history;
 pub mod ir;
+pub mod metrics;
+pub mod query;
+pub mod stop_times;
 pub mod tests;
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use crate::server::db_transit::{Agency, Shape, Stop};
+
+use super::ir::{Id, RouteIR, RouteMetadata, ScheduleIR, TripIR};
+
+/// Assembles a `ScheduleIR` piece by piece, mostly useful for building test fixtures
+#[derive(Debug, Default)]
+pub struct ScheduleIRBuilder {
+    routes: HashMap<Id, RouteIR>,
+    shapes: HashMap<String, Shape>,
+    stops: HashMap<String, Stop>,
+    agencies: HashMap<String, Agency>,
+
+    // Route that calls to `trip` add to
+    current_route: Option<Id>,
+}
+
+impl ScheduleIR {
+    pub fn builder() -> ScheduleIRBuilder {
+        ScheduleIRBuilder::default()
+    }
+}
+
+impl ScheduleIRBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route with no trips, or selects it if it already exists. Following calls to `trip`
+    /// add to this route
+    pub fn route(mut self, route_id: impl Into<Id>) -> Self {
+        let route_id: Id = route_id.into();
+
+        self.routes
+            .entry(route_id.clone())
+            .or_insert_with(|| RouteIR {
+                route_id: route_id.clone(),
+                metadata: RouteMetadata::default(),
+                trips: HashMap::new().into(),
+            });
+        self.current_route = Some(route_id);
+
+        self
+    }
+
+    /// Sets the display details of the most recently selected route
+    pub fn route_metadata(mut self, metadata: RouteMetadata) -> Self {
+        let route_id = self
+            .current_route
+            .as_ref()
+            .expect("A route must be added before its metadata");
+
+        self.routes.get_mut(route_id).unwrap().metadata = metadata;
+
+        self
+    }
+
+    /// Adds a trip to the most recently selected route
+    pub fn trip(mut self, trip: TripIR) -> Self {
+        let route_id = self
+            .current_route
+            .as_ref()
+            .expect("A route must be added before its trips");
+
+        self.routes
+            .get_mut(route_id)
+            .unwrap()
+            .trips
+            .insert(trip.trip_id.clone(), trip);
+
+        self
+    }
+
+    pub fn stop(mut self, stop: Stop) -> Self {
+        let stop_id = stop.stop_id.clone().expect("Stop must have an id");
+        self.stops.insert(stop_id, stop);
+
+        self
+    }
+
+    pub fn shape(mut self, shape: Shape) -> Self {
+        let shape_id = shape.shape_id.clone().expect("Shape must have an id");
+        self.shapes.insert(shape_id, shape);
+
+        self
+    }
+
+    pub fn agency(mut self, agency: Agency) -> Self {
+        let agency_id = agency.agency_id.clone().unwrap_or_default();
+        self.agencies.insert(agency_id, agency);
+
+        self
+    }
+
+    pub fn build(self) -> ScheduleIR {
+        ScheduleIR {
+            routes: self.routes.into(),
+            shapes: self.shapes.into(),
+            stops: self.stops.into(),
+            agencies: self.agencies.into(),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use crate::server::db_transit::{
+    CompactFullSchedule, CompactRoute, CompactStopTime, CompactTrip, StopPattern, StopTime,
+};
+
+use super::ir::{RouteIR, ScheduleIR, TripIR, sorted_values};
+
+/// Hands out pattern ids while trips are converted to `CompactTrip`s, trips visiting the same
+/// stops with the same sequence numbers share one
+#[derive(Debug, Default)]
+pub struct StopPatternTable {
+    ids: HashMap<(Vec<String>, Vec<u32>), u32>,
+}
+
+impl StopPatternTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn intern(&mut self, stop_ids: Vec<String>, stop_sequences: Vec<u32>) -> u32 {
+        let next_id = self.ids.len() as u32;
+        *self
+            .ids
+            .entry((stop_ids, stop_sequences))
+            .or_insert(next_id)
+    }
+
+    /// Every pattern handed out so far, by id
+    pub fn into_patterns(self) -> Vec<StopPattern> {
+        let mut patterns: Vec<StopPattern> = self
+            .ids
+            .into_iter()
+            .map(|((stop_ids, stop_sequences), pattern_id)| StopPattern {
+                pattern_id: Some(pattern_id),
+                stop_ids,
+                stop_sequences,
+            })
+            .collect();
+        patterns.sort_by_key(|p| p.pattern_id);
+
+        patterns
+    }
+}
+
+impl TripIR {
+    /// Trip with its stops moved into a pattern in `patterns`
+    pub fn to_compact(&self, patterns: &mut StopPatternTable) -> CompactTrip {
+        let mut stop_ids = Vec::with_capacity(self.stop_times.len());
+        let mut stop_sequences = Vec::with_capacity(self.stop_times.len());
+        let mut stop_times = Vec::with_capacity(self.stop_times.len());
+
+        let mut keys: Vec<u32> = self.stop_times.keys().copied().collect();
+        keys.sort();
+        for key in keys {
+            let stop_time = &self.stop_times[&key];
+
+            stop_ids.push(stop_time.stop_id.as_deref().unwrap_or_default().to_owned());
+            stop_sequences.push(stop_time.stop_sequence.unwrap_or(key));
+            stop_times.push(CompactStopTime {
+                arrival_time: stop_time.arrival_time,
+                departure_time: stop_time.departure_time,
+            });
+        }
+
+        CompactTrip {
+            trip_id: Some(self.trip_id.to_string()),
+            headsign: self.headsign.as_deref().map(str::to_owned),
+            shape_id: self.shape_id.clone(),
+            pattern_id: Some(patterns.intern(stop_ids, stop_sequences)),
+            stop_times,
+            direction: self.direction,
+            mask_start_date: Some(self.mask_start_date.to_string()),
+            date_mask: Some(self.date_mask),
+        }
+    }
+}
+
+impl CompactTrip {
+    /// The trip's full stop times, given the pattern it references. `None` if the pattern has a
+    /// different number of stops than the trip has stop times
+    pub fn expand_stop_times(&self, pattern: &StopPattern) -> Option<Vec<StopTime>> {
+        if pattern.stop_ids.len() != self.stop_times.len()
+            || pattern.stop_sequences.len() != self.stop_times.len()
+        {
+            return None;
+        }
+
+        let stop_times = self
+            .stop_times
+            .iter()
+            .zip(&pattern.stop_ids)
+            .zip(&pattern.stop_sequences)
+            .map(|((times, stop_id), stop_sequence)| StopTime {
+                stop_id: (!stop_id.is_empty()).then(|| stop_id.clone()),
+                arrival_time: times.arrival_time,
+                departure_time: times.departure_time,
+                stop_sequence: Some(*stop_sequence),
+            })
+            .collect();
+
+        Some(stop_times)
+    }
+}
+
+impl RouteIR {
+    pub fn to_compact(&self, patterns: &mut StopPatternTable) -> CompactRoute {
+        CompactRoute {
+            route_id: Some(self.route_id.to_string()),
+            trips: sorted_values(&self.trips)
+                .map(|t| t.to_compact(patterns))
+                .collect(),
+            route_short_name: self.metadata.short_name.clone(),
+            route_long_name: self.metadata.long_name.clone(),
+            route_color: self.metadata.color.clone(),
+            route_type: self.metadata.route_type,
+        }
+    }
+}
+
+impl From<&ScheduleIR> for CompactFullSchedule {
+    fn from(value: &ScheduleIR) -> Self {
+        let mut patterns = StopPatternTable::new();
+        let routes = sorted_values(&value.routes)
+            .map(|r| r.to_compact(&mut patterns))
+            .collect();
+
+        Self {
+            routes,
+            shapes: sorted_values(&value.shapes).cloned().collect(),
+            stops: sorted_values(&value.stops).cloned().collect(),
+            agencies: sorted_values(&value.agencies).cloned().collect(),
+            stop_patterns: patterns.into_patterns(),
+        }
+    }
+}
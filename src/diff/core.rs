@@ -1,24 +1,161 @@
 use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 
-use crate::server::db_transit::{ScheduleDiff, Shape, Stop, TripExt, TripIdTuple};
+use logge_rs::error;
 
-use super::ir::{ScheduleIR, TripIR};
+use crate::error::ScheduleError;
+use crate::server::db_transit::{
+    AgencyList, DateMaskUpdate, Position, RouteMetadataUpdate, ScheduleDiff, Shape, ShapePointDiff,
+    Stop, TripExt, TripIdTuple,
+};
+
+use super::ir::{AgencyIRs, Id, MaskStartDate, RouteIR, RouteMetadata, ScheduleIR, TripIR};
+
+// (route_id, trip_id)
+pub type TripKey = (Id, Id);
+
+/// Replaces `removed` points starting at `start` with `points`, in a shape that had `prev_len`
+/// points
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointSplice {
+    pub start: usize,
+    pub removed: usize,
+    pub points: Vec<Position>,
+    pub prev_len: usize,
+}
+
+impl PointSplice {
+    /// Single splice turning `prev` into `points`, covering everything between their common prefix
+    /// and suffix. `None` if that's more than half of the new shape, sending it whole is simpler
+    pub fn between(prev: &[Position], points: &[Position]) -> Option<Self> {
+        let prefix = prev.iter().zip(points).take_while(|(a, b)| a == b).count();
+        let suffix = prev[prefix..]
+            .iter()
+            .rev()
+            .zip(points[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let inserted = &points[prefix..points.len() - suffix];
+        if inserted.len() * 2 > points.len() {
+            return None;
+        }
+
+        Some(Self {
+            start: prefix,
+            removed: prev.len() - prefix - suffix,
+            points: inserted.to_vec(),
+            prev_len: prev.len(),
+        })
+    }
+
+    /// Whether `points` is already the result of this splice
+    pub fn is_applied(&self, points: &[Position]) -> bool {
+        points.len() == self.prev_len - self.removed + self.points.len()
+            && points.get(self.start..self.start + self.points.len()) == Some(&self.points[..])
+    }
+
+    /// Splices into `points` if it's the shape this was made from. Shapes that already have the
+    /// spliced points are left as they are, so applying twice is the same as applying once. `false`
+    /// if `points` is neither, and wasn't changed
+    pub fn apply(&self, points: &mut Vec<Position>) -> bool {
+        if self.is_applied(points) {
+            return true;
+        }
+        if points.len() != self.prev_len || self.start + self.removed > self.prev_len {
+            return false;
+        }
+
+        points.splice(
+            self.start..self.start + self.removed,
+            self.points.iter().cloned(),
+        );
+        true
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// Don't count a trip as changed when only its service days differ, see
+    /// `TripIR::equals_ignoring_dates`. Clients keep the old days for such trips
+    pub ignore_dates: bool,
+}
+
+impl TripIR {
+    /// Compares everything except `date_mask` and `mask_start_date`
+    pub fn equals_ignoring_dates(&self, other: &Self) -> bool {
+        self.trip_id == other.trip_id
+            && self.stop_times == other.stop_times
+            && self.headsign == other.headsign
+            && self.shape_id == other.shape_id
+            && self.direction == other.direction
+    }
+}
 
 impl ScheduleIR {
     // In in this situation self is the newest
     pub fn get_diff(&self, prev: &Self) -> ScheduleUpdate {
+        self.get_diff_with_options(prev, &DiffOptions::default())
+    }
+
+    pub fn get_diff_with_options(&self, prev: &Self, options: &DiffOptions) -> ScheduleUpdate {
         let (added_stops, removed_stop_ids) = self.get_stop_diffs(prev);
-        let (added_shapes, removed_shape_ids) = self.get_shape_diffs(prev);
-        let (added_trips, removed_trip_ids) = self.get_trip_diffs(prev);
+        let (mut added_shapes, mut removed_shape_ids) = self.get_shape_diffs(prev);
+        let shape_point_diffs =
+            split_shape_point_diffs(prev, &mut added_shapes, &mut removed_shape_ids);
+        let (mut added_trips, mut removed_trip_ids) =
+            self.get_trip_diffs_with_options(prev, options);
+        let date_mask_updates =
+            split_date_mask_updates(prev, &mut added_trips, &mut removed_trip_ids);
+        let agencies = self.get_agency_diffs(prev);
+        let route_metadata_updates = self.get_route_metadata_diffs(prev);
+        let removed_route_ids = self.get_removed_route_ids(prev);
 
         ScheduleUpdate {
             added_trips,
             removed_trip_ids,
+            date_mask_updates,
             added_shapes,
             removed_shape_ids,
+            shape_point_diffs,
             added_stops,
             removed_stop_ids,
+            agencies,
+            route_metadata_updates,
+            removed_route_ids,
+        }
+    }
+
+    /// New metadata of every route whose metadata changed, and of every route `prev` doesn't have
+    /// at all, which is how new routes are added
+    pub fn get_route_metadata_diffs(&self, prev: &Self) -> HashMap<Id, RouteMetadata> {
+        self.routes
+            .values()
+            .filter(|route| {
+                prev.routes
+                    .get(&route.route_id)
+                    .is_none_or(|prev_route| prev_route.metadata != route.metadata)
+            })
+            .map(|route| (route.route_id.clone(), route.metadata.clone()))
+            .collect()
+    }
+
+    /// Routes in `prev` that are gone from `self`
+    pub fn get_removed_route_ids(&self, prev: &Self) -> HashSet<Id> {
+        prev.routes
+            .keys()
+            .filter(|route_id| !self.routes.contains_key(*route_id))
+            .cloned()
+            .collect()
+    }
+
+    // Agencies almost never change, so rather than tracking them individually the full set is
+    // replaced whenever any of them differ
+    pub fn get_agency_diffs(&self, prev: &Self) -> Option<AgencyIRs> {
+        if self.agencies != prev.agencies {
+            Some(self.agencies.clone())
+        } else {
+            None
         }
     }
 
@@ -78,51 +215,56 @@ impl ScheduleIR {
         (added_shapes, removed_shape_ids)
     }
 
-    pub fn get_trip_diffs(
+    pub fn get_trip_diffs(&self, prev: &Self) -> (HashMap<TripKey, TripIR>, HashSet<TripKey>) {
+        self.get_trip_diffs_with_options(prev, &DiffOptions::default())
+    }
+
+    pub fn get_trip_diffs_with_options(
         &self,
         prev: &Self,
-    ) -> (HashMap<(String, String), TripIR>, HashSet<(String, String)>) {
-        let mut added_trips: HashMap<(String, String), TripIR> = HashMap::new();
-        let mut removed_trip_ids: HashSet<(String, String)> = HashSet::new();
+        options: &DiffOptions,
+    ) -> (HashMap<TripKey, TripIR>, HashSet<TripKey>) {
+        let unchanged = |prev_trip: &TripIR, trip: &TripIR| {
+            if options.ignore_dates {
+                prev_trip.equals_ignoring_dates(trip)
+            } else {
+                prev_trip == trip
+            }
+        };
 
+        let mut added_trips: HashMap<TripKey, TripIR> = HashMap::new();
+        let mut removed_trip_ids: HashSet<TripKey> = HashSet::new();
+
+        // A route only in one of the schedules has no trips in the other, so all of its trips are
+        // added or removed
         for route in self.routes.values() {
+            let prev_trips = prev.routes.get(&route.route_id).map(|r| &r.trips);
+
             for trip in route.trips.values() {
-                if prev
-                    .routes
-                    .get(&route.route_id)
-                    .expect("new route")
-                    .trips
-                    .contains_key(&trip.trip_id)
-                {
-                    if prev
-                        .routes
-                        .get(&route.route_id)
-                        .expect("new route")
-                        .trips
-                        .get(&trip.trip_id)
-                        != Some(trip)
-                    {
-                        // Updated entry, add to both lists
-                        removed_trip_ids.insert((route.route_id.clone(), trip.trip_id.clone()));
+                match prev_trips.and_then(|trips| trips.get(&trip.trip_id)) {
+                    Some(prev_trip) => {
+                        if !unchanged(prev_trip, trip) {
+                            // Updated entry, add to both lists
+                            removed_trip_ids.insert((route.route_id.clone(), trip.trip_id.clone()));
+                            added_trips.insert(
+                                (route.route_id.clone(), trip.trip_id.clone()),
+                                trip.clone(),
+                            );
+                        }
+                    }
+                    None => {
+                        // This is an added entry
                         added_trips
                             .insert((route.route_id.clone(), trip.trip_id.clone()), trip.clone());
                     }
-                } else {
-                    // This is an added entry
-                    added_trips
-                        .insert((route.route_id.clone(), trip.trip_id.clone()), trip.clone());
                 }
             }
         }
         for route in prev.routes.values() {
+            let trips = self.routes.get(&route.route_id).map(|r| &r.trips);
+
             for trip in route.trips.values() {
-                if !self
-                    .routes
-                    .get(&route.route_id)
-                    .expect("new route")
-                    .trips
-                    .contains_key(&trip.trip_id)
-                {
+                if !trips.is_some_and(|trips| trips.contains_key(&trip.trip_id)) {
                     // Deleted entry
                     removed_trip_ids.insert((route.route_id.clone(), trip.trip_id.clone()));
                 }
@@ -133,17 +275,167 @@ impl ScheduleIR {
     }
 }
 
+// Pulls the updated trips whose only change is their service days out of `added_trips` and
+// `removed_trip_ids`, so just the new dates are sent instead of every stop time
+fn split_date_mask_updates(
+    prev: &ScheduleIR,
+    added_trips: &mut HashMap<TripKey, TripIR>,
+    removed_trip_ids: &mut HashSet<TripKey>,
+) -> HashMap<TripKey, (MaskStartDate, u32)> {
+    let mut date_mask_updates = HashMap::new();
+
+    added_trips.retain(|key, trip| {
+        let dates_only = removed_trip_ids.contains(key)
+            && prev
+                .routes
+                .get(&key.0)
+                .and_then(|r| r.trips.get(&key.1))
+                .is_some_and(|prev_trip| prev_trip.equals_ignoring_dates(trip));
+
+        if dates_only {
+            removed_trip_ids.remove(key);
+            date_mask_updates.insert(key.clone(), (trip.mask_start_date, trip.date_mask));
+        }
+
+        !dates_only
+    });
+
+    date_mask_updates
+}
+
+// Pulls the updated shapes that only changed in a small range of points out of `added_shapes` and
+// `removed_shape_ids`, so just that range is sent instead of the whole shape
+fn split_shape_point_diffs(
+    prev: &ScheduleIR,
+    added_shapes: &mut HashMap<String, Shape>,
+    removed_shape_ids: &mut HashSet<String>,
+) -> HashMap<String, Vec<PointSplice>> {
+    let mut shape_point_diffs = HashMap::new();
+
+    added_shapes.retain(|shape_id, shape| {
+        let splice = if removed_shape_ids.contains(shape_id) {
+            prev.shapes
+                .get(shape_id)
+                .and_then(|p| PointSplice::between(&p.points, &shape.points))
+        } else {
+            None
+        };
+
+        match splice {
+            Some(splice) => {
+                removed_shape_ids.remove(shape_id);
+                shape_point_diffs.insert(shape_id.clone(), vec![splice]);
+                false
+            }
+            None => true,
+        }
+    });
+
+    shape_point_diffs
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct ScheduleUpdate {
-    // (route_id, trip_id)
-    pub added_trips: HashMap<(String, String), TripIR>,
-    pub removed_trip_ids: HashSet<(String, String)>,
+    pub added_trips: HashMap<TripKey, TripIR>,
+    pub removed_trip_ids: HashSet<TripKey>,
+    // New (mask_start_date, date_mask) for trips where nothing else changed
+    pub date_mask_updates: HashMap<TripKey, (MaskStartDate, u32)>,
 
     pub added_shapes: HashMap<String, Shape>,
     pub removed_shape_ids: HashSet<String>,
+    // Splices for shapes where only some points changed, applied in order
+    pub shape_point_diffs: HashMap<String, Vec<PointSplice>>,
 
     pub added_stops: HashMap<String, Stop>,
     pub removed_stop_ids: HashSet<String>,
+
+    // Replacement for the full set of agencies, if any changed
+    pub agencies: Option<AgencyIRs>,
+
+    // Replacement metadata for routes where it changed. A route the schedule doesn't have yet is
+    // added with it
+    pub route_metadata_updates: HashMap<Id, RouteMetadata>,
+    // Routes dropped from the schedule, their trips are in `removed_trip_ids` too
+    pub removed_route_ids: HashSet<Id>,
+}
+
+/// Number of sample ids shown per category by the alternate (`{:#}`) format of `ScheduleUpdate`
+const SUMMARY_SAMPLE_IDS: usize = 3;
+
+fn write_summary_category(
+    f: &mut std::fmt::Formatter<'_>,
+    name: &str,
+    added: impl ExactSizeIterator<Item = String>,
+    removed: impl ExactSizeIterator<Item = String>,
+) -> std::fmt::Result {
+    write!(f, "+{}/-{} {}", added.len(), removed.len(), name)?;
+
+    if f.alternate() {
+        let mut added: Vec<String> = added.collect();
+        let mut removed: Vec<String> = removed.collect();
+        added.sort();
+        removed.sort();
+
+        let samples: Vec<String> = added
+            .into_iter()
+            .map(|id| format!("+{}", id))
+            .chain(removed.into_iter().map(|id| format!("-{}", id)))
+            .take(SUMMARY_SAMPLE_IDS)
+            .collect();
+        if !samples.is_empty() {
+            write!(f, " ({})", samples.join(", "))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts per category, e.g. `ScheduleUpdate{ +5123/-4998 trips, +2/-0 stops, +0/-1 shapes }`. The
+/// alternate format also lists a few sample ids per category
+impl std::fmt::Display for ScheduleUpdate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let trip_key = |(route_id, trip_id): &TripKey| format!("{}/{}", route_id, trip_id);
+
+        write!(f, "ScheduleUpdate{{ ")?;
+        write_summary_category(
+            f,
+            "trips",
+            self.added_trips.keys().map(trip_key),
+            self.removed_trip_ids.iter().map(trip_key),
+        )?;
+        write!(f, ", ")?;
+        write_summary_category(
+            f,
+            "stops",
+            self.added_stops.keys().cloned(),
+            self.removed_stop_ids.iter().cloned(),
+        )?;
+        write!(f, ", ")?;
+        write_summary_category(
+            f,
+            "shapes",
+            self.added_shapes.keys().cloned(),
+            self.removed_shape_ids.iter().cloned(),
+        )?;
+
+        if !self.date_mask_updates.is_empty() {
+            write!(f, ", {} date changes", self.date_mask_updates.len())?;
+        }
+        if !self.shape_point_diffs.is_empty() {
+            write!(f, ", {} shape splices", self.shape_point_diffs.len())?;
+        }
+        if !self.route_metadata_updates.is_empty() {
+            write!(f, ", {} route changes", self.route_metadata_updates.len())?;
+        }
+        if !self.removed_route_ids.is_empty() {
+            write!(f, ", {} routes removed", self.removed_route_ids.len())?;
+        }
+        if self.agencies.is_some() {
+            write!(f, ", agencies replaced")?;
+        }
+
+        write!(f, " }}")
+    }
 }
 
 impl Default for ScheduleUpdate {
@@ -155,6 +447,11 @@ impl Default for ScheduleUpdate {
             removed_trip_ids: HashSet::new(),
             removed_stop_ids: HashSet::new(),
             removed_shape_ids: HashSet::new(),
+            shape_point_diffs: HashMap::new(),
+            date_mask_updates: HashMap::new(),
+            agencies: None,
+            route_metadata_updates: HashMap::new(),
+            removed_route_ids: HashSet::new(),
         }
     }
 }
@@ -168,27 +465,138 @@ impl From<ScheduleUpdate> for ScheduleDiff {
             removed_trip_ids,
             removed_stop_ids,
             removed_shape_ids,
+            shape_point_diffs,
+            date_mask_updates,
+            agencies,
+            route_metadata_updates,
+            removed_route_ids,
         } = value;
 
         Self {
             added_trips: added_trips
                 .into_iter()
-                .map(|((_, id), tr)| TripExt {
+                .map(|((rid, _), tr)| TripExt {
                     trip: Some(tr.into()),
-                    route_id: Some(id),
+                    route_id: Some(rid.to_string()),
                 })
                 .collect(),
             removed_trip_ids: removed_trip_ids
                 .into_iter()
                 .map(|(rid, tid)| TripIdTuple {
-                    trip_id: Some(tid),
-                    route_id: Some(rid),
+                    trip_id: Some(tid.to_string()),
+                    route_id: Some(rid.to_string()),
                 })
                 .collect(),
             added_shapes: added_shapes.into_values().collect(),
             removed_shape_ids: removed_shape_ids.into_iter().collect(),
             added_stops: added_stops.into_values().collect(),
             removed_stop_ids: removed_stop_ids.into_iter().collect(),
+            agencies: agencies.map(|a| AgencyList {
+                agencies: a.into_inner().into_values().collect(),
+            }),
+            shape_point_diffs: shape_point_diffs
+                .into_iter()
+                .map(|(shape_id, splices)| ShapePointDiff {
+                    shape_id: Some(shape_id),
+                    splices: splices.into_iter().map(Into::into).collect(),
+                })
+                .collect(),
+            date_mask_updates: date_mask_updates
+                .into_iter()
+                .map(
+                    |((rid, tid), (mask_start_date, date_mask))| DateMaskUpdate {
+                        route_id: Some(rid.to_string()),
+                        trip_id: Some(tid.to_string()),
+                        mask_start_date: Some(mask_start_date.to_string()),
+                        date_mask: Some(date_mask),
+                    },
+                )
+                .collect(),
+            route_metadata_updates: route_metadata_updates
+                .into_iter()
+                .map(|(route_id, metadata)| RouteMetadataUpdate {
+                    route_id: Some(route_id.to_string()),
+                    route_short_name: metadata.short_name,
+                    route_long_name: metadata.long_name,
+                    route_color: metadata.color,
+                    route_type: metadata.route_type,
+                })
+                .collect(),
+            removed_route_ids: removed_route_ids
+                .into_iter()
+                .map(|route_id| route_id.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// The trip changes of a `ScheduleUpdate` belonging to a single route
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RouteScheduleUpdate {
+    pub added_trips: HashMap<Id, TripIR>,
+    pub removed_trip_ids: HashSet<Id>,
+    pub date_mask_updates: HashMap<Id, (MaskStartDate, u32)>,
+}
+
+impl ScheduleUpdate {
+    /// Trip changes split up by route, leaving out stops, shapes, agencies and route metadata.
+    /// Routes without changes are left out
+    pub fn by_route(&self) -> HashMap<String, RouteScheduleUpdate> {
+        let mut res: HashMap<String, RouteScheduleUpdate> = HashMap::new();
+
+        for ((route_id, trip_id), trip) in self.added_trips.iter() {
+            res.entry(route_id.to_string())
+                .or_default()
+                .added_trips
+                .insert(trip_id.clone(), trip.clone());
+        }
+        for (route_id, trip_id) in self.removed_trip_ids.iter() {
+            res.entry(route_id.to_string())
+                .or_default()
+                .removed_trip_ids
+                .insert(trip_id.clone());
+        }
+        for ((route_id, trip_id), mask) in self.date_mask_updates.iter() {
+            res.entry(route_id.to_string())
+                .or_default()
+                .date_mask_updates
+                .insert(trip_id.clone(), *mask);
+        }
+
+        res
+    }
+}
+
+impl RouteScheduleUpdate {
+    /// Back into a full update only touching `route_id`
+    pub fn into_schedule_update(self, route_id: &str) -> ScheduleUpdate {
+        let route_id: Id = route_id.into();
+        let key = |trip_id: Id| (route_id.clone(), trip_id);
+
+        ScheduleUpdate {
+            added_trips: self
+                .added_trips
+                .into_iter()
+                .map(|(tid, trip)| (key(tid), trip))
+                .collect(),
+            removed_trip_ids: self.removed_trip_ids.into_iter().map(key).collect(),
+            date_mask_updates: self
+                .date_mask_updates
+                .into_iter()
+                .map(|(tid, mask)| (key(tid), mask))
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<PointSplice> for crate::server::db_transit::PointSplice {
+    fn from(value: PointSplice) -> Self {
+        Self {
+            start: Some(value.start as u32),
+            removed: Some(value.removed as u32),
+            points: value.points,
+            prev_len: Some(value.prev_len as u32),
         }
     }
 }
@@ -244,7 +652,122 @@ where
     res
 }
 
+/// Ids removed by both updates, and entries added by both with the same value
+fn intersect_ids<T, U>(
+    added1: &HashMap<T, U>,
+    added2: &HashMap<T, U>,
+    removed1: &HashSet<T>,
+    removed2: &HashSet<T>,
+) -> (HashMap<T, U>, HashSet<T>)
+where
+    T: Clone + Hash + Eq,
+    U: Clone + PartialEq,
+{
+    let mut added = HashMap::new();
+    let mut removed = HashSet::new();
+
+    for id in get_all_ids(added1, added2, removed1, removed2) {
+        if removed1.contains(&id) && removed2.contains(&id) {
+            removed.insert(id.clone());
+        }
+
+        if let (Some(value1), Some(value2)) = (added1.get(&id), added2.get(&id))
+            && value1 == value2
+        {
+            added.insert(id, value1.clone());
+        }
+    }
+
+    (added, removed)
+}
+
+/// Entries present in both maps with the same value
+fn intersect_values<T, U>(values1: &HashMap<T, U>, values2: &HashMap<T, U>) -> HashMap<T, U>
+where
+    T: Clone + Hash + Eq,
+    U: Clone + PartialEq,
+{
+    values1
+        .iter()
+        .filter(|(k, v)| values2.get(*k) == Some(*v))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
 impl ScheduleUpdate {
+    /// The changes both updates agree on, for updates from the same base state. An id added by
+    /// both with different values is dropped, as is anything only one of them changes
+    pub fn intersect(&self, other: &ScheduleUpdate) -> Self {
+        let ScheduleUpdate {
+            removed_shape_ids,
+            removed_stop_ids,
+            removed_trip_ids,
+            added_shapes,
+            added_stops,
+            added_trips,
+            shape_point_diffs,
+            date_mask_updates,
+            agencies,
+            route_metadata_updates,
+            removed_route_ids,
+        } = self;
+
+        let ScheduleUpdate {
+            removed_shape_ids: other_removed_shape_ids,
+            removed_stop_ids: other_removed_stop_ids,
+            removed_trip_ids: other_removed_trip_ids,
+            added_shapes: other_added_shapes,
+            added_stops: other_added_stops,
+            added_trips: other_added_trips,
+            shape_point_diffs: other_shape_point_diffs,
+            date_mask_updates: other_date_mask_updates,
+            agencies: other_agencies,
+            route_metadata_updates: other_route_metadata_updates,
+            removed_route_ids: other_removed_route_ids,
+        } = other;
+
+        let (final_added_shapes, final_removed_shape_ids) = intersect_ids(
+            added_shapes,
+            other_added_shapes,
+            removed_shape_ids,
+            other_removed_shape_ids,
+        );
+        let (final_added_stops, final_removed_stop_ids) = intersect_ids(
+            added_stops,
+            other_added_stops,
+            removed_stop_ids,
+            other_removed_stop_ids,
+        );
+        let (final_added_trips, final_removed_trip_ids) = intersect_ids(
+            added_trips,
+            other_added_trips,
+            removed_trip_ids,
+            other_removed_trip_ids,
+        );
+
+        Self {
+            added_shapes: final_added_shapes,
+            added_trips: final_added_trips,
+            added_stops: final_added_stops,
+            removed_trip_ids: final_removed_trip_ids,
+            removed_shape_ids: final_removed_shape_ids,
+            removed_stop_ids: final_removed_stop_ids,
+            shape_point_diffs: intersect_values(shape_point_diffs, other_shape_point_diffs),
+            date_mask_updates: intersect_values(date_mask_updates, other_date_mask_updates),
+            agencies: agencies
+                .clone()
+                .filter(|a| Some(a) == other_agencies.as_ref()),
+            route_metadata_updates: intersect_values(
+                route_metadata_updates,
+                other_route_metadata_updates,
+            ),
+            removed_route_ids: removed_route_ids
+                .intersection(other_removed_route_ids)
+                .cloned()
+                .collect(),
+        }
+    }
+
     pub fn combine(&self, other: &ScheduleUpdate) -> Self {
         let ScheduleUpdate {
             removed_shape_ids,
@@ -253,6 +776,11 @@ impl ScheduleUpdate {
             added_shapes,
             added_stops,
             added_trips,
+            shape_point_diffs,
+            date_mask_updates,
+            agencies,
+            route_metadata_updates,
+            removed_route_ids,
         } = self;
 
         let ScheduleUpdate {
@@ -262,6 +790,11 @@ impl ScheduleUpdate {
             added_shapes: other_added_shapes,
             added_stops: other_added_stops,
             added_trips: other_added_trips,
+            shape_point_diffs: other_shape_point_diffs,
+            date_mask_updates: other_date_mask_updates,
+            agencies: other_agencies,
+            route_metadata_updates: other_route_metadata_updates,
+            removed_route_ids: other_removed_route_ids,
         } = other;
 
         let mut final_added_shapes = HashMap::new();
@@ -373,6 +906,54 @@ impl ScheduleUpdate {
             }
         }
 
+        // Earlier splices are dropped if the shape is replaced or removed later
+        let mut final_shape_point_diffs: HashMap<String, Vec<PointSplice>> = shape_point_diffs
+            .iter()
+            .filter(|(k, _)| {
+                !other_removed_shape_ids.contains(*k) && !other_added_shapes.contains_key(*k)
+            })
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        for (shape_id, splices) in other_shape_point_diffs {
+            if let Some(shape) = final_added_shapes.get_mut(shape_id) {
+                // Shape is already being sent in full, splice into it directly
+                for splice in splices {
+                    if !splice.apply(&mut shape.points) {
+                        error!(
+                            "Splice of {} points at {} doesn't fit shape {} with {} points",
+                            splice.removed,
+                            splice.start,
+                            shape_id,
+                            shape.points.len()
+                        );
+                    }
+                }
+            } else {
+                final_shape_point_diffs
+                    .entry(shape_id.clone())
+                    .or_default()
+                    .extend(splices.iter().cloned());
+            }
+        }
+
+        // Earlier date changes are overwritten if the trip is replaced or removed later
+        let mut final_date_mask_updates: HashMap<TripKey, (MaskStartDate, u32)> = date_mask_updates
+            .iter()
+            .filter(|(k, _)| {
+                !other_removed_trip_ids.contains(*k) && !other_added_trips.contains_key(*k)
+            })
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        for (trip_id, (mask_start_date, date_mask)) in other_date_mask_updates {
+            if let Some(trip) = final_added_trips.get_mut(trip_id) {
+                // Trip is already being sent in full, fold the new dates into it
+                trip.mask_start_date = *mask_start_date;
+                trip.date_mask = *date_mask;
+            } else {
+                final_date_mask_updates.insert(trip_id.clone(), (*mask_start_date, *date_mask));
+            }
+        }
+
         Self {
             added_shapes: final_added_shapes,
             added_trips: final_added_trips,
@@ -380,32 +961,116 @@ impl ScheduleUpdate {
             removed_trip_ids: final_removed_trip_ids,
             removed_shape_ids: final_removed_shape_ids,
             removed_stop_ids: final_removed_stop_ids,
+            shape_point_diffs: final_shape_point_diffs,
+            date_mask_updates: final_date_mask_updates,
+            // The later replacement wins
+            agencies: other_agencies.clone().or_else(|| agencies.clone()),
+            // A route removed later takes its earlier metadata with it, its trips are removed the
+            // same way
+            route_metadata_updates: route_metadata_updates
+                .iter()
+                .filter(|(k, _)| !other_removed_route_ids.contains(*k))
+                .chain(other_route_metadata_updates)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            removed_route_ids: removed_route_ids
+                .union(other_removed_route_ids)
+                .cloned()
+                .collect(),
         }
     }
 }
 
 impl ScheduleUpdate {
-    pub fn apply_to_schedule(&self, mut response: ScheduleIR) -> ScheduleIR {
-        for shape_id in self.removed_shape_ids.iter() {
-            response.shapes.remove(shape_id);
+    /// Routes the update refers to that `schedule` doesn't have, sorted. Trips can only be added
+    /// to a route the update doesn't remove, or one it adds through `route_metadata_updates`
+    pub fn missing_routes(&self, schedule: &ScheduleIR) -> Vec<Id> {
+        let has_route = |route_id: &Id| schedule.routes.contains_key(route_id);
+        let has_route_after = |route_id: &Id| {
+            self.route_metadata_updates.contains_key(route_id)
+                || (has_route(route_id) && !self.removed_route_ids.contains(route_id))
+        };
+
+        let mut missing: Vec<Id> = self
+            .removed_trip_ids
+            .iter()
+            .map(|(route_id, _)| route_id)
+            .filter(|route_id| !has_route(route_id))
+            .chain(
+                self.added_trips
+                    .keys()
+                    .chain(self.date_mask_updates.keys())
+                    .map(|(route_id, _)| route_id)
+                    .filter(|route_id| !has_route_after(route_id)),
+            )
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        missing.sort();
+
+        missing
+    }
+
+    /// Trips whose dates the update changes that `schedule` won't have once the update's removals
+    /// are applied, sorted. Unlike added trips, date changes can't create a trip
+    pub fn missing_trips(&self, schedule: &ScheduleIR) -> Vec<TripKey> {
+        let mut missing: Vec<TripKey> = self
+            .date_mask_updates
+            .keys()
+            .filter(|key| !self.added_trips.contains_key(*key))
+            .filter(|key| {
+                let (route_id, trip_id) = key;
+
+                self.removed_trip_ids.contains(*key)
+                    || self.removed_route_ids.contains(route_id)
+                    || !schedule
+                        .routes
+                        .get(route_id)
+                        .is_some_and(|r| r.trips.contains_key(trip_id))
+            })
+            .cloned()
+            .collect();
+        missing.sort();
+
+        missing
+    }
+
+    /// Applies the update in two passes, every removal (shapes, stops, trips along with their stop
+    /// times, then routes) before any addition. An id that is both removed and added is an update,
+    /// and always ends up with the added value. Route metadata is applied before trips are added,
+    /// creating the routes the schedule doesn't have yet. Date mask updates and shape splices are
+    /// applied last, in place. Applying the same update twice gives the same result as applying it
+    /// once, see `PointSplice::apply`
+    ///
+    /// Fails without applying anything if the update refers to a route `response` doesn't have,
+    /// e.g. one combined from a feed that wasn't route filtered, or changes the dates of a trip it
+    /// doesn't have, rather than panicking halfway through. Also fails if a splice's shape is
+    /// missing or doesn't have the points it was made from
+    pub fn apply_to_schedule(&self, mut response: ScheduleIR) -> Result<ScheduleIR, ScheduleError> {
+        let missing_routes = self.missing_routes(&response);
+        if !missing_routes.is_empty() {
+            return Err(format!(
+                "Not applying update, schedule is missing routes {:?}",
+                missing_routes
+            )
+            .into());
         }
-        for shape_id in self.added_shapes.keys() {
-            response.shapes.insert(
-                shape_id.clone(),
-                self.added_shapes.get(shape_id).unwrap().clone(),
-            );
+        let missing_trips = self.missing_trips(&response);
+        if !missing_trips.is_empty() {
+            return Err(format!(
+                "Not applying update, schedule is missing trips {:?}",
+                missing_trips
+            )
+            .into());
         }
 
+        for shape_id in self.removed_shape_ids.iter() {
+            response.shapes.remove(shape_id);
+        }
         for stop_id in self.removed_stop_ids.iter() {
             response.stops.remove(stop_id);
         }
-        for stop_id in self.added_stops.keys() {
-            response.stops.insert(
-                stop_id.clone(),
-                self.added_stops.get(stop_id).unwrap().clone(),
-            );
-        }
-
         for (route_id, trip_id) in self.removed_trip_ids.iter() {
             response
                 .routes
@@ -414,15 +1079,79 @@ impl ScheduleUpdate {
                 .trips
                 .remove(trip_id);
         }
-        for ids in self.added_trips.keys() {
+        for route_id in self.removed_route_ids.iter() {
+            response.routes.remove(route_id);
+        }
+
+        if let Some(agencies) = &self.agencies {
+            response.agencies = agencies.clone();
+        }
+        for (shape_id, shape) in self.added_shapes.iter() {
+            response.shapes.insert(shape_id.clone(), shape.clone());
+        }
+        for (stop_id, stop) in self.added_stops.iter() {
+            response.stops.insert(stop_id.clone(), stop.clone());
+        }
+        for (route_id, metadata) in self.route_metadata_updates.iter() {
+            match response.routes.get_mut(route_id) {
+                Some(route) => route.metadata = metadata.clone(),
+                None => {
+                    response.routes.insert(
+                        route_id.clone(),
+                        RouteIR {
+                            route_id: route_id.clone(),
+                            metadata: metadata.clone(),
+                            trips: HashMap::new().into(),
+                        },
+                    );
+                }
+            }
+        }
+        for ((route_id, trip_id), trip) in self.added_trips.iter() {
             response
                 .routes
-                .get_mut(&ids.0)
+                .get_mut(route_id)
+                .expect("Unable to find route in schedule")
+                .trips
+                .insert(trip_id.clone(), trip.clone());
+        }
+
+        for ((route_id, trip_id), (mask_start_date, date_mask)) in self.date_mask_updates.iter() {
+            let trip = response
+                .routes
+                .get_mut(route_id)
                 .expect("Unable to find route in schedule")
                 .trips
-                .insert(ids.1.clone(), self.added_trips.get(ids).unwrap().clone());
+                .get_mut(trip_id)
+                .expect("Missing trips are checked before applying");
+
+            trip.mask_start_date = *mask_start_date;
+            trip.date_mask = *date_mask;
+        }
+        for (shape_id, splices) in self.shape_point_diffs.iter() {
+            let shape = response
+                .shapes
+                .get_mut(shape_id)
+                .ok_or_else(|| format!("Schedule is missing spliced shape {}", shape_id))?;
+            // Splices follow each other, only the last one says whether they're all applied
+            if splices.last().is_some_and(|s| s.is_applied(&shape.points)) {
+                continue;
+            }
+
+            for splice in splices {
+                if !splice.apply(&mut shape.points) {
+                    return Err(format!(
+                        "Splice of {} points at {} doesn't fit shape {} with {} points",
+                        splice.removed,
+                        splice.start,
+                        shape_id,
+                        shape.points.len()
+                    )
+                    .into());
+                }
+            }
         }
 
-        response
+        Ok(response)
     }
 }
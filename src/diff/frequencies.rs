@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Seek},
+};
+
+use zip::{ZipArchive, result::ZipError};
+
+use crate::{
+    diff::{
+        ir::{Id, ScheduleIR, TripIR, parse_time},
+        stop_times::split_fields,
+    },
+    error::ScheduleError,
+};
+
+pub const FREQUENCIES_FILE: &'static str = "frequencies.txt";
+
+/// Row of a feed's frequencies.txt. `trip_id` departs every `headway_secs` from `start_time` until
+/// `end_time`, its own stop times only giving the offsets from its first departure. Times are
+/// seconds since the start of the service day, like stop times
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frequency {
+    pub trip_id: String,
+    pub start_time: u32,
+    pub end_time: u32,
+    pub headway_secs: u32,
+}
+
+fn format_time(secs: u32) -> String {
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Parses frequencies.txt, finding the columns by their header. Fails on a row with a time or
+/// headway that can't be parsed
+pub fn parse_frequencies(reader: impl Read) -> Result<Vec<Frequency>, ScheduleError> {
+    let mut lines = BufReader::new(reader).lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    let columns = split_fields(header.trim_start_matches('\u{feff}'));
+    let column = |name: &str| {
+        columns
+            .iter()
+            .position(|c| c.trim() == name)
+            .ok_or_else(|| format!("{} has no {} column", FREQUENCIES_FILE, name))
+    };
+    let (trip_id, start_time, end_time, headway_secs) = (
+        column("trip_id")?,
+        column("start_time")?,
+        column("end_time")?,
+        column("headway_secs")?,
+    );
+
+    let mut frequencies = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields = split_fields(&line);
+        let field = |column: usize| fields.get(column).map_or("", |f| f.trim());
+        // Header is line 1
+        let invalid = || format!("Invalid row on line {} of {}", i + 2, FREQUENCIES_FILE);
+
+        frequencies.push(Frequency {
+            trip_id: field(trip_id).to_owned(),
+            start_time: parse_time(field(start_time)).ok_or_else(invalid)?,
+            end_time: parse_time(field(end_time)).ok_or_else(invalid)?,
+            headway_secs: field(headway_secs).parse().map_err(|_| invalid())?,
+        });
+    }
+
+    Ok(frequencies)
+}
+
+/// frequencies.txt of a zipped feed, empty if it doesn't have one. `gtfs_parsing` doesn't read it,
+/// so it's taken straight from the archive
+pub fn read_frequencies<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<Vec<Frequency>, ScheduleError> {
+    match archive.by_name(FREQUENCIES_FILE) {
+        Ok(file) => parse_frequencies(file),
+        Err(ZipError::FileNotFound) => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `template` departing at `departure` instead, every stop time shifted by the same amount
+fn shifted_trip(template: &TripIR, first_departure: u32, departure: u32) -> TripIR {
+    let offset = departure as i64 - first_departure as i64;
+    // An arrival before the first departure could otherwise go below midnight
+    let shift = |time: Option<u32>| time.map(|t| (t as i64 + offset).max(0) as u32);
+    let stop_times: HashMap<_, _> = template
+        .stop_times
+        .iter()
+        .map(|(seq, st)| {
+            let mut st = st.clone();
+            st.arrival_time = shift(st.arrival_time);
+            st.departure_time = shift(st.departure_time);
+            (*seq, st)
+        })
+        .collect();
+
+    TripIR {
+        trip_id: format!("{}@{}", template.trip_id, format_time(departure)).into(),
+        stop_times: stop_times.into(),
+        ..template.clone()
+    }
+}
+
+impl ScheduleIR {
+    /// Replaces every trip named in `frequencies` with one trip per departure, each a copy of it
+    /// with the stop times shifted so its first departure is at the departure time. They're named
+    /// `<trip_id>@<HH:MM:SS>` after the departure, the way GTFS-realtime tells them apart. Trips
+    /// with no stop times and rows with no headway are left alone. Returns the number of trips
+    /// generated
+    pub fn expand_frequencies(&mut self, frequencies: &[Frequency]) -> usize {
+        let mut by_trip: HashMap<&str, Vec<&Frequency>> = HashMap::new();
+        for frequency in frequencies.iter().filter(|f| f.headway_secs > 0) {
+            by_trip
+                .entry(frequency.trip_id.as_str())
+                .or_default()
+                .push(frequency);
+        }
+
+        let mut generated = 0;
+        for route in self.routes.values_mut() {
+            // Trip ids are only unique within a route, so every route's trip by that id is
+            // expanded
+            let templates: Vec<Id> = route
+                .trips
+                .keys()
+                .filter(|trip_id| by_trip.contains_key(&***trip_id))
+                .cloned()
+                .collect();
+
+            for trip_id in templates {
+                let template = &route.trips[&trip_id];
+                let Some(first_departure) = template
+                    .stop_times
+                    .iter()
+                    .min_by_key(|(seq, _)| **seq)
+                    .and_then(|(_, st)| st.departure_time.or(st.arrival_time))
+                else {
+                    continue;
+                };
+
+                let trips: Vec<TripIR> = by_trip[&*trip_id]
+                    .iter()
+                    .flat_map(|f| (f.start_time..f.end_time).step_by(f.headway_secs as usize))
+                    .map(|departure| shifted_trip(template, first_departure, departure))
+                    .collect();
+
+                route.trips.remove(&trip_id);
+                generated += trips.len();
+                for trip in trips {
+                    route.trips.insert(trip.trip_id.clone(), trip);
+                }
+            }
+        }
+
+        generated
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+
+use crate::error::ScheduleError;
+
+use super::{core::ScheduleUpdate, ir::ScheduleIR};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub timestamp: u32,
+    // Turns this entry's schedule into the newest one
+    pub to_latest: ScheduleUpdate,
+    // Turns the newest schedule back into this entry's schedule
+    pub from_latest: ScheduleUpdate,
+}
+
+/// History of schedule states, oldest first. Only the newest state is stored in full, older states
+/// are reconstructed on demand by applying the stored updates to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleHistory {
+    latest: Option<(u32, ScheduleIR)>,
+    // `content_hash` of the newest schedule
+    latest_content_hash: Option<blake3::Hash>,
+    entries: VecDeque<HistoryEntry>,
+    // Timestamps of states dropped by `replace_latest` that are newer than the oldest entry, sorted
+    replaced: Vec<u32>,
+    // Timestamp of the first of the updates `replace_latest` merged into the newest state
+    burst_start: Option<u32>,
+}
+
+impl Default for ScheduleHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScheduleHistory {
+    pub const fn new() -> Self {
+        Self {
+            latest: None,
+            latest_content_hash: None,
+            entries: VecDeque::new(),
+            replaced: Vec::new(),
+            burst_start: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn latest(&self) -> Option<(u32, &ScheduleIR)> {
+        self.latest.as_ref().map(|(ts, ir)| (*ts, ir))
+    }
+
+    pub fn latest_content_hash(&self) -> Option<blake3::Hash> {
+        self.latest_content_hash
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+
+    pub fn timestamps(&self) -> impl Iterator<Item = u32> {
+        self.entries.iter().map(|e| e.timestamp)
+    }
+
+    pub fn get(&self, timestamp: u32) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|e| e.timestamp == timestamp)
+    }
+
+    /// Whether a state dropped by `replace_latest` falls after `from` and at or before `to`. A
+    /// client on it isn't covered by the diff from `from`, since the diff doesn't undo the changes
+    /// only that state had
+    pub fn replaced_between(&self, from: u32, to: u32) -> bool {
+        self.replaced.iter().any(|ts| (from + 1..=to).contains(ts))
+    }
+
+    /// Timestamp of the first update merged into the newest state by `replace_latest`, or of the
+    /// newest state itself if none were
+    pub fn burst_start(&self) -> Option<u32> {
+        self.burst_start
+    }
+
+    /// Newest entry at or before `timestamp`, see `nearest_diff` in the server
+    pub fn at_or_before(&self, timestamp: u32) -> Option<&HistoryEntry> {
+        self.entries.iter().rev().find(|e| e.timestamp <= timestamp)
+    }
+
+    /// Adds a new newest state, evicting the oldest one if there are already `max_len` entries.
+    /// Returns the timestamps of the evicted entries, more than one if `max_len` was lowered
+    pub fn push(&mut self, timestamp: u32, schedule: ScheduleIR, max_len: usize) -> Vec<u32> {
+        self.push_retaining(timestamp, schedule, max_len, max_len, |_| false)
+    }
+
+    /// Same as `push`, except the oldest entry isn't evicted while `keep` returns true for it, the
+    /// history growing past `max_len` up to `max_retained_len` instead. Entries only ever go from
+    /// the oldest end, a client on one evicted from the middle would be sent the diff from the
+    /// entry before it, which doesn't undo that entry's changes
+    pub fn push_retaining(
+        &mut self,
+        timestamp: u32,
+        schedule: ScheduleIR,
+        max_len: usize,
+        max_retained_len: usize,
+        keep: impl Fn(u32) -> bool,
+    ) -> Vec<u32> {
+        let (forward, backward) = match &self.latest {
+            Some((_, prev)) => (schedule.get_diff(prev), prev.get_diff(&schedule)),
+            None => (ScheduleUpdate::default(), ScheduleUpdate::default()),
+        };
+
+        for entry in self.entries.iter_mut() {
+            entry.to_latest = entry.to_latest.combine(&forward);
+            entry.from_latest = backward.combine(&entry.from_latest);
+        }
+
+        let max_len = max_len.max(1);
+        let max_retained_len = max_retained_len.max(max_len);
+        let mut evicted = Vec::new();
+        while let Some(oldest) = self.entries.front()
+            && self.entries.len() >= max_len
+            && (self.entries.len() >= max_retained_len || !keep(oldest.timestamp))
+        {
+            evicted.extend(self.entries.pop_front().map(|e| e.timestamp));
+        }
+
+        self.entries.push_back(HistoryEntry {
+            timestamp,
+            to_latest: ScheduleUpdate::default(),
+            from_latest: ScheduleUpdate::default(),
+        });
+        self.latest_content_hash = Some(schedule.content_hash());
+        self.latest = Some((timestamp, schedule));
+        self.burst_start = Some(timestamp);
+        // Nothing older than the oldest entry is diffed from anyway
+        let oldest = self.entries.front().map(|e| e.timestamp);
+        self.replaced
+            .retain(|ts| oldest.is_some_and(|oldest| *ts > oldest));
+
+        evicted
+    }
+
+    /// Replaces the newest state with `schedule` at `timestamp`, keeping the history's length.
+    /// Returns the timestamp of the replaced entry. Clients still on it have to be sent a full
+    /// schedule, see `replaced_between`
+    pub fn replace_latest(&mut self, timestamp: u32, schedule: ScheduleIR) -> Option<u32> {
+        // Older entries' updates still lead to the replaced schedule, which is still `latest`, so
+        // pushing after dropping its entry moves them on to the new one
+        let replaced = self.entries.pop_back().map(|e| e.timestamp);
+        let burst_start = self.burst_start;
+        self.replaced.extend(replaced);
+        self.push(timestamp, schedule, usize::MAX);
+        self.burst_start = burst_start.or(Some(timestamp));
+
+        replaced
+    }
+
+    /// Rebuilds the full schedule as it was at `timestamp`. Fails if that entry has been evicted or
+    /// its update doesn't apply to the newest state
+    pub fn reconstruct_at(&self, timestamp: u32) -> Result<ScheduleIR, ScheduleError> {
+        let missing = || format!("Timestamp {} is no longer in the history", timestamp);
+
+        let (_, latest) = self.latest.as_ref().ok_or_else(missing)?;
+        let entry = self.get(timestamp).ok_or_else(missing)?;
+
+        entry.from_latest.apply_to_schedule(latest.clone())
+    }
+
+    /// Update turning the schedule at `from_ts` into the one at `to_ts`, going through the newest
+    /// state. Fails if either entry has been evicted
+    pub fn diff_between(&self, from_ts: u32, to_ts: u32) -> Result<ScheduleUpdate, ScheduleError> {
+        let missing = |ts| format!("Timestamp {} is no longer in the history", ts);
+
+        let from = self.get(from_ts).ok_or_else(|| missing(from_ts))?;
+        let to = self.get(to_ts).ok_or_else(|| missing(to_ts))?;
+
+        Ok(from.to_latest.combine(&to.from_latest))
+    }
+
+    /// Checks that every entry can be rebuilt and that its update leads back to the newest state.
+    /// Requires a full copy of the schedule per entry, so it is expensive
+    pub fn verify(&self) -> bool {
+        match self.latest.as_ref() {
+            Some(_) => self.find_mismatch().is_none(),
+            None => self.entries.is_empty(),
+        }
+    }
+
+    /// First entry that fails `verify`, if any. An update that doesn't apply counts as a mismatch
+    pub fn find_mismatch(&self) -> Option<&HistoryEntry> {
+        let (_, latest) = self.latest.as_ref()?;
+
+        self.entries.iter().find(|entry| {
+            !entry
+                .from_latest
+                .apply_to_schedule(latest.clone())
+                .and_then(|schedule| entry.to_latest.apply_to_schedule(schedule))
+                .is_ok_and(|schedule| &schedule == latest)
+        })
+    }
+
+    /// Rough measure of the memory held, counting the stop times stored in the newest schedule and
+    /// in every update
+    pub fn stop_time_count(&self) -> usize {
+        let latest = self
+            .latest
+            .iter()
+            .flat_map(|(_, ir)| ir.routes.values())
+            .flat_map(|r| r.trips.values())
+            .map(|t| t.stop_times.len())
+            .sum::<usize>();
+
+        let updates = self
+            .entries
+            .iter()
+            .flat_map(|e| {
+                e.to_latest
+                    .added_trips
+                    .values()
+                    .chain(e.from_latest.added_trips.values())
+            })
+            .map(|t| t.stop_times.len())
+            .sum::<usize>();
+
+        latest + updates
+    }
+}
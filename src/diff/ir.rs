@@ -1,11 +1,21 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use chrono::{Datelike, Days, NaiveDate, Weekday};
+use chrono_tz::{America::New_York, Tz};
 use gtfs_parsing::schedule::{calendar::ExceptionType, trips::DirectionType};
+use logge_rs::{info, warn};
+use prost::Message;
 
 use crate::{
-    get_nyc_datetime,
-    server::db_transit::{FullSchedule, Position, Route, Shape, Stop, StopTime, Transfer, Trip},
+    error::ScheduleError,
+    get_agency_datetime,
+    server::db_transit::{
+        Agency, FullSchedule, Position, Route, Shape, Stop, StopTime, Transfer, Trip,
+    },
+    service_date,
 };
 
 macro_rules! make_collection_wrapper_type {
@@ -41,9 +51,53 @@ macro_rules! make_collection_wrapper_type {
     };
 }
 
-make_collection_wrapper_type!(RouteIRs, HashMap<String, RouteIR>);
+/// Identifier shared between a map key, its value, and any diffs referring to it. Cloning only bumps
+/// a reference count, so the same id is never reallocated once parsed
+pub type Id = Arc<str>;
+
+/// First day of a trip's `date_mask`, sent as `YYYYMMDD`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MaskStartDate(NaiveDate);
+
+impl MaskStartDate {
+    pub fn date(self) -> NaiveDate {
+        self.0
+    }
+}
+
+impl From<NaiveDate> for MaskStartDate {
+    fn from(value: NaiveDate) -> Self {
+        Self(value)
+    }
+}
+
+impl std::fmt::Display for MaskStartDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.format("%Y%m%d"))
+    }
+}
+
+impl std::str::FromStr for MaskStartDate {
+    type Err = ScheduleError;
+
+    /// Exactly 8 digits, chrono alone would also take e.g. a sign or a longer year
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ScheduleError::ParseError(format!("invalid mask start date {:?}", s));
+
+        if s.len() != 8 || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        NaiveDate::parse_from_str(s, "%Y%m%d")
+            .map(Self)
+            .map_err(|_| invalid())
+    }
+}
+
+make_collection_wrapper_type!(RouteIRs, HashMap<Id, RouteIR>);
 make_collection_wrapper_type!(ShapeIRs, HashMap<String, Shape>);
 make_collection_wrapper_type!(StopIRs, HashMap<String, Stop>);
+make_collection_wrapper_type!(AgencyIRs, HashMap<String, Agency>);
 
 // Create intermediate representations that use HashMap instead of Vec
 #[derive(Debug, Clone, PartialEq)]
@@ -51,40 +105,219 @@ pub struct ScheduleIR {
     pub routes: RouteIRs,
     pub shapes: ShapeIRs,
     pub stops: StopIRs,
+    pub agencies: AgencyIRs,
+}
+
+make_collection_wrapper_type!(TripIRs, HashMap<Id, TripIR>);
+
+/// Display details of a route from the feed's routes.txt
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RouteMetadata {
+    pub short_name: Option<String>,
+    pub long_name: Option<String>,
+    pub color: Option<String>,
+    pub route_type: Option<u32>,
+}
+
+/// Kind of vehicle a route runs, from the GTFS `route_type` codes. Extended codes are kept as
+/// `Other`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteType {
+    Tram,
+    Subway,
+    Rail,
+    Bus,
+    Ferry,
+    CableTram,
+    AerialLift,
+    Funicular,
+    Trolleybus,
+    Monorail,
+    Other(u32),
 }
 
-make_collection_wrapper_type!(TripIRs, HashMap<String, TripIR>);
+impl From<u32> for RouteType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::Tram,
+            1 => Self::Subway,
+            2 => Self::Rail,
+            3 => Self::Bus,
+            4 => Self::Ferry,
+            5 => Self::CableTram,
+            6 => Self::AerialLift,
+            7 => Self::Funicular,
+            11 => Self::Trolleybus,
+            12 => Self::Monorail,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<RouteType> for u32 {
+    fn from(value: RouteType) -> Self {
+        match value {
+            RouteType::Tram => 0,
+            RouteType::Subway => 1,
+            RouteType::Rail => 2,
+            RouteType::Bus => 3,
+            RouteType::Ferry => 4,
+            RouteType::CableTram => 5,
+            RouteType::AerialLift => 6,
+            RouteType::Funicular => 7,
+            RouteType::Trolleybus => 11,
+            RouteType::Monorail => 12,
+            RouteType::Other(other) => other,
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct RouteIR {
-    pub route_id: String,
+    pub route_id: Id,
+    pub metadata: RouteMetadata,
 
+    /// Keyed by trip id, which only has to be unique within the route. Merged feeds can reuse a
+    /// trip id on another route, so anything indexing trips across routes must key them by
+    /// `(route_id, trip_id)` like `TripKey`, see `ScheduleIR::duplicate_trip_ids`
     pub trips: TripIRs,
 }
 
+impl RouteIR {
+    pub fn route_type(&self) -> Option<RouteType> {
+        self.metadata.route_type.map(RouteType::from)
+    }
+}
+
+// Conversions to the GRPC types go through these so responses are the same for the same IR, every
+// list is ordered by the key it's stored under in the IR
+pub(super) fn sorted_values<K: Ord, V>(map: &HashMap<K, V>) -> impl Iterator<Item = &V> {
+    let mut entries: Vec<(&K, &V)> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    entries.into_iter().map(|(_, v)| v)
+}
+
+fn into_sorted_values<K: Ord, V>(map: HashMap<K, V>) -> impl Iterator<Item = V> {
+    let mut entries: Vec<(K, V)> = map.into_iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    entries.into_iter().map(|(_, v)| v)
+}
+
 impl From<RouteIR> for Route {
     fn from(value: RouteIR) -> Self {
-        let RouteIR { route_id, trips } = value;
+        let RouteIR {
+            route_id,
+            metadata,
+            trips,
+        } = value;
 
         Self {
-            route_id: Some(route_id),
-            trips: trips.into_inner().into_values().map(TripIR::into).collect(),
+            route_id: Some(route_id.to_string()),
+            trips: into_sorted_values(trips.into_inner())
+                .map(TripIR::into)
+                .collect(),
+            route_short_name: metadata.short_name,
+            route_long_name: metadata.long_name,
+            route_color: metadata.color,
+            route_type: metadata.route_type,
         }
     }
 }
 
-make_collection_wrapper_type!(StopTimeIRs, HashMap<u32, StopTime>);
+impl From<&RouteIR> for Route {
+    fn from(value: &RouteIR) -> Self {
+        Self {
+            route_id: Some(value.route_id.to_string()),
+            trips: sorted_values(&value.trips).map(Trip::from).collect(),
+            route_short_name: value.metadata.short_name.clone(),
+            route_long_name: value.metadata.long_name.clone(),
+            route_color: value.metadata.color.clone(),
+            route_type: value.metadata.route_type,
+        }
+    }
+}
+
+/// Same as the proto `StopTime`, but the stop id is shared by every stop time at the stop, see
+/// `intern`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopTimeIR {
+    pub stop_id: Option<Id>,
+    pub arrival_time: Option<u32>,
+    pub departure_time: Option<u32>,
+    pub stop_sequence: Option<u32>,
+}
+
+impl StopTimeIR {
+    fn from_proto(value: StopTime, stop_ids: &mut HashSet<Id>) -> Self {
+        let StopTime {
+            stop_id,
+            arrival_time,
+            departure_time,
+            stop_sequence,
+        } = value;
+
+        Self {
+            stop_id: stop_id.map(|id| intern(stop_ids, id)),
+            arrival_time,
+            departure_time,
+            stop_sequence,
+        }
+    }
+
+    /// Fails with the offending time if the arrival or departure isn't an `HH:MM:SS` time, blank
+    /// ones are left unset
+    fn from_parsed(
+        value: gtfs_parsing::schedule::stop_times::StopTime,
+        stop_ids: &mut HashSet<Id>,
+    ) -> Result<Self, String> {
+        let gtfs_parsing::schedule::stop_times::StopTime {
+            stop_id,
+            arrival_time,
+            departure_time,
+            stop_sequence,
+            ..
+        } = value;
+        let time = |time: Option<String>| match time {
+            Some(time) if !time.trim().is_empty() => parse_time(&time).map(Some).ok_or(time),
+            _ => Ok(None),
+        };
+        let (arrival_time, departure_time) = (time(arrival_time)?, time(departure_time)?);
+
+        Ok(Self {
+            stop_id: stop_id.map(|id| intern(stop_ids, id)),
+            arrival_time,
+            departure_time,
+            stop_sequence: Some(stop_sequence),
+        })
+    }
+}
+
+impl From<&StopTimeIR> for StopTime {
+    fn from(value: &StopTimeIR) -> Self {
+        Self {
+            stop_id: value.stop_id.as_deref().map(str::to_owned),
+            arrival_time: value.arrival_time,
+            departure_time: value.departure_time,
+            stop_sequence: value.stop_sequence,
+        }
+    }
+}
+
+make_collection_wrapper_type!(StopTimeIRs, HashMap<u32, StopTimeIR>);
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TripIR {
-    pub trip_id: String,
+    pub trip_id: Id,
     pub stop_times: StopTimeIRs,
 
-    pub headsign: Option<String>,
+    /// Shared by every trip with the same headsign, see `intern`
+    pub headsign: Option<Id>,
     pub shape_id: Option<String>,
     pub direction: Option<u32>,
 
-    pub mask_start_date: String,
+    pub mask_start_date: MaskStartDate,
     pub date_mask: u32,
 }
 
@@ -101,26 +334,271 @@ impl From<TripIR> for Trip {
         } = value;
 
         Self {
-            trip_id: Some(trip_id),
-            stop_times: stop_times.into_inner().into_values().collect(),
-            headsign,
+            trip_id: Some(trip_id.to_string()),
+            stop_times: sorted_values(&stop_times).map(StopTime::from).collect(),
+            headsign: headsign.map(|h| h.to_string()),
             shape_id,
             direction,
-            mask_start_date: Some(mask_start_date),
+            mask_start_date: Some(mask_start_date.to_string()),
             date_mask: Some(date_mask),
         }
     }
 }
 
+impl From<&TripIR> for Trip {
+    fn from(value: &TripIR) -> Self {
+        Self {
+            trip_id: Some(value.trip_id.to_string()),
+            stop_times: sorted_values(&value.stop_times)
+                .map(StopTime::from)
+                .collect(),
+            headsign: value.headsign.as_deref().map(str::to_owned),
+            shape_id: value.shape_id.clone(),
+            direction: value.direction,
+            mask_start_date: Some(value.mask_start_date.to_string()),
+            date_mask: Some(value.date_mask),
+        }
+    }
+}
+
 // StopTime only implements PartialEq but Eq is just a marker trait so we don't need to do anything
 impl Eq for TripIR {}
 
+impl TripIR {
+    /// Fills in missing times the way GTFS reads them. A stop time with only an arrival or only a
+    /// departure doesn't dwell, so the other is the same. One with neither is interpolated
+    /// between the timed stops either side of it, evenly by position in the trip. Stop times
+    /// before the first timed stop or after the last are left without times. Returns the number of
+    /// stop times changed
+    pub fn fill_stop_times(&mut self) -> usize {
+        let mut changed = 0;
+        for st in self.stop_times.values_mut() {
+            match (st.arrival_time, st.departure_time) {
+                (None, Some(time)) => st.arrival_time = Some(time),
+                (Some(time), None) => st.departure_time = Some(time),
+                _ => continue,
+            }
+            changed += 1;
+        }
+
+        let mut sequences: Vec<u32> = self.stop_times.keys().copied().collect();
+        sequences.sort();
+        // Position, arrival and departure of the stop times with times, all of them have both now
+        let timed: Vec<(usize, u32, u32)> = sequences
+            .iter()
+            .enumerate()
+            .filter_map(|(i, seq)| {
+                let st = &self.stop_times[seq];
+                Some((i, st.arrival_time?, st.departure_time?))
+            })
+            .collect();
+
+        for pair in timed.windows(2) {
+            let [(start, _, from), (end, to, _)] = [pair[0], pair[1]];
+            for i in start + 1..end {
+                let offset = (to as i64 - from as i64) * (i - start) as i64 / (end - start) as i64;
+                let time = Some((from as i64 + offset) as u32);
+                let st = self
+                    .stop_times
+                    .get_mut(&sequences[i])
+                    .expect("Sequence was taken from the stop times");
+                st.arrival_time = time;
+                st.departure_time = time;
+                changed += 1;
+            }
+        }
+
+        changed
+    }
+}
+
+/// Picks the `direction` of a trip from its feed entry
+pub type DirectionResolver = fn(&gtfs_parsing::schedule::trips::Trip) -> Option<u32>;
+
+/// MTA convention, 0 for uptown and 1 for downtown
+pub fn default_direction(trip: &gtfs_parsing::schedule::trips::Trip) -> Option<u32> {
+    trip.direction_id
+        .map(|d| if d == DirectionType::Uptown { 0 } else { 1 })
+}
+
+/// Late night trips of the previous service day are still running until then
+pub const DEFAULT_SERVICE_DAY_CUTOFF_HOUR: u32 = 3;
+
+/// Optional processing applied while building a `ScheduleIR` from a parsed feed
+#[derive(Debug, Clone)]
+pub struct ScheduleOptions {
+    /// Merge shapes with identical points, see `ScheduleIR::dedup_shapes`
+    pub dedup_shapes: bool,
+    /// Used for every trip's `direction`, e.g. to tell express and local patterns apart by
+    /// headsign
+    pub direction_resolver: DirectionResolver,
+    /// Trips not active in the first this many days of the window are kept without their stop
+    /// times, to save memory
+    pub min_active_within_days: Option<u8>,
+    /// Leave out trips without any stop times in the feed, clients have no use for them. Trips
+    /// emptied by `min_active_within_days` are kept either way
+    pub skip_empty_trips: bool,
+    /// Only keep these routes and their trips, e.g. for a subway only deployment. Unset keeps
+    /// every route
+    pub route_filter: Option<HashSet<String>>,
+    /// Only keep routes of these types, see `ScheduleIR::retain_route_types`. Unset keeps every
+    /// route
+    pub route_types: Option<Vec<RouteType>>,
+    /// `min_active_within_days` for the routes of a type, e.g. a shorter one for buses, which have
+    /// far more trips. Types not listed use `min_active_within_days`
+    pub route_type_active_within_days: HashMap<RouteType, u8>,
+    /// Drop stops and shapes no kept trip uses, see `ScheduleIR::prune_unused`
+    pub prune_unused: bool,
+    /// Hour the service day starts when building from the current time, see `service_date`
+    pub service_day_cutoff_hour: u32,
+    /// Clean up headsigns that only differ in whitespace or case, see
+    /// `ScheduleIR::normalize_headsigns`
+    pub normalize_headsigns: bool,
+    /// Turn headway based trips into one trip per departure, see `ScheduleIR::expand_frequencies`.
+    /// `gtfs_parsing` doesn't read frequencies.txt, so this only applies when the server builds the
+    /// schedule from the feed's zip
+    pub expand_frequencies: bool,
+    /// Give every stop time both an arrival and a departure, see `TripIR::fill_stop_times`
+    pub fill_stop_times: bool,
+    /// Clean up names and headsigns, see `ScheduleIR::sanitize_text`
+    pub sanitize_text: bool,
+}
+
+impl Default for ScheduleOptions {
+    fn default() -> Self {
+        Self {
+            dedup_shapes: false,
+            direction_resolver: default_direction,
+            min_active_within_days: None,
+            skip_empty_trips: true,
+            route_filter: None,
+            route_types: None,
+            route_type_active_within_days: HashMap::new(),
+            prune_unused: false,
+            service_day_cutoff_hour: DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+            normalize_headsigns: false,
+            expand_frequencies: false,
+            fill_stop_times: false,
+            sanitize_text: true,
+        }
+    }
+}
+
+impl ScheduleOptions {
+    pub fn with_direction_resolver(self, direction_resolver: DirectionResolver) -> Self {
+        Self {
+            direction_resolver,
+            ..self
+        }
+    }
+}
+
+/// Problems found in the feed while building the IR that didn't stop it from being built
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduleWarning {
+    /// The stop is kept, but without a position
+    InvalidStopPosition {
+        stop_id: String,
+        stop_lat: String,
+        stop_lon: String,
+    },
+    /// `dropped` stop times of the trip repeated the `stop_sequence` of another and were
+    /// overwritten by it, see `stop_times::duplicate_stop_sequences`
+    DuplicateStopSequence { trip_id: String, dropped: usize },
+    /// The stop time was dropped, its arrival or departure `time` isn't an `HH:MM:SS` time
+    InvalidStopTime {
+        trip_id: String,
+        stop_sequence: u32,
+        time: String,
+    },
+    /// `field` of the stop, route or trip `id` had characters removed, see
+    /// `ScheduleIR::sanitize_text`
+    SanitizedText { field: &'static str, id: String },
+}
+
+impl std::fmt::Display for ScheduleWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidStopPosition {
+                stop_id,
+                stop_lat,
+                stop_lon,
+            } => write!(
+                f,
+                "Unable to parse position ({:?}, {:?}) of stop {}",
+                stop_lat, stop_lon, stop_id
+            ),
+            Self::DuplicateStopSequence { trip_id, dropped } => write!(
+                f,
+                "Dropped {} stop times of trip {} that repeated a stop_sequence",
+                dropped, trip_id
+            ),
+            Self::InvalidStopTime {
+                trip_id,
+                stop_sequence,
+                time,
+            } => write!(
+                f,
+                "Dropped stop time {} of trip {} with invalid time {:?}",
+                stop_sequence, trip_id, time
+            ),
+            Self::SanitizedText { field, id } => {
+                write!(f, "Removed stray characters from {} of {}", field, id)
+            }
+        }
+    }
+}
+
 impl ScheduleIR {
     pub fn try_from_schedule_with_dates(
         value: gtfs_parsing::schedule::Schedule,
         start_date: NaiveDate,
         days: u8,
     ) -> Self {
+        Self::try_from_schedule_with_options(value, start_date, days, &ScheduleOptions::default())
+    }
+
+    /// Builds the IR starting from the feed agency's current service day, same as `From`
+    pub fn from_schedule_with_options(
+        value: gtfs_parsing::schedule::Schedule,
+        options: &ScheduleOptions,
+    ) -> Self {
+        let tz = agency_timezone(&value);
+
+        // By default, keep the next 32
+        Self::try_from_schedule_with_options(
+            value,
+            service_date(&get_agency_datetime(tz), options.service_day_cutoff_hour),
+            32,
+            options,
+        )
+    }
+
+    /// Same as `try_from_schedule_with_warnings`, logging the warnings instead
+    pub fn try_from_schedule_with_options(
+        value: gtfs_parsing::schedule::Schedule,
+        start_date: NaiveDate,
+        days: u8,
+        options: &ScheduleOptions,
+    ) -> Self {
+        let (res, warnings) =
+            Self::try_from_schedule_with_warnings(value, start_date, days, options);
+
+        for warning in warnings {
+            warn!("{}", warning);
+        }
+
+        res
+    }
+
+    pub fn try_from_schedule_with_warnings(
+        value: gtfs_parsing::schedule::Schedule,
+        start_date: NaiveDate,
+        days: u8,
+        options: &ScheduleOptions,
+    ) -> (Self, Vec<ScheduleWarning>) {
+        let mut warnings = Vec::new();
+
         let gtfs_parsing::schedule::Schedule {
             trips: s_trips,
             routes: s_routes,
@@ -130,102 +608,173 @@ impl ScheduleIR {
             stops: s_stops,
             stop_times: mut s_stop_times,
             transfers: mut s_transfers,
-            agencies: _, // Slightly more explicit than ..
+            agencies: s_agencies,
         } = value;
 
         let mut routes = RouteIRs(HashMap::new());
-        for route_id in s_routes.into_keys() {
+        for (route_id, route) in s_routes {
+            if let Some(route_filter) = &options.route_filter
+                && !route_filter.contains(&route_id)
+            {
+                continue;
+            }
+
+            let gtfs_parsing::schedule::routes::Route {
+                route_short_name,
+                route_long_name,
+                route_color,
+                route_type,
+                ..
+            } = route;
+            let route_type = route_type as u32;
+
+            if let Some(route_types) = &options.route_types
+                && !route_types.contains(&route_type.into())
+            {
+                continue;
+            }
+
+            let route_id: Id = route_id.into();
             routes.insert(
                 route_id.clone(),
                 RouteIR {
                     route_id,
+                    metadata: RouteMetadata {
+                        short_name: route_short_name,
+                        long_name: route_long_name,
+                        color: route_color,
+                        route_type: Some(route_type),
+                    },
                     trips: TripIRs(HashMap::default()),
                 },
             );
         }
 
-        let start_date_str = format!(
-            "{:04}{:02}{:02}",
-            start_date.year(),
-            start_date.month(),
-            start_date.day()
-        );
+        let mask_start_date = MaskStartDate::from(start_date);
 
-        for (
-            trip_id,
-            gtfs_parsing::schedule::trips::Trip {
+        // Resolve the active days in the window once per service, rather than once per trip. Each
+        // trip then only needs a single lookup on its service_id
+        let mut service_masks: HashMap<String, u32> = HashMap::new();
+
+        for day in 0..days {
+            let date = start_date
+                .checked_add_days(Days::new(day as u64))
+                .unwrap_or_else(|| panic!("Unable to add {} days to date {}", days, start_date));
+            let dow = date.weekday();
+            let date_str = format!("{:04}{:02}{:02}", date.year(), date.month(), date.day());
+
+            for (service_id, service) in s_services.iter() {
+                let active = match dow {
+                    Weekday::Mon => service.monday.into(),
+                    Weekday::Tue => service.tuesday.into(),
+                    Weekday::Wed => service.wednesday.into(),
+                    Weekday::Thu => service.thursday.into(),
+                    Weekday::Fri => service.friday.into(),
+                    Weekday::Sat => service.saturday.into(),
+                    Weekday::Sun => service.sunday.into(),
+                } && service.start_date <= date_str
+                    && service.end_date >= date_str;
+
+                if active {
+                    *service_masks.entry(service_id.clone()).or_default() |= 1 << day;
+                }
+            }
+
+            // Exceptions override the base calendar for their date
+            for (service_id, service_exceptions) in s_service_exceptions.iter() {
+                if let Some(service_exception) = service_exceptions.get(&date_str) {
+                    let mask = service_masks.entry(service_id.clone()).or_default();
+
+                    // Exhaustive so a new exception type doesn't silently count as a removal
+                    match service_exception.exception_type {
+                        ExceptionType::Added => *mask |= 1 << day,
+                        ExceptionType::Removed => *mask &= !(1 << day),
+                    }
+                }
+            }
+        }
+
+        // Thousands of trips share each headsign and stop, so they share its allocation too
+        let mut headsigns: HashSet<Id> = HashSet::new();
+        let mut stop_ids: HashSet<Id> = HashSet::new();
+
+        let mut skipped_empty_trips = 0;
+        for (trip_id, s_trip) in s_trips {
+            let direction = (options.direction_resolver)(&s_trip);
+            let gtfs_parsing::schedule::trips::Trip {
                 shape_id,
                 trip_headsign: headsign,
-                direction_id,
                 ref route_id,
                 ref service_id,
                 ..
-            },
-        ) in s_trips
-        {
-            let mut date_mask = 0u32;
-
-            for day in 0..days {
-                let date = start_date
-                    .checked_add_days(Days::new(day as u64))
-                    .unwrap_or_else(|| {
-                        panic!("Unable to add {} days to date {}", days, start_date)
-                    });
-                let dow = date.weekday();
-                let date_str = format!("{:04}{:02}{:02}", date.year(), date.month(), date.day());
-
-                let mut active = false;
-
-                if let Some(service) = s_services.get(service_id) {
-                    active = match dow {
-                        Weekday::Mon => service.monday.into(),
-                        Weekday::Tue => service.tuesday.into(),
-                        Weekday::Wed => service.wednesday.into(),
-                        Weekday::Thu => service.thursday.into(),
-                        Weekday::Fri => service.friday.into(),
-                        Weekday::Sat => service.saturday.into(),
-                        Weekday::Sun => service.sunday.into(),
-                    } && service.start_date <= date_str
-                        && service.end_date >= date_str;
-                }
-                if let Some(service_exceptions) = s_service_exceptions.get(service_id) {
-                    if let Some(service_exception) = service_exceptions.get(&date_str) {
-                        active = service_exception.exception_type == ExceptionType::Added;
-                    }
-                }
+            } = s_trip;
 
-                if active {
-                    date_mask += 1 << day;
-                }
+            if !routes.contains_key(route_id.as_str()) {
+                // Unknown or filtered out route
+                continue;
             }
 
+            let date_mask = service_masks.get(service_id).copied().unwrap_or_default();
+
             if date_mask == 0 {
                 // No active dates found, skip this trip
                 continue;
             }
 
-            let stop_times = StopTimeIRs(
-                s_stop_times
-                    .remove(&trip_id)
-                    .unwrap_or_default()
-                    .into_iter()
-                    .map(|(k, v)| (k, v.into()))
-                    .collect(),
-            );
+            let s_trip_stop_times = s_stop_times.remove(&trip_id).unwrap_or_default();
+            if options.skip_empty_trips && s_trip_stop_times.is_empty() {
+                skipped_empty_trips += 1;
+                continue;
+            }
+
+            let active_within_days = routes[route_id.as_str()]
+                .route_type()
+                .and_then(|t| options.route_type_active_within_days.get(&t).copied())
+                .or(options.min_active_within_days);
+            let stop_times = match active_within_days {
+                // Lowest set bit is the first active day
+                Some(days) if date_mask.trailing_zeros() >= days.into() => {
+                    StopTimeIRs(HashMap::new())
+                }
+                _ => StopTimeIRs(
+                    s_trip_stop_times
+                        .into_iter()
+                        .filter_map(|(k, v)| match StopTimeIR::from_parsed(v, &mut stop_ids) {
+                            Ok(stop_time) => Some((k, stop_time)),
+                            Err(time) => {
+                                warnings.push(ScheduleWarning::InvalidStopTime {
+                                    trip_id: trip_id.clone(),
+                                    stop_sequence: k,
+                                    time,
+                                });
+                                None
+                            }
+                        })
+                        .collect(),
+                ),
+            };
+
+            let trip_id: Id = trip_id.into();
 
             let trip = TripIR {
                 trip_id: trip_id.clone(),
                 shape_id,
-                headsign,
-                direction: direction_id.map(|s| if s == DirectionType::Uptown { 0 } else { 1 }),
+                headsign: headsign.map(|h| intern(&mut headsigns, h)),
+                direction,
                 stop_times,
                 date_mask,
-                mask_start_date: start_date_str.clone(),
+                mask_start_date,
             };
 
-            if let Some(route) = routes.get_mut(route_id) {
-                route.trips.insert(trip_id, trip);
-            }
+            routes
+                .get_mut(route_id.as_str())
+                .expect("Route was checked above")
+                .trips
+                .insert(trip_id, trip);
+        }
+
+        if skipped_empty_trips > 0 {
+            info!("Skipped {} trips without stop times", skipped_empty_trips);
         }
 
         let shapes: ShapeIRs = ShapeIRs(
@@ -250,7 +799,7 @@ impl ScheduleIR {
                 .collect(),
         );
 
-        let stops: StopIRs = StopIRs(
+        let mut stops: StopIRs = StopIRs(
             s_stops
                 .into_iter()
                 .map(|(k, stop)| {
@@ -260,19 +809,45 @@ impl ScheduleIR {
                         stop_lon,
                         stop_name,
                         parent_station,
+                        location_type,
+                        wheelchair_boarding,
                         ..
                     } = stop;
 
-                    let transfers_from = s_transfers
+                    let mut transfers_from: Vec<Transfer> = s_transfers
                         .remove(&stop_id)
                         .unwrap_or_default()
                         .into_iter()
                         .map(|t| Transfer {
                             from_stop_id: t.from_stop_id,
                             to_stop_id: t.to_stop_id,
-                            min_transfer_time: t.min_transfer_time,
+                            // Missing means no extra time is needed beyond walking
+                            min_transfer_time: Some(t.min_transfer_time.unwrap_or_default()),
                         })
                         .collect();
+                    // Keep the order independent of the feed's row order
+                    transfers_from.sort_by(|a, b| {
+                        (&a.to_stop_id, a.min_transfer_time)
+                            .cmp(&(&b.to_stop_id, b.min_transfer_time))
+                    });
+
+                    let position = if let (Some(lat), Some(lon)) = (stop_lat, stop_lon) {
+                        if let (Ok(plat), Ok(plon)) = (lat.parse(), lon.parse()) {
+                            Some(Position {
+                                lat: Some(plat),
+                                lon: Some(plon),
+                            })
+                        } else {
+                            warnings.push(ScheduleWarning::InvalidStopPosition {
+                                stop_id: stop_id.clone(),
+                                stop_lat: lat,
+                                stop_lon: lon,
+                            });
+                            None
+                        }
+                    } else {
+                        None
+                    };
 
                     (
                         k,
@@ -282,36 +857,343 @@ impl ScheduleIR {
                             parent_stop_id: parent_station,
                             transfers_from,
                             route_ids: Vec::new(), // TODO calculate this
-                            position: if let (Some(lat), Some(lon)) = (stop_lat, stop_lon) {
-                                if let (Ok(lat), Ok(lon)) = (lat.parse(), lon.parse()) {
-                                    Some(Position {
-                                        lat: Some(lat),
-                                        lon: Some(lon),
-                                    })
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            },
+                            position,
+                            location_type: location_type.map(|t| t as u32),
+                            wheelchair_boarding: wheelchair_boarding.map(|w| w as u32),
                         },
                     )
                 })
                 .collect(),
         );
 
-        Self {
+        // Transfers can reference stops that aren't in the feed, clients have no use for these
+        let mut dropped_transfers: usize = s_transfers.values().map(Vec::len).sum();
+        let stop_ids: HashSet<String> = stops.keys().cloned().collect();
+        for stop in stops.values_mut() {
+            let before = stop.transfers_from.len();
+            stop.transfers_from.retain(|t| {
+                t.to_stop_id
+                    .as_ref()
+                    .is_some_and(|id| stop_ids.contains(id))
+            });
+            dropped_transfers += before - stop.transfers_from.len();
+        }
+        if dropped_transfers > 0 {
+            warn!("Dropped {} transfers with unknown stops", dropped_transfers);
+        }
+
+        let agencies: AgencyIRs = AgencyIRs(
+            s_agencies
+                .into_iter()
+                .map(|(k, agency)| {
+                    let gtfs_parsing::schedule::agency::Agency {
+                        agency_id,
+                        agency_name,
+                        agency_url,
+                        agency_timezone,
+                        ..
+                    } = agency;
+
+                    (
+                        k,
+                        Agency {
+                            agency_id,
+                            agency_name: Some(agency_name),
+                            agency_url: Some(agency_url),
+                            agency_timezone: Some(agency_timezone),
+                        },
+                    )
+                })
+                .collect(),
+        );
+
+        let mut res = Self {
             routes,
             stops,
             shapes,
+            agencies,
+        };
+
+        if options.sanitize_text {
+            warnings.extend(res.sanitize_text());
+        }
+
+        if options.dedup_shapes {
+            let removed = res.dedup_shapes();
+            info!("Removed {} duplicate shapes", removed);
+        }
+
+        if options.normalize_headsigns {
+            let changed = res.normalize_headsigns();
+            info!("Normalized the headsigns of {} trips", changed);
+        }
+
+        if options.fill_stop_times {
+            let filled: usize = res
+                .routes
+                .values_mut()
+                .flat_map(|r| r.trips.values_mut())
+                .map(TripIR::fill_stop_times)
+                .sum();
+            info!("Filled in the times of {} stop times", filled);
+        }
+
+        if options.prune_unused {
+            let (removed_stops, removed_shapes) = res.prune_unused();
+            info!(
+                "Removed {} unused stops and {} unused shapes",
+                removed_stops, removed_shapes
+            );
+        }
+
+        (res, warnings)
+    }
+
+    /// Merges shapes with identical points into the one with the smallest id, pointing their trips
+    /// at it instead. Returns the number of shapes removed
+    pub fn dedup_shapes(&mut self) -> usize {
+        let mut shape_ids: Vec<String> = self.shapes.keys().cloned().collect();
+        shape_ids.sort();
+
+        // Positions are floats, compare their exact bits instead
+        let mut canonical: HashMap<Vec<(Option<u64>, Option<u64>)>, String> = HashMap::new();
+        let mut replacements: HashMap<String, String> = HashMap::new();
+
+        for shape_id in shape_ids {
+            let key = self.shapes[&shape_id]
+                .points
+                .iter()
+                .map(|p| (p.lat.map(f64::to_bits), p.lon.map(f64::to_bits)))
+                .collect();
+
+            if let Some(canonical_id) = canonical.get(&key) {
+                replacements.insert(shape_id, canonical_id.clone());
+            } else {
+                canonical.insert(key, shape_id);
+            }
+        }
+
+        for shape_id in replacements.keys() {
+            self.shapes.remove(shape_id);
+        }
+
+        for trip in self.routes.values_mut().flat_map(|r| r.trips.values_mut()) {
+            if let Some(canonical_id) = trip.shape_id.as_ref().and_then(|id| replacements.get(id)) {
+                trip.shape_id = Some(canonical_id.clone());
+            }
+        }
+
+        replacements.len()
+    }
+}
+
+/// Returns the copy of `value` in `table`, adding it if it isn't there yet
+fn intern(table: &mut HashSet<Id>, value: String) -> Id {
+    if let Some(existing) = table.get(value.as_str()) {
+        return existing.clone();
+    }
+
+    let value: Id = value.into();
+    table.insert(value.clone());
+
+    value
+}
+
+/// Whether `c` has no place in a name, e.g. a byte order mark left at the start of a field or the
+/// replacement character a lenient parser put in place of invalid UTF-8
+fn is_stray_char(c: char) -> bool {
+    c == '\u{feff}' || c == char::REPLACEMENT_CHARACTER || c.is_control()
+}
+
+/// `text` without any stray characters, `None` if it had none
+fn sanitized(text: &str) -> Option<String> {
+    text.contains(is_stray_char)
+        .then(|| text.chars().filter(|c| !is_stray_char(*c)).collect())
+}
+
+impl ScheduleIR {
+    /// Removes byte order marks, control characters and replacement characters from stop names,
+    /// route names and headsigns. Text is valid UTF-8 by the time it's a `String`, so these are
+    /// what's left of a badly encoded feed, invalid sequences having been decoded to U+FFFD.
+    /// Returns a warning for each field changed
+    pub fn sanitize_text(&mut self) -> Vec<ScheduleWarning> {
+        let mut warnings = Vec::new();
+        let mut sanitize = |text: &mut Option<String>, field, id: &str| {
+            if let Some(clean) = text.as_deref().and_then(sanitized) {
+                *text = Some(clean);
+                warnings.push(ScheduleWarning::SanitizedText {
+                    field,
+                    id: id.to_owned(),
+                });
+            }
+        };
+
+        for (stop_id, stop) in self.stops.iter_mut() {
+            sanitize(&mut stop.stop_name, "stop_name", stop_id);
+        }
+
+        for (route_id, route) in self.routes.iter_mut() {
+            sanitize(&mut route.metadata.short_name, "route_short_name", route_id);
+            sanitize(&mut route.metadata.long_name, "route_long_name", route_id);
         }
+
+        // Headsigns are shared between trips, so each is only cleaned once and stays shared
+        let mut headsigns: HashMap<Id, Option<Id>> = HashMap::new();
+        for trip in self.routes.values_mut().flat_map(|r| r.trips.values_mut()) {
+            let Some(headsign) = &trip.headsign else {
+                continue;
+            };
+            let clean = headsigns
+                .entry(headsign.clone())
+                .or_insert_with(|| sanitized(headsign).map(Id::from));
+
+            if let Some(clean) = clean {
+                trip.headsign = Some(clean.clone());
+                warnings.push(ScheduleWarning::SanitizedText {
+                    field: "trip_headsign",
+                    id: trip.trip_id.to_string(),
+                });
+            }
+        }
+
+        warnings
     }
+
+    /// Trims headsigns and collapses runs of whitespace in them. Headsigns that are then the same
+    /// ignoring case all use the spelling most trips have, the smallest one on ties. Returns the
+    /// number of trips whose headsign changed
+    pub fn normalize_headsigns(&mut self) -> usize {
+        let collapse = |headsign: &str| headsign.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        // Spellings of each headsign ignoring case, with how many trips use each
+        let mut spellings: HashMap<String, HashMap<String, usize>> = HashMap::new();
+        for trip in self.routes.values().flat_map(|r| r.trips.values()) {
+            if let Some(headsign) = &trip.headsign {
+                let headsign = collapse(headsign);
+                *spellings
+                    .entry(headsign.to_lowercase())
+                    .or_default()
+                    .entry(headsign)
+                    .or_default() += 1;
+            }
+        }
+
+        let canonical: HashMap<String, Id> = spellings
+            .into_iter()
+            .map(|(key, counts)| {
+                let (spelling, _) = counts
+                    .into_iter()
+                    .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))
+                    .expect("Every key has a spelling");
+                (key, spelling.into())
+            })
+            .collect();
+
+        let mut changed = 0;
+        for trip in self.routes.values_mut().flat_map(|r| r.trips.values_mut()) {
+            if let Some(headsign) = &trip.headsign {
+                let normalized = &canonical[&collapse(headsign).to_lowercase()];
+                if normalized != headsign {
+                    changed += 1;
+                }
+                trip.headsign = Some(normalized.clone());
+            }
+        }
+
+        changed
+    }
+
+    /// Stops some stop time visits, along with their parents
+    fn used_stop_ids(&self) -> HashSet<String> {
+        let mut stop_ids: HashSet<String> = self
+            .routes
+            .values()
+            .flat_map(|r| r.trips.values())
+            .flat_map(|t| t.stop_times.values())
+            .filter_map(|st| st.stop_id.as_deref().map(str::to_owned))
+            .collect();
+        let parent_ids: Vec<String> = stop_ids
+            .iter()
+            .filter_map(|id| self.stops.get(id))
+            .filter_map(|s| s.parent_stop_id.clone())
+            .collect();
+        stop_ids.extend(parent_ids);
+
+        stop_ids
+    }
+
+    /// Stops no trip serves, e.g. closed stations, ordered by stop id. Parents of served stops
+    /// don't count, see `prune_unused` for removing them
+    pub fn orphan_stops(&self) -> Vec<&Stop> {
+        let used = self.used_stop_ids();
+        let mut orphans: Vec<&Stop> = self
+            .stops
+            .iter()
+            .filter(|(id, _)| !used.contains(*id))
+            .map(|(_, stop)| stop)
+            .collect();
+        orphans.sort_by(|a, b| a.stop_id.cmp(&b.stop_id));
+
+        orphans
+    }
+
+    /// Removes the routes not of one of `route_types`, along with their trips. Routes without a
+    /// type are removed too. Returns the number of routes removed
+    pub fn retain_route_types(&mut self, route_types: &[RouteType]) -> usize {
+        let before = self.routes.len();
+        self.routes.retain(|_, route| {
+            route
+                .route_type()
+                .is_some_and(|route_type| route_types.contains(&route_type))
+        });
+
+        before - self.routes.len()
+    }
+
+    /// Removes stops no stop time visits and shapes no trip follows, keeping the parents of visited
+    /// stops. Transfers to removed stops are dropped too. Returns the number of stops and shapes
+    /// removed
+    pub fn prune_unused(&mut self) -> (usize, usize) {
+        let shape_ids: HashSet<String> = self
+            .routes
+            .values()
+            .flat_map(|r| r.trips.values())
+            .filter_map(|t| t.shape_id.clone())
+            .collect();
+        let stop_ids = self.used_stop_ids();
+
+        let (stops_before, shapes_before) = (self.stops.len(), self.shapes.len());
+        self.stops.retain(|id, _| stop_ids.contains(id));
+        self.shapes.retain(|id, _| shape_ids.contains(id));
+
+        for stop in self.stops.values_mut() {
+            stop.transfers_from.retain(|t| {
+                t.to_stop_id
+                    .as_ref()
+                    .is_some_and(|id| stop_ids.contains(id))
+            });
+        }
+
+        (
+            stops_before - self.stops.len(),
+            shapes_before - self.shapes.len(),
+        )
+    }
+}
+
+/// Timezone the feed's service days follow, taken from its agencies. Falls back to New York when
+/// no agency has a valid timezone
+pub fn agency_timezone(schedule: &gtfs_parsing::schedule::Schedule) -> Tz {
+    schedule
+        .agencies
+        .values()
+        .find_map(|a| a.agency_timezone.parse::<Tz>().ok())
+        .unwrap_or(New_York)
 }
 
 impl From<gtfs_parsing::schedule::Schedule> for ScheduleIR {
     fn from(value: gtfs_parsing::schedule::Schedule) -> Self {
-        // By default, keep the next 32
-        Self::try_from_schedule_with_dates(value, get_nyc_datetime().date_naive(), 32)
+        Self::from_schedule_with_options(value, &ScheduleOptions::default())
     }
 }
 
@@ -321,49 +1203,202 @@ impl From<ScheduleIR> for FullSchedule {
             routes,
             shapes,
             stops,
+            agencies,
         } = value;
 
         Self {
-            routes: routes
-                .into_inner()
-                .into_values()
+            routes: into_sorted_values(routes.into_inner())
                 .map(RouteIR::into)
                 .collect(),
-            shapes: shapes.into_inner().into_values().collect(),
-            stops: stops.into_inner().into_values().collect(),
+            shapes: into_sorted_values(shapes.into_inner()).collect(),
+            stops: into_sorted_values(stops.into_inner()).collect(),
+            agencies: into_sorted_values(agencies.into_inner()).collect(),
         }
     }
 }
 
-impl From<gtfs_parsing::schedule::stop_times::StopTime> for StopTime {
-    fn from(value: gtfs_parsing::schedule::stop_times::StopTime) -> Self {
-        let gtfs_parsing::schedule::stop_times::StopTime {
-            stop_id,
-            arrival_time,
-            departure_time,
-            stop_sequence,
-            ..
-        } = value;
+// Avoids cloning the whole IR when it still needs to be kept around afterwards
+impl From<&ScheduleIR> for FullSchedule {
+    fn from(value: &ScheduleIR) -> Self {
         Self {
-            stop_id,
-            arrival_time: time_str_to_int(arrival_time),
-            departure_time: time_str_to_int(departure_time),
-            stop_sequence: Some(stop_sequence),
+            routes: sorted_values(&value.routes).map(Route::from).collect(),
+            shapes: sorted_values(&value.shapes).cloned().collect(),
+            stops: sorted_values(&value.stops).cloned().collect(),
+            agencies: sorted_values(&value.agencies).cloned().collect(),
         }
     }
 }
 
-// Converts a time string to a number of seconds since midnight
-fn time_str_to_int(time: Option<String>) -> Option<u32> {
-    let parts: Vec<u32> = time?
-        .split(":")
-        .map(|p| p.parse::<u32>().unwrap_or_default())
-        .collect();
+/// Rebuilds the IR from a schedule sent to clients, e.g. one uploaded to be diffed against. Fails if
+/// anything the IR is keyed by is missing
+impl TryFrom<FullSchedule> for ScheduleIR {
+    type Error = ScheduleError;
 
-    let mut res = 0u32;
-    for (i, part) in parts.into_iter().enumerate() {
-        res += part * 60u32.pow(i as u32);
+    fn try_from(value: FullSchedule) -> Result<Self, Self::Error> {
+        let FullSchedule {
+            routes,
+            shapes,
+            stops,
+            agencies,
+        } = value;
+        let missing = |what: String| ScheduleError::ParseError(format!("{} has no id", what));
+
+        let mut headsigns: HashSet<Id> = HashSet::new();
+        let mut stop_ids: HashSet<Id> = HashSet::new();
+        let mut route_irs = HashMap::new();
+        for route in routes {
+            let Route {
+                route_id,
+                trips,
+                route_short_name,
+                route_long_name,
+                route_color,
+                route_type,
+            } = route;
+            let route_id: Id = route_id.ok_or_else(|| missing("Route".to_owned()))?.into();
+
+            let mut trip_irs = HashMap::new();
+            for trip in trips {
+                let Trip {
+                    trip_id,
+                    headsign,
+                    shape_id,
+                    stop_times,
+                    direction,
+                    mask_start_date,
+                    date_mask,
+                } = trip;
+                let trip_id: Id = trip_id
+                    .ok_or_else(|| missing(format!("Trip on route {}", route_id)))?
+                    .into();
+                let stop_times = stop_times
+                    .into_iter()
+                    .map(|st| match st.stop_sequence {
+                        Some(seq) => Ok((seq, StopTimeIR::from_proto(st, &mut stop_ids))),
+                        None => Err(ScheduleError::ParseError(format!(
+                            "Stop time of trip {} has no stop sequence",
+                            trip_id
+                        ))),
+                    })
+                    .collect::<Result<HashMap<_, _>, _>>()?;
+                let mask_start_date = mask_start_date
+                    .ok_or_else(|| {
+                        ScheduleError::ParseError(format!(
+                            "Trip {} has no mask start date",
+                            trip_id
+                        ))
+                    })?
+                    .parse()?;
+
+                trip_irs.insert(
+                    trip_id.clone(),
+                    TripIR {
+                        trip_id,
+                        stop_times: StopTimeIRs(stop_times),
+                        headsign: headsign.map(|h| intern(&mut headsigns, h)),
+                        shape_id,
+                        direction,
+                        mask_start_date,
+                        date_mask: date_mask.unwrap_or_default(),
+                    },
+                );
+            }
+
+            route_irs.insert(
+                route_id.clone(),
+                RouteIR {
+                    route_id,
+                    metadata: RouteMetadata {
+                        short_name: route_short_name,
+                        long_name: route_long_name,
+                        color: route_color,
+                        route_type,
+                    },
+                    trips: TripIRs(trip_irs),
+                },
+            );
+        }
+
+        let shapes = shapes
+            .into_iter()
+            .map(|shape| match shape.shape_id.clone() {
+                Some(shape_id) => Ok((shape_id, shape)),
+                None => Err(missing("Shape".to_owned())),
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        let stops = stops
+            .into_iter()
+            .map(|stop| match stop.stop_id.clone() {
+                Some(stop_id) => Ok((stop_id, stop)),
+                None => Err(missing("Stop".to_owned())),
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        // A feed with a single agency can leave its id out
+        let agencies = agencies
+            .into_iter()
+            .map(|agency| (agency.agency_id.clone().unwrap_or_default(), agency))
+            .collect();
+
+        Ok(Self {
+            routes: RouteIRs(route_irs),
+            shapes: ShapeIRs(shapes),
+            stops: StopIRs(stops),
+            agencies: AgencyIRs(agencies),
+        })
+    }
+}
+
+impl ScheduleIR {
+    /// Hash of the schedule's contents, the same for the same schedule however the feed it came
+    /// from was laid out. Computed over the encoded `FullSchedule`, which is ordered by id
+    pub fn content_hash(&self) -> blake3::Hash {
+        blake3::hash(&FullSchedule::from(self).encode_to_vec())
+    }
+
+    /// One page of the full schedule, splitting routes into chunks of `page_size` in route id
+    /// order. Stops, shapes and agencies are only included in the first page. Returns the number of
+    /// the next page if there are routes left
+    pub fn full_schedule_page(&self, page: u32, page_size: u32) -> (FullSchedule, Option<u32>) {
+        let routes: Vec<&RouteIR> = sorted_values(&self.routes).collect();
+
+        let page_size = page_size.max(1) as usize;
+        let start = (page as usize).saturating_mul(page_size);
+        let next_page = (start.saturating_add(page_size) < routes.len()).then_some(page + 1);
+
+        let routes = routes
+            .into_iter()
+            .skip(start)
+            .take(page_size)
+            .map(Route::from)
+            .collect();
+
+        let schedule = if page == 0 {
+            FullSchedule {
+                routes,
+                shapes: sorted_values(&self.shapes).cloned().collect(),
+                stops: sorted_values(&self.stops).cloned().collect(),
+                agencies: sorted_values(&self.agencies).cloned().collect(),
+            }
+        } else {
+            FullSchedule {
+                routes,
+                ..Default::default()
+            }
+        };
+
+        (schedule, next_page)
     }
+}
+
+/// Parses a GTFS `HH:MM:SS` time into seconds since midnight, hours go past 24 for trips running
+/// after midnight. None if it isn't one
+pub fn parse_time(time: &str) -> Option<u32> {
+    let mut parts = time.trim().split(':').map(|p| p.parse::<u32>().ok());
+    let (Some(Some(h)), Some(Some(m)), Some(Some(s)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
 
-    Some(res)
+    (m < 60 && s < 60).then_some(h * 3600 + m * 60 + s)
 }
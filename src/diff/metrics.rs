@@ -0,0 +1,145 @@
+use prost::Message;
+
+use crate::server::db_transit::{self, ScheduleDiff};
+
+use super::{core::ScheduleUpdate, ir::Id};
+
+/// Size of a single update, for tracking how diffs grow over time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffMetrics {
+    pub timestamp: u32,
+
+    pub added_trips: usize,
+    pub removed_trips: usize,
+    pub date_mask_updates: usize,
+    // Stop times sent as part of added trips
+    pub changed_stop_times: usize,
+
+    pub added_stops: usize,
+    pub removed_stops: usize,
+    pub added_shapes: usize,
+    pub removed_shapes: usize,
+
+    // Size of the diff once encoded for clients, before compression
+    pub encoded_bytes: usize,
+}
+
+impl DiffMetrics {
+    pub fn new(timestamp: u32, update: &ScheduleUpdate) -> Self {
+        Self {
+            timestamp,
+            added_trips: update.added_trips.len(),
+            removed_trips: update.removed_trip_ids.len(),
+            date_mask_updates: update.date_mask_updates.len(),
+            changed_stop_times: update
+                .added_trips
+                .values()
+                .map(|t| t.stop_times.len())
+                .sum(),
+            added_stops: update.added_stops.len(),
+            removed_stops: update.removed_stop_ids.len(),
+            added_shapes: update.added_shapes.len(),
+            removed_shapes: update.removed_shape_ids.len(),
+            encoded_bytes: ScheduleDiff::from(update.clone()).encoded_len(),
+        }
+    }
+}
+
+impl ScheduleUpdate {
+    /// Share of `total_trips` the update adds, removes or replaces, counting a replaced trip once.
+    /// Changes to only a trip's dates aren't counted
+    pub fn changed_trip_ratio(&self, total_trips: usize) -> f64 {
+        if total_trips == 0 {
+            return 0.0;
+        }
+
+        let removed_only = self
+            .removed_trip_ids
+            .iter()
+            .filter(|key| !self.added_trips.contains_key(*key))
+            .count();
+
+        (self.added_trips.len() + removed_only) as f64 / total_trips as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TripChangeKind {
+    Added,
+    Removed,
+    /// Removed and added back, or only its dates changed
+    Updated,
+}
+
+/// A single trip changed by an update
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TripChange {
+    pub route_id: Id,
+    pub trip_id: Id,
+    // Unknown for removed trips, the update only has their ids
+    pub headsign: Option<String>,
+    pub kind: TripChangeKind,
+}
+
+impl ScheduleUpdate {
+    /// Every trip the update touches, sorted by route then trip id
+    pub fn changed_trip_summary(&self) -> Vec<TripChange> {
+        let mut res: Vec<TripChange> = self
+            .added_trips
+            .iter()
+            .map(|(key, trip)| TripChange {
+                route_id: key.0.clone(),
+                trip_id: key.1.clone(),
+                headsign: trip.headsign.as_deref().map(str::to_owned),
+                kind: if self.removed_trip_ids.contains(key) {
+                    TripChangeKind::Updated
+                } else {
+                    TripChangeKind::Added
+                },
+            })
+            .collect();
+
+        res.extend(
+            self.removed_trip_ids
+                .iter()
+                .filter(|key| !self.added_trips.contains_key(*key))
+                .map(|(route_id, trip_id)| TripChange {
+                    route_id: route_id.clone(),
+                    trip_id: trip_id.clone(),
+                    headsign: None,
+                    kind: TripChangeKind::Removed,
+                }),
+        );
+
+        res.extend(
+            self.date_mask_updates
+                .keys()
+                .map(|(route_id, trip_id)| TripChange {
+                    route_id: route_id.clone(),
+                    trip_id: trip_id.clone(),
+                    headsign: None,
+                    kind: TripChangeKind::Updated,
+                }),
+        );
+
+        res.sort_by(|a, b| (&a.route_id, &a.trip_id).cmp(&(&b.route_id, &b.trip_id)));
+        res
+    }
+}
+
+impl From<TripChange> for db_transit::TripChange {
+    fn from(value: TripChange) -> Self {
+        let kind = match value.kind {
+            TripChangeKind::Added => db_transit::TripChangeKind::Added,
+            TripChangeKind::Removed => db_transit::TripChangeKind::Removed,
+            TripChangeKind::Updated => db_transit::TripChangeKind::Updated,
+        };
+
+        Self {
+            route_id: Some(value.route_id.to_string()),
+            trip_id: Some(value.trip_id.to_string()),
+            headsign: value.headsign,
+            kind: Some(kind.into()),
+        }
+    }
+}
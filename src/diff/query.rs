@@ -0,0 +1,344 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Days, NaiveDate, TimeZone, Timelike};
+
+use crate::server::db_transit::Stop;
+
+use super::ir::{Id, RouteIR, ScheduleIR, StopTimeIR, TripIR};
+
+/// Outgoing connection from a stop, see `ScheduleIR::build_adjacency`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edge {
+    /// Walking transfer listed in the stop's `transfers_from`
+    Transfer {
+        to_stop_id: String,
+        min_transfer_time: Option<u32>,
+    },
+    /// Riding `trip_id` to the next stop it visits. Times are seconds since midnight. The trip id
+    /// isn't unique across routes, see `ScheduleIR::duplicate_trip_ids`
+    Ride {
+        to_stop_id: String,
+        trip_id: Id,
+        depart: u32,
+        arrive: u32,
+    },
+}
+
+/// Whether `date_mask`, starting on `mask_start_date`, has `date` set
+pub fn mask_has_date(mask_start_date: NaiveDate, date_mask: u32, date: NaiveDate) -> bool {
+    match (date - mask_start_date).num_days() {
+        day @ 0..32 => date_mask & (1 << day) != 0,
+        _ => false,
+    }
+}
+
+impl TripIR {
+    /// Whether the trip's date mask has `date` set
+    pub fn is_active_on(&self, date: NaiveDate) -> bool {
+        mask_has_date(self.mask_start_date.date(), self.date_mask, date)
+    }
+
+    /// Earliest and latest time of any of the trip's stop times, in seconds since midnight of its
+    /// service day. None if none of them have a time
+    pub fn time_span(&self) -> Option<(u32, u32)> {
+        let times = self
+            .stop_times
+            .values()
+            .flat_map(|st| [st.arrival_time, st.departure_time])
+            .flatten();
+
+        times.fold(None, |span, t| match span {
+            None => Some((t, t)),
+            Some((first, last)) => Some((first.min(t), last.max(t))),
+        })
+    }
+
+    /// Whether the trip is running at `dt`, i.e. it's between its first and last stop time on a
+    /// service day it's active on. Times past 24:00:00 belong to the previous day's service, so
+    /// a trip leaving at 23:50 on a Monday is still running at 00:10 on Tuesday even if it isn't
+    /// active on Tuesday
+    pub fn is_active_at<T: TimeZone>(&self, dt: &DateTime<T>) -> bool {
+        let Some((first, last)) = self.time_span() else {
+            return false;
+        };
+        let date = dt.date_naive();
+        let secs = dt.time().num_seconds_from_midnight();
+
+        // Each service day back the trip could still be running on is another 24h of offset
+        (0..=last / 86400).any(|days_back| {
+            let offset = secs + days_back * 86400;
+
+            date.checked_sub_days(Days::new(days_back as u64))
+                .is_some_and(|d| self.is_active_on(d) && (first..=last).contains(&offset))
+        })
+    }
+
+    /// The trip's stop time at `stop_id`, the first one by sequence if it visits more than once.
+    /// Scans every stop time, trips have few enough that an index per trip isn't worth the memory
+    pub fn stop_time_for(&self, stop_id: &str) -> Option<&StopTimeIR> {
+        self.stop_times
+            .values()
+            .filter(|st| st.stop_id.as_deref() == Some(stop_id))
+            .min_by_key(|st| st.stop_sequence)
+    }
+
+    /// Stop times in the order the trip visits them
+    pub fn ordered_stop_times(&self) -> Vec<&StopTimeIR> {
+        let mut res: Vec<&StopTimeIR> = self.stop_times.values().collect();
+        res.sort_by_key(|st| st.stop_sequence);
+        res
+    }
+
+    /// Keys of stop times whose `stop_sequence` doesn't match their key or doesn't increase over
+    /// the previous one, e.g. a sequence repeated under another key. Gaps between sequences are
+    /// allowed. Parsed trips are keyed by sequence so they never have any, duplicates in the feed
+    /// are caught by `stop_times::duplicate_stop_sequences` instead
+    pub fn invalid_stop_sequences(&self) -> Vec<u32> {
+        let mut keys: Vec<u32> = self.stop_times.keys().copied().collect();
+        keys.sort();
+
+        let mut prev = None;
+        let mut res = Vec::new();
+
+        for key in keys {
+            let stop_sequence = self.stop_times[&key].stop_sequence;
+
+            if stop_sequence != Some(key) || stop_sequence <= prev {
+                res.push(key);
+            }
+            prev = prev.max(stop_sequence);
+        }
+
+        res
+    }
+}
+
+impl RouteIR {
+    /// Distinct ordered stop sequences the route's trips follow, per direction, e.g. for drawing a
+    /// line diagram. Patterns with the most stops come first. Trips without a direction count as
+    /// direction 0
+    pub fn stop_patterns(&self) -> HashMap<u32, Vec<Vec<String>>> {
+        let mut patterns: HashMap<u32, HashSet<Vec<String>>> = HashMap::new();
+
+        for trip in self.trips.values() {
+            let pattern: Vec<String> = trip
+                .ordered_stop_times()
+                .into_iter()
+                .filter_map(|st| st.stop_id.as_deref().map(str::to_owned))
+                .collect();
+
+            if !pattern.is_empty() {
+                patterns
+                    .entry(trip.direction.unwrap_or_default())
+                    .or_default()
+                    .insert(pattern);
+            }
+        }
+
+        patterns
+            .into_iter()
+            .map(|(direction, patterns)| {
+                let mut patterns: Vec<Vec<String>> = patterns.into_iter().collect();
+                patterns.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+                (direction, patterns)
+            })
+            .collect()
+    }
+}
+
+impl ScheduleIR {
+    pub fn trip_count(&self) -> usize {
+        self.routes.values().map(|r| r.trips.len()).sum()
+    }
+
+    /// Every stop time at `stop_id`, along with the trip and route it belongs to. Trips visiting
+    /// the stop more than once appear once per visit
+    pub fn trips_at_stop(&self, stop_id: &str) -> Vec<(&RouteIR, &TripIR, &StopTimeIR)> {
+        let mut res = Vec::new();
+
+        for route in self.routes.values() {
+            for trip in route.trips.values() {
+                for stop_time in trip.stop_times.values() {
+                    if stop_time.stop_id.as_deref() == Some(stop_id) {
+                        res.push((route, trip, stop_time));
+                    }
+                }
+            }
+        }
+
+        res
+    }
+
+    /// The next `limit` departures from `stop_id` at or after `from_time` (seconds since midnight)
+    /// on `date`, sorted by departure time
+    pub fn next_departures(
+        &self,
+        stop_id: &str,
+        date: NaiveDate,
+        from_time: u32,
+        limit: usize,
+    ) -> Vec<(&RouteIR, &TripIR, &StopTimeIR)> {
+        let mut res: Vec<_> = self
+            .trips_at_stop(stop_id)
+            .into_iter()
+            .filter(|(_, trip, stop_time)| {
+                trip.is_active_on(date) && stop_time.departure_time.is_some_and(|t| t >= from_time)
+            })
+            .collect();
+
+        res.sort_by_key(|(_, trip, stop_time)| (stop_time.departure_time, trip.trip_id.clone()));
+        res.truncate(limit);
+
+        res
+    }
+
+    /// Copy of the schedule limited to the stops inside the given box, the trips serving at least
+    /// one of them, and the shapes those trips use. Stops without a position are left out. Routes
+    /// are always kept, even if they end up with no trips
+    pub fn within_bbox(&self, min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> Self {
+        let mut res = self.clone();
+
+        res.stops.retain(|_, stop| {
+            stop.position
+                .as_ref()
+                .is_some_and(|p| match (p.lat, p.lon) {
+                    (Some(lat), Some(lon)) => {
+                        (min_lat..=max_lat).contains(&lat) && (min_lon..=max_lon).contains(&lon)
+                    }
+                    _ => false,
+                })
+        });
+
+        for route in res.routes.values_mut() {
+            route.trips.retain(|_, trip| {
+                trip.stop_times.values().any(|st| {
+                    st.stop_id
+                        .as_deref()
+                        .is_some_and(|id| res.stops.contains_key(id))
+                })
+            });
+        }
+
+        let shape_ids: HashSet<String> = res
+            .routes
+            .values()
+            .flat_map(|r| r.trips.values())
+            .filter_map(|t| t.shape_id.clone())
+            .collect();
+        res.shapes.retain(|id, _| shape_ids.contains(id));
+
+        res
+    }
+
+    /// Outgoing edges per stop id, for building a trip planner graph. Each stop's transfers come
+    /// first, then its rides ordered by departure. A ride connects consecutive stop times of a trip
+    /// and is left out if either end is missing its stop id or time, a stop time without a
+    /// departure time departs at its arrival time and vice versa
+    pub fn build_adjacency(&self) -> HashMap<String, Vec<Edge>> {
+        let mut res: HashMap<String, Vec<Edge>> = HashMap::new();
+
+        for (stop_id, stop) in self.stops.iter() {
+            let transfers = stop.transfers_from.iter().filter_map(|t| {
+                Some(Edge::Transfer {
+                    to_stop_id: t.to_stop_id.clone()?,
+                    min_transfer_time: t.min_transfer_time,
+                })
+            });
+            res.entry(stop_id.clone()).or_default().extend(transfers);
+        }
+
+        // (depart, trip_id, to_stop_id, arrive) so they sort by departure
+        let mut rides: HashMap<String, Vec<(u32, Id, String, u32)>> = HashMap::new();
+        for trip in self.routes.values().flat_map(|r| r.trips.values()) {
+            for pair in trip.ordered_stop_times().windows(2) {
+                let (from, to) = (pair[0], pair[1]);
+                let (Some(from_stop_id), Some(to_stop_id)) = (&from.stop_id, &to.stop_id) else {
+                    continue;
+                };
+                let (Some(depart), Some(arrive)) = (
+                    from.departure_time.or(from.arrival_time),
+                    to.arrival_time.or(to.departure_time),
+                ) else {
+                    continue;
+                };
+
+                rides.entry(from_stop_id.to_string()).or_default().push((
+                    depart,
+                    trip.trip_id.clone(),
+                    to_stop_id.to_string(),
+                    arrive,
+                ));
+            }
+        }
+
+        for (stop_id, mut rides) in rides {
+            rides.sort();
+            res.entry(stop_id)
+                .or_default()
+                .extend(
+                    rides
+                        .into_iter()
+                        .map(|(depart, trip_id, to_stop_id, arrive)| Edge::Ride {
+                            to_stop_id,
+                            trip_id,
+                            depart,
+                            arrive,
+                        }),
+                );
+        }
+
+        res.retain(|_, edges| !edges.is_empty());
+        res
+    }
+
+    /// Trip ids used by more than one route, along with those routes sorted by id. Parsing a single
+    /// feed can't produce these since it keys trips by id, but merged or hand-built schedules can
+    pub fn duplicate_trip_ids(&self) -> HashMap<Id, Vec<Id>> {
+        let mut route_ids: HashMap<Id, Vec<Id>> = HashMap::new();
+
+        for route in self.routes.values() {
+            for trip_id in route.trips.keys() {
+                route_ids
+                    .entry(trip_id.clone())
+                    .or_default()
+                    .push(route.route_id.clone());
+            }
+        }
+
+        route_ids.retain(|_, route_ids| route_ids.len() > 1);
+        for route_ids in route_ids.values_mut() {
+            route_ids.sort();
+        }
+
+        route_ids
+    }
+
+    /// Stops directly under `parent_id`, e.g. the platforms of a station
+    pub fn station_children(&self, parent_id: &str) -> Vec<&Stop> {
+        self.stops
+            .values()
+            .filter(|s| s.parent_stop_id.as_deref() == Some(parent_id))
+            .collect()
+    }
+
+    /// Follows `parent_stop_id` up from `stop_id` to the top-level station. A parent missing from
+    /// the schedule ends the walk, as does a cycle, in which case the last stop before repeating is
+    /// returned
+    pub fn resolve_station(&self, stop_id: &str) -> Option<&Stop> {
+        let mut stop = self.stops.get(stop_id)?;
+        let mut seen: HashSet<&str> = HashSet::from([stop_id]);
+
+        while let Some(parent_id) = stop.parent_stop_id.as_deref() {
+            if !seen.insert(parent_id) {
+                break;
+            }
+
+            match self.stops.get(parent_id) {
+                Some(parent) => stop = parent,
+                None => break,
+            }
+        }
+
+        Some(stop)
+    }
+}
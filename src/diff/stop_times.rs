@@ -0,0 +1,99 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Seek},
+};
+
+use gtfs_parsing::schedule::Schedule;
+use logge_rs::warn;
+use zip::ZipArchive;
+
+use crate::{diff::ir::ScheduleWarning, error::ScheduleError};
+
+pub const STOP_TIMES_FILE: &'static str = "stop_times.txt";
+
+/// Fields of a CSV line, with quotes around a field removed and doubled quotes inside one unescaped
+pub fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+
+    fields
+}
+
+/// Number of rows of each trip in stop_times.txt, finding the trip_id column by its header. Rows
+/// are decoded lossily like the rest of the feed's text, so a stray byte doesn't fail the count.
+/// Rows too short to have a trip_id are logged and skipped
+pub fn count_stop_time_rows(reader: impl Read) -> Result<HashMap<String, usize>, ScheduleError> {
+    let mut lines = BufReader::new(reader).split(b'\n').enumerate();
+    let header = match lines.next() {
+        Some((_, line)) => String::from_utf8_lossy(&line?).into_owned(),
+        None => String::new(),
+    };
+    let trip_id = split_fields(header.trim_start_matches('\u{feff}'))
+        .iter()
+        .position(|c| c.trim() == "trip_id")
+        .ok_or_else(|| format!("{} has no trip_id column", STOP_TIMES_FILE))?;
+
+    let mut rows: HashMap<String, usize> = HashMap::new();
+    for (i, line) in lines {
+        let line = line?;
+        let line = String::from_utf8_lossy(&line);
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match split_fields(&line).get(trip_id) {
+            Some(id) => *rows.entry(id.trim().to_owned()).or_default() += 1,
+            None => warn!(
+                "Skipped line {} of {} without a trip_id",
+                i + 1,
+                STOP_TIMES_FILE
+            ),
+        }
+    }
+
+    Ok(rows)
+}
+
+/// `count_stop_time_rows` of a zipped feed's stop_times.txt, which every feed has
+pub fn read_stop_time_rows<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<HashMap<String, usize>, ScheduleError> {
+    count_stop_time_rows(archive.by_name(STOP_TIMES_FILE)?)
+}
+
+/// Trips with fewer stop times in `schedule` than they have `rows` in stop_times.txt. They're keyed
+/// by `stop_sequence` while parsing, so rows repeating one overwrite each other before the IR is
+/// built
+pub fn duplicate_stop_sequences(
+    schedule: &Schedule,
+    rows: &HashMap<String, usize>,
+) -> Vec<ScheduleWarning> {
+    let mut warnings: Vec<ScheduleWarning> = rows
+        .iter()
+        .filter_map(|(trip_id, &rows)| {
+            let kept = schedule.stop_times.get(trip_id).map_or(0, |st| st.len());
+
+            (kept < rows).then(|| ScheduleWarning::DuplicateStopSequence {
+                trip_id: trip_id.clone(),
+                dropped: rows - kept,
+            })
+        })
+        .collect();
+    // Same order every run
+    warnings.sort_by_key(|w| w.to_string());
+
+    warnings
+}
@@ -1,16 +1,38 @@
 #![cfg(test)]
 
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Cursor, Write},
+};
 
-use chrono::NaiveDate;
+use chrono::{NaiveDate, TimeZone};
+use chrono_tz::America::{Los_Angeles, New_York};
 use gtfs_parsing::schedule::Schedule;
+use prost::Message;
+use zip::{ZipArchive, ZipWriter, write::SimpleFileOptions};
 
 use crate::{
-    diff::{core::ScheduleUpdate, ir::ScheduleIR},
-    shared::db_transit::{FullSchedule, Position, Shape, Stop},
+    diff::{
+        core::{DiffOptions, PointSplice, RouteScheduleUpdate, ScheduleUpdate},
+        frequencies::parse_frequencies,
+        history::ScheduleHistory,
+        ir::{
+            MaskStartDate, RouteMetadata, RouteType, ScheduleIR, ScheduleOptions, ScheduleWarning,
+            agency_timezone, default_direction,
+        },
+        metrics::{DiffMetrics, TripChange, TripChangeKind},
+        query::Edge,
+        stop_times::{count_stop_time_rows, duplicate_stop_sequences, read_stop_time_rows},
+    },
+    get_agency_datetime, pin_clock,
+    server::db_transit::{
+        Agency, CompactFullSchedule, FullSchedule, Position, ScheduleDiff, Shape, Stop, Transfer,
+        Trip,
+    },
+    service_date,
 };
 
-use super::ir::{RouteIR, TripIR};
+use super::ir::{Id, RouteIR, StopTimeIR, TripIR};
 
 macro_rules! setup_new_schedule {
     ($dir:expr, $bounds:expr) => {{
@@ -44,6 +66,143 @@ macro_rules! setup_new_schedule {
     }};
 }
 
+macro_rules! setup_zip_schedule {
+    ($dir:expr) => {{
+        let bytes = std::fs::read(format!("./gtfs_data/{}/gtfs_supplemented.zip", $dir)).unwrap();
+
+        gtfs_parsing::schedule::Schedule::from_zip(
+            ZipArchive::new(Cursor::new(bytes)).unwrap(),
+            None,
+        )
+    }};
+}
+
+// Agency row for the MTA, which most fixture feeds are for
+pub(crate) const MTA_AGENCY: &str =
+    "MTA NYCT,MTA New York City Transit,https://www.mta.info,America/New_York\n";
+
+// GTFS feed built up from CSV rows, shared by every test that needs a parsed feed. Files start out
+// with just their header
+#[derive(Debug, Clone)]
+pub(crate) struct FeedFixture {
+    // (file name, contents including the header)
+    files: Vec<(&'static str, String)>,
+}
+
+impl FeedFixture {
+    pub(crate) fn new() -> Self {
+        let headers = [
+            (
+                "agency.txt",
+                "agency_id,agency_name,agency_url,agency_timezone\n",
+            ),
+            (
+                "stops.txt",
+                "stop_id,stop_name,stop_lat,stop_lon,location_type,parent_station\n",
+            ),
+            (
+                "stop_times.txt",
+                "trip_id,stop_id,arrival_time,departure_time,stop_sequence\n",
+            ),
+            (
+                "calendar.txt",
+                "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n",
+            ),
+            ("calendar_dates.txt", "service_id,date,exception_type\n"),
+            (
+                "shapes.txt",
+                "shape_id,shape_pt_sequence,shape_pt_lat,shape_pt_lon\n",
+            ),
+            (
+                "transfers.txt",
+                "from_stop_id,to_stop_id,transfer_type,min_transfer_time\n",
+            ),
+            (
+                "routes.txt",
+                "agency_id,route_id,route_short_name,route_long_name,route_type,route_color\n",
+            ),
+            (
+                "trips.txt",
+                "route_id,trip_id,service_id,trip_headsign,direction_id,shape_id\n",
+            ),
+        ];
+
+        Self {
+            files: headers
+                .into_iter()
+                .map(|(name, header)| (name, header.to_owned()))
+                .collect(),
+        }
+    }
+
+    /// Feed with the MTA as its only agency
+    pub(crate) fn mta() -> Self {
+        Self::new().rows("agency.txt", MTA_AGENCY)
+    }
+
+    /// Adds `rows` to the end of the file `name`
+    pub(crate) fn rows(mut self, name: &str, rows: &str) -> Self {
+        let (_, contents) = self
+            .files
+            .iter_mut()
+            .find(|(n, _)| *n == name)
+            .unwrap_or_else(|| panic!("{} has no header, add it with `file`", name));
+        contents.push_str(rows);
+
+        self
+    }
+
+    /// Replaces the file `name`, header included, or adds it if it's not one of the defaults, e.g.
+    /// for other columns or frequencies.txt
+    pub(crate) fn file(mut self, name: &'static str, contents: &str) -> Self {
+        match self.files.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, existing)) => *existing = contents.to_owned(),
+            None => self.files.push((name, contents.to_owned())),
+        }
+
+        self
+    }
+
+    fn contents(&self, name: &str) -> &str {
+        self.files
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, contents)| contents.as_str())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn schedule(&self) -> Schedule {
+        let reader = |name| Cursor::new(self.contents(name).as_bytes().to_vec());
+
+        Schedule::from_readers(
+            reader("agency.txt"),
+            reader("stops.txt"),
+            reader("stop_times.txt"),
+            reader("calendar.txt"),
+            reader("calendar_dates.txt"),
+            reader("shapes.txt"),
+            reader("transfers.txt"),
+            reader("routes.txt"),
+            reader("trips.txt"),
+            None,
+        )
+        .unwrap()
+    }
+
+    /// The feed as the zip the server downloads
+    pub(crate) fn zip(&self) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in self.files.iter() {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+
+        writer.finish().unwrap().into_inner()
+    }
+}
+
 fn nm<T, U>() -> HashMap<T, U> {
     HashMap::default()
 }
@@ -102,11 +261,13 @@ fn test_shape_diffs() {
         routes: nm().into(),
         stops: nm().into(),
         shapes: shapes1.into(),
+        agencies: nm().into(),
     };
     let ir2 = ScheduleIR {
         routes: nm().into(),
         stops: nm().into(),
         shapes: shapes2.into(),
+        agencies: nm().into(),
     };
 
     let (added_shapes, removed_shape_ids) = ir2.get_shape_diffs(&ir1);
@@ -140,6 +301,8 @@ fn test_stop_diffs() {
             lon: Some(2.0),
         }),
         route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
     };
 
     let stop2 = Stop {
@@ -152,6 +315,8 @@ fn test_stop_diffs() {
             lon: Some(3.0),
         }),
         route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
     };
 
     let stop3 = Stop {
@@ -164,6 +329,8 @@ fn test_stop_diffs() {
             lon: Some(4.0),
         }),
         route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
     };
 
     let stop4 = Stop {
@@ -176,28 +343,19 @@ fn test_stop_diffs() {
             lon: Some(4.0),
         }),
         route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
     };
 
-    let mut stops1 = nm();
-    let mut stops2 = nm();
-
-    stops1.insert(id1.clone(), stop1.clone());
-    stops1.insert(id2.clone(), stop2.clone());
-
-    stops2.insert(id2.clone(), stop2.clone());
-    stops2.insert(id3.clone(), stop3.clone());
-    stops2.insert(id1.clone(), stop4.clone());
-
-    let ir1 = ScheduleIR {
-        routes: nm().into(),
-        shapes: nm().into(),
-        stops: stops1.into(),
-    };
-    let ir2 = ScheduleIR {
-        routes: nm().into(),
-        shapes: nm().into(),
-        stops: stops2.into(),
-    };
+    let ir1 = ScheduleIR::builder()
+        .stop(stop1.clone())
+        .stop(stop2.clone())
+        .build();
+    let ir2 = ScheduleIR::builder()
+        .stop(stop2.clone())
+        .stop(stop3.clone())
+        .stop(stop4.clone())
+        .build();
 
     let (added_stops, removed_stop_ids) = ir2.get_stop_diffs(&ir1);
 
@@ -228,7 +386,7 @@ fn test_diff_full(schedule1: ScheduleIR, schedule2: ScheduleIR) {
     assert_eq!(two_minus_one.added_trips.len(), 6644);
     assert_eq!(two_minus_one.removed_trip_ids.len(), 6647);
 
-    let exp_schedule1 = one_minus_two.apply_to_schedule(schedule2.clone());
+    let exp_schedule1 = one_minus_two.apply_to_schedule(schedule2.clone()).unwrap();
     assert_eq!(schedule1.routes.len(), exp_schedule1.routes.len());
     let (mut s1_trips, mut es1_trips): (Vec<_>, Vec<_>) = (
         schedule1
@@ -274,7 +432,7 @@ fn test_diff_full(schedule1: ScheduleIR, schedule2: ScheduleIR) {
     assert_eq!(s1_stops, es1_stops);
     assert_eq!(schedule1, exp_schedule1);
 
-    let exp_schedule2 = two_minus_one.apply_to_schedule(schedule1);
+    let exp_schedule2 = two_minus_one.apply_to_schedule(schedule1).unwrap();
     assert_eq!(schedule2.routes.len(), exp_schedule2.routes.len());
     let (mut s2_trips, mut es2_trips): (Vec<_>, Vec<_>) = (
         schedule2
@@ -383,15 +541,15 @@ fn test_id_ne(schedule: ScheduleIR) {
     assert_eq!(schedule, schedule2);
 
     schedule2.routes.get_mut("A").unwrap().trips.insert(
-        "Test Trip".to_owned(),
+        "Test Trip".into(),
         TripIR {
-            trip_id: "Test Trip".to_owned(),
+            trip_id: "Test Trip".into(),
             stop_times: HashMap::new().into(),
             headsign: None,
             shape_id: None,
             direction: None,
             date_mask: 1,
-            mask_start_date: "20250401".to_owned(),
+            mask_start_date: "20250401".parse().unwrap(),
         },
     );
 
@@ -496,7 +654,7 @@ fn test_ranges(schedule: Schedule) {
             ..
         } in trips.into_inner().into_values()
         {
-            assert_eq!(mask_start_date, "20250401");
+            assert_eq!(mask_start_date.to_string(), "20250401");
             assert_ne!(date_mask, 0);
         }
     }
@@ -543,15 +701,15 @@ fn test_combine() {
     let stop_id2: String = "StopId2".to_owned();
     let stop_id3: String = "StopId3".to_owned();
 
-    let trip_id1: String = "TripId1".to_owned();
-    let trip_id2: String = "TripId2".to_owned();
-    let trip_id3: String = "TripId3".to_owned();
+    let trip_id1: Id = "TripId1".into();
+    let trip_id2: Id = "TripId2".into();
+    let trip_id3: Id = "TripId3".into();
 
-    let route_id1: String = "RouteId1".to_owned();
-    let route_id2: String = "RouteId2".to_owned();
-    let route_id3: String = "RouteId3".to_owned();
+    let route_id1: Id = "RouteId1".into();
+    let route_id2: Id = "RouteId2".into();
+    let route_id3: Id = "RouteId3".into();
 
-    let date_str: String = "20250401".to_owned();
+    let start_date: MaskStartDate = "20250401".parse().unwrap();
 
     let test_shape1: Shape = Shape {
         shape_id: Some(shape_id1.clone()),
@@ -573,6 +731,8 @@ fn test_combine() {
         position: None,
         parent_stop_id: None,
         route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
     };
     let test_stop3: Stop = Stop {
         stop_id: Some(stop_id3.clone()),
@@ -581,13 +741,15 @@ fn test_combine() {
         position: None,
         parent_stop_id: None,
         route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
     };
 
     let test_trip1: TripIR = TripIR {
         trip_id: trip_id1.clone(),
         shape_id: None,
         stop_times: HashMap::new().into(),
-        mask_start_date: date_str.clone(),
+        mask_start_date: start_date,
         date_mask: 1,
         headsign: None,
         direction: None,
@@ -596,7 +758,7 @@ fn test_combine() {
         trip_id: trip_id2.clone(),
         shape_id: None,
         stop_times: HashMap::new().into(),
-        mask_start_date: date_str.clone(),
+        mask_start_date: start_date,
         date_mask: 1,
         headsign: None,
         direction: None,
@@ -605,7 +767,7 @@ fn test_combine() {
         trip_id: trip_id3.clone(),
         shape_id: None,
         stop_times: HashMap::new().into(),
-        mask_start_date: date_str.clone(),
+        mask_start_date: start_date,
         date_mask: 1,
         headsign: None,
         direction: None,
@@ -626,6 +788,11 @@ fn test_combine() {
             ((route_id2.clone(), trip_id2.clone()), test_trip2.clone()),
             ((route_id1.clone(), trip_id1.clone()), test_trip1.clone()),
         ]),
+        shape_point_diffs: HashMap::new(),
+        date_mask_updates: HashMap::new(),
+        agencies: None,
+        route_metadata_updates: HashMap::new(),
+        removed_route_ids: HashSet::new(),
     };
     let diff2 = ScheduleUpdate {
         removed_stop_ids: HashSet::from_iter(vec![stop_id2.clone()].into_iter()),
@@ -639,10 +806,40 @@ fn test_combine() {
             ((route_id3.clone(), trip_id3.clone()), test_trip3.clone()),
             ((route_id2.clone(), trip_id2.clone()), test_trip2.clone()),
         ]),
+        shape_point_diffs: HashMap::new(),
+        date_mask_updates: HashMap::new(),
+        agencies: None,
+        route_metadata_updates: HashMap::new(),
+        removed_route_ids: HashSet::new(),
     };
 
     let combo = diff1.combine(&diff2);
 
+    // Applying the combined diff should be the same as applying each in order
+    let test_stop1: Stop = Stop {
+        stop_id: Some(stop_id1.clone()),
+        stop_name: None,
+        transfers_from: vec![],
+        position: None,
+        parent_stop_id: None,
+        route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
+    };
+    let base = ScheduleIR::builder()
+        .route(route_id1.clone())
+        .trip(test_trip1.clone())
+        .route(route_id2.clone())
+        .route(route_id3.clone())
+        .stop(test_stop1)
+        .build();
+    assert_eq!(
+        combo.apply_to_schedule(base.clone()).unwrap(),
+        diff2
+            .apply_to_schedule(diff1.apply_to_schedule(base).unwrap())
+            .unwrap()
+    );
+
     assert_eq!(
         combo.removed_stop_ids,
         HashSet::from_iter(vec![stop_id1].into_iter())
@@ -682,3 +879,2356 @@ fn test_combine() {
         ])
     );
 }
+
+#[test]
+fn test_intersect() {
+    let route_id: Id = "RouteId1".into();
+    let key = |trip_id: &str| (route_id.clone(), Id::from(trip_id));
+    let start_date: MaskStartDate = "20250401".parse().unwrap();
+    let test_trip = |trip_id: &str, date_mask: u32| TripIR {
+        trip_id: trip_id.into(),
+        shape_id: None,
+        stop_times: HashMap::new().into(),
+        mask_start_date: start_date,
+        date_mask,
+        headsign: None,
+        direction: None,
+    };
+    let test_stop = |stop_id: &str| Stop {
+        stop_id: Some(stop_id.to_owned()),
+        stop_name: None,
+        transfers_from: vec![],
+        position: None,
+        parent_stop_id: None,
+        route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
+    };
+
+    let diff1 = ScheduleUpdate {
+        removed_trip_ids: HashSet::from_iter([key("TripId1"), key("TripId2")]),
+        added_trips: HashMap::from_iter([
+            (key("TripId3"), test_trip("TripId3", 1)),
+            (key("TripId4"), test_trip("TripId4", 1)),
+            (key("TripId5"), test_trip("TripId5", 1)),
+        ]),
+        removed_stop_ids: HashSet::from_iter(["StopId1".to_owned()]),
+        added_stops: HashMap::from_iter([("StopId2".to_owned(), test_stop("StopId2"))]),
+        date_mask_updates: HashMap::from_iter([
+            (key("TripId6"), (start_date, 3)),
+            (key("TripId7"), (start_date, 3)),
+        ]),
+        ..Default::default()
+    };
+    let diff2 = ScheduleUpdate {
+        removed_trip_ids: HashSet::from_iter([key("TripId2")]),
+        added_trips: HashMap::from_iter([
+            (key("TripId3"), test_trip("TripId3", 1)),
+            // Same trip with different dates
+            (key("TripId4"), test_trip("TripId4", 2)),
+        ]),
+        removed_stop_ids: HashSet::from_iter(["StopId1".to_owned()]),
+        added_stops: HashMap::from_iter([("StopId3".to_owned(), test_stop("StopId3"))]),
+        date_mask_updates: HashMap::from_iter([
+            (key("TripId6"), (start_date, 3)),
+            (key("TripId7"), (start_date, 7)),
+        ]),
+        ..Default::default()
+    };
+
+    let shared = diff1.intersect(&diff2);
+
+    assert_eq!(shared, diff2.intersect(&diff1));
+    assert_eq!(
+        shared.removed_trip_ids,
+        HashSet::from_iter([key("TripId2")])
+    );
+    assert_eq!(
+        shared.added_trips,
+        HashMap::from_iter([(key("TripId3"), test_trip("TripId3", 1))])
+    );
+    assert_eq!(
+        shared.removed_stop_ids,
+        HashSet::from_iter(["StopId1".to_owned()])
+    );
+    assert!(shared.added_stops.is_empty());
+    assert_eq!(
+        shared.date_mask_updates,
+        HashMap::from_iter([(key("TripId6"), (start_date, 3))])
+    );
+    assert!(shared.added_shapes.is_empty() && shared.removed_shape_ids.is_empty());
+}
+
+#[test]
+fn test_update_summary() {
+    let route_id: Id = "RouteId1".into();
+    let key = |trip_id: &str| (route_id.clone(), Id::from(trip_id));
+    let test_trip = |trip_id: &str| TripIR {
+        trip_id: trip_id.into(),
+        shape_id: None,
+        stop_times: HashMap::new().into(),
+        mask_start_date: "20250401".parse().unwrap(),
+        date_mask: 1,
+        headsign: None,
+        direction: None,
+    };
+
+    let update = ScheduleUpdate {
+        added_trips: HashMap::from_iter(
+            ["TripId4", "TripId2", "TripId3", "TripId1"].map(|id| (key(id), test_trip(id))),
+        ),
+        removed_trip_ids: HashSet::from_iter([key("TripId5")]),
+        removed_shape_ids: HashSet::from_iter(["ShapeId1".to_owned()]),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        ScheduleUpdate::default().to_string(),
+        "ScheduleUpdate{ +0/-0 trips, +0/-0 stops, +0/-0 shapes }"
+    );
+    assert_eq!(
+        update.to_string(),
+        "ScheduleUpdate{ +4/-1 trips, +0/-0 stops, +0/-1 shapes }"
+    );
+    assert_eq!(
+        format!("{:#}", update),
+        "ScheduleUpdate{ +4/-1 trips (+RouteId1/TripId1, +RouteId1/TripId2, +RouteId1/TripId3), \
+         +0/-0 stops, +0/-1 shapes (-ShapeId1) }"
+    );
+}
+
+// One route of 40 trips with 50 stop times each, where only the first trip differs per version
+fn versioned_schedule(version: u32) -> ScheduleIR {
+    let mut builder = ScheduleIR::builder().route("RouteId1");
+
+    for i in 0..40 {
+        let trip_id: Id = if i == 0 {
+            format!("TripId{}-{}", i, version).into()
+        } else {
+            format!("TripId{}", i).into()
+        };
+        let stop_times: HashMap<u32, StopTimeIR> = (0..50)
+            .map(|seq| {
+                (
+                    seq,
+                    StopTimeIR {
+                        stop_id: Some(format!("StopId{}", seq).into()),
+                        arrival_time: Some(seq * 60),
+                        departure_time: Some(seq * 60),
+                        stop_sequence: Some(seq),
+                    },
+                )
+            })
+            .collect();
+
+        builder = builder.trip(TripIR {
+            trip_id,
+            stop_times: stop_times.into(),
+            headsign: None,
+            shape_id: None,
+            direction: None,
+            mask_start_date: "20250401".parse().unwrap(),
+            date_mask: 1,
+        });
+    }
+
+    builder.build()
+}
+
+#[test]
+fn test_history_reconstruct() {
+    let mut history = ScheduleHistory::new();
+
+    for version in 0..12 {
+        let evicted = history.push(version, versioned_schedule(version), 10);
+        assert_eq!(evicted, Vec::from_iter(version.checked_sub(10)));
+    }
+
+    assert_eq!(history.len(), 10);
+    assert_eq!(history.latest(), Some((11, &versioned_schedule(11))));
+    assert!(history.reconstruct_at(0).is_none());
+    assert!(history.reconstruct_at(1).is_none());
+
+    for version in 2..12 {
+        assert_eq!(
+            history.reconstruct_at(version),
+            Some(versioned_schedule(version))
+        );
+    }
+    assert!(history.verify());
+
+    // Ten full copies would hold 20000 stop times, the diffs only add one trip each way per entry
+    let single_schedule = 40 * 50;
+    assert!(history.stop_time_count() < 2 * single_schedule);
+}
+
+#[test]
+fn test_history_diff_between() {
+    let mut history = ScheduleHistory::new();
+
+    for version in 0..3 {
+        history.push(version, versioned_schedule(version), 2);
+    }
+
+    // Version 0 was evicted
+    assert!(history.diff_between(0, 2).is_err());
+    assert!(history.diff_between(1, 0).is_err());
+
+    let forward = history.diff_between(1, 2).unwrap();
+    assert_eq!(
+        forward.apply_to_schedule(versioned_schedule(1)).unwrap(),
+        versioned_schedule(2)
+    );
+
+    let backward = history.diff_between(2, 1).unwrap();
+    assert_eq!(
+        backward.apply_to_schedule(versioned_schedule(2)).unwrap(),
+        versioned_schedule(1)
+    );
+
+    let mut history = ScheduleHistory::new();
+    for version in 0..3 {
+        history.push(version, versioned_schedule(version), 10);
+    }
+
+    let middle = history.diff_between(0, 1).unwrap();
+    assert_eq!(
+        middle.apply_to_schedule(versioned_schedule(0)).unwrap(),
+        versioned_schedule(1)
+    );
+    assert_eq!(middle.added_trips.len(), 1);
+    assert_eq!(middle.removed_trip_ids.len(), 1);
+}
+
+#[test]
+fn test_diff_metrics() {
+    let mut schedule = versioned_schedule(1);
+    schedule.stops.insert(
+        "StopId1".to_owned(),
+        Stop {
+            stop_id: Some("StopId1".to_owned()),
+            ..Default::default()
+        },
+    );
+    let update = schedule.get_diff(&versioned_schedule(0));
+    let metrics = DiffMetrics::new(100, &update);
+
+    assert_eq!(
+        metrics,
+        DiffMetrics {
+            timestamp: 100,
+            added_trips: 1,
+            removed_trips: 1,
+            date_mask_updates: 0,
+            changed_stop_times: 50,
+            added_stops: 1,
+            removed_stops: 0,
+            added_shapes: 0,
+            removed_shapes: 0,
+            encoded_bytes: metrics.encoded_bytes,
+        }
+    );
+    assert!(metrics.encoded_bytes > 50 * 4);
+
+    let empty = DiffMetrics::new(100, &ScheduleUpdate::default());
+    assert_eq!(empty.added_trips, 0);
+    assert_eq!(empty.encoded_bytes, 0);
+}
+
+#[test]
+fn test_diff_ignoring_dates() {
+    let weekday = trip("TripId1", vec![stop_time("StopId1", 1, 3600)]);
+    let weekend = TripIR {
+        date_mask: 0b1100000,
+        ..weekday.clone()
+    };
+    assert!(weekday.equals_ignoring_dates(&weekend));
+    assert_ne!(weekday, weekend);
+
+    let prev = ScheduleIR::builder().route("RouteId1").trip(weekday).build();
+    let schedule = ScheduleIR::builder().route("RouteId1").trip(weekend).build();
+
+    let (added_trips, removed_trip_ids) = schedule.get_trip_diffs(&prev);
+    assert_eq!(added_trips.len(), 1);
+    assert_eq!(removed_trip_ids.len(), 1);
+
+    let diff = schedule.get_diff_with_options(&prev, &DiffOptions { ignore_dates: true });
+    assert!(diff.added_trips.is_empty());
+    assert!(diff.removed_trip_ids.is_empty());
+
+    // Other changes still count
+    let mut moved = schedule.clone();
+    moved
+        .routes
+        .get_mut("RouteId1")
+        .unwrap()
+        .trips
+        .get_mut("TripId1")
+        .unwrap()
+        .headsign = Some("Somewhere else".into());
+    let diff = moved.get_diff_with_options(&prev, &DiffOptions { ignore_dates: true });
+    assert_eq!(diff.added_trips.len(), 1);
+    assert_eq!(diff.removed_trip_ids.len(), 1);
+}
+
+#[test]
+fn test_date_mask_updates() {
+    let weekday = trip("TripId1", vec![stop_time("StopId1", 1, 3600)]);
+    let weekend = TripIR {
+        date_mask: 0b1100000,
+        ..weekday.clone()
+    };
+    let key: (Id, Id) = ("RouteId1".into(), "TripId1".into());
+
+    let prev = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(weekday.clone())
+        .build();
+    let schedule = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(weekend.clone())
+        .build();
+
+    let diff = schedule.get_diff(&prev);
+    assert!(diff.added_trips.is_empty());
+    assert!(diff.removed_trip_ids.is_empty());
+    assert_eq!(
+        diff.date_mask_updates,
+        HashMap::from([(key.clone(), ("20250401".parse().unwrap(), 0b1100000))])
+    );
+    assert_eq!(diff.apply_to_schedule(prev.clone()).unwrap(), schedule);
+
+    // A later full replacement of the trip takes over
+    let moved = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(TripIR {
+            headsign: Some("Somewhere else".into()),
+            ..weekend.clone()
+        })
+        .build();
+    let combined = diff.combine(&moved.get_diff(&schedule));
+    assert!(combined.date_mask_updates.is_empty());
+    assert_eq!(combined.apply_to_schedule(prev.clone()).unwrap(), moved);
+
+    // Dates changing after a full replacement are folded into it
+    let combined = moved.get_diff(&schedule).combine(&prev.get_diff(&schedule));
+    assert!(combined.date_mask_updates.is_empty());
+    assert_eq!(combined.added_trips[&key].date_mask, 1);
+}
+
+#[test]
+fn test_apply_removals_first() {
+    let key: (Id, Id) = ("RouteId1".into(), "TripId1".into());
+    let before = trip(
+        "TripId1",
+        vec![stop_time("StopId1", 1, 3600), stop_time("StopId2", 2, 3700)],
+    );
+    let after = trip(
+        "TripId1",
+        vec![stop_time("StopId1", 1, 3600), stop_time("StopId2", 2, 3760)],
+    );
+
+    let schedule = ScheduleIR::builder().route("RouteId1").trip(before).build();
+    let update = ScheduleUpdate {
+        removed_trip_ids: HashSet::from([key.clone()]),
+        added_trips: HashMap::from([(key.clone(), after.clone())]),
+        removed_stop_ids: HashSet::from(["StopId1".to_owned()]),
+        added_stops: HashMap::from([(
+            "StopId1".to_owned(),
+            Stop {
+                stop_id: Some("StopId1".to_owned()),
+                stop_name: Some("Renamed".to_owned()),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+
+    let applied = update.apply_to_schedule(schedule).unwrap();
+    let stop_time = &applied.routes["RouteId1"].trips["TripId1"].stop_times[&2];
+    assert_eq!(stop_time.departure_time, Some(3760));
+    assert_eq!(
+        applied.stops["StopId1"].stop_name.as_deref(),
+        Some("Renamed")
+    );
+
+    assert_eq!(update.apply_to_schedule(applied.clone()).unwrap(), applied);
+}
+
+#[test]
+fn test_shape_point_diffs() {
+    let point = |i: u32| Position {
+        lat: Some(40.0 + i as f64 / 1000.0),
+        lon: Some(-74.0),
+    };
+    let shape = |n: u32| Shape {
+        shape_id: Some("ShapeId1".to_owned()),
+        points: (0..n).map(point).collect(),
+    };
+
+    let prev = ScheduleIR::builder().shape(shape(10)).build();
+    let extended = ScheduleIR::builder().shape(shape(13)).build();
+
+    let diff = extended.get_diff(&prev);
+    assert!(diff.added_shapes.is_empty());
+    assert!(diff.removed_shape_ids.is_empty());
+    assert_eq!(
+        diff.shape_point_diffs["ShapeId1"],
+        vec![PointSplice {
+            start: 10,
+            removed: 0,
+            points: vec![point(10), point(11), point(12)],
+            prev_len: 10,
+        }]
+    );
+    assert_eq!(diff.apply_to_schedule(prev.clone()).unwrap(), extended);
+    // Already spliced, left as is
+    assert_eq!(diff.apply_to_schedule(extended.clone()).unwrap(), extended);
+    // Neither the shape the splice was made from nor its result
+    let shortened = ScheduleIR::builder().shape(shape(5)).build();
+    assert!(diff.apply_to_schedule(shortened).is_err());
+    assert!(
+        diff.apply_to_schedule(ScheduleIR::builder().build())
+            .is_err()
+    );
+
+    // Splices from consecutive updates are applied one after the other
+    let extended_again = ScheduleIR::builder().shape(shape(16)).build();
+    let combined = diff.combine(&extended_again.get_diff(&extended));
+    assert_eq!(combined.shape_point_diffs["ShapeId1"].len(), 2);
+    assert_eq!(
+        combined.apply_to_schedule(prev.clone()).unwrap(),
+        extended_again
+    );
+    assert_eq!(
+        combined.apply_to_schedule(extended_again.clone()).unwrap(),
+        extended_again
+    );
+
+    // Mostly new points, simpler to send it whole
+    let rerouted = ScheduleIR::builder().shape(shape(30)).build();
+    let diff = rerouted.get_diff(&prev);
+    assert!(diff.shape_point_diffs.is_empty());
+    assert_eq!(diff.added_shapes.len(), 1);
+    assert_eq!(diff.apply_to_schedule(prev).unwrap(), rerouted);
+}
+
+#[test]
+fn test_stop_time_for() {
+    let trip = trip(
+        "TripId1",
+        vec![
+            stop_time("StopId1", 1, 3600),
+            stop_time("StopId2", 2, 3700),
+            stop_time("StopId1", 3, 3800),
+        ],
+    );
+
+    assert_eq!(
+        trip.stop_time_for("StopId2"),
+        Some(&stop_time("StopId2", 2, 3700))
+    );
+    // Loops back to its first stop, the earlier visit wins
+    assert_eq!(
+        trip.stop_time_for("StopId1"),
+        Some(&stop_time("StopId1", 1, 3600))
+    );
+    assert_eq!(trip.stop_time_for("StopId3"), None);
+}
+
+#[test]
+fn test_full_schedule_deterministic() {
+    let mut builder = ScheduleIR::builder();
+    for r in 0..20 {
+        builder = builder.route(format!("RouteId{}", r));
+        for t in 0..20 {
+            builder = builder.trip(trip(
+                &format!("TripId{}-{}", r, t),
+                (0..20).map(|seq| stop_time("StopId1", seq, seq * 60)).collect(),
+            ));
+        }
+    }
+    for i in 0..50 {
+        builder = builder
+            .stop(Stop {
+                stop_id: Some(format!("StopId{}", i)),
+                ..Default::default()
+            })
+            .shape(Shape {
+                shape_id: Some(format!("ShapeId{}", i)),
+                points: Vec::new(),
+            });
+    }
+    let ir = builder.build();
+
+    // Rebuilding the maps changes their iteration order, the output mustn't
+    let full = FullSchedule::from(&ir).encode_to_vec();
+    assert_eq!(FullSchedule::from(ir.clone()).encode_to_vec(), full);
+
+    let rebuilt = ScheduleIR {
+        routes: ir
+            .routes
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<HashMap<_, _>>()
+            .into(),
+        stops: ir
+            .stops
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<HashMap<_, _>>()
+            .into(),
+        ..ir.clone()
+    };
+    assert_eq!(FullSchedule::from(&rebuilt).encode_to_vec(), full);
+
+    let decoded = FullSchedule::decode(&full[..]).unwrap();
+    assert!(
+        decoded
+            .routes
+            .windows(2)
+            .all(|w| w[0].route_id < w[1].route_id)
+    );
+    assert!(decoded.stops.windows(2).all(|w| w[0].stop_id < w[1].stop_id));
+    assert!(
+        decoded.routes[0]
+            .trips
+            .windows(2)
+            .all(|w| w[0].trip_id < w[1].trip_id)
+    );
+    assert!(
+        decoded.routes[0].trips[0]
+            .stop_times
+            .windows(2)
+            .all(|w| w[0].stop_sequence < w[1].stop_sequence)
+    );
+}
+
+#[test]
+fn test_agency_timezone() {
+    let schedule = FeedFixture::new()
+        .rows(
+            "agency.txt",
+            "BART,Bay Area Rapid Transit,https://www.bart.gov,America/Los_Angeles\n",
+        )
+        .schedule();
+    assert_eq!(agency_timezone(&schedule), Los_Angeles);
+    assert_eq!(get_agency_datetime(Los_Angeles).timezone(), Los_Angeles);
+
+    // Nothing to go off of, assume the MTA
+    let schedule = FeedFixture::new().schedule();
+    assert_eq!(agency_timezone(&schedule), New_York);
+}
+
+#[test]
+fn test_agencies() {
+    let schedule = FeedFixture::mta().schedule();
+    let ir = ScheduleIR::try_from_schedule_with_dates(
+        schedule,
+        NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+        1,
+    );
+
+    let agency = Agency {
+        agency_id: Some("MTA NYCT".to_owned()),
+        agency_name: Some("MTA New York City Transit".to_owned()),
+        agency_url: Some("https://www.mta.info".to_owned()),
+        agency_timezone: Some("America/New_York".to_owned()),
+    };
+    assert_eq!(ir.agencies.values().collect::<Vec<_>>(), vec![&agency]);
+
+    let full_schedule: FullSchedule = (&ir).into();
+    assert_eq!(full_schedule.agencies, vec![agency.clone()]);
+
+    // Unchanged agencies aren't sent at all
+    assert_eq!(ir.get_diff(&ir).agencies, None);
+
+    let renamed = ScheduleIR::builder()
+        .agency(Agency {
+            agency_name: Some("MTA".to_owned()),
+            ..agency
+        })
+        .build();
+    let diff = renamed.get_diff(&ir);
+    assert_eq!(diff.agencies, Some(renamed.agencies.clone()));
+    assert_eq!(
+        diff.apply_to_schedule(ir).unwrap().agencies,
+        renamed.agencies
+    );
+}
+
+fn stop_time(stop_id: &str, stop_sequence: u32, time: u32) -> StopTimeIR {
+    StopTimeIR {
+        stop_id: Some(stop_id.into()),
+        arrival_time: Some(time),
+        departure_time: Some(time),
+        stop_sequence: Some(stop_sequence),
+    }
+}
+
+fn trip(trip_id: &str, stop_times: Vec<StopTimeIR>) -> TripIR {
+    TripIR {
+        trip_id: trip_id.into(),
+        stop_times: stop_times
+            .into_iter()
+            .map(|st| (st.stop_sequence.unwrap(), st))
+            .collect::<HashMap<_, _>>()
+            .into(),
+        headsign: None,
+        shape_id: None,
+        direction: None,
+        mask_start_date: "20250401".parse().unwrap(),
+        date_mask: 1,
+    }
+}
+
+#[test]
+fn test_trips_at_stop() {
+    let schedule = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(trip(
+            "TripId1",
+            vec![stop_time("StopId1", 1, 60), stop_time("StopId2", 2, 120)],
+        ))
+        .trip(trip("TripId2", vec![stop_time("StopId2", 1, 300)]))
+        .route("RouteId2")
+        .trip(trip("TripId3", vec![stop_time("StopId3", 1, 60)]))
+        .build();
+
+    let mut found: Vec<_> = schedule
+        .trips_at_stop("StopId2")
+        .into_iter()
+        .map(|(r, t, st)| (&*r.route_id, &*t.trip_id, st.departure_time))
+        .collect();
+    found.sort();
+
+    assert_eq!(
+        found,
+        vec![
+            ("RouteId1", "TripId1", Some(120)),
+            ("RouteId1", "TripId2", Some(300))
+        ]
+    );
+    assert!(schedule.trips_at_stop("StopId4").is_empty());
+}
+
+#[test]
+#[ignore]
+fn test_trips_at_stop_fixture() {
+    let schedule = ScheduleIR::try_from_schedule_with_dates(
+        setup_zip_schedule!("schedule").unwrap(),
+        NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+        1,
+    );
+
+    // Times Sq-42 St, southbound
+    let found = schedule.trips_at_stop("127S");
+
+    assert_eq!(found.len(), 540);
+    for (_, _, stop_time) in found {
+        assert_eq!(stop_time.stop_id.as_deref(), Some("127S"));
+    }
+}
+
+#[test]
+fn test_next_departures() {
+    let mut inactive = trip("TripId4", vec![stop_time("StopId1", 1, 400)]);
+    inactive.date_mask = 0b10;
+
+    let schedule = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(trip("TripId1", vec![stop_time("StopId1", 1, 100)]))
+        .trip(trip("TripId2", vec![stop_time("StopId1", 1, 500)]))
+        .trip(inactive)
+        .route("RouteId2")
+        .trip(trip("TripId3", vec![stop_time("StopId1", 1, 300)]))
+        .trip(trip(
+            "TripId5",
+            vec![stop_time("StopId2", 1, 250), stop_time("StopId1", 2, 700)],
+        ))
+        .build();
+    let date = NaiveDate::from_ymd_opt(2025, 4, 1).unwrap();
+
+    let found: Vec<_> = schedule
+        .next_departures("StopId1", date, 200, 2)
+        .into_iter()
+        .map(|(r, t, st)| (&*r.route_id, &*t.trip_id, st.departure_time))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("RouteId2", "TripId3", Some(300)),
+            ("RouteId1", "TripId2", Some(500))
+        ]
+    );
+
+    // TripId4 only runs the next day
+    let found: Vec<_> = schedule
+        .next_departures("StopId1", date.succ_opt().unwrap(), 200, 10)
+        .into_iter()
+        .map(|(_, t, _)| &*t.trip_id)
+        .collect();
+    assert_eq!(found, vec!["TripId4"]);
+}
+
+#[test]
+fn test_within_bbox() {
+    let stop = |id: &str, lat: f64, lon: f64| Stop {
+        stop_id: Some(id.to_owned()),
+        stop_name: None,
+        parent_stop_id: None,
+        transfers_from: vec![],
+        position: Some(Position {
+            lat: Some(lat),
+            lon: Some(lon),
+        }),
+        route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
+    };
+    let shape = |id: &str| Shape {
+        shape_id: Some(id.to_owned()),
+        points: vec![],
+    };
+
+    let mut trip1 = trip(
+        "TripId1",
+        vec![stop_time("StopId1", 1, 60), stop_time("StopId3", 2, 120)],
+    );
+    trip1.shape_id = Some("ShapeId1".to_owned());
+    let mut trip2 = trip(
+        "TripId2",
+        vec![stop_time("StopId3", 1, 60), stop_time("StopId4", 2, 120)],
+    );
+    trip2.shape_id = Some("ShapeId2".to_owned());
+
+    let schedule = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(trip1.clone())
+        .trip(trip2)
+        .stop(stop("StopId1", 40.5, -74.5))
+        .stop(stop("StopId2", 40.7, -73.9))
+        .stop(stop("StopId3", 41.5, -74.5))
+        .stop(stop("StopId4", 40.5, -72.0))
+        .stop(Stop {
+            position: None,
+            ..stop("StopId5", 0.0, 0.0)
+        })
+        .shape(shape("ShapeId1"))
+        .shape(shape("ShapeId2"))
+        .build();
+
+    let bounded = schedule.within_bbox(40.0, -75.0, 41.0, -73.0);
+
+    let mut stop_ids: Vec<_> = bounded.stops.keys().cloned().collect();
+    stop_ids.sort();
+    assert_eq!(stop_ids, vec!["StopId1", "StopId2"]);
+
+    let route = bounded.routes.get("RouteId1").unwrap();
+    assert_eq!(route.trips.values().collect::<Vec<_>>(), vec![&trip1]);
+    assert_eq!(bounded.shapes.keys().collect::<Vec<_>>(), vec!["ShapeId1"]);
+}
+
+#[test]
+fn test_station_grouping() {
+    let stop = |id: &str, parent: Option<&str>| Stop {
+        stop_id: Some(id.to_owned()),
+        stop_name: None,
+        parent_stop_id: parent.map(str::to_owned),
+        transfers_from: vec![],
+        position: None,
+        route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
+    };
+
+    let schedule = ScheduleIR::builder()
+        .stop(stop("127", None))
+        .stop(stop("127N", Some("127")))
+        .stop(stop("127S", Some("127")))
+        .stop(stop("127S-1", Some("127S")))
+        .stop(stop("Loop", Some("Loop")))
+        .stop(stop("CycleA", Some("CycleB")))
+        .stop(stop("CycleB", Some("CycleA")))
+        .build();
+
+    let mut children: Vec<_> = schedule
+        .station_children("127")
+        .into_iter()
+        .filter_map(|s| s.stop_id.as_deref())
+        .collect();
+    children.sort();
+    assert_eq!(children, vec!["127N", "127S"]);
+
+    let resolved = |id: &str| {
+        schedule
+            .resolve_station(id)
+            .and_then(|s| s.stop_id.as_deref())
+    };
+    assert_eq!(resolved("127S-1"), Some("127"));
+    assert_eq!(resolved("127N"), Some("127"));
+    assert_eq!(resolved("127"), Some("127"));
+    assert_eq!(resolved("Loop"), Some("Loop"));
+    assert_eq!(resolved("CycleA"), Some("CycleB"));
+    assert_eq!(resolved("Missing"), None);
+}
+
+#[test]
+fn test_dangling_transfers() {
+    let schedule = FeedFixture::new()
+        .rows(
+            "stops.txt",
+            "StopId1,Stop 1,40.0,-74.0,1,\n\
+             StopId2,Stop 2,40.1,-74.1,1,\n",
+        )
+        .rows(
+            "transfers.txt",
+            "StopId1,StopId2,2,\n\
+             StopId1,StopId3,2,180\n\
+             StopId3,StopId1,2,180\n",
+        )
+        .schedule();
+    let ir = ScheduleIR::try_from_schedule_with_dates(
+        schedule,
+        NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+        1,
+    );
+
+    assert_eq!(
+        ir.stops.get("StopId1").unwrap().transfers_from,
+        vec![Transfer {
+            from_stop_id: Some("StopId1".to_owned()),
+            to_stop_id: Some("StopId2".to_owned()),
+            min_transfer_time: Some(0),
+        }]
+    );
+    assert!(ir.stops.get("StopId2").unwrap().transfers_from.is_empty());
+}
+
+#[test]
+fn test_date_mask_across_dst() {
+    let schedule = FeedFixture::mta()
+        .rows("routes.txt", "MTA NYCT,A,A,8 Av Express,1,0039A6\n")
+        .rows(
+            "calendar.txt",
+            "Weekday,1,1,1,1,1,0,0,20260101,20261231\n\
+             Weekend,0,0,0,0,0,1,1,20260101,20261231\n\
+             ChangeDay,1,1,1,1,1,1,1,20260308,20260308\n",
+        )
+        .rows("calendar_dates.txt", "Weekday,20260308,1\n")
+        .rows(
+            "trips.txt",
+            "A,WeekdayTrip,Weekday,,,\n\
+             A,WeekendTrip,Weekend,,,\n\
+             A,ChangeDayTrip,ChangeDay,,,\n",
+        )
+        .rows(
+            "stop_times.txt",
+            "WeekdayTrip,A02S,02:30:00,02:30:00,1\n\
+             WeekendTrip,A02S,02:30:00,02:30:00,1\n\
+             ChangeDayTrip,A02S,02:30:00,02:30:00,1\n",
+        )
+        .schedule();
+    // Wednesday 2026-03-04 to Tuesday 2026-03-17, clocks go forward on Sunday 2026-03-08 (bit 4).
+    // Masks are per date, so the 23 hour day must still count as exactly one
+    let ir = ScheduleIR::try_from_schedule_with_dates(
+        schedule,
+        NaiveDate::from_ymd_opt(2026, 3, 4).unwrap(),
+        14,
+    );
+    let mask = |trip_id: &str| ir.routes["A"].trips[trip_id].date_mask;
+
+    // Bits run from the window start, Wed Thu Fri Sat Sun Mon Tue twice. The weekday service also
+    // runs on the change day through its exception
+    assert_eq!(mask("WeekdayTrip"), 0b11_0011_1111_0111);
+    assert_eq!(mask("WeekendTrip"), 0b00_1100_0001_1000);
+    assert_eq!(mask("ChangeDayTrip"), 0b00_0000_0001_0000);
+    assert_eq!(
+        ir.routes["A"].trips["WeekdayTrip"].mask_start_date,
+        "20260304".parse().unwrap()
+    );
+}
+
+#[test]
+fn test_parsed_stop_times() {
+    let schedule = FeedFixture::mta()
+        .rows("routes.txt", "MTA NYCT,A,A,8 Av Express,1,0039A6\n")
+        .rows("calendar.txt", "Daily,1,1,1,1,1,1,1,20260101,20261231\n")
+        .rows("trips.txt", "A,LateTrip,Daily,,,\n")
+        .rows(
+            "stop_times.txt",
+            "LateTrip,A02S,08:30:15,08:31:00,1\n\
+             LateTrip,A03S,25:05:09,25:05:09,2\n",
+        )
+        .schedule();
+    let ir = ScheduleIR::try_from_schedule_with_dates(
+        schedule,
+        NaiveDate::from_ymd_opt(2026, 3, 4).unwrap(),
+        1,
+    );
+    let stop_times = &ir.routes["A"].trips["LateTrip"].stop_times;
+
+    assert_eq!(stop_times[&1].arrival_time, Some(8 * 3600 + 30 * 60 + 15));
+    assert_eq!(stop_times[&1].departure_time, Some(8 * 3600 + 31 * 60));
+    // Past midnight, still counted from the start of the service day
+    assert_eq!(stop_times[&2].arrival_time, Some(25 * 3600 + 5 * 60 + 9));
+}
+
+#[test]
+fn test_invalid_stop_time() {
+    let schedule = FeedFixture::mta()
+        .rows("routes.txt", "MTA NYCT,A,A,8 Av Express,1,0039A6\n")
+        .rows("calendar.txt", "Daily,1,1,1,1,1,1,1,20260101,20261231\n")
+        .rows("trips.txt", "A,TripId1,Daily,,,\n")
+        .rows(
+            "stop_times.txt",
+            "TripId1,A02S,08:30:00,08:31:00,1\n\
+             TripId1,A03S,,,2\n\
+             TripId1,A05S,08:3x:00,08:40:00,3\n",
+        )
+        .schedule();
+    let (ir, warnings) = ScheduleIR::try_from_schedule_with_warnings(
+        schedule,
+        NaiveDate::from_ymd_opt(2026, 3, 4).unwrap(),
+        1,
+        &ScheduleOptions::default(),
+    );
+    let stop_times = &ir.routes["A"].trips["TripId1"].stop_times;
+
+    // Rejected rather than read as midnight, blank times are still allowed
+    assert!(!stop_times.contains_key(&3));
+    assert_eq!(stop_times[&2].arrival_time, None);
+    assert!(warnings.contains(&ScheduleWarning::InvalidStopTime {
+        trip_id: "TripId1".to_owned(),
+        stop_sequence: 3,
+        time: "08:3x:00".to_owned(),
+    }));
+}
+
+#[test]
+fn test_invalid_stop_position() {
+    let schedule = FeedFixture::new()
+        .rows(
+            "stops.txt",
+            "StopId1,Stop 1,40.0,-74.0,1,\n\
+             StopId2,Stop 2,not_a_number,-74.1,1,\n",
+        )
+        .schedule();
+    let (ir, warnings) = ScheduleIR::try_from_schedule_with_warnings(
+        schedule,
+        NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+        1,
+        &ScheduleOptions::default(),
+    );
+
+    assert_eq!(
+        warnings,
+        vec![ScheduleWarning::InvalidStopPosition {
+            stop_id: "StopId2".to_owned(),
+            stop_lat: "not_a_number".to_owned(),
+            stop_lon: "-74.1".to_owned(),
+        }]
+    );
+    assert!(ir.stops.get("StopId1").unwrap().position.is_some());
+    assert!(ir.stops.get("StopId2").unwrap().position.is_none());
+}
+
+#[test]
+fn test_dedup_shapes() {
+    let shape = |id: &str, lat: f64| Shape {
+        shape_id: Some(id.to_owned()),
+        points: vec![
+            Position {
+                lat: Some(lat),
+                lon: Some(-74.0),
+            },
+            Position {
+                lat: Some(lat + 0.1),
+                lon: Some(-74.1),
+            },
+        ],
+    };
+    let trip_with_shape = |trip_id: &str, shape_id: &str| TripIR {
+        shape_id: Some(shape_id.to_owned()),
+        ..trip(trip_id, vec![])
+    };
+
+    let mut schedule = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(trip_with_shape("TripId1", "ShapeId1"))
+        .trip(trip_with_shape("TripId2", "ShapeId2"))
+        .trip(trip_with_shape("TripId3", "ShapeId3"))
+        .shape(shape("ShapeId1", 40.0))
+        .shape(shape("ShapeId2", 40.0))
+        .shape(shape("ShapeId3", 41.0))
+        .build();
+
+    assert_eq!(schedule.dedup_shapes(), 1);
+
+    let mut shape_ids: Vec<_> = schedule.shapes.keys().cloned().collect();
+    shape_ids.sort();
+    assert_eq!(shape_ids, vec!["ShapeId1", "ShapeId3"]);
+
+    let trips = &schedule.routes.get("RouteId1").unwrap().trips;
+    assert_eq!(
+        trips.get("TripId2").unwrap().shape_id.as_deref(),
+        Some("ShapeId1")
+    );
+    assert_eq!(
+        trips.get("TripId3").unwrap().shape_id.as_deref(),
+        Some("ShapeId3")
+    );
+}
+
+#[test]
+fn test_content_hash() {
+    let to_ir = |stops: &str, transfers: &str| {
+        ScheduleIR::try_from_schedule_with_dates(
+            FeedFixture::new()
+                .rows("stops.txt", stops)
+                .rows("transfers.txt", transfers)
+                .schedule(),
+            NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+            1,
+        )
+    };
+    let stop1 = "StopId1,Stop 1,40.0,-74.0,1,\n";
+    let stop2 = "StopId2,Stop 2,40.1,-74.1,1,\n";
+    let stop3 = "StopId3,Stop 3,40.2,-74.2,1,\n";
+    let transfer12 = "StopId1,StopId2,2,\n";
+    let transfer13 = "StopId1,StopId3,2,180\n";
+
+    let ir = to_ir(
+        &[stop1, stop2, stop3].concat(),
+        &[transfer12, transfer13].concat(),
+    );
+    let reordered = to_ir(
+        &[stop3, stop1, stop2].concat(),
+        &[transfer13, transfer12].concat(),
+    );
+    let changed = to_ir(&[stop1, stop2].concat(), &[transfer12].concat());
+
+    assert_eq!(ir.content_hash(), reordered.content_hash());
+    assert_ne!(ir.content_hash(), changed.content_hash());
+}
+
+#[test]
+fn test_direction_resolver() {
+    let schedule = || {
+        FeedFixture::mta()
+            .rows("calendar.txt", "Weekday,1,1,1,1,1,0,0,20250101,20251231\n")
+            .rows("routes.txt", "MTA NYCT,2,2,7 Avenue Express,1,EE352E\n")
+            .rows(
+                "trips.txt",
+                "2,TripId1,Weekday,Wakefield-241 St,0,\n\
+                 2,TripId2,Weekday,Flatbush Av-Brooklyn College,1,\n\
+                 2,TripId3,Weekday,Wakefield-241 St Express,0,\n",
+            )
+            .schedule()
+    };
+    let directions = |options: &ScheduleOptions| {
+        let ir = ScheduleIR::try_from_schedule_with_options(
+            schedule(),
+            NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+            1,
+            options,
+        );
+        let trips = &ir.routes.get("2").unwrap().trips;
+
+        ["TripId1", "TripId2", "TripId3"].map(|id| trips.get(id).unwrap().direction)
+    };
+
+    assert_eq!(
+        directions(&ScheduleOptions::default()),
+        [Some(0), Some(1), Some(0)]
+    );
+
+    let options = ScheduleOptions::default().with_direction_resolver(|trip| {
+        match trip.trip_headsign.as_deref() {
+            Some("Wakefield-241 St Express") => Some(2),
+            _ => default_direction(trip),
+        }
+    });
+    assert_eq!(directions(&options), [Some(0), Some(1), Some(2)]);
+}
+
+#[test]
+fn test_route_metadata() {
+    let schedule = |color: &str| {
+        FeedFixture::new()
+            .rows(
+                "routes.txt",
+                &format!("MTA NYCT,2,2,7 Avenue Express,1,{}\n", color),
+            )
+            .schedule()
+    };
+    let ir = |color: &str| {
+        ScheduleIR::try_from_schedule_with_dates(
+            schedule(color),
+            NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+            1,
+        )
+    };
+    let red = ir("EE352E");
+
+    let metadata = RouteMetadata {
+        short_name: Some("2".to_owned()),
+        long_name: Some("7 Avenue Express".to_owned()),
+        color: Some("EE352E".to_owned()),
+        route_type: Some(1),
+    };
+    assert_eq!(red.routes.get("2").unwrap().metadata, metadata);
+
+    let full_schedule: FullSchedule = (&red).into();
+    let route = &full_schedule.routes[0];
+    assert_eq!(route.route_short_name.as_deref(), Some("2"));
+    assert_eq!(route.route_long_name.as_deref(), Some("7 Avenue Express"));
+    assert_eq!(route.route_color.as_deref(), Some("EE352E"));
+    assert_eq!(route.route_type, Some(1));
+
+    // Unchanged metadata isn't sent at all
+    assert!(red.get_diff(&red).route_metadata_updates.is_empty());
+
+    let blue = ir("0039A6");
+    let diff = blue.get_diff(&red);
+    assert_eq!(
+        diff.route_metadata_updates,
+        HashMap::from_iter([(
+            Id::from("2"),
+            RouteMetadata {
+                color: Some("0039A6".to_owned()),
+                ..metadata
+            }
+        )])
+    );
+    assert_eq!(diff.apply_to_schedule(red).unwrap(), blue);
+
+    let diff = ScheduleDiff::from(diff);
+    assert_eq!(diff.route_metadata_updates.len(), 1);
+    assert_eq!(
+        diff.route_metadata_updates[0].route_color.as_deref(),
+        Some("0039A6")
+    );
+}
+
+#[test]
+fn test_ordered_stop_times() {
+    let well_formed = trip(
+        "TripId1",
+        vec![
+            stop_time("StopId3", 3, 3800),
+            stop_time("StopId1", 1, 3600),
+            stop_time("StopId2", 2, 3700),
+        ],
+    );
+    assert_eq!(
+        well_formed.ordered_stop_times(),
+        vec![
+            &stop_time("StopId1", 1, 3600),
+            &stop_time("StopId2", 2, 3700),
+            &stop_time("StopId3", 3, 3800),
+        ]
+    );
+    assert!(well_formed.invalid_stop_sequences().is_empty());
+
+    let mut duplicate = trip(
+        "TripId2",
+        vec![stop_time("StopId1", 1, 3600), stop_time("StopId2", 2, 3700)],
+    );
+    duplicate
+        .stop_times
+        .insert(3, stop_time("StopId3", 2, 3800));
+    assert_eq!(duplicate.invalid_stop_sequences(), vec![3]);
+
+    let gap = trip(
+        "TripId3",
+        vec![
+            stop_time("StopId1", 1, 3600),
+            stop_time("StopId2", 10, 3700),
+        ],
+    );
+    assert_eq!(
+        gap.ordered_stop_times(),
+        vec![
+            &stop_time("StopId1", 1, 3600),
+            &stop_time("StopId2", 10, 3700)
+        ]
+    );
+    assert!(gap.invalid_stop_sequences().is_empty());
+}
+
+#[test]
+fn test_duplicate_stop_sequences() {
+    let feed = FeedFixture::mta().rows(
+        "stop_times.txt",
+        "TripId1,L01S,08:00:00,08:00:00,1\n\
+         TripId1,L02S,08:05:00,08:05:00,2\n\
+         TripId1,L03S,08:10:00,08:10:00,2\n\
+         TripId2,L01S,09:00:00,09:00:00,1\n\
+         TripId2,L02S,09:05:00,09:05:00,10\n",
+    );
+    let rows = read_stop_time_rows(&mut ZipArchive::new(Cursor::new(feed.zip())).unwrap()).unwrap();
+    assert_eq!(
+        rows,
+        HashMap::from([("TripId1".into(), 3), ("TripId2".into(), 2)])
+    );
+
+    // The gap in TripId2 is allowed
+    assert_eq!(
+        duplicate_stop_sequences(&feed.schedule(), &rows),
+        vec![ScheduleWarning::DuplicateStopSequence {
+            trip_id: "TripId1".into(),
+            dropped: 1,
+        }]
+    );
+
+    // Free text before trip_id can have commas
+    let rows = count_stop_time_rows(Cursor::new(
+        "stop_headsign,trip_id,stop_sequence\n\
+         \"Canarsie, Rockaway Pkwy\",TripId1,1\n\
+         \"8 Av \"\"Local\"\"\",TripId1,2\n",
+    ))
+    .unwrap();
+    assert_eq!(rows, HashMap::from([("TripId1".into(), 2)]));
+
+    // A badly encoded row is still counted, a row without a trip_id is skipped
+    let rows = count_stop_time_rows(Cursor::new(
+        b"stop_id,trip_id,stop_sequence\r\n\
+          8 Av\xff,TripId1,1\r\n\
+          Canarsie,TripId1,2\r\n\
+          Truncated\r\n"
+            .to_vec(),
+    ))
+    .unwrap();
+    assert_eq!(rows, HashMap::from([("TripId1".into(), 2)]));
+}
+
+#[test]
+fn test_min_active_within_days() {
+    let calendar_row =
+        |service_id: &str, date: &str| format!("{},1,1,1,1,1,1,1,{},{}\n", service_id, date, date);
+    let schedule = FeedFixture::mta()
+        .rows("calendar.txt", &calendar_row("Day0", "20250401"))
+        .rows("calendar.txt", &calendar_row("Day30", "20250501"))
+        .rows("routes.txt", "MTA NYCT,L,L,14 St-Canarsie Local,1,A7A9AC\n")
+        .rows(
+            "trips.txt",
+            "L,TripId1,Day0,Canarsie-Rockaway Pkwy,1,\n\
+             L,TripId2,Day30,Canarsie-Rockaway Pkwy,1,\n",
+        )
+        .rows(
+            "stop_times.txt",
+            "TripId1,L01S,08:00:00,08:00:00,1\n\
+             TripId2,L01S,09:00:00,09:00:00,1\n",
+        )
+        .schedule();
+    let options = ScheduleOptions {
+        min_active_within_days: Some(7),
+        ..Default::default()
+    };
+
+    let ir = ScheduleIR::try_from_schedule_with_options(
+        schedule,
+        NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+        32,
+        &options,
+    );
+    let trips = &ir.routes.get("L").unwrap().trips;
+
+    assert_eq!(trips.get("TripId1").unwrap().stop_times.len(), 1);
+    // Still there so clients know it exists, just without its stop times
+    let far_trip = trips.get("TripId2").unwrap();
+    assert_eq!(far_trip.date_mask, 1 << 30);
+    assert!(far_trip.stop_times.is_empty());
+}
+
+#[test]
+fn test_stop_patterns() {
+    let directed = |trip_id: &str, direction: u32, stop_ids: &[&str]| TripIR {
+        direction: Some(direction),
+        ..trip(
+            trip_id,
+            stop_ids
+                .iter()
+                .enumerate()
+                .map(|(i, id)| stop_time(id, i as u32 + 1, 3600 + 60 * i as u32))
+                .collect(),
+        )
+    };
+
+    let schedule = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(directed("Local1", 0, &["S1", "S2", "S3", "S4"]))
+        .trip(directed("Local2", 0, &["S1", "S2", "S3", "S4"]))
+        .trip(directed("Express", 0, &["S1", "S4"]))
+        .trip(directed("Back", 1, &["S4", "S3", "S2", "S1"]))
+        .build();
+    let patterns = schedule.routes.get("RouteId1").unwrap().stop_patterns();
+
+    assert_eq!(patterns.len(), 2);
+    assert_eq!(
+        patterns.get(&0).unwrap(),
+        &vec![vec!["S1", "S2", "S3", "S4"], vec!["S1", "S4"],]
+    );
+    assert_eq!(
+        patterns.get(&1).unwrap(),
+        &vec![vec!["S4", "S3", "S2", "S1"]]
+    );
+}
+
+#[test]
+fn test_by_route() {
+    let key = |route_id: &str, trip_id: &str| -> (Id, Id) { (route_id.into(), trip_id.into()) };
+
+    let update = ScheduleUpdate {
+        added_trips: HashMap::from([
+            (key("A", "A1"), trip("A1", vec![stop_time("S1", 1, 3600)])),
+            (key("L", "L1"), trip("L1", vec![stop_time("S2", 1, 3600)])),
+        ]),
+        removed_trip_ids: HashSet::from([key("L", "L2"), key("G", "G1")]),
+        date_mask_updates: HashMap::from([(key("A", "A2"), ("20250401".parse().unwrap(), 3))]),
+        removed_stop_ids: HashSet::from(["S3".to_owned()]),
+        ..Default::default()
+    };
+    let by_route = update.by_route();
+
+    assert_eq!(by_route.len(), 3);
+    assert_eq!(
+        by_route.get("A").unwrap(),
+        &RouteScheduleUpdate {
+            added_trips: HashMap::from([("A1".into(), trip("A1", vec![stop_time("S1", 1, 3600)]))]),
+            removed_trip_ids: HashSet::new(),
+            date_mask_updates: HashMap::from([("A2".into(), ("20250401".parse().unwrap(), 3))]),
+        }
+    );
+    assert_eq!(
+        by_route.get("L").unwrap(),
+        &RouteScheduleUpdate {
+            added_trips: HashMap::from([("L1".into(), trip("L1", vec![stop_time("S2", 1, 3600)]))]),
+            removed_trip_ids: HashSet::from(["L2".into()]),
+            date_mask_updates: HashMap::new(),
+        }
+    );
+
+    // Putting the routes back together gives the trip changes of the original
+    let rebuilt = by_route
+        .into_iter()
+        .map(|(route_id, route_update)| route_update.into_schedule_update(&route_id))
+        .fold(ScheduleUpdate::default(), |acc, u| acc.combine(&u));
+    assert_eq!(
+        rebuilt,
+        ScheduleUpdate {
+            removed_stop_ids: HashSet::new(),
+            ..update
+        }
+    );
+}
+
+#[test]
+fn test_changed_trip_summary() {
+    let key = |route_id: &str, trip_id: &str| -> (Id, Id) { (route_id.into(), trip_id.into()) };
+    let with_headsign = |trip_id: &str, headsign: &str| TripIR {
+        headsign: Some(headsign.into()),
+        ..trip(trip_id, vec![stop_time("S1", 1, 3600)])
+    };
+
+    let update = ScheduleUpdate {
+        added_trips: HashMap::from([
+            (key("L", "L1"), with_headsign("L1", "Canarsie")),
+            (key("A", "A1"), with_headsign("A1", "Far Rockaway")),
+        ]),
+        removed_trip_ids: HashSet::from([key("A", "A1"), key("A", "A2")]),
+        date_mask_updates: HashMap::from([(key("G", "G1"), ("20250401".parse().unwrap(), 3))]),
+        ..Default::default()
+    };
+    let change = |route_id: &str, trip_id: &str, headsign: Option<&str>, kind| TripChange {
+        route_id: route_id.into(),
+        trip_id: trip_id.into(),
+        headsign: headsign.map(str::to_owned),
+        kind,
+    };
+
+    assert_eq!(
+        update.changed_trip_summary(),
+        vec![
+            change("A", "A1", Some("Far Rockaway"), TripChangeKind::Updated),
+            change("A", "A2", None, TripChangeKind::Removed),
+            change("G", "G1", None, TripChangeKind::Updated),
+            change("L", "L1", Some("Canarsie"), TripChangeKind::Added),
+        ]
+    );
+}
+
+#[test]
+fn test_service_exceptions() {
+    // 2025-04-01 is a Tuesday, so the window starts Tuesday and day 4 is Saturday
+    let schedule = FeedFixture::mta()
+        .rows("calendar.txt", "Weekday,1,1,1,1,1,0,0,20250101,20251231\n")
+        .rows(
+            "calendar_dates.txt",
+            "Weekday,20250405,1\n\
+             Weekday,20250402,2\n",
+        )
+        .rows("routes.txt", "MTA NYCT,L,L,14 St-Canarsie Local,1,A7A9AC\n")
+        .rows("trips.txt", "L,TripId1,Weekday,Canarsie-Rockaway Pkwy,1,\n")
+        .schedule();
+    let ir = ScheduleIR::try_from_schedule_with_dates(
+        schedule,
+        NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+        7,
+    );
+    let trip = ir.routes.get("L").unwrap().trips.get("TripId1").unwrap();
+
+    // Added turns on Saturday, Removed turns off Wednesday
+    assert_eq!(trip.date_mask, 0b1011101);
+    assert!(trip.is_active_on(NaiveDate::from_ymd_opt(2025, 4, 5).unwrap()));
+    assert!(!trip.is_active_on(NaiveDate::from_ymd_opt(2025, 4, 2).unwrap()));
+}
+
+#[test]
+fn test_is_active_at() {
+    let at = |d: u32, h: u32, m: u32| New_York.with_ymd_and_hms(2025, 4, d, h, m, 0).unwrap();
+    // Only active on 20250401
+    let trip = trip(
+        "TripId1",
+        vec![stop_time("A", 1, 8 * 3600), stop_time("B", 2, 9 * 3600)],
+    );
+
+    assert!(trip.is_active_at(&at(1, 8, 30)));
+    assert!(trip.is_active_at(&at(1, 9, 0)));
+    assert!(!trip.is_active_at(&at(1, 7, 59)));
+    assert!(!trip.is_active_at(&at(1, 9, 1)));
+    assert!(!trip.is_active_at(&at(2, 8, 30)));
+}
+
+#[test]
+fn test_is_active_at_past_midnight() {
+    let at = |d: u32, h: u32, m: u32| New_York.with_ymd_and_hms(2025, 4, d, h, m, 0).unwrap();
+    // 23:50 to 24:20 on 20250401
+    let trip = trip(
+        "TripId1",
+        vec![stop_time("A", 1, 85800), stop_time("B", 2, 87600)],
+    );
+
+    assert!(trip.is_active_at(&at(1, 23, 55)));
+    // Still the 1st's service even though the trip isn't active on the 2nd
+    assert!(trip.is_active_at(&at(2, 0, 10)));
+    assert!(!trip.is_active_at(&at(2, 0, 21)));
+    // The 31st's service would be running at this point, but the trip isn't active on it
+    assert!(!trip.is_active_at(&at(1, 0, 10)));
+    assert_eq!(trip.time_span(), Some((85800, 87600)));
+}
+
+#[test]
+fn test_build_adjacency() {
+    let stop = |stop_id: &str, transfers_from: Vec<Transfer>| Stop {
+        stop_id: Some(stop_id.to_owned()),
+        stop_name: None,
+        parent_stop_id: None,
+        transfers_from,
+        position: None,
+        route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
+    };
+
+    let schedule = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(trip(
+            "Trip1",
+            vec![stop_time("S1", 1, 3600), stop_time("S2", 2, 3900)],
+        ))
+        .stop(stop("S1", vec![]))
+        .stop(stop(
+            "S2",
+            vec![Transfer {
+                from_stop_id: Some("S2".to_owned()),
+                to_stop_id: Some("S3".to_owned()),
+                min_transfer_time: Some(180),
+            }],
+        ))
+        .stop(stop("S3", vec![]))
+        .build();
+    let adjacency = schedule.build_adjacency();
+
+    assert_eq!(adjacency.len(), 2);
+    assert_eq!(
+        adjacency.get("S1").unwrap(),
+        &vec![Edge::Ride {
+            to_stop_id: "S2".to_owned(),
+            trip_id: "Trip1".into(),
+            depart: 3600,
+            arrive: 3900,
+        }]
+    );
+    assert_eq!(
+        adjacency.get("S2").unwrap(),
+        &vec![Edge::Transfer {
+            to_stop_id: "S3".to_owned(),
+            min_transfer_time: Some(180),
+        }]
+    );
+}
+
+#[test]
+fn test_compact_trips() {
+    let schedule = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(trip(
+            "Trip1",
+            vec![stop_time("S1", 1, 3600), stop_time("S2", 2, 3900)],
+        ))
+        .trip(trip(
+            "Trip2",
+            vec![stop_time("S1", 1, 7200), stop_time("S2", 2, 7500)],
+        ))
+        .route("RouteId2")
+        .trip(trip(
+            "Trip3",
+            vec![stop_time("S2", 1, 3600), stop_time("S3", 3, 3700)],
+        ))
+        .build();
+    let compact = CompactFullSchedule::from(&schedule);
+
+    // Trip1 and Trip2 share a pattern
+    assert_eq!(compact.stop_patterns.len(), 2);
+
+    for route in compact.routes.iter() {
+        let route_ir = schedule
+            .routes
+            .get(route.route_id.as_deref().unwrap())
+            .unwrap();
+
+        for compact_trip in route.trips.iter() {
+            let pattern = compact
+                .stop_patterns
+                .iter()
+                .find(|p| p.pattern_id == compact_trip.pattern_id)
+                .unwrap();
+            let trip_ir = route_ir
+                .trips
+                .get(compact_trip.trip_id.as_deref().unwrap())
+                .unwrap();
+
+            assert_eq!(
+                compact_trip.expand_stop_times(pattern).unwrap(),
+                Trip::from(trip_ir).stop_times
+            );
+        }
+    }
+}
+
+#[test]
+fn test_duplicate_trip_ids() {
+    let schedule = ScheduleIR::builder()
+        .route("RouteId1")
+        .trip(trip("Shared", vec![stop_time("S1", 1, 3600)]))
+        .trip(trip("Trip1", vec![stop_time("S1", 1, 3700)]))
+        .route("RouteId2")
+        .trip(trip("Shared", vec![stop_time("S2", 1, 3600)]))
+        .trip(trip("Trip2", vec![stop_time("S2", 1, 3700)]))
+        .build();
+
+    assert_eq!(
+        schedule.duplicate_trip_ids(),
+        HashMap::from([(
+            Id::from("Shared"),
+            vec![Id::from("RouteId1"), Id::from("RouteId2")]
+        )])
+    );
+
+    // Both copies survive a diff, since trips are keyed by route as well
+    let (added, removed) = schedule.get_trip_diffs(
+        &ScheduleIR::builder()
+            .route("RouteId1")
+            .route("RouteId2")
+            .build(),
+    );
+    assert!(removed.is_empty());
+    assert!(added.contains_key(&(Id::from("RouteId1"), Id::from("Shared"))));
+    assert!(added.contains_key(&(Id::from("RouteId2"), Id::from("Shared"))));
+}
+
+#[test]
+fn test_mask_start_date() {
+    let date: MaskStartDate = "20250401".parse().unwrap();
+    assert_eq!(date.date(), NaiveDate::from_ymd_opt(2025, 4, 1).unwrap());
+    assert_eq!(date.to_string(), "20250401");
+    assert_eq!(
+        MaskStartDate::from(NaiveDate::from_ymd_opt(987, 6, 5).unwrap()).to_string(),
+        "09870605"
+    );
+
+    for malformed in [
+        "",
+        "2025041",
+        "202504011",
+        "2025-04-01",
+        "+2025041",
+        "20251301",
+        "20250230",
+        "2025040a",
+        "２０２５０４０１",
+    ] {
+        assert!(
+            malformed.parse::<MaskStartDate>().is_err(),
+            "{:?} should be rejected",
+            malformed
+        );
+    }
+}
+
+#[test]
+fn test_skip_empty_trips() {
+    let schedule = FeedFixture::mta()
+        .rows("calendar.txt", "Daily,1,1,1,1,1,1,1,20250401,20250501\n")
+        .rows("routes.txt", "MTA NYCT,L,L,14 St-Canarsie Local,1,A7A9AC\n")
+        .rows(
+            "trips.txt",
+            "L,TripId1,Daily,Canarsie-Rockaway Pkwy,1,\n\
+             L,Empty,Daily,Canarsie-Rockaway Pkwy,1,\n",
+        )
+        .rows("stop_times.txt", "TripId1,L01S,08:00:00,08:00:00,1\n")
+        .schedule();
+    let build = |skip_empty_trips| {
+        ScheduleIR::try_from_schedule_with_options(
+            schedule.clone(),
+            NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+            32,
+            &ScheduleOptions {
+                skip_empty_trips,
+                ..Default::default()
+            },
+        )
+    };
+
+    let skipped = build(true);
+    let trips = &skipped.routes.get("L").unwrap().trips;
+    assert_eq!(trips.len(), 1);
+    assert!(trips.contains_key("TripId1"));
+
+    let kept = build(false);
+    let trips = &kept.routes.get("L").unwrap().trips;
+    assert_eq!(trips.len(), 2);
+    assert!(trips.get("Empty").unwrap().stop_times.is_empty());
+}
+
+#[test]
+fn test_apply_with_missing_route() {
+    let filtered = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", vec![stop_time("A02S", 1, 3600)]))
+        .build();
+    let mut update = ScheduleUpdate::default();
+    update.removed_trip_ids.insert(("A".into(), "A1".into()));
+    update.added_trips.insert(
+        ("B".into(), "B1".into()),
+        trip("B1", vec![stop_time("D14S", 1, 3600)]),
+    );
+
+    assert_eq!(update.missing_routes(&filtered), vec![Id::from("B")]);
+    assert!(update.apply_to_schedule(filtered.clone()).is_err());
+
+    let full = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", vec![stop_time("A02S", 1, 3600)]))
+        .route("B")
+        .build();
+    assert!(update.missing_routes(&full).is_empty());
+
+    let applied = update.apply_to_schedule(full).unwrap();
+    assert!(applied.routes["A"].trips.is_empty());
+    assert!(applied.routes["B"].trips.contains_key("B1"));
+}
+
+#[test]
+fn test_apply_with_missing_trip() {
+    let schedule = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", vec![stop_time("A02S", 1, 3600)]))
+        .build();
+    let mask_start_date = schedule.routes["A"].trips["A1"].mask_start_date;
+    let mut update = ScheduleUpdate::default();
+    update
+        .date_mask_updates
+        .insert(("A".into(), "A2".into()), (mask_start_date, 0b10));
+
+    assert_eq!(
+        update.missing_trips(&schedule),
+        vec![(Id::from("A"), Id::from("A2"))]
+    );
+    assert!(update.apply_to_schedule(schedule.clone()).is_err());
+
+    // A trip the same update removes is missing too
+    update.date_mask_updates.clear();
+    update
+        .date_mask_updates
+        .insert(("A".into(), "A1".into()), (mask_start_date, 0b10));
+    update.removed_trip_ids.insert(("A".into(), "A1".into()));
+    assert_eq!(update.missing_trips(&schedule).len(), 1);
+
+    update.removed_trip_ids.clear();
+    assert!(update.missing_trips(&schedule).is_empty());
+    let applied = update.apply_to_schedule(schedule).unwrap();
+    assert_eq!(applied.routes["A"].trips["A1"].date_mask, 0b10);
+}
+
+#[test]
+fn test_diff_added_and_removed_routes() {
+    let prev = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", vec![stop_time("A02S", 1, 3600)]))
+        .route("B")
+        .trip(trip("B1", vec![stop_time("D14S", 1, 3600)]))
+        .trip(trip("B2", vec![stop_time("D14S", 1, 7200)]))
+        .build();
+    // B is gone and a new shuttle route S appears
+    let next = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", vec![stop_time("A02S", 1, 3600)]))
+        .route("S")
+        .route_metadata(RouteMetadata {
+            short_name: Some("S".to_owned()),
+            ..Default::default()
+        })
+        .trip(trip("S1", vec![stop_time("901S", 1, 3600)]))
+        .build();
+
+    let update = next.get_diff(&prev);
+    assert_eq!(
+        update.added_trips.keys().cloned().collect::<HashSet<_>>(),
+        HashSet::from([(Id::from("S"), Id::from("S1"))])
+    );
+    assert_eq!(
+        update.removed_trip_ids,
+        HashSet::from([
+            (Id::from("B"), Id::from("B1")),
+            (Id::from("B"), Id::from("B2"))
+        ])
+    );
+    assert_eq!(update.removed_route_ids, HashSet::from([Id::from("B")]));
+    assert!(update.route_metadata_updates.contains_key("S"));
+    assert_eq!(update.apply_to_schedule(prev.clone()).unwrap(), next);
+
+    // And back again
+    let backward = prev.get_diff(&next);
+    assert_eq!(backward.apply_to_schedule(next.clone()).unwrap(), prev);
+    // Round trip through both, S is added then removed
+    assert_eq!(
+        update
+            .combine(&backward)
+            .apply_to_schedule(prev.clone())
+            .unwrap(),
+        prev
+    );
+    assert_eq!(
+        backward
+            .combine(&update)
+            .apply_to_schedule(next.clone())
+            .unwrap(),
+        next
+    );
+
+    let diff = ScheduleDiff::from(update);
+    assert_eq!(diff.removed_route_ids, vec!["B".to_owned()]);
+}
+
+#[test]
+fn test_route_filter() {
+    let schedule = FeedFixture::mta()
+        .rows("calendar.txt", "Daily,1,1,1,1,1,1,1,20250401,20250501\n")
+        .rows(
+            "routes.txt",
+            "MTA NYCT,L,L,14 St-Canarsie Local,1,A7A9AC\n\
+             MTA NYCT,A,A,8 Avenue Express,1,0039A6\n\
+             MTA NYCT,M14,M14,14 St Crosstown,3,00AEEF\n",
+        )
+        .rows(
+            "trips.txt",
+            "L,L1,Daily,Canarsie-Rockaway Pkwy,1,L..S\n\
+             A,A1,Daily,Far Rockaway,1,A..S\n\
+             M14,M141,Daily,Avenue D,1,M14..E\n",
+        )
+        .rows(
+            "stop_times.txt",
+            "L1,L01S,08:00:00,08:00:00,1\n\
+             A1,A02S,08:00:00,08:00:00,1\n\
+             M141,401234,08:00:00,08:00:00,1\n",
+        )
+        .rows(
+            "stops.txt",
+            "L01,8 Av,40.739777,-74.002578,1,\n\
+             L01S,8 Av,40.739777,-74.002578,,L01\n\
+             A02S,Inwood-207 St,40.868072,-73.919899,,\n\
+             401234,14 St/8 Av,40.740000,-74.002000,,\n",
+        )
+        .rows(
+            "shapes.txt",
+            "L..S,0,40.739777,-74.002578\n\
+             A..S,0,40.868072,-73.919899\n\
+             M14..E,0,40.740000,-74.002000\n",
+        )
+        .rows("transfers.txt", "L01S,401234,2,300\n")
+        .schedule();
+    let build = |prune_unused| {
+        ScheduleIR::try_from_schedule_with_options(
+            schedule.clone(),
+            NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+            32,
+            &ScheduleOptions {
+                route_filter: Some(HashSet::from_iter(["L".to_owned(), "A".to_owned()])),
+                prune_unused,
+                ..Default::default()
+            },
+        )
+    };
+
+    let filtered = build(false);
+    let mut route_ids: Vec<&str> = filtered.routes.keys().map(|id| id.as_ref()).collect();
+    route_ids.sort();
+    assert_eq!(route_ids, vec!["A", "L"]);
+    assert_eq!(filtered.trip_count(), 2);
+    // Without pruning everything else is kept
+    assert!(filtered.stops.contains_key("401234"));
+    assert!(filtered.shapes.contains_key("M14..E"));
+
+    let pruned = build(true);
+    assert_eq!(pruned.routes, filtered.routes);
+    let mut stop_ids: Vec<&String> = pruned.stops.keys().collect();
+    stop_ids.sort();
+    // The parent of a visited stop is kept
+    assert_eq!(stop_ids, vec!["A02S", "L01", "L01S"]);
+    let mut shape_ids: Vec<&String> = pruned.shapes.keys().collect();
+    shape_ids.sort();
+    assert_eq!(shape_ids, vec!["A..S", "L..S"]);
+    assert!(pruned.stops.get("L01S").unwrap().transfers_from.is_empty());
+}
+
+#[test]
+fn test_service_date() {
+    let nyc = |rfc3339: &str| {
+        chrono::DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .with_timezone(&New_York)
+    };
+    let date = |day| NaiveDate::from_ymd_opt(2025, 4, day).unwrap();
+
+    // Still the previous day's late night service
+    assert_eq!(service_date(&nyc("2025-04-02T00:30:00-04:00"), 3), date(1));
+    assert_eq!(service_date(&nyc("2025-04-02T02:59:59-04:00"), 3), date(1));
+    assert_eq!(service_date(&nyc("2025-04-02T03:00:00-04:00"), 3), date(2));
+    assert_eq!(service_date(&nyc("2025-04-02T03:30:00-04:00"), 3), date(2));
+    assert_eq!(service_date(&nyc("2025-04-01T23:30:00-04:00"), 3), date(1));
+    // A cutoff of 0 is the calendar day
+    assert_eq!(service_date(&nyc("2025-04-02T00:30:00-04:00"), 0), date(2));
+
+    let schedule = FeedFixture::mta()
+        .rows("calendar.txt", "Daily,1,1,1,1,1,1,1,20250301,20250601\n")
+        .rows("routes.txt", "MTA NYCT,L,L,14 St-Canarsie Local,1,A7A9AC\n")
+        .rows("trips.txt", "L,TripId1,Daily,Canarsie-Rockaway Pkwy,1,\n")
+        .rows("stop_times.txt", "TripId1,L01S,24:40:00,24:40:00,1\n")
+        .schedule();
+    let start_date = |rfc3339: &str| {
+        pin_clock(Some(nyc(rfc3339).to_utc()));
+        let ir =
+            ScheduleIR::from_schedule_with_options(schedule.clone(), &ScheduleOptions::default());
+        pin_clock(None);
+
+        ir.routes
+            .get("L")
+            .unwrap()
+            .trips
+            .get("TripId1")
+            .unwrap()
+            .mask_start_date
+    };
+
+    assert_eq!(start_date("2025-04-02T00:30:00-04:00"), date(1).into());
+    assert_eq!(start_date("2025-04-02T03:30:00-04:00"), date(2).into());
+}
+
+#[test]
+fn test_shared_headsigns() {
+    let schedule = FeedFixture::new()
+        .rows("calendar.txt", "Daily,1,1,1,1,1,1,1,20250401,20250501\n")
+        .rows(
+            "routes.txt",
+            "MTA NYCT,1,1,Broadway-7 Avenue Local,1,EE352E\n",
+        )
+        .rows(
+            "trips.txt",
+            "1,TripId1,Daily,Manhattan - 96 St,0,\n\
+             1,TripId2,Daily,Manhattan - 96 St,0,\n\
+             1,TripId3,Daily,MANHATTAN  -  96 St ,0,\n\
+             1,TripId4,Daily,South Ferry,1,\n",
+        )
+        .rows(
+            "stop_times.txt",
+            "TripId1,101N,08:00:00,08:00:00,1\n\
+             TripId2,101N,09:00:00,09:00:00,1\n\
+             TripId3,101N,10:00:00,10:00:00,1\n\
+             TripId4,101S,11:00:00,11:00:00,1\n",
+        )
+        .schedule();
+    let build = |normalize_headsigns| {
+        ScheduleIR::try_from_schedule_with_options(
+            schedule.clone(),
+            NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+            32,
+            &ScheduleOptions {
+                normalize_headsigns,
+                ..Default::default()
+            },
+        )
+    };
+    let headsign = |ir: &ScheduleIR, trip_id: &str| {
+        ir.routes
+            .get("1")
+            .unwrap()
+            .trips
+            .get(trip_id)
+            .unwrap()
+            .headsign
+            .clone()
+            .unwrap()
+    };
+
+    let ir = build(false);
+    assert!(std::sync::Arc::ptr_eq(
+        &headsign(&ir, "TripId1"),
+        &headsign(&ir, "TripId2")
+    ));
+    // Left as is without normalizing
+    assert_eq!(headsign(&ir, "TripId3").as_ref(), "MANHATTAN  -  96 St ");
+
+    // Clients still get the full string
+    let trip = Trip::from(ir.routes.get("1").unwrap().trips.get("TripId1").unwrap());
+    assert_eq!(trip.headsign.as_deref(), Some("Manhattan - 96 St"));
+
+    // Stop ids are shared the same way
+    let stop_id = |trip_id: &str| {
+        ir.routes["1"].trips[trip_id].stop_times[&1]
+            .stop_id
+            .clone()
+            .unwrap()
+    };
+    assert!(std::sync::Arc::ptr_eq(
+        &stop_id("TripId1"),
+        &stop_id("TripId2")
+    ));
+    assert_eq!(trip.stop_times[0].stop_id.as_deref(), Some("101N"));
+
+    let normalized = build(true);
+    assert!(std::sync::Arc::ptr_eq(
+        &headsign(&normalized, "TripId1"),
+        &headsign(&normalized, "TripId3")
+    ));
+    assert_eq!(
+        headsign(&normalized, "TripId3").as_ref(),
+        "Manhattan - 96 St"
+    );
+    assert_eq!(headsign(&normalized, "TripId4").as_ref(), "South Ferry");
+}
+
+#[test]
+fn test_orphan_stops() {
+    let stop = |stop_id: &str, parent_stop_id: Option<&str>| Stop {
+        stop_id: Some(stop_id.to_owned()),
+        stop_name: None,
+        transfers_from: vec![],
+        position: None,
+        parent_stop_id: parent_stop_id.map(str::to_owned),
+        route_ids: vec![],
+        location_type: None,
+        wheelchair_boarding: None,
+    };
+    let ir = ScheduleIR::builder()
+        .route("L")
+        .trip(trip(
+            "L1",
+            vec![stop_time("L01S", 1, 3600), stop_time("L02S", 2, 3700)],
+        ))
+        .stop(stop("L01", None))
+        .stop(stop("L01S", Some("L01")))
+        .stop(stop("L02S", None))
+        .stop(stop("L29S", None))
+        .build();
+
+    let orphans = ir.orphan_stops();
+
+    assert_eq!(orphans, vec![&stop("L29S", None)]);
+    assert!(ScheduleIR::builder().build().orphan_stops().is_empty());
+}
+
+#[test]
+fn test_expand_frequencies() {
+    let mut ir = ScheduleIR::builder()
+        .route("GS")
+        .trip(trip(
+            "GS1",
+            vec![stop_time("901S", 1, 3600), stop_time("902S", 2, 3750)],
+        ))
+        .trip(trip("GS2", vec![stop_time("901S", 1, 7200)]))
+        .build();
+    let frequencies = parse_frequencies(Cursor::new(
+        "trip_id,start_time,end_time,headway_secs,exact_times\n\
+         GS1,06:00:00,07:00:00,900,0\n\
+         GS1,25:00:00,25:10:00,600,0\n",
+    ))
+    .unwrap();
+
+    assert_eq!(ir.expand_frequencies(&frequencies), 5);
+
+    let trips = &ir.routes["GS"].trips;
+    let mut trip_ids: Vec<&str> = trips.keys().map(|id| &**id).collect();
+    trip_ids.sort();
+    assert_eq!(
+        trip_ids,
+        vec![
+            "GS1@06:00:00",
+            "GS1@06:15:00",
+            "GS1@06:30:00",
+            "GS1@06:45:00",
+            "GS1@25:00:00",
+            "GS2"
+        ]
+    );
+
+    // Same offsets as the template, starting at the departure
+    let generated = &trips["GS1@06:15:00"];
+    assert_eq!(generated.stop_times[&1], stop_time("901S", 1, 22500));
+    assert_eq!(generated.stop_times[&2], stop_time("902S", 2, 22650));
+    assert_eq!(
+        trips["GS1@25:00:00"].stop_times[&1].departure_time,
+        Some(90000)
+    );
+    assert_eq!(trips["GS2"].stop_times[&1].departure_time, Some(7200));
+
+    // Quoted fields are unquoted, times that aren't `HH:MM:SS` fail the file
+    let quoted = parse_frequencies(Cursor::new(
+        "trip_id,start_time,end_time,headway_secs\n\
+         \"GS,1\",\"06:00:00\",07:00:00,900\n",
+    ))
+    .unwrap();
+    assert_eq!(quoted[0].trip_id, "GS,1");
+    assert_eq!(quoted[0].start_time, 21600);
+    for time in ["6:00", "06:60:00", "06:00:00:00", "six"] {
+        let row = format!(
+            "trip_id,start_time,end_time,headway_secs\nGS1,{},07:00:00,900\n",
+            time
+        );
+        assert!(parse_frequencies(Cursor::new(row)).is_err(), "{}", time);
+    }
+}
+
+#[test]
+fn test_retain_route_types() {
+    let typed = |route_type| RouteMetadata {
+        route_type,
+        ..Default::default()
+    };
+    let mut ir = ScheduleIR::builder()
+        .route("A")
+        .route_metadata(typed(Some(1)))
+        .trip(trip("A1", vec![stop_time("A02S", 1, 3600)]))
+        .route("M14")
+        .route_metadata(typed(Some(3)))
+        .trip(trip("M141", vec![stop_time("401234", 1, 3600)]))
+        .route("X")
+        .route_metadata(typed(None))
+        .route("Q")
+        .route_metadata(typed(Some(1)))
+        .build();
+
+    assert_eq!(ir.routes["M14"].route_type(), Some(RouteType::Bus));
+    assert_eq!(RouteType::from(715), RouteType::Other(715));
+    assert_eq!(u32::from(RouteType::Monorail), 12);
+
+    assert_eq!(ir.retain_route_types(&[RouteType::Subway]), 2);
+    let mut route_ids: Vec<&str> = ir.routes.keys().map(|id| id.as_ref()).collect();
+    route_ids.sort();
+    assert_eq!(route_ids, vec!["A", "Q"]);
+    assert_eq!(ir.trip_count(), 1);
+
+    // Same when building from the feed
+    let schedule = FeedFixture::new()
+        .rows(
+            "routes.txt",
+            "MTA NYCT,A,A,8 Av Express,1,0039A6\n\
+             MTA NYCT,M14,M14,14 St Crosstown,3,00AEEF\n",
+        )
+        .rows("calendar.txt", "Daily,1,1,1,1,1,1,1,20250101,20251231\n")
+        .rows(
+            "trips.txt",
+            "A,A1,Daily,,,\n\
+             M14,M141,Daily,,,\n",
+        )
+        .rows(
+            "stop_times.txt",
+            "A1,A02S,08:00:00,08:00:00,1\n\
+             M141,401234,08:00:00,08:00:00,1\n",
+        )
+        .schedule();
+    let ir = ScheduleIR::try_from_schedule_with_options(
+        schedule,
+        NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+        32,
+        &ScheduleOptions {
+            route_types: Some(vec![RouteType::Subway]),
+            ..Default::default()
+        },
+    );
+    let route_ids: Vec<&str> = ir.routes.keys().map(|id| id.as_ref()).collect();
+    assert_eq!(route_ids, vec!["A"]);
+    assert_eq!(ir.routes["A"].route_type(), Some(RouteType::Subway));
+}
+
+#[test]
+fn test_sanitize_text() {
+    let schedule = FeedFixture::new()
+        .rows(
+            "stops.txt",
+            "L01,\u{feff}8 Av,40.739777,-74.002578,1,\n\
+             A02,Inwood-207 St,40.868072,-73.919899,1,\n",
+        )
+        .schedule();
+    let build = |sanitize_text| {
+        ScheduleIR::try_from_schedule_with_warnings(
+            schedule.clone(),
+            NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+            32,
+            &ScheduleOptions {
+                sanitize_text,
+                ..Default::default()
+            },
+        )
+    };
+
+    let (ir, warnings) = build(true);
+    assert_eq!(ir.stops["L01"].stop_name.as_deref(), Some("8 Av"));
+    assert_eq!(ir.stops["A02"].stop_name.as_deref(), Some("Inwood-207 St"));
+    assert_eq!(
+        warnings,
+        vec![ScheduleWarning::SanitizedText {
+            field: "stop_name",
+            id: "L01".to_owned()
+        }]
+    );
+
+    let (ir, warnings) = build(false);
+    assert_eq!(ir.stops["L01"].stop_name.as_deref(), Some("\u{feff}8 Av"));
+    assert!(warnings.is_empty());
+
+    // Trips sharing a headsign still share it once it's cleaned
+    let headsign = |trip_id: &str| TripIR {
+        headsign: Some("Far\u{7}Rockaway\u{feff}".into()),
+        ..trip(trip_id, vec![stop_time("A02S", 1, 3600)])
+    };
+    let mut ir = ScheduleIR::builder()
+        .route("A")
+        .trip(headsign("A1"))
+        .trip(headsign("A2"))
+        .build();
+
+    assert_eq!(ir.sanitize_text().len(), 2);
+    let trips = &ir.routes["A"].trips;
+    assert_eq!(trips["A1"].headsign.as_deref(), Some("FarRockaway"));
+    assert!(std::sync::Arc::ptr_eq(
+        trips["A1"].headsign.as_ref().unwrap(),
+        trips["A2"].headsign.as_ref().unwrap()
+    ));
+    assert!(ir.sanitize_text().is_empty());
+
+    // Invalid UTF-8 a lenient parser decoded lossily
+    let mut ir = ScheduleIR::builder()
+        .stop(Stop {
+            stop_id: Some("A03".to_owned()),
+            stop_name: Some(String::from_utf8_lossy(b"Dyckman\xff St").into_owned()),
+            ..Default::default()
+        })
+        .build();
+
+    assert_eq!(
+        ir.sanitize_text(),
+        vec![ScheduleWarning::SanitizedText {
+            field: "stop_name",
+            id: "A03".to_owned()
+        }]
+    );
+    assert_eq!(ir.stops["A03"].stop_name.as_deref(), Some("Dyckman St"));
+}
+
+#[test]
+fn test_fill_stop_times() {
+    let partial = |stop_sequence, arrival_time, departure_time| StopTimeIR {
+        stop_id: Some("S1".into()),
+        arrival_time,
+        departure_time,
+        stop_sequence: Some(stop_sequence),
+    };
+    let mut trip = trip(
+        "A1",
+        vec![
+            partial(1, None, Some(100)),
+            partial(2, Some(200), None),
+            partial(3, Some(300), Some(330)),
+        ],
+    );
+
+    assert_eq!(trip.fill_stop_times(), 2);
+    assert_eq!(trip.stop_times[&1], partial(1, Some(100), Some(100)));
+    assert_eq!(trip.stop_times[&2], partial(2, Some(200), Some(200)));
+    // Dwells are kept
+    assert_eq!(trip.stop_times[&3], partial(3, Some(300), Some(330)));
+
+    assert_eq!(trip.fill_stop_times(), 0);
+}
+
+#[test]
+fn test_interpolate_stop_times() {
+    let untimed = |stop_sequence| StopTimeIR {
+        stop_id: Some("S1".into()),
+        arrival_time: None,
+        departure_time: None,
+        stop_sequence: Some(stop_sequence),
+    };
+    let mut departs = stop_time("S1", 2, 1000);
+    departs.arrival_time = Some(950);
+    let mut trip = trip(
+        "A1",
+        vec![
+            untimed(1),
+            departs,
+            untimed(4),
+            untimed(5),
+            untimed(8),
+            stop_time("S1", 10, 1400),
+            untimed(11),
+        ],
+    );
+
+    assert_eq!(trip.fill_stop_times(), 3);
+    // Evenly spaced from the departure to the next arrival, sequence gaps don't matter
+    let times: Vec<Option<u32>> = [4, 5, 8]
+        .into_iter()
+        .map(|seq| trip.stop_times[&seq].arrival_time)
+        .collect();
+    assert_eq!(times, vec![Some(1100), Some(1200), Some(1300)]);
+    assert_eq!(trip.stop_times[&5].departure_time, Some(1200));
+    // Nothing to interpolate from outside the timed stops
+    assert_eq!(trip.stop_times[&1], untimed(1));
+    assert_eq!(trip.stop_times[&11], untimed(11));
+}
+
+#[test]
+fn test_full_schedule_round_trip() {
+    let mut with_headsign = trip("A1", vec![stop_time("A02S", 1, 3600)]);
+    with_headsign.headsign = Some("Far Rockaway".into());
+    let ir = ScheduleIR::builder()
+        .route("A")
+        .route_metadata(RouteMetadata {
+            short_name: Some("A".to_owned()),
+            color: Some("0039A6".to_owned()),
+            ..Default::default()
+        })
+        .trip(with_headsign)
+        .trip(trip(
+            "A2",
+            vec![stop_time("A02S", 1, 3700), stop_time("A03S", 2, 3800)],
+        ))
+        .stop(Stop {
+            stop_id: Some("A02S".to_owned()),
+            ..Default::default()
+        })
+        .shape(Shape {
+            shape_id: Some("A..S".to_owned()),
+            points: vec![Position {
+                lat: Some(40.8),
+                lon: Some(-73.9),
+            }],
+        })
+        .agency(Agency {
+            agency_id: Some("MTA NYCT".to_owned()),
+            ..Default::default()
+        })
+        .build();
+
+    let full_schedule = FullSchedule::from(&ir);
+    assert_eq!(ScheduleIR::try_from(full_schedule.clone()).unwrap(), ir);
+
+    // Everything the IR is keyed by has to be there
+    let mut no_trip_id = full_schedule.clone();
+    no_trip_id.routes[0].trips[0].trip_id = None;
+    assert!(ScheduleIR::try_from(no_trip_id).is_err());
+
+    let mut no_sequence = full_schedule.clone();
+    no_sequence.routes[0].trips[1].stop_times[0].stop_sequence = None;
+    assert!(ScheduleIR::try_from(no_sequence).is_err());
+
+    let mut no_stop_id = full_schedule;
+    no_stop_id.stops[0].stop_id = None;
+    assert!(ScheduleIR::try_from(no_stop_id).is_err());
+}
+
+#[test]
+#[ignore]
+fn test_full_schedule_round_trip_fixture() {
+    let ir = ScheduleIR::try_from_schedule_with_dates(
+        setup_zip_schedule!("schedule").unwrap(),
+        NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+        32,
+    );
+
+    let rebuilt = ScheduleIR::try_from(FullSchedule::from(&ir)).unwrap();
+
+    assert_eq!(rebuilt, ir);
+    assert_eq!(rebuilt.content_hash(), ir.content_hash());
+}
+
+#[test]
+fn test_stop_accessibility() {
+    let schedule = |wheelchair_boarding: &str| {
+        FeedFixture::new()
+            .file(
+                "stops.txt",
+                &format!(
+                    "stop_id,stop_name,stop_lat,stop_lon,location_type,parent_station,wheelchair_boarding\n\
+                     A27,42 St-Port Authority Bus Terminal,40.757308,-73.989735,1,,1\n\
+                     A27S,42 St-Port Authority Bus Terminal,40.757308,-73.989735,0,A27,{}\n",
+                    wheelchair_boarding
+                ),
+            )
+            .schedule()
+    };
+    let ir = |wheelchair_boarding: &str| {
+        ScheduleIR::try_from_schedule_with_dates(
+            schedule(wheelchair_boarding),
+            NaiveDate::from_ymd_opt(2025, 4, 1).unwrap(),
+            1,
+        )
+    };
+    let inaccessible = ir("2");
+
+    let station = &inaccessible.stops["A27"];
+    let platform = &inaccessible.stops["A27S"];
+    assert_eq!(
+        (station.location_type, station.wheelchair_boarding),
+        (Some(1), Some(1))
+    );
+    assert_eq!(
+        (platform.location_type, platform.wheelchair_boarding),
+        (Some(0), Some(2))
+    );
+
+    // Survives the trip to clients and back
+    let encoded = FullSchedule::from(&inaccessible).encode_to_vec();
+    let decoded = ScheduleIR::try_from(FullSchedule::decode(&encoded[..]).unwrap()).unwrap();
+    assert_eq!(decoded.stops, inaccessible.stops);
+
+    // The platform becoming accessible is a stop change like any other
+    let accessible = ir("1");
+    let (added_stops, removed_stop_ids) = accessible.get_stop_diffs(&inaccessible);
+    assert_eq!(added_stops.len(), 1);
+    assert_eq!(added_stops["A27S"].wheelchair_boarding, Some(1));
+    assert_eq!(removed_stop_ids, HashSet::from(["A27S".to_owned()]));
+}
@@ -1,4 +1,8 @@
-use std::{fmt::Display, net::AddrParseError};
+use std::{
+    convert::Infallible,
+    fmt::Display,
+    net::{AddrParseError, SocketAddr},
+};
 
 use tokio::sync::mpsc::error::SendError;
 use zip::result::ZipError;
@@ -16,6 +20,12 @@ pub enum ScheduleError {
     HyperError(hyper::Error),
     HyperHttpError(hyper::http::Error),
     HyperLegacyError(hyper_util::client::legacy::Error),
+    // Data was fetched fine but isn't in the expected format
+    ParseError(String),
+    // Request body went over the limit, holds the limit in bytes
+    RequestTooLarge(usize),
+    // Another process is already listening on the address
+    AddrInUse(SocketAddr),
 
     RawError(String),
 }
@@ -34,6 +44,13 @@ impl Display for ScheduleError {
             ScheduleError::HyperError(error) => f.write_fmt(format_args!("{}", error)),
             ScheduleError::HyperHttpError(error) => f.write_fmt(format_args!("{}", error)),
             ScheduleError::HyperLegacyError(error) => f.write_fmt(format_args!("{}", error)),
+            ScheduleError::ParseError(s) => f.write_str(s),
+            ScheduleError::AddrInUse(addr) => {
+                f.write_fmt(format_args!("port {} already in use", addr.port()))
+            }
+            ScheduleError::RequestTooLarge(limit) => {
+                f.write_fmt(format_args!("Request body larger than {} bytes", limit))
+            }
             ScheduleError::RawError(s) => f.write_str(s),
         }
     }
@@ -83,6 +100,12 @@ impl From<&str> for ScheduleError {
     }
 }
 
+impl From<Infallible> for ScheduleError {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
+
 impl From<std::io::Error> for ScheduleError {
     fn from(value: std::io::Error) -> Self {
         Self::IOError(value)
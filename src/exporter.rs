@@ -0,0 +1,110 @@
+use std::{convert::Infallible, fmt::Write, net::SocketAddr};
+
+use futures_core::future::BoxFuture;
+use http_body_util::Full;
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{CONTENT_TYPE, HeaderValue},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use logge_rs::{error, info};
+use tokio::net::TcpListener;
+
+use crate::{error::ScheduleError, server::bind_listener};
+
+pub mod tests;
+
+const METRICS_PATH: &'static str = "/metrics";
+// Version of the Prometheus text format
+const METRICS_CONTENT_TYPE: &'static str = "text/plain; version=0.0.4";
+
+/// Produces the current metrics of a process, see `MetricsText`
+pub type RenderMetrics = fn() -> BoxFuture<'static, String>;
+
+/// Builds a response in the Prometheus text format, one metric at a time
+#[derive(Debug, Default)]
+pub struct MetricsText(String);
+
+impl MetricsText {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Value that only goes up, `name` should end in `_total`
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) -> &mut Self {
+        self.metric(name, help, "counter", value)
+    }
+
+    /// Value that can go up and down
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) -> &mut Self {
+        self.metric(name, help, "gauge", value)
+    }
+
+    fn metric(
+        &mut self,
+        name: &str,
+        help: &str,
+        kind: &str,
+        value: impl std::fmt::Display,
+    ) -> &mut Self {
+        // Writing to a String can't fail
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+        let _ = writeln!(self.0, "{} {}", name, value);
+
+        self
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+async fn serve_metrics(
+    req: Request<Incoming>,
+    render: RenderMetrics,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let (status, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET, METRICS_PATH) => (StatusCode::OK, render().await),
+        (_, METRICS_PATH) => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+        _ => (StatusCode::NOT_FOUND, String::new()),
+    };
+
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(METRICS_CONTENT_TYPE));
+
+    Ok(resp)
+}
+
+/// Serves `render`'s output on `/metrics` over HTTP/1.1, the way Prometheus scrapes it. Separate
+/// from the gRPC listeners so it can be bound to an internal address
+pub async fn metrics_loop(addr: SocketAddr, render: RenderMetrics) -> Result<(), ScheduleError> {
+    let listener = bind_listener(addr).await?;
+    info!("Serving metrics on {}", addr);
+
+    serve_metrics_connections(listener, render).await
+}
+
+async fn serve_metrics_connections(
+    listener: TcpListener,
+    render: RenderMetrics,
+) -> Result<(), ScheduleError> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let io = TokioIo::new(stream);
+
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(io, service_fn(|req| serve_metrics(req, render)))
+                .await
+            {
+                error!("Error serving metrics connection: {}", err);
+            }
+        });
+    }
+}
@@ -0,0 +1,113 @@
+#![cfg(test)]
+
+use futures_core::future::BoxFuture;
+use tokio::net::TcpListener;
+
+use crate::{cacher, server};
+
+use super::{MetricsText, RenderMetrics, serve_metrics_connections};
+
+fn test_metrics() -> BoxFuture<'static, String> {
+    Box::pin(async {
+        let mut text = MetricsText::new();
+        text.counter("test_requests_total", "Requests", 3)
+            .gauge("test_ratio", "Ratio", 0.5);
+
+        text.into_string()
+    })
+}
+
+/// Serves `render` on a free port, returning its base url
+async fn spawn_exporter(render: RenderMetrics) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve_metrics_connections(listener, render));
+
+    format!("http://{}", addr)
+}
+
+async fn scrape(url: &str) -> String {
+    let resp = reqwest::get(url).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "text/plain; version=0.0.4"
+    );
+
+    resp.text().await.unwrap()
+}
+
+#[test]
+fn test_metrics_text() {
+    let mut text = MetricsText::new();
+    text.counter("test_requests_total", "Requests", 3)
+        .gauge("test_ratio", "Ratio", 0.5);
+
+    assert_eq!(
+        text.into_string(),
+        "# HELP test_requests_total Requests\n\
+         # TYPE test_requests_total counter\n\
+         test_requests_total 3\n\
+         # HELP test_ratio Ratio\n\
+         # TYPE test_ratio gauge\n\
+         test_ratio 0.5\n"
+    );
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let base = spawn_exporter(test_metrics).await;
+
+    let body = scrape(&format!("{}/metrics", base)).await;
+    assert!(body.contains("test_requests_total 3\n"));
+
+    let resp = reqwest::get(format!("{}/other", base)).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let resp = reqwest::Client::new()
+        .post(format!("{}/metrics", base))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn test_scrape_server_and_cacher() {
+    let server_body = scrape(&format!(
+        "{}/metrics",
+        spawn_exporter(server::render_metrics).await
+    ))
+    .await;
+    for name in [
+        "transit_feed_fetches_total",
+        "transit_feed_fetch_failures_total",
+        "transit_content_stale",
+        "transit_history_length",
+    ] {
+        assert!(
+            server_body.contains(&format!("# TYPE {} ", name)),
+            "{}",
+            name
+        );
+    }
+
+    let cacher_body = scrape(&format!(
+        "{}/metrics",
+        spawn_exporter(cacher::render_metrics).await
+    ))
+    .await;
+    for name in [
+        "transit_cacher_connections_total",
+        "transit_cache_hits_total",
+        "transit_cache_misses_total",
+        "transit_cache_hit_ratio",
+        "transit_cache_entries",
+    ] {
+        assert!(
+            cacher_body.contains(&format!("# TYPE {} ", name)),
+            "{}",
+            name
+        );
+    }
+}
@@ -1,13 +1,48 @@
-use chrono::{DateTime, Utc};
+use std::cell::Cell;
+
+use chrono::{DateTime, NaiveDate, TimeZone, Timelike, Utc};
 use chrono_tz::{America::New_York, Tz};
 
+pub mod config;
 pub mod diff;
 pub mod error;
+pub mod exporter;
 
 pub mod cacher;
 pub mod server;
 
+thread_local! {
+    // Set by `pin_clock`. Per thread so tests pinning different instants don't see each other's
+    static PINNED_NOW: Cell<Option<DateTime<Utc>>> = const { Cell::new(None) };
+}
+
+/// Makes every clock read on this thread return `now` instead of the real time, or goes back to
+/// the real time with `None`. Meant for tests of time-dependent behavior
+pub fn pin_clock(now: Option<DateTime<Utc>>) {
+    PINNED_NOW.set(now);
+}
+
+fn utc_now() -> DateTime<Utc> {
+    PINNED_NOW.get().unwrap_or_else(Utc::now)
+}
+
 pub fn get_nyc_datetime() -> DateTime<Tz> {
-    let curr_time = Utc::now();
-    curr_time.with_timezone(&New_York)
+    get_agency_datetime(New_York)
+}
+
+pub fn get_agency_datetime(tz: Tz) -> DateTime<Tz> {
+    let curr_time = utc_now();
+    curr_time.with_timezone(&tz)
+}
+
+/// Service day `dt` belongs to. Service runs past midnight, so times before `cutoff_hour` still
+/// belong to the previous day, e.g. a 1 AM train is part of the previous day's service
+pub fn service_date<T: TimeZone>(dt: &DateTime<T>, cutoff_hour: u32) -> NaiveDate {
+    let date = dt.date_naive();
+
+    if dt.hour() < cutoff_hour {
+        date.pred_opt().unwrap_or(date)
+    } else {
+        date
+    }
 }
@@ -1,46 +1,423 @@
-use std::ops::DerefMut;
-use std::{collections::HashMap, sync::LazyLock};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt::Display,
+    ops::Range,
+    sync::{
+        Arc, LazyLock, Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
-use chrono::{DateTime, Days, Timelike};
-use chrono_tz::Tz;
+use chrono::{DateTime, Days, NaiveDate, TimeZone, Timelike};
+use chrono_tz::{America::New_York, Tz};
 
 use db_transit::schedule_server::{Schedule, ScheduleServer};
 use db_transit::{
-    FullSchedule, LastUpdateRequest, LastUpdateResponse, ScheduleDiff, ScheduleRequest,
-    ScheduleResponse,
+    Departure, DiffAgainstRequest, FullSchedule, GeometryRequest, GeometryResponse,
+    LastUpdateRequest, LastUpdateResponse, NextDeparturesRequest, NextDeparturesResponse,
+    RouteDiffRequest, RouteDiffResponse, ScheduleDiff, ScheduleRequest, ScheduleResponse,
+    SchedulesRequest, SchedulesResponse, Trip, TripChangesRequest, TripChangesResponse, TripExt,
+    TripIdTuple,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{OnceCell, RwLock, Semaphore, watch},
 };
-use tokio::runtime::{Handle, RuntimeMetrics};
-use tokio::sync::RwLock;
+use tokio_stream::wrappers::TcpListenerStream;
 use tonic::{Request, Response, Status};
+use tonic_health::{
+    pb::health_server::HealthServer,
+    server::{HealthReporter, HealthService},
+};
 
-use crate::diff::{core::ScheduleUpdate, ir::ScheduleIR};
-use crate::get_nyc_datetime;
-use std::io::Cursor;
-use std::time::Duration;
+use crate::config::{FeedConfig, SERVER_CONFIG};
+use crate::diff::{
+    core::ScheduleUpdate,
+    frequencies::read_frequencies,
+    history::ScheduleHistory,
+    ir::{MaskStartDate, ScheduleIR, ScheduleOptions},
+    metrics::DiffMetrics,
+    query::mask_has_date,
+    stop_times::{duplicate_stop_sequences, read_stop_time_rows},
+};
+use crate::{get_nyc_datetime, service_date};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use blake3::Hash;
-use logge_rs::{error, info};
+use logge_rs::{debug, error, info, warn};
+use prost::Message;
 use tokio::time::sleep;
 use tonic::{codec::CompressionEncoding, transport::Server};
+use tower::util::MapRequestLayer;
 
 use crate::error::ScheduleError;
+use crate::exporter::MetricsText;
+use archive::archive_evicted;
+use feed::{FeedSource, HttpFeedSource};
+use futures_core::future::BoxFuture;
+use timestamp::UpdateTimestamp;
 use zip::ZipArchive;
 
-const SUPP_URL: &'static str = "https://rrgtfsfeeds.s3.amazonaws.com/gtfs_supplemented.zip";
-const MAX_HISTORY_LEN: usize = 10;
+// Prefix of the files in the temp directory feeds are parsed from, followed by the process id and
+// a counter
+const FEED_FILE_PREFIX: &'static str = "transit-server-feed";
+// Every zip file starts with a local file header
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+// Updates to keep size metrics for, these are small so keep well beyond the history
+const MAX_METRICS_LEN: usize = 1000;
+
+// Departures returned when the client doesn't specify a limit
+const DEFAULT_DEPARTURES: u32 = 10;
 
 const INTERVAL_M: u32 = 1;
+// Package of the generated service, the main feed is served under it
+const MAIN_FEED_NAME: &'static str = "db_transit";
+// Clients can set this to tie their requests to the server's logs
+const REQUEST_ID_HEADER: &'static str = "x-request-id";
+// Seconds clients are told to wait when the schedule isn't loaded yet
+const RETRY_AFTER_S: u32 = 5;
+// How often the health of the updater is checked
+const HEALTH_CHECK_INTERVAL_S: u64 = 30;
+// Longest the update loop sleeps at once, even if the next update is further off
+const MAX_UPDATE_SLEEP: Duration = Duration::from_secs(60);
 const LAST_VALID: u32 = (60 / INTERVAL_M) - 1;
+// Hours of the New York day the feed is expected to change, it can go quiet overnight
+const SERVICE_HOURS: Range<u32> = 6..22;
+
+// Newest state the service answers from, replaced as a whole by each update, see `current_state`
+pub static STATE: LazyLock<watch::Sender<Arc<ServerState>>> =
+    LazyLock::new(|| watch::channel(Arc::new(ServerState::default())).0);
+// States of the feeds in `ServerConfig::extra_feeds` by name, the main feed's is `STATE`
+static FEED_STATES: LazyLock<RwLock<HashMap<String, Arc<watch::Sender<Arc<ServerState>>>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+// Time of the last successful feed fetch, whether or not it had changes
+pub static LAST_SUCCESS_LOCK: RwLock<Option<DateTime<Tz>>> = RwLock::const_new(None);
+// Time the schedule contents last changed, unlike `LAST_SUCCESS_LOCK` fetches of an unchanged feed
+// don't count
+pub static LAST_CHANGE_LOCK: RwLock<Option<DateTime<Tz>>> = RwLock::const_new(None);
+// Id for requests that don't bring their own
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+// Tells apart the files of feeds fetched at the same time
+static NEXT_FEED_FILE: AtomicU64 = AtomicU64::new(0);
+// Shared with the health service registered in `server_loop`
+pub static HEALTH_REPORTER: LazyLock<HealthReporter> = LazyLock::new(HealthReporter::new);
+// Holds the size of each update, oldest first
+pub static METRICS_LOCK: RwLock<VecDeque<DiffMetrics>> = RwLock::const_new(VecDeque::new());
+// Feed fetches of every feed, and how many of them failed even after falling back
+static FEED_FETCHES: AtomicU64 = AtomicU64::new(0);
+static FEED_FETCH_FAILURES: AtomicU64 = AtomicU64::new(0);
+// Limits how many feeds are parsed at once, sized from `max_concurrent_parses` on first use
+static PARSE_PERMITS: OnceCell<Semaphore> = OnceCell::const_new();
+static DIFF_PERMITS: OnceCell<Semaphore> = OnceCell::const_new();
 
-// Holds the history of full schedule states for current day
-// Holds the full state of the schedule in GRPC format
-pub static FULL_LOCK: RwLock<Option<(u32, FullSchedule)>> = RwLock::const_new(None);
-// Holds history of diffs, indexed by applicable timestamp
+pub mod archive;
+pub mod feed;
+pub mod tests;
+pub mod timestamp;
 
 pub mod db_transit {
     tonic::include_proto!("db_transit"); // The string specified here must match the proto package name
 }
 
+/// Everything requests are answered from. The updater builds a new one off to the side and
+/// publishes it in one go, so readers never wait on an update and always see a single update's
+/// state
+#[derive(Debug, Clone, Default)]
+pub struct ServerState {
+    // History of schedule states for current day. Only the newest is kept in full
+    pub history: ScheduleHistory,
+    // Full state of the schedule in GRPC format, along with its timestamp
+    pub full: Option<(u32, FullSchedule)>,
+    // Hash of the feed the newest schedule was built from. Feeds fetched later with the same
+    // contents keep the first one's, so clients and the cacher see no change
+    pub feed_hash: Option<Hash>,
+    // History of diffs, indexed by applicable timestamp
+    pub diffs: HashMap<UpdateTimestamp, ScheduleDiff>,
+    // Timestamp of the newest update that changed stops or shapes
+    pub geometry_timestamp: Option<u32>,
+    // First state of the service day, kept even once the history has evicted it
+    pub baseline: Option<Baseline>,
+    // When a client last reported being on each state, see `HistoryLimits`. Requests are recorded
+    // on whichever state answered them, so this is behind a lock unlike the rest
+    pub last_requested: Arc<Mutex<HashMap<UpdateTimestamp, u32>>>,
+}
+
+impl ServerState {
+    /// Diff for a client on `timestamp`, see `nearest_diff`. None if the client could be on a state
+    /// that was replaced after the diff's, which the diff doesn't lead from
+    fn diff_for(&self, timestamp: UpdateTimestamp) -> Option<(UpdateTimestamp, &ScheduleDiff)> {
+        nearest_diff(&self.diffs, timestamp)
+            .filter(|(ts, _)| !self.history.replaced_between(ts.get(), timestamp.get()))
+    }
+
+    /// Notes that a client just reported being on the state at `timestamp`
+    fn record_request(&self, timestamp: UpdateTimestamp) {
+        let Ok(now) = UpdateTimestamp::from_datetime(&get_nyc_datetime()) else {
+            return;
+        };
+
+        // Only ever holds a plain insert, a panic can't leave the map half updated
+        self.last_requested
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(timestamp, now.get());
+    }
+}
+
+/// How many states the history holds and which it evicts, see `ServerConfig::history_limits`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryLimits {
+    pub max_len: usize,
+    // Length the history can grow to while holding on to recently requested states
+    pub max_retained_len: usize,
+    // The oldest state is kept while requested within this many seconds, 0 never keeps it
+    pub retain_requested_secs: u32,
+    // Updates within this many seconds of the newest state replace it, 0 keeps every update
+    pub min_update_gap: u32,
+}
+
+impl HistoryLimits {
+    /// Holds the newest `max_len` states, every update getting its own
+    pub const fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            max_retained_len: max_len,
+            retain_requested_secs: 0,
+            min_update_gap: 0,
+        }
+    }
+}
+
+/// First state of a service day along with the update from it to the newest state, so clients that
+/// hold on to it can be sent one diff all day instead of chaining them
+#[derive(Debug, Clone)]
+pub struct Baseline {
+    pub timestamp: u32,
+    // Shared since every state of the day holds the same one
+    pub schedule: Arc<ScheduleIR>,
+    pub to_latest: ScheduleUpdate,
+    // `to_latest` in GRPC format
+    pub diff: ScheduleDiff,
+}
+
+/// Snapshot of the newest published state. Holding on to it doesn't block the updater, which
+/// publishes a new state rather than changing this one
+pub fn current_state() -> Arc<ServerState> {
+    STATE.borrow().clone()
+}
+
+fn publish_state(state: ServerState) {
+    STATE.send_replace(Arc::new(state));
+}
+
+/// State of the extra feed `name`, starting out empty the first time it's asked for
+pub async fn register_feed(name: &str) -> Arc<watch::Sender<Arc<ServerState>>> {
+    FEED_STATES
+        .write()
+        .await
+        .entry(name.to_owned())
+        .or_insert_with(|| Arc::new(watch::channel(Arc::new(ServerState::default())).0))
+        .clone()
+}
+
+/// Extra feed a request was sent to, set by `route_feed`
+#[derive(Debug, Clone)]
+struct FeedName(String);
+
+/// tonic routes requests by the generated service name, so the one `ScheduleServer` is registered
+/// and requests to an extra feed's `/<feed>.Schedule/` path are pointed at it, tagged with the
+/// feed they were meant for
+fn route_feed(mut request: hyper::Request<tonic::body::Body>) -> hyper::Request<tonic::body::Body> {
+    let path = request.uri().path();
+    let Some((feed, method)) = path
+        .strip_prefix('/')
+        .and_then(|p| p.split_once(".Schedule/"))
+        .filter(|(feed, _)| *feed != MAIN_FEED_NAME && !feed.contains('/'))
+    else {
+        return request;
+    };
+
+    let feed = FeedName(feed.to_owned());
+    if let Ok(uri) = format!("/{}.Schedule/{}", MAIN_FEED_NAME, method).parse() {
+        *request.uri_mut() = uri;
+        request.extensions_mut().insert(feed);
+    }
+
+    request
+}
+
+/// Newest state of the feed `request` was sent to
+async fn request_state<T>(request: &Request<T>) -> Result<Arc<ServerState>, Status> {
+    let Some(FeedName(feed)) = request.extensions().get::<FeedName>() else {
+        return Ok(current_state());
+    };
+
+    FEED_STATES
+        .read()
+        .await
+        .get(feed)
+        .map(|state| state.borrow().clone())
+        .ok_or_else(|| Status::not_found(format!("Unknown feed {}", feed)))
+}
+
+/// Diff from the newest stored timestamp at or before `timestamp`. A client reporting a timestamp
+/// that was never stored has at least the state of the one before it, so that diff still applies
+fn nearest_diff(
+    diff_map: &HashMap<UpdateTimestamp, ScheduleDiff>,
+    timestamp: UpdateTimestamp,
+) -> Option<(UpdateTimestamp, &ScheduleDiff)> {
+    diff_map
+        .iter()
+        .filter(|(ts, _)| **ts <= timestamp)
+        .max_by_key(|(ts, _)| **ts)
+        .map(|(ts, diff)| (*ts, diff))
+}
+
+/// `YYYYMMDD` date sent by a client
+fn parse_request_date(date: &str) -> Result<NaiveDate, Status> {
+    date.parse::<MaskStartDate>()
+        .map(MaskStartDate::date)
+        .map_err(|_| {
+            Status::invalid_argument(format!("Invalid date {:?}, expected YYYYMMDD", date))
+        })
+}
+
+/// Whether `trip` runs on `date`, going by its date mask
+fn trip_runs_on(trip: &Trip, date: NaiveDate) -> bool {
+    trip.mask_start_date
+        .as_deref()
+        .and_then(|start| start.parse::<MaskStartDate>().ok())
+        .is_some_and(|start| mask_has_date(start.date(), trip.date_mask.unwrap_or_default(), date))
+}
+
+/// Drops the trips of `schedule` that don't run on `date`
+fn retain_trips_on(schedule: &mut FullSchedule, date: NaiveDate) {
+    for route in schedule.routes.iter_mut() {
+        route.trips.retain(|trip| trip_runs_on(trip, date));
+    }
+}
+
+/// `diff` for a client that only has the trips running on `date`, see `retain_trips_on`. Added
+/// trips that don't run on it are removed instead, in case the client has an older version that
+/// did. The client may not have a trip whose days changed, so it's sent in full from `latest`,
+/// the state `diff` leads to, if it now runs on `date` and removed otherwise
+fn retain_diff_on(diff: &ScheduleDiff, latest: &ScheduleIR, date: NaiveDate) -> ScheduleDiff {
+    let mut diff = diff.clone();
+
+    let (added_trips, not_running): (Vec<TripExt>, Vec<TripExt>) = diff
+        .added_trips
+        .into_iter()
+        .partition(|t| t.trip.as_ref().is_some_and(|trip| trip_runs_on(trip, date)));
+    diff.added_trips = added_trips;
+    diff.removed_trip_ids
+        .extend(not_running.into_iter().map(|t| TripIdTuple {
+            trip_id: t.trip.and_then(|trip| trip.trip_id),
+            route_id: t.route_id,
+        }));
+
+    for update in std::mem::take(&mut diff.date_mask_updates) {
+        let trip = latest
+            .routes
+            .get(update.route_id())
+            .and_then(|route| route.trips.get(update.trip_id()))
+            .filter(|trip| trip.is_active_on(date));
+
+        match trip {
+            Some(trip) => diff.added_trips.push(TripExt {
+                route_id: update.route_id,
+                trip: Some(trip.into()),
+            }),
+            None => diff.removed_trip_ids.push(TripIdTuple {
+                trip_id: update.trip_id,
+                route_id: update.route_id,
+            }),
+        }
+    }
+
+    diff
+}
+
+/// Wraps a response, leaving it uncompressed if it's under the configured size threshold
+async fn sized_response<T: Message>(message: T) -> Response<T> {
+    let min_compress_bytes = SERVER_CONFIG.read().await.min_compress_bytes;
+    let compress = message.encoded_len() >= min_compress_bytes;
+
+    let mut response = Response::new(message);
+    if !compress {
+        response.disable_compression();
+    }
+
+    response
+}
+
+/// Status for requests arriving before the first schedule has been loaded, with a hint for how long
+/// clients should wait before trying again
+fn not_ready() -> Status {
+    let mut status = Status::unavailable("Schedule not loaded yet, retry shortly");
+    status
+        .metadata_mut()
+        .insert("retry-after", RETRY_AFTER_S.into());
+
+    status
+}
+
+/// Started when a request arrives, turned into an `AccessLog` once the response is ready
+struct RequestTimer {
+    request_id: String,
+    client_timestamp: u32,
+    start: Instant,
+}
+
+impl RequestTimer {
+    fn start(request_id: String, client_timestamp: u32) -> Self {
+        Self {
+            request_id,
+            client_timestamp,
+            start: Instant::now(),
+        }
+    }
+
+    fn finish(self, kind: &'static str, response_bytes: usize) -> AccessLog {
+        AccessLog {
+            request_id: self.request_id,
+            client_timestamp: self.client_timestamp,
+            kind,
+            response_bytes,
+            elapsed: self.start.elapsed(),
+        }
+    }
+}
+
+/// Single line logged per `get_schedule` call
+#[derive(Debug)]
+struct AccessLog {
+    request_id: String,
+    client_timestamp: u32,
+    // What was sent: up-to-date, diff, full-page, full or unavailable
+    kind: &'static str,
+    // Encoded size before compression
+    response_bytes: usize,
+    elapsed: Duration,
+}
+
+impl Display for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "request_id={} client_timestamp={} kind={} response_bytes={} elapsed_ms={:.3}",
+            self.request_id,
+            self.client_timestamp,
+            self.kind,
+            self.response_bytes,
+            self.elapsed.as_secs_f64() * 1000.0
+        )
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ScheduleService {}
 
@@ -48,32 +425,372 @@ pub struct ScheduleService {}
 impl Schedule for ScheduleService {
     async fn get_schedule(
         &self,
-        _request: Request<ScheduleRequest>,
+        request: Request<ScheduleRequest>,
     ) -> Result<Response<ScheduleResponse>, Status> {
-        println!("Recieved new request at {:?}", get_nyc_datetime());
+        let request_id = request
+            .metadata()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned)
+            .unwrap_or_else(|| NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed).to_string());
+        let state = request_state(&request).await?;
+        let ScheduleRequest {
+            timestamp,
+            page_size,
+            page,
+            baseline,
+            for_date,
+        } = request.into_inner();
+        let for_date = for_date.as_deref().map(parse_request_date).transpose()?;
         // Timestamp user was last updated
-        // let timestamp = _request.into_inner().timestamp.unwrap_or(0);
-        // let diff_map = DIFFS_LOCK.read().await;
-
-        if let Some((timestamp, sched)) = FULL_LOCK.read().await.clone() {
-            println!("Done processing new request at {:?}", get_nyc_datetime());
-            Ok(Response::new(ScheduleResponse {
-                full_schedule: Some(sched),
-                schedule_diff: None,
-                timestamp: Some(timestamp),
-            }))
+        let timestamp = timestamp.unwrap_or(0);
+        let timer = RequestTimer::start(request_id, timestamp);
+        let baseline_timestamp = state.baseline.as_ref().map(|b| b.timestamp);
+        // Clients sent a date-filtered schedule keep sending the same date, so their diffs have to
+        // be filtered the same way
+        let narrow = |diff: &ScheduleDiff| match (for_date, state.history.latest()) {
+            (Some(date), Some((_, latest))) => retain_diff_on(diff, latest, date),
+            _ => diff.clone(),
+        };
+
+        if let Some((rec_timestamp, sched)) = state.full.as_ref() {
+            let (kind, response) = if timestamp == *rec_timestamp {
+                // Client is already on the newest state, there's nothing to send
+                state.record_request(UpdateTimestamp(timestamp));
+                let response = ScheduleResponse {
+                    full_schedule: None,
+                    schedule_diff: None,
+                    timestamp: Some(*rec_timestamp),
+                    next_page: None,
+                    baseline_timestamp,
+                };
+
+                ("up-to-date", response)
+            } else if let Some(base) = state.baseline.as_ref()
+                && baseline == Some(true)
+                && timestamp == base.timestamp
+            {
+                let response = ScheduleResponse {
+                    full_schedule: None,
+                    schedule_diff: Some(narrow(&base.diff)),
+                    timestamp: Some(*rec_timestamp),
+                    next_page: None,
+                    baseline_timestamp,
+                };
+
+                ("baseline", response)
+            } else if let Some((diff_timestamp, diff)) = state.diff_for(UpdateTimestamp(timestamp))
+            {
+                state.record_request(diff_timestamp);
+                let response = ScheduleResponse {
+                    full_schedule: None,
+                    schedule_diff: Some(narrow(diff)),
+                    timestamp: Some(*rec_timestamp),
+                    next_page: None,
+                    baseline_timestamp,
+                };
+
+                ("diff", response)
+            } else if let Some(page_size) = page_size {
+                // Page over the stored IR so every page comes from the same update, the timestamp
+                // lets clients notice if an update happened between pages
+                let Some((ir_timestamp, ir)) = state.history.latest() else {
+                    debug!("{}", timer.finish("unavailable", 0));
+                    return Err(not_ready());
+                };
+                let (mut full_schedule, next_page) =
+                    ir.full_schedule_page(page.unwrap_or(0), page_size);
+                if let Some(date) = for_date {
+                    retain_trips_on(&mut full_schedule, date);
+                }
+
+                let response = ScheduleResponse {
+                    full_schedule: Some(full_schedule),
+                    schedule_diff: None,
+                    timestamp: Some(ir_timestamp),
+                    next_page,
+                    baseline_timestamp,
+                };
+
+                ("full-page", response)
+            } else {
+                let mut full_schedule = sched.clone();
+                if let Some(date) = for_date {
+                    retain_trips_on(&mut full_schedule, date);
+                }
+
+                let response = ScheduleResponse {
+                    full_schedule: Some(full_schedule),
+                    schedule_diff: None,
+                    timestamp: Some(*rec_timestamp),
+                    next_page: None,
+                    baseline_timestamp,
+                };
+
+                ("full", response)
+            };
+
+            debug!("{}", timer.finish(kind, response.encoded_len()));
+            Ok(sized_response(response).await)
         } else {
-            Err(Status::new(tonic::Code::Internal, "Unable to find data"))
+            debug!("{}", timer.finish("unavailable", 0));
+            Err(not_ready())
         }
     }
 
     async fn get_last_update(
         &self,
-        _request: Request<LastUpdateRequest>,
+        request: Request<LastUpdateRequest>,
     ) -> Result<Response<LastUpdateResponse>, Status> {
-        let timestamp: Option<u32> = FULL_LOCK.read().await.as_ref().map(|(ts, _)| ts).cloned();
+        let state = request_state(&request).await?;
+        let timestamp: Option<u32> = state.full.as_ref().map(|(ts, _)| ts).cloned();
+        let hash = state.feed_hash.map(|h| h.as_bytes().to_vec());
+        let mut diff_timestamps: Vec<u32> = state.diffs.keys().map(|ts| ts.get()).collect();
+        diff_timestamps.sort();
+
+        Ok(Response::new(LastUpdateResponse {
+            timestamp,
+            hash,
+            diff_timestamps,
+        }))
+    }
+
+    async fn get_schedules(
+        &self,
+        request: Request<SchedulesRequest>,
+    ) -> Result<Response<SchedulesResponse>, Status> {
+        let state = request_state(&request).await?;
+        let timestamps: BTreeSet<u32> = request.into_inner().timestamps.into_iter().collect();
+
+        let (rec_timestamp, sched) = state.full.as_ref().ok_or_else(not_ready)?;
+
+        let mut responses = HashMap::new();
+        let mut full_timestamps = Vec::new();
+
+        for timestamp in timestamps {
+            let schedule_diff = if timestamp == *rec_timestamp {
+                state.record_request(UpdateTimestamp(timestamp));
+                None
+            } else if let Some((diff_timestamp, diff)) = state.diff_for(UpdateTimestamp(timestamp))
+            {
+                state.record_request(diff_timestamp);
+                Some(diff.clone())
+            } else {
+                full_timestamps.push(timestamp);
+                continue;
+            };
+
+            responses.insert(
+                timestamp,
+                ScheduleResponse {
+                    timestamp: Some(*rec_timestamp),
+                    full_schedule: None,
+                    schedule_diff,
+                    next_page: None,
+                    baseline_timestamp: None,
+                },
+            );
+        }
+
+        // Sent once no matter how many timestamps need it
+        let full_schedule = (!full_timestamps.is_empty()).then(|| sched.clone());
+
+        Ok(sized_response(SchedulesResponse {
+            timestamp: Some(*rec_timestamp),
+            responses,
+            full_timestamps,
+            full_schedule,
+        })
+        .await)
+    }
+
+    async fn get_route_diff(
+        &self,
+        request: Request<RouteDiffRequest>,
+    ) -> Result<Response<RouteDiffResponse>, Status> {
+        let state = request_state(&request).await?;
+        let RouteDiffRequest {
+            route_id,
+            timestamp,
+        } = request.into_inner();
+
+        let route_id = route_id
+            .ok_or_else(|| Status::new(tonic::Code::InvalidArgument, "Missing route_id"))?;
+        let timestamp = timestamp.unwrap_or(0);
+
+        let history = &state.history;
+        let (latest_timestamp, _) = history.latest().ok_or_else(not_ready)?;
+        let entry = history.at_or_before(timestamp).ok_or_else(|| {
+            Status::not_found(format!(
+                "No diff from timestamp {}, get the full schedule instead",
+                timestamp
+            ))
+        })?;
+        // Same as `ServerState::diff_for`, the entry's diff doesn't undo a replaced state's changes
+        if history.replaced_between(entry.timestamp, timestamp) {
+            return Err(Status::failed_precondition(format!(
+                "State at timestamp {} was replaced, get the full schedule instead",
+                timestamp
+            )));
+        }
+
+        let schedule_diff = entry
+            .to_latest
+            .by_route()
+            .remove(&route_id)
+            .unwrap_or_default()
+            .into_schedule_update(&route_id)
+            .into();
 
-        Ok(Response::new(LastUpdateResponse { timestamp }))
+        Ok(sized_response(RouteDiffResponse {
+            timestamp: Some(latest_timestamp),
+            schedule_diff: Some(schedule_diff),
+        })
+        .await)
+    }
+
+    async fn diff_against(
+        &self,
+        request: Request<DiffAgainstRequest>,
+    ) -> Result<Response<ScheduleResponse>, Status> {
+        let state = request_state(&request).await?;
+        // Oversized uploads are already rejected while decoding, see `schedule_server`
+        let request = request.into_inner();
+        let permit = diff_permits().await.try_acquire().map_err(|_| {
+            Status::resource_exhausted("Too many schedules being diffed, try again later")
+        })?;
+
+        let client_schedule: ScheduleIR = request
+            .full_schedule
+            .ok_or_else(|| Status::invalid_argument("Missing full_schedule"))?
+            .try_into()
+            .map_err(|e: ScheduleError| Status::invalid_argument(e.to_string()))?;
+
+        // As slow as diffing an update, so it's kept off the threads answering other requests
+        let (timestamp, update) = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+
+            state
+                .history
+                .latest()
+                .map(|(timestamp, schedule)| (timestamp, schedule.get_diff(&client_schedule)))
+        })
+        .await
+        .map_err(|e| Status::internal(format!("Diff task failed: {}", e)))?
+        .ok_or_else(not_ready)?;
+
+        Ok(sized_response(ScheduleResponse {
+            timestamp: Some(timestamp),
+            full_schedule: None,
+            schedule_diff: Some(update.into()),
+            next_page: None,
+            baseline_timestamp: None,
+        })
+        .await)
+    }
+
+    async fn get_geometry(
+        &self,
+        request: Request<GeometryRequest>,
+    ) -> Result<Response<GeometryResponse>, Status> {
+        let state = request_state(&request).await?;
+        let (_, sched) = state.full.as_ref().ok_or_else(not_ready)?;
+        let timestamp = state.geometry_timestamp;
+
+        if request
+            .into_inner()
+            .timestamp
+            .is_some_and(|ts| Some(ts) == timestamp)
+        {
+            return Ok(Response::new(GeometryResponse {
+                timestamp,
+                ..Default::default()
+            }));
+        }
+
+        Ok(sized_response(GeometryResponse {
+            timestamp,
+            stops: sched.stops.clone(),
+            shapes: sched.shapes.clone(),
+        })
+        .await)
+    }
+
+    async fn get_latest_trip_changes(
+        &self,
+        request: Request<TripChangesRequest>,
+    ) -> Result<Response<TripChangesResponse>, Status> {
+        let state = request_state(&request).await?;
+        let history = &state.history;
+        let (timestamp, _) = history.latest().ok_or_else(not_ready)?;
+
+        // The entry before the newest holds exactly the latest update, none on the first one
+        let changes = match history.entries().rev().nth(1) {
+            Some(prev) => prev
+                .to_latest
+                .changed_trip_summary()
+                .into_iter()
+                .map(|mut change| {
+                    // The reverse update adds removed trips back, so it knows their headsigns
+                    if change.headsign.is_none() {
+                        change.headsign = prev
+                            .from_latest
+                            .added_trips
+                            .get(&(change.route_id.clone(), change.trip_id.clone()))
+                            .and_then(|t| t.headsign.as_deref().map(str::to_owned));
+                    }
+
+                    change.into()
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Ok(Response::new(TripChangesResponse {
+            timestamp: Some(timestamp),
+            changes,
+        }))
+    }
+
+    async fn get_next_departures(
+        &self,
+        request: Request<NextDeparturesRequest>,
+    ) -> Result<Response<NextDeparturesResponse>, Status> {
+        let state = request_state(&request).await?;
+        let NextDeparturesRequest {
+            stop_id,
+            from_time,
+            limit,
+        } = request.into_inner();
+
+        let stop_id =
+            stop_id.ok_or_else(|| Status::new(tonic::Code::InvalidArgument, "Missing stop_id"))?;
+        let now = get_nyc_datetime();
+        let date = service_date(&now, SERVER_CONFIG.read().await.service_day_cutoff_hour);
+        let from_time = from_time.unwrap_or(now.num_seconds_from_midnight());
+        // Before the cutoff it's still the previous service day, whose times run past 24:00:00
+        let from_time = if date < now.date_naive() {
+            from_time + 86400
+        } else {
+            from_time
+        };
+        let limit = limit.unwrap_or(DEFAULT_DEPARTURES) as usize;
+
+        let history = &state.history;
+        let (_, schedule) = history.latest().ok_or_else(not_ready)?;
+
+        let departures = schedule
+            .next_departures(&stop_id, date, from_time, limit)
+            .into_iter()
+            .map(|(route, trip, stop_time)| Departure {
+                route_id: Some(route.route_id.to_string()),
+                trip_id: Some(trip.trip_id.to_string()),
+                headsign: trip.headsign.as_deref().map(str::to_owned),
+                stop_time: Some(stop_time.into()),
+            })
+            .collect();
+
+        Ok(Response::new(NextDeparturesResponse { departures }))
     }
 }
 
@@ -92,7 +809,12 @@ fn get_next_update(dt: DateTime<Tz>) -> DateTime<Tz> {
                 .with_second(0)
                 .unwrap()
         } else {
-            dt.with_hour(dt.hour() + 1).unwrap().with_minute(0).unwrap()
+            dt.with_hour(dt.hour() + 1)
+                .unwrap()
+                .with_minute(0)
+                .unwrap()
+                .with_second(0)
+                .unwrap()
         }
     } else {
         dt.with_minute((interval + 1) * INTERVAL_M)
@@ -102,241 +824,914 @@ fn get_next_update(dt: DateTime<Tz>) -> DateTime<Tz> {
     }
 }
 
+/// How long to sleep before `next_update` is due, capped at `MAX_UPDATE_SLEEP`. Zero if it's
+/// already due
+fn time_until_update(now: DateTime<Tz>, next_update: DateTime<Tz>) -> Duration {
+    (next_update - now)
+        .to_std()
+        .unwrap_or_default()
+        .min(MAX_UPDATE_SLEEP)
+}
+
+fn check_zip_magic(magic: &[u8], content_type: Option<&str>) -> Result<(), ScheduleError> {
+    if magic == ZIP_MAGIC {
+        Ok(())
+    } else {
+        Err(ScheduleError::ParseError(format!(
+            "feed response was not a zip, got {}",
+            content_type.unwrap_or("no content type")
+        )))
+    }
+}
+
+/// Checks the feed's hash against the manifest, which starts with the expected blake3 hash in hex.
+/// Anything after it, like a file name in `b3sum` output, is ignored
+fn check_feed_checksum(manifest: &str, hash: &Hash) -> Result<(), ScheduleError> {
+    let expected = manifest.split_whitespace().next().unwrap_or_default();
+    let expected = Hash::from_hex(expected).map_err(|e| {
+        ScheduleError::ParseError(format!(
+            "invalid checksum {:?} in manifest: {}",
+            expected, e
+        ))
+    })?;
+
+    if &expected == hash {
+        Ok(())
+    } else {
+        Err(ScheduleError::ParseError(format!(
+            "feed checksum {} doesn't match manifest checksum {}",
+            hash, expected
+        )))
+    }
+}
+
+/// Which feed a schedule was built from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedKind {
+    Supplemented,
+    /// Regular scheduled feed, only used when the supplemented one can't be fetched
+    Regular,
+}
+
 /// Get the current MTA zip file, check it for differences using the optional hash, and process it
-/// Leaves out any information outside of the current calendar day
+/// Leaves out any information outside of the current calendar day. Falls back to the regular feed
+/// if the supplemented `source` can't be fetched, returning which one was used. Only `source` is
+/// checked against the checksums at `manifest_url`
 async fn get_update(
+    source: &dyn FeedSource,
+    fallback: Option<&dyn FeedSource>,
+    manifest_url: Option<&str>,
     old_hash: Option<Hash>,
     old_schedule: Option<&ScheduleIR>,
-) -> Result<(Option<ScheduleIR>, Option<Hash>), ScheduleError> {
-    let resp: Vec<u8> = reqwest::get(SUPP_URL).await?.bytes().await?.into();
+) -> Result<(Option<ScheduleIR>, Option<Hash>, FeedKind), ScheduleError> {
+    FEED_FETCHES.fetch_add(1, Ordering::Relaxed);
 
-    let hash = blake3::hash(resp.as_slice());
+    let res = fetch_update(
+        source,
+        fallback,
+        manifest_url,
+        old_hash,
+        old_schedule,
+        parse_feed,
+    )
+    .await;
+    if res.is_err() {
+        FEED_FETCH_FAILURES.fetch_add(1, Ordering::Relaxed);
+    }
 
-    // if old_hash.is_some() && old_hash.unwrap() == hash {
-    //     // No need to update, hash is the same as previous
-    //     Ok((None, None))
-    // } else {
-    let schedule: ScheduleIR =
-        gtfs_parsing::schedule::Schedule::from_zip(ZipArchive::new(Cursor::new(resp))?, None)
-            .ok_or("Unable to parse server response")?
-            .into();
+    res
+}
+
+/// Turns a parked feed zip into the IR, see `parse_feed`
+type FeedParser = fn(File, &ScheduleOptions) -> Result<ScheduleIR, ScheduleError>;
+
+async fn fetch_update(
+    source: &dyn FeedSource,
+    fallback: Option<&dyn FeedSource>,
+    manifest_url: Option<&str>,
+    old_hash: Option<Hash>,
+    old_schedule: Option<&ScheduleIR>,
+    parser: FeedParser,
+) -> Result<(Option<ScheduleIR>, Option<Hash>, FeedKind), ScheduleError> {
+    // Never reuses a file, another update may still be parsing its own
+    let path = std::env::temp_dir().join(format!(
+        "{}-{}-{}.zip",
+        FEED_FILE_PREFIX,
+        std::process::id(),
+        NEXT_FEED_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let res = park_and_parse(&path, source, fallback, manifest_url, old_hash, parser).await;
 
     // // Check equality directly, we can save a lot of space if updates are infrequent
     // if old_schedule.is_some() && old_schedule.unwrap() == &schedule {
     //     Ok((None, Some(hash)))
     // } else {
-    Ok((Some(schedule), Some(hash)))
-    // }
+    res
     // }
 }
 
-async fn update_global_state(schedule: ScheduleIR) {
+async fn parse_permits() -> &'static Semaphore {
+    PARSE_PERMITS
+        .get_or_init(|| async {
+            Semaphore::new(SERVER_CONFIG.read().await.max_concurrent_parses.max(1))
+        })
+        .await
+}
+
+async fn diff_permits() -> &'static Semaphore {
+    DIFF_PERMITS
+        .get_or_init(|| async {
+            Semaphore::new(SERVER_CONFIG.read().await.max_concurrent_diffs.max(1))
+        })
+        .await
+}
+
+/// Streams the feed into a file at `path` and has `parser` read it from there, so the zip is never
+/// held in memory, let alone alongside the parsed schedule. The file is removed afterwards, whether
+/// or not fetching and parsing succeeded
+async fn park_and_parse(
+    path: &Path,
+    source: &dyn FeedSource,
+    fallback: Option<&dyn FeedSource>,
+    manifest_url: Option<&str>,
+    old_hash: Option<Hash>,
+    parser: FeedParser,
+) -> Result<(Option<ScheduleIR>, Option<Hash>, FeedKind), ScheduleError> {
+    // Fails rather than truncating a file another parse may still be reading
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(path)?;
+
+    let res = async {
+        let (hash, kind) = match source.fetch(&mut file).await {
+            Ok(hash) => (hash, FeedKind::Supplemented),
+            Err(e) => {
+                let Some(fallback) = fallback else {
+                    return Err(e);
+                };
+
+                warn!(
+                    "Unable to fetch supplemented feed, falling back to regular feed: {}",
+                    e
+                );
+                (fallback.fetch(&mut file).await?, FeedKind::Regular)
+            }
+        };
+
+        if old_hash == Some(hash) {
+            // No need to update, hash is the same as previous
+            return Ok((None, Some(hash), kind));
+        }
+
+        // The manifest only covers the supplemented feed
+        if let Some(manifest_url) = manifest_url
+            && kind == FeedKind::Supplemented
+        {
+            let manifest = reqwest::get(manifest_url).await?.text().await?;
+            check_feed_checksum(&manifest, &hash)?;
+        }
+
+        let _permit = parse_permits()
+            .await
+            .acquire()
+            .await
+            .expect("Parse semaphore is never closed");
+
+        let options = SERVER_CONFIG.read().await.schedule_options();
+        // gtfs_parsing only parses a whole zip at once, so instead of splitting it up the parse
+        // runs where it can't hold up request handlers on the runtime's worker threads
+        let schedule = tokio::task::spawn_blocking(move || parser(file, &options))
+            .await
+            .map_err(|e| format!("Feed parsing task failed: {}", e))??;
+
+        Ok((Some(schedule), Some(hash), kind))
+    }
+    .await;
+
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!(
+            "Unable to remove parsed feed file {}: {}",
+            path.display(),
+            e
+        );
+    }
+
+    res
+}
+
+/// Parses a feed zip into the IR. CPU bound for hundreds of milliseconds, so it shouldn't run on
+/// the async runtime directly
+fn parse_feed(file: File, options: &ScheduleOptions) -> Result<ScheduleIR, ScheduleError> {
+    let mut archive = ZipArchive::new(BufReader::new(file))?;
+    let frequencies = if options.expand_frequencies {
+        read_frequencies(&mut archive)?
+    } else {
+        Vec::new()
+    };
+    // Only needed for warnings, so a stop_times.txt that can't be counted doesn't fail the update
+    let stop_time_rows = read_stop_time_rows(&mut archive)
+        .inspect_err(|e| warn!("Unable to check for duplicate stop sequences: {}", e))
+        .unwrap_or_default();
+    let schedule = gtfs_parsing::schedule::Schedule::from_zip(archive, None)
+        .ok_or("Unable to parse server response")?;
+
+    for warning in duplicate_stop_sequences(&schedule, &stop_time_rows) {
+        warn!("{}", warning);
+    }
+
+    let mut ir = ScheduleIR::from_schedule_with_options(schedule, options);
+    if !frequencies.is_empty() {
+        let generated = ir.expand_frequencies(&frequencies);
+        info!(
+            "Generated {} trips from {} frequencies",
+            generated,
+            frequencies.len()
+        );
+    }
+
+    Ok(ir)
+}
+
+/// Adds `schedule` to `history` as the newest state, returning the new history along with the diff
+/// from each of its states to the newest. An update less than `min_update_gap` seconds after the
+/// first update of the burst the newest state belongs to replaces it instead, so a burst of updates
+/// takes up a single entry. The oldest state stays while it was `requested` within
+/// `retain_requested_secs` of `now`. Pure, so the diff pipeline can be tested without touching the
+/// global state
+fn compute_state_update(
+    mut history: ScheduleHistory,
+    now: u32,
+    schedule: ScheduleIR,
+    limits: &HistoryLimits,
+    requested: &HashMap<UpdateTimestamp, u32>,
+) -> (ScheduleHistory, HashMap<UpdateTimestamp, ScheduleDiff>) {
+    let min_update_gap = limits.min_update_gap;
+    let latest = history.latest().map(|(ts, _)| ts);
+    // Two updates within the same second would otherwise share a timestamp, and clients on the
+    // first would never be sent the second
+    let timestamp = match latest {
+        Some(latest) => now.max(latest + 1),
+        None => now,
+    };
+
+    // Measured from the first update of a burst, a feed changing more often than the gap would
+    // otherwise keep replacing the same entry forever
+    let burst_start = history.burst_start();
+
+    match (latest, burst_start) {
+        (Some(latest), Some(burst_start)) if now.saturating_sub(burst_start) < min_update_gap => {
+            info!(
+                "Update at {} is within {}s of the update at {}, replacing state {}",
+                timestamp, min_update_gap, burst_start, latest
+            );
+            history.replace_latest(timestamp, schedule);
+        }
+        _ => {
+            let recently_requested = |ts| {
+                requested
+                    .get(&UpdateTimestamp(ts))
+                    .is_some_and(|at| now.saturating_sub(*at) < limits.retain_requested_secs)
+            };
+            history.push_retaining(
+                timestamp,
+                schedule,
+                limits.max_len,
+                limits.max_retained_len,
+                recently_requested,
+            );
+        }
+    }
+
+    // Rebuilding each previous state requires a full copy of the schedule, so only pay for it in
+    // debug builds
+    if cfg!(debug_assertions)
+        && let Some(entry) = history.find_mismatch()
+    {
+        error!(
+            "Mismatched diff combining values for state {}, to latest {}, from latest {}, check \
+             code",
+            entry.timestamp, entry.to_latest, entry.from_latest
+        );
+    }
+
+    let diffs = history
+        .entries()
+        .map(|e| (UpdateTimestamp(e.timestamp), e.to_latest.clone().into()))
+        .collect();
+
+    (history, diffs)
+}
+
+/// Service day `timestamp` falls on in New York, see `service_date`
+fn service_day(timestamp: u32, cutoff_hour: u32) -> Option<NaiveDate> {
+    let dt = New_York.timestamp_opt(timestamp as i64, 0).single()?;
+
+    Some(service_date(&dt, cutoff_hour))
+}
+
+/// `prev`'s baseline moved forward to `schedule`, or a new one starting at `schedule` if it's the
+/// first state of a service day
+fn next_baseline(
+    prev: Option<&Baseline>,
+    timestamp: u32,
+    schedule: &ScheduleIR,
+    cutoff_hour: u32,
+) -> Baseline {
+    match prev {
+        Some(base)
+            if service_day(base.timestamp, cutoff_hour) == service_day(timestamp, cutoff_hour) =>
+        {
+            let to_latest = schedule.get_diff(&base.schedule);
+
+            Baseline {
+                timestamp: base.timestamp,
+                schedule: base.schedule.clone(),
+                diff: to_latest.clone().into(),
+                to_latest,
+            }
+        }
+        _ => {
+            let to_latest = ScheduleUpdate::default();
+
+            Baseline {
+                timestamp,
+                schedule: Arc::new(schedule.clone()),
+                diff: to_latest.clone().into(),
+                to_latest,
+            }
+        }
+    }
+}
+
+/// State following `prev` with `schedule` as the newest. This is the slow part of an update, and
+/// only reads `prev` so requests keep being answered from it in the meantime. The history is copied
+/// rather than taken since readers may still hold `prev`
+fn next_state(
+    prev: &ServerState,
+    schedule: ScheduleIR,
+    hash: Hash,
+    now: u32,
+    limits: &HistoryLimits,
+    cutoff_hour: u32,
+) -> ServerState {
+    let full_schedule: FullSchedule = (&schedule).into();
+    let geometry_changed = match prev.history.latest() {
+        Some((_, prev_schedule)) => {
+            let (added_stops, removed_stop_ids) = schedule.get_stop_diffs(prev_schedule);
+            let (added_shapes, removed_shape_ids) = schedule.get_shape_diffs(prev_schedule);
+
+            !(added_stops.is_empty()
+                && removed_stop_ids.is_empty()
+                && added_shapes.is_empty()
+                && removed_shape_ids.is_empty())
+        }
+        None => true,
+    };
+
+    let mut requested = prev
+        .last_requested
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    let (history, diffs) =
+        compute_state_update(prev.history.clone(), now, schedule, limits, &requested);
+    requested.retain(|ts, _| history.get(ts.get()).is_some());
+    let (timestamp, schedule) = history
+        .latest()
+        .expect("History must have a newest state after an update");
+    let baseline = next_baseline(prev.baseline.as_ref(), timestamp, schedule, cutoff_hour);
+
+    ServerState {
+        history,
+        full: Some((timestamp, full_schedule)),
+        feed_hash: Some(hash),
+        diffs,
+        geometry_timestamp: if geometry_changed {
+            Some(timestamp)
+        } else {
+            prev.geometry_timestamp
+        },
+        baseline: Some(baseline),
+        last_requested: Arc::new(Mutex::new(requested)),
+    }
+}
+
+/// The update from the newest schedule of `prev` to `schedule`, along with the share of trips it
+/// changes relative to whichever has more trips. `None` for the first update, which has nothing to
+/// compare to. Diffed directly, since a burst replacing the newest history entry leaves no entry
+/// holding exactly this update
+fn trip_churn(prev: &ServerState, schedule: &ScheduleIR) -> Option<(ScheduleUpdate, f64)> {
+    let (_, prev_schedule) = prev.history.latest()?;
+    let update = schedule.get_diff(prev_schedule);
+    let churn = update.changed_trip_ratio(prev_schedule.trip_count().max(schedule.trip_count()));
+
+    Some((update, churn))
+}
+
+/// Publishes `schedule` as the newest state, returns whether it was. Updates held back by
+/// `ServerConfig::suppress_trip_churn` aren't
+async fn update_global_state(schedule: ScheduleIR, hash: Hash) -> bool {
     let time = get_nyc_datetime();
+    let now = match UpdateTimestamp::from_datetime(&time) {
+        Ok(now) => now,
+        Err(e) => {
+            error!("Unable to update global state: {}", e);
+            return false;
+        }
+    };
 
     info!("Starting global state update");
 
-    let timestamp = time.timestamp();
+    let (limits, archive_dir, max_trip_churn, suppress_trip_churn, cutoff_hour) = {
+        let config = SERVER_CONFIG.read().await;
+        (
+            config.history_limits(),
+            config.archive_dir.clone(),
+            config.max_trip_churn,
+            config.suppress_trip_churn,
+            config.service_day_cutoff_hour,
+        )
+    };
 
     {
-        // let mut history_locked = HISTORY_LOCK.write().await;
-        // let mut diffs_locked = DIFFS_LOCK.write().await;
-        //
-        // // Remove the first entry
-        // if history_locked.len() == MAX_HISTORY_LEN {
-        //     let (ts, os) = history_locked.remove(0);
-        //     let od = diffs_locked.remove(&ts);
-        //
-        //     drop(os);
-        //     drop(od);
-        // }
-        //
-        // let prev_diff: ScheduleUpdate = schedule.get_diff(
-        //     history_locked
-        //         .last()
-        //         .map(|(_, (ir, _))| ir)
-        //         .unwrap_or(&schedule),
-        // );
-        //
-        // history_locked.push((
-        //     timestamp as u32,
-        //     (schedule.clone().into(), ScheduleUpdate::default()),
-        // ));
-
-        let full_schedule: FullSchedule = schedule.clone().into();
-
-
-        *(FULL_LOCK.write().await.deref_mut())  =
-            Some((timestamp as u32, full_schedule))
-        ;
-
-        // let mut diffs_map = HashMap::new();
-        // for (p_timestamp, (p_schedule, p_update)) in history_locked.iter_mut() {
-        //     // This is the diff from directly comparing the current schedule to the previous
-        //     let update = schedule.get_diff(p_schedule);
-        //     // Alternative way of getting to the same state (ideally)
-        //     let alt_update = p_update.combine(&prev_diff);
-        //
-        //     let alt_schedule = p_schedule.clone();
-        //     let alt_schedule = alt_update.apply_to_schedule(alt_schedule);
-        //
-        //     if alt_schedule != schedule {
-        //         error!("Mismatched diff combining values, check code");
-        //     }
-        //
-        //     diffs_map.insert(*p_timestamp, schedule.get_diff(p_schedule).into());
-        //
-        //     let t = std::mem::replace(p_update, update);
-        //     drop(t);
-        // }
-        //
-        // *diffs_locked = diffs_map;
-        //
-        // // diffs_locked.shrink_to_fit();
-        // // history_locked.shrink_to_fit();
-        // println!(
-        //     "Diffs capacity: {}, history capacity: {}",
-        //     diffs_locked.capacity(),
-        //     history_locked.capacity()
-        // );
-        //
-        // println!(
-        //     "Diffs nested capacity: {}, history diff capacity: {}, routes capacity: {}, other schedule capacity: {}",
-        //     diffs_locked
-        //         .values()
-        //         .map(|d| d.added_trips.capacity()
-        //             + d.added_stops.capacity()
-        //             + d.added_shapes.capacity()
-        //             + d.removed_trip_ids.capacity()
-        //             + d.removed_stop_ids.capacity()
-        //             + d.removed_shape_ids.capacity())
-        //         .sum::<usize>(),
-        //     history_locked
-        //         .iter()
-        //         .map(|(_, (_, up))| up.added_trips.capacity()
-        //             + up.added_stops.capacity()
-        //             + up.added_shapes.capacity()
-        //             + up.removed_trip_ids.capacity()
-        //             + up.removed_stop_ids.capacity()
-        //             + up.removed_shape_ids.capacity())
-        //         .sum::<usize>(),
-        //     history_locked
-        //         .iter()
-        //         .map(|(_, (ir, _))| ir.routes.capacity())
-        //         .sum::<usize>(),
-        //     history_locked
-        //         .iter()
-        //         .map(|(_, (ir, _))| ir
-        //             .routes
-        //             .values()
-        //             .flat_map(|r| r.trips.values())
-        //             .map(|t| t.stop_times.capacity())
-        //             .sum::<usize>()
-        //             + ir.shapes.capacity()
-        //             + ir.stops.capacity())
-        //         .sum::<usize>(),
-        // );
+        let prev_state = current_state();
+        let update = trip_churn(&prev_state, &schedule);
+
+        if let Some((_, churn)) = update
+            && churn > max_trip_churn
+        {
+            error!(
+                "Update changes {:.0}% of trips, over the {:.0}% limit, check whether the feed's \
+                 trip ids were regenerated",
+                churn * 100.0,
+                max_trip_churn * 100.0
+            );
+
+            // Not a successful update, so the old schedule still goes stale
+            if suppress_trip_churn {
+                error!("Not publishing the suspicious update");
+                return false;
+            }
+        }
+
+        let state = next_state(&prev_state, schedule, hash, now.get(), &limits, cutoff_hour);
+        let timestamp = state
+            .full
+            .as_ref()
+            .map(|(ts, _)| *ts)
+            .expect("State must have a full schedule after an update");
+
+        if let Some(archive_dir) = archive_dir {
+            match archive_evicted(&archive_dir, &prev_state.history, &state.history) {
+                Ok(paths) => {
+                    for path in paths {
+                        info!("Archived evicted state to {}", path.display());
+                    }
+                }
+                Err(e) => error!("Unable to archive evicted state: {}", e),
+            }
+        }
+
+        if let Some((update, _)) = update {
+            let metrics = DiffMetrics::new(timestamp, &update);
+            info!("Update metrics: {:?}", metrics);
+
+            let mut metrics_locked = METRICS_LOCK.write().await;
+            if metrics_locked.len() >= MAX_METRICS_LEN {
+                metrics_locked.pop_front();
+            }
+            metrics_locked.push_back(metrics);
+        }
+
+        publish_state(state);
+        *LAST_CHANGE_LOCK.write().await = Some(time);
     }
 
+    record_successful_update().await;
+
     verify_global_state().await;
 
     info!("Finished global state update");
+
+    true
+}
+
+async fn record_successful_update() {
+    let now = get_nyc_datetime();
+    *LAST_SUCCESS_LOCK.write().await = Some(now);
+
+    // Stale contents are still the best schedule there is, so they're only reported through the
+    // `transit_content_stale` metric rather than taking the service out of rotation
+    if content_stale(now).await {
+        error!(
+            "Feed contents haven't changed since {:?}, over {} hours ago",
+            *LAST_CHANGE_LOCK.read().await,
+            SERVER_CONFIG.read().await.stale_content_hours
+        );
+    }
+
+    if current_state().full.is_some() {
+        HEALTH_REPORTER
+            .set_serving::<ScheduleServer<ScheduleService>>()
+            .await;
+    }
+}
+
+/// `is_content_stale` for the current contents and configured staleness
+async fn content_stale(now: DateTime<Tz>) -> bool {
+    let last_change = *LAST_CHANGE_LOCK.read().await;
+    let stale_hours = SERVER_CONFIG.read().await.stale_content_hours;
+
+    is_content_stale(last_change, now, stale_hours)
+}
+
+/// The contents are stale if they haven't changed in over `stale_hours`, only checked during
+/// service hours since the feed isn't expected to change overnight
+fn is_content_stale(
+    last_change: Option<DateTime<Tz>>,
+    now: DateTime<Tz>,
+    stale_hours: u32,
+) -> bool {
+    SERVICE_HOURS.contains(&now.hour())
+        && last_change.is_some_and(|last_change| {
+            now - last_change > chrono::Duration::hours(stale_hours.into())
+        })
+}
+
+/// The updater is behind if it hasn't fetched the feed successfully in over two update intervals
+fn is_updater_behind(last_success: Option<DateTime<Tz>>, now: DateTime<Tz>) -> bool {
+    match last_success {
+        Some(last_success) => now - last_success > chrono::Duration::minutes(2 * INTERVAL_M as i64),
+        None => true,
+    }
+}
+
+/// Marks the schedule service as not serving whenever the updater falls behind, it's marked as
+/// serving again by the next successful update
+async fn health_loop() {
+    loop {
+        let last_success = *LAST_SUCCESS_LOCK.read().await;
+
+        if is_updater_behind(last_success, get_nyc_datetime()) {
+            HEALTH_REPORTER
+                .set_not_serving::<ScheduleServer<ScheduleService>>()
+                .await;
+        }
+
+        sleep(Duration::from_secs(HEALTH_CHECK_INTERVAL_S)).await;
+    }
+}
+
+/// Size metrics for the last `n` updates, oldest first
+pub async fn recent_diff_metrics(n: usize) -> Vec<DiffMetrics> {
+    let metrics = METRICS_LOCK.read().await;
+
+    metrics
+        .iter()
+        .skip(metrics.len().saturating_sub(n))
+        .cloned()
+        .collect()
+}
+
+/// Diff from each state in `history` to the newest, worked out from the rebuilt schedules rather
+/// than taken from the stored updates
+fn rebuild_diffs(history: &ScheduleHistory) -> HashMap<UpdateTimestamp, ScheduleDiff> {
+    let Some((_, latest)) = history.latest() else {
+        return HashMap::new();
+    };
+
+    history
+        .timestamps()
+        .filter_map(|ts| match history.reconstruct_at(ts) {
+            Ok(schedule) => Some((UpdateTimestamp(ts), latest.get_diff(&schedule).into())),
+            Err(e) => {
+                // Left out, so clients on that state get the full schedule
+                error!("Unable to rebuild state {} for its diff: {}", ts, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// `state` with its diffs rebuilt, if they don't cover exactly the states in its history
+fn resync_diffs(state: &ServerState) -> Option<ServerState> {
+    let mut h_times: Vec<u32> = state.history.timestamps().collect();
+    let mut d_times: Vec<u32> = state.diffs.keys().map(|ts| ts.get()).collect();
+    h_times.sort();
+    d_times.sort();
+
+    if h_times == d_times {
+        return None;
+    }
+
+    error!(
+        "Diffs out of sync with history, diffs for {:?} but states {:?}, rebuilding diffs. Check \
+         code",
+        d_times, h_times
+    );
+
+    Some(ServerState {
+        diffs: rebuild_diffs(&state.history),
+        ..state.clone()
+    })
 }
 
 async fn verify_global_state() {
-    // info!(
-    //     "Global state contains {} diffs",
-    //     HISTORY_LOCK.read().await.len()
-    // );
-    //
-    // assert_eq!(
-    //     HISTORY_LOCK.read().await.len(),
-    //     DIFFS_LOCK.read().await.len()
-    // );
-    // let mut h_times: Vec<u32> = HISTORY_LOCK.read().await.iter().map(|h| h.0).collect();
-    // let mut d_times: Vec<u32> = DIFFS_LOCK.read().await.keys().cloned().collect();
-    // h_times.sort();
-    // d_times.sort();
-    //
-    // assert_eq!(h_times, d_times);
-    //
-    // for (timestamp, diff) in DIFFS_LOCK.read().await.iter() {
-    //     info!(
-    //         "Timestamp {} contains {} added trips and {} removed trips",
-    //         timestamp,
-    //         diff.added_trips.len(),
-    //         diff.removed_trip_ids.len()
-    //     );
-    // }
-    //
-    // for (timestamp, (ir, diff)) in HISTORY_LOCK.read().await.iter() {
-    //     info!(
-    //         "Timestamp {} ir contains {} trips, update contains {} added trips and {} removed trips",
-    //         timestamp,
-    //         ir.routes.values().map(|r| r.trips.len()).sum::<usize>(),
-    //         diff.added_trips.len(),
-    //         diff.removed_trip_ids.len()
-    //     )
-    // }
+    let mut state = current_state();
+
+    info!("Global state contains {} diffs", state.history.len());
+
+    // Clients would be sent the wrong diff, or a full schedule they don't need, but that's no
+    // reason to take down the updater
+    if let Some(resynced) = resync_diffs(&state) {
+        state = Arc::new(resynced);
+        STATE.send_replace(state.clone());
+    }
+
+    for (timestamp, diff) in state.diffs.iter() {
+        info!(
+            "Timestamp {} contains {} added trips and {} removed trips",
+            timestamp,
+            diff.added_trips.len(),
+            diff.removed_trip_ids.len()
+        );
+    }
+
+    let history = &state.history;
+    if let Some((timestamp, ir)) = history.latest() {
+        info!(
+            "Newest timestamp {} ir contains {} trips, history holds {} stop times in total",
+            timestamp,
+            ir.routes.values().map(|r| r.trips.len()).sum::<usize>(),
+            history.stop_time_count()
+        )
+    }
 }
 
 pub async fn update_loop() -> Result<(), ScheduleError> {
-    let update = get_update(None, None).await?;
+    let (source, fallback, manifest_url) = {
+        let config = SERVER_CONFIG.read().await;
+        (
+            HttpFeedSource::new(config.feed_url.clone()),
+            config.fallback_feed_url.clone().map(HttpFeedSource::new),
+            config.feed_manifest_url.clone(),
+        )
+    };
+    let fallback = fallback.as_ref().map(|f| f as &dyn FeedSource);
+    let manifest_url = manifest_url.as_deref();
+
+    let update = get_update(&source, fallback, manifest_url, None, None).await?;
+    info!("Loaded initial schedule from {:?} feed", update.2);
     let (mut curr_schedule, mut curr_hash) = (
         update.0.expect("Unable to get initial schedule"),
         update.1.expect("Unable to get initial hash"),
     );
 
-    update_global_state(curr_schedule.clone()).await;
+    update_global_state(curr_schedule.clone(), curr_hash).await;
 
     let mut next_update = get_next_update(get_nyc_datetime());
 
     loop {
         if get_nyc_datetime() >= next_update {
-            match get_update(Some(curr_hash), Some(&curr_schedule)).await? {
-                (Some(new_schedule), Some(new_hash)) => {
-                    info!("Found new update");
-                    (curr_schedule, curr_hash) = (new_schedule, new_hash);
-                    // TODO fix the logic on entering new day
-                    update_global_state(curr_schedule.clone()).await;
-                }
-                (None, Some(new_hash)) => {
-                    info!("Found no new update");
-                    curr_hash = new_hash;
-                }
-                (None, None) => {
-                    info!("Found no new update");
-                }
-                u => panic!("Unexpected result: {:?}", u),
-            }
+            let update = get_update(
+                &source,
+                fallback,
+                manifest_url,
+                Some(curr_hash),
+                Some(&curr_schedule),
+            )
+            .await?;
+            handle_update(update, &mut curr_schedule, &mut curr_hash).await;
 
             next_update = get_next_update(get_nyc_datetime());
         }
 
-        sleep(Duration::new(30, 0)).await;
+        sleep(time_until_update(get_nyc_datetime(), next_update)).await;
         // info!(
         //     "Trying to recreate memory issue so this is like, a very long line and if i print it every second the idea is that, if the file issue is the true issue, the issue will pop up more quickly. "
         // );
     }
 }
 
-pub async fn server_loop() -> Result<(), ScheduleError> {
-    info!("Server waiting for initial schedule");
-    // Try to get initial schedule
-    while let None = *FULL_LOCK.read().await {
-        sleep(Duration::new(1, 0)).await;
+/// Publishes a fetched update of the main feed, moving `curr_schedule` and `curr_hash` on to it.
+/// A suppressed update leaves them be, so the next fetch of the same feed is checked again rather
+/// than passing as unchanged
+async fn handle_update(
+    update: (Option<ScheduleIR>, Option<Hash>, FeedKind),
+    curr_schedule: &mut ScheduleIR,
+    curr_hash: &mut Hash,
+) {
+    match update {
+        (Some(new_schedule), Some(new_hash), _)
+            if current_state().history.latest_content_hash()
+                == Some(new_schedule.content_hash()) =>
+        {
+            // File changed but its contents didn't, e.g. rows were reordered. Nothing to publish,
+            // only the next fetch is compared against the new file
+            info!("Found new feed file with no changes");
+            *curr_hash = new_hash;
+            record_successful_update().await;
+        }
+        (Some(new_schedule), Some(new_hash), kind) => {
+            info!("Found new update in {:?} feed", kind);
+            // TODO fix the logic on entering new day
+            if update_global_state(new_schedule.clone(), new_hash).await {
+                (*curr_schedule, *curr_hash) = (new_schedule, new_hash);
+            }
+        }
+        (None, Some(new_hash), _) => {
+            info!("Found no new update");
+            *curr_hash = new_hash;
+            record_successful_update().await;
+        }
+        (None, None, _) => {
+            info!("Found no new update");
+            record_successful_update().await;
+        }
+        u => panic!("Unexpected result: {:?}", u),
     }
+}
+
+/// Keeps the state of an extra feed up to date, see `ServerConfig::extra_feeds`. Failures are only
+/// logged, so a broken extra feed doesn't take the main one down with it
+pub async fn feed_update_loop(feed: FeedConfig) {
+    let state = register_feed(&feed.name).await;
+    let source = HttpFeedSource::new(feed.feed_url);
+    let mut curr_hash = None;
+    let mut next_update = get_nyc_datetime();
+
+    loop {
+        if get_nyc_datetime() >= next_update {
+            // The manifest is only for the main feed
+            match get_update(&source, None, None, curr_hash, None).await {
+                Ok((Some(schedule), Some(hash), _)) => {
+                    curr_hash = Some(hash);
+                    let prev = state.borrow().clone();
+
+                    if prev.history.latest_content_hash() == Some(schedule.content_hash()) {
+                        info!("Found no new update for feed {}", feed.name);
+                    } else {
+                        info!("Found new update for feed {}", feed.name);
+                        let (limits, cutoff_hour) = {
+                            let config = SERVER_CONFIG.read().await;
+                            (config.history_limits(), config.service_day_cutoff_hour)
+                        };
+                        match UpdateTimestamp::from_datetime(&get_nyc_datetime()) {
+                            Ok(now) => {
+                                state.send_replace(Arc::new(next_state(
+                                    &prev,
+                                    schedule,
+                                    hash,
+                                    now.get(),
+                                    &limits,
+                                    cutoff_hour,
+                                )));
+                            }
+                            Err(e) => error!("Unable to update feed {}: {}", feed.name, e),
+                        }
+                    }
+                }
+                Ok(_) => info!("Found no new update for feed {}", feed.name),
+                Err(e) => error!("Unable to update feed {}: {}", feed.name, e),
+            }
+
+            next_update = get_next_update(get_nyc_datetime());
+        }
+
+        sleep(time_until_update(get_nyc_datetime(), next_update)).await;
+    }
+}
 
-    info!("Server thread recieved initial schedule");
+/// The response encoding is negotiated from the client's `grpc-accept-encoding`, preferring gzip
+/// when both are accepted. Requests are limited to `max_diff_against_bytes`, `DiffAgainst` uploads
+/// are the only large ones
+pub async fn schedule_server() -> ScheduleServer<ScheduleService> {
+    let (max_message_bytes, max_request_bytes) = {
+        let config = SERVER_CONFIG.read().await;
+        (config.max_message_bytes, config.max_diff_against_bytes)
+    };
 
-    let addr = "[::1]:50052".parse()?;
+    ScheduleServer::new(ScheduleService::default())
+        .max_decoding_message_size(max_request_bytes)
+        .max_encoding_message_size(max_message_bytes)
+        .accept_compressed(CompressionEncoding::Gzip)
+        .accept_compressed(CompressionEncoding::Zstd)
+        .send_compressed(CompressionEncoding::Gzip)
+        .send_compressed(CompressionEncoding::Zstd)
+}
+
+/// Current server metrics in the Prometheus text format, see `exporter::metrics_loop`
+pub fn render_metrics() -> BoxFuture<'static, String> {
+    Box::pin(async {
+        let mut text = MetricsText::new();
 
-    Server::builder()
-        .add_service(
-            ScheduleServer::new(ScheduleService::default())
-                .send_compressed(CompressionEncoding::Gzip),
+        text.counter(
+            "transit_feed_fetches_total",
+            "Feed fetches attempted, across every feed",
+            FEED_FETCHES.load(Ordering::Relaxed),
         )
-        .serve(addr)
-        .await?;
+        .counter(
+            "transit_feed_fetch_failures_total",
+            "Feed fetches that failed, after falling back if possible",
+            FEED_FETCH_FAILURES.load(Ordering::Relaxed),
+        );
+
+        if let Some(last_success) = *LAST_SUCCESS_LOCK.read().await {
+            text.gauge(
+                "transit_last_update_age_seconds",
+                "Seconds since the main feed was last fetched successfully",
+                (get_nyc_datetime() - last_success).num_seconds() as f64,
+            );
+        }
+
+        text.gauge(
+            "transit_content_stale",
+            "1 if the feed contents have gone unchanged for over `stale_content_hours`",
+            if content_stale(get_nyc_datetime()).await {
+                1.0
+            } else {
+                0.0
+            },
+        );
+
+        text.gauge(
+            "transit_history_length",
+            "Past states clients can get a diff from",
+            current_state().history.len() as f64,
+        );
+
+        if let Some(metrics) = METRICS_LOCK.read().await.back() {
+            text.gauge(
+                "transit_last_diff_added_trips",
+                "Trips added by the latest update",
+                metrics.added_trips as f64,
+            )
+            .gauge(
+                "transit_last_diff_removed_trips",
+                "Trips removed by the latest update",
+                metrics.removed_trips as f64,
+            )
+            .gauge(
+                "transit_last_diff_date_mask_updates",
+                "Trips whose days changed in the latest update",
+                metrics.date_mask_updates as f64,
+            )
+            .gauge(
+                "transit_last_diff_changed_stop_times",
+                "Stop times sent with the trips added by the latest update",
+                metrics.changed_stop_times as f64,
+            )
+            .gauge(
+                "transit_last_diff_encoded_bytes",
+                "Encoded size of the latest update, before compression",
+                metrics.encoded_bytes as f64,
+            );
+        }
+
+        text.into_string()
+    })
+}
+
+/// Binds the server's address, reporting a port that's already taken as such rather than as a
+/// generic transport error
+pub(crate) async fn bind_listener(addr: SocketAddr) -> Result<TcpListener, ScheduleError> {
+    TcpListener::bind(addr).await.map_err(|e| {
+        if e.kind() == ErrorKind::AddrInUse {
+            ScheduleError::AddrInUse(addr)
+        } else {
+            e.into()
+        }
+    })
+}
+
+pub fn health_server() -> HealthServer<HealthService> {
+    HealthServer::new(HealthService::from_health_reporter(HEALTH_REPORTER.clone()))
+}
+
+pub async fn server_loop() -> Result<(), ScheduleError> {
+    // Start serving right away so probes can see the service isn't ready yet, it's marked as
+    // serving once the initial schedule is in place
+    if current_state().full.is_none() {
+        HEALTH_REPORTER
+            .set_not_serving::<ScheduleServer<ScheduleService>>()
+            .await;
+    }
+    let health = tokio::spawn(health_loop());
+
+    let addr = SERVER_CONFIG.read().await.listen_addr;
+    let listener = bind_listener(addr).await?;
+
+    let res = Server::builder()
+        .layer(MapRequestLayer::new(route_feed))
+        .add_service(health_server())
+        .add_service(schedule_server().await)
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await;
+    health.abort();
+    res?;
 
     unreachable!()
 }
@@ -0,0 +1,113 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use prost::Message;
+
+use crate::{diff::history::ScheduleHistory, error::ScheduleError};
+
+use super::db_transit::ArchivedState;
+
+const ARCHIVE_PREFIX: &str = "state-";
+const ARCHIVE_EXTENSION: &str = ".pb";
+
+/// Archive file of the state at `timestamp`
+pub fn archive_path(dir: &Path, timestamp: u32) -> PathBuf {
+    dir.join(format!(
+        "{}{}{}",
+        ARCHIVE_PREFIX, timestamp, ARCHIVE_EXTENSION
+    ))
+}
+
+/// Timestamps of the states archived in `dir`, oldest first. Other files are ignored
+pub fn archived_timestamps(dir: &Path) -> Result<Vec<u32>, ScheduleError> {
+    let mut res = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let timestamp = name
+            .to_str()
+            .and_then(|n| n.strip_prefix(ARCHIVE_PREFIX))
+            .and_then(|n| n.strip_suffix(ARCHIVE_EXTENSION))
+            .and_then(|n| n.parse().ok());
+
+        res.extend(timestamp);
+    }
+    res.sort();
+
+    Ok(res)
+}
+
+pub fn read_archived(dir: &Path, timestamp: u32) -> Result<ArchivedState, ScheduleError> {
+    let path = archive_path(dir, timestamp);
+    let archived = ArchivedState::decode(fs::read(&path)?.as_slice()).map_err(|e| {
+        ScheduleError::ParseError(format!("invalid archive {}: {}", path.display(), e))
+    })?;
+
+    if archived.timestamp != Some(timestamp) || archived.schedule.is_none() {
+        return Err(ScheduleError::ParseError(format!(
+            "archive {} doesn't hold the schedule at {}",
+            path.display(),
+            timestamp
+        )));
+    }
+
+    Ok(archived)
+}
+
+/// Archived state that was current at `timestamp`, the newest one archived at or before it
+pub fn replay(dir: &Path, timestamp: u32) -> Result<ArchivedState, ScheduleError> {
+    let archived = archived_timestamps(dir)?
+        .into_iter()
+        .rev()
+        .find(|ts| *ts <= timestamp)
+        .ok_or_else(|| {
+            ScheduleError::RawError(format!(
+                "No state archived at or before {} in {}",
+                timestamp,
+                dir.display()
+            ))
+        })?;
+
+    read_archived(dir, archived)
+}
+
+/// Writes every entry of `prev` that's missing from `next` to `dir`, along with the update to the
+/// newest state of `next`, returning the files written. Evicted states can only be rebuilt from
+/// the history they were evicted from, so this has to be given the old history rather than the
+/// evicted timestamps
+pub fn archive_evicted(
+    dir: &Path,
+    prev: &ScheduleHistory,
+    next: &ScheduleHistory,
+) -> Result<Vec<PathBuf>, ScheduleError> {
+    let (Some((_, prev_latest)), Some((latest_timestamp, latest))) = (prev.latest(), next.latest())
+    else {
+        return Ok(Vec::new());
+    };
+
+    let mut res = Vec::new();
+    let mut to_next_latest = None;
+
+    for entry in prev.entries().filter(|e| next.get(e.timestamp).is_none()) {
+        // Entries only lead to the newest state of the history they're in, carry them on to the
+        // new one
+        let to_next_latest = to_next_latest.get_or_insert_with(|| latest.get_diff(prev_latest));
+
+        let schedule = prev.reconstruct_at(entry.timestamp)?;
+        let archived = ArchivedState {
+            timestamp: Some(entry.timestamp),
+            schedule: Some(schedule.into()),
+            latest_timestamp: Some(latest_timestamp),
+            to_latest: Some(entry.to_latest.combine(to_next_latest).into()),
+        };
+
+        fs::create_dir_all(dir)?;
+        let path = archive_path(dir, entry.timestamp);
+        fs::write(&path, archived.encode_to_vec())?;
+        res.push(path);
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,66 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use blake3::Hash;
+use futures_core::future::BoxFuture;
+
+use crate::error::ScheduleError;
+
+use super::{ZIP_MAGIC, check_zip_magic};
+
+/// Where the updater gets the feed zip from
+pub trait FeedSource: Send + Sync {
+    /// Streams the feed zip into `file`, replacing anything already in it, and returns its hash.
+    /// The file is left at the start, ready to be read
+    fn fetch<'a>(&'a self, file: &'a mut File) -> BoxFuture<'a, Result<Hash, ScheduleError>>;
+}
+
+/// Downloads the feed from `url` on every fetch
+#[derive(Debug, Clone)]
+pub struct HttpFeedSource {
+    url: Cow<'static, str>,
+}
+
+impl HttpFeedSource {
+    pub fn new(url: impl Into<Cow<'static, str>>) -> Self {
+        Self { url: url.into() }
+    }
+}
+
+impl FeedSource for HttpFeedSource {
+    fn fetch<'a>(&'a self, file: &'a mut File) -> BoxFuture<'a, Result<Hash, ScheduleError>> {
+        Box::pin(async move {
+            let mut resp = reqwest::get(self.url.as_ref()).await?.error_for_status()?;
+            let content_type = resp
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned);
+
+            // Written and hashed a chunk at a time, so the whole zip is never in memory
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            let mut hasher = blake3::Hasher::new();
+
+            while let Some(chunk) = resp.chunk().await? {
+                hasher.update(&chunk);
+                file.write_all(&chunk)?;
+            }
+
+            // S3 sometimes answers with an HTML error page and a 200, catch it here rather than as
+            // an opaque zip error
+            let mut magic = Vec::with_capacity(ZIP_MAGIC.len());
+            file.seek(SeekFrom::Start(0))?;
+            (&mut *file)
+                .take(ZIP_MAGIC.len() as u64)
+                .read_to_end(&mut magic)?;
+            check_zip_magic(&magic, content_type.as_deref())?;
+            file.seek(SeekFrom::Start(0))?;
+
+            Ok(hasher.finalize())
+        })
+    }
+}
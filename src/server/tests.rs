@@ -0,0 +1,2405 @@
+#![cfg(test)]
+
+use std::{
+    collections::HashMap,
+    io::{Seek, SeekFrom, Write},
+    net::SocketAddr,
+    sync::{Arc, atomic::Ordering},
+};
+
+use chrono::{DateTime, Duration, TimeZone};
+use chrono_tz::{America::New_York, Tz};
+use futures_core::future::BoxFuture;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use prost::Message;
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, MutexGuard, Semaphore},
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{
+    codec::CompressionEncoding,
+    server::NamedService,
+    transport::{Channel, Server},
+};
+use tonic_health::pb::{
+    HealthCheckRequest, health_check_response::ServingStatus, health_client::HealthClient,
+};
+use tower::util::MapRequestLayer;
+
+use crate::{
+    cacher::{CACHE_HITS, serve_connections, tests::CacheStateGuard},
+    config::{CACHER_CONFIG, CacherConfig, SERVER_CONFIG, ServerConfig},
+    diff::{
+        history::ScheduleHistory,
+        ir::{DEFAULT_SERVICE_DAY_CUTOFF_HOUR, ScheduleIR, ScheduleOptions, StopTimeIR, TripIR},
+        tests::FeedFixture,
+    },
+    error::ScheduleError,
+    get_nyc_datetime, pin_clock,
+};
+
+use super::{
+    FeedKind, FeedParser, HEALTH_REPORTER, HistoryLimits, LAST_CHANGE_LOCK, LAST_SUCCESS_LOCK,
+    RequestTimer, STATE, ScheduleService, ServerState,
+    archive::{archive_evicted, archive_path, replay},
+    bind_listener, check_feed_checksum, check_zip_magic, compute_state_update, current_state,
+    db_transit::{
+        self, ArchivedState, DiffAgainstRequest, FullSchedule, GeometryRequest, LastUpdateRequest,
+        NextDeparturesRequest, RouteDiffRequest, ScheduleDiff, ScheduleRequest, ScheduleResponse,
+        SchedulesRequest, Stop, StopTime, schedule_client::ScheduleClient,
+        schedule_server::ScheduleServer,
+    },
+    feed::{FeedSource, HttpFeedSource},
+    fetch_update, get_next_update, get_update, handle_update, health_server, is_content_stale,
+    is_updater_behind, nearest_diff, next_state, park_and_parse, parse_feed, publish_state,
+    record_successful_update, register_feed, render_metrics, route_feed, schedule_server,
+    time_until_update,
+    timestamp::UpdateTimestamp,
+    update_global_state, verify_global_state,
+};
+
+// Tests reading or writing the global state hold this so they don't see each other's updates
+static GLOBAL_STATE: Mutex<()> = Mutex::const_new(());
+
+// Holds `GLOBAL_STATE`, and puts the state, server config and update times back the way they were
+// once dropped. Restoring on drop covers tests that panic, which would otherwise leave their state
+// to the next
+struct GlobalStateGuard {
+    state: Arc<ServerState>,
+    config: ServerConfig,
+    last_success: Option<DateTime<Tz>>,
+    last_change: Option<DateTime<Tz>>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl GlobalStateGuard {
+    async fn lock() -> Self {
+        let lock = GLOBAL_STATE.lock().await;
+
+        Self {
+            state: current_state(),
+            config: SERVER_CONFIG.read().await.clone(),
+            last_success: *LAST_SUCCESS_LOCK.read().await,
+            last_change: *LAST_CHANGE_LOCK.read().await,
+            _lock: lock,
+        }
+    }
+}
+
+impl Drop for GlobalStateGuard {
+    fn drop(&mut self) {
+        STATE.send_replace(self.state.clone());
+        // The test's own config guards are gone by now, so this only fails if a task it spawned is
+        // still holding the config
+        if let Ok(mut config) = SERVER_CONFIG.try_write() {
+            *config = self.config.clone();
+        }
+        if let Ok(mut last_success) = LAST_SUCCESS_LOCK.try_write() {
+            *last_success = self.last_success;
+        }
+        if let Ok(mut last_change) = LAST_CHANGE_LOCK.try_write() {
+            *last_change = self.last_change;
+        }
+    }
+}
+
+// Starts the schedule service on an ephemeral port, returning its address
+async fn spawn_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(
+        Server::builder()
+            .layer(MapRequestLayer::new(route_feed))
+            .add_service(health_server())
+            .add_service(schedule_server().await)
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    addr
+}
+
+async fn connect(addr: SocketAddr) -> ScheduleClient<Channel> {
+    ScheduleClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_compression_negotiation() {
+    let addr = spawn_server().await;
+
+    for (encoding, name) in [
+        (CompressionEncoding::Zstd, "zstd"),
+        (CompressionEncoding::Gzip, "gzip"),
+    ] {
+        let mut client = connect(addr).await.accept_compressed(encoding);
+        let resp = client.get_last_update(LastUpdateRequest {}).await.unwrap();
+
+        assert_eq!(
+            resp.metadata()
+                .get("grpc-encoding")
+                .and_then(|v| v.to_str().ok()),
+            Some(name)
+        );
+    }
+}
+
+fn trip(trip_id: &str, stop_id: &str) -> TripIR {
+    let stop_time = StopTimeIR {
+        stop_id: Some(stop_id.into()),
+        arrival_time: Some(3600),
+        departure_time: Some(3600),
+        stop_sequence: Some(1),
+    };
+
+    TripIR {
+        trip_id: trip_id.into(),
+        stop_times: HashMap::from([(1, stop_time)]).into(),
+        headsign: None,
+        shape_id: None,
+        direction: None,
+        mask_start_date: "20250401".parse().unwrap(),
+        date_mask: 1,
+    }
+}
+
+fn sorted_routes(mut schedule: FullSchedule) -> FullSchedule {
+    schedule.routes.sort_by(|a, b| a.route_id.cmp(&b.route_id));
+    schedule
+}
+
+#[tokio::test]
+async fn test_paged_full_schedule() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let schedule = ScheduleIR::builder()
+        .stop(Stop {
+            stop_id: Some("S1".to_owned()),
+            ..Default::default()
+        })
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .route("B")
+        .trip(trip("B1", "S1"))
+        .route("C")
+        .trip(trip("C1", "S1"))
+        .build();
+    let expected = sorted_routes(FullSchedule::from(&schedule));
+
+    update_global_state(schedule, blake3::hash(b"paged")).await;
+
+    let addr = spawn_server().await;
+    let mut client = connect(addr).await;
+
+    let first = client
+        .get_schedule(ScheduleRequest {
+            timestamp: None,
+            page_size: Some(2),
+            page: None,
+            baseline: None,
+            for_date: None,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(first.next_page, Some(1));
+
+    let second = client
+        .get_schedule(ScheduleRequest {
+            timestamp: None,
+            page_size: Some(2),
+            page: first.next_page,
+            baseline: None,
+            for_date: None,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(second.next_page, None);
+    assert_eq!(first.timestamp, second.timestamp);
+
+    let mut reassembled = first.full_schedule.unwrap();
+    let second = second.full_schedule.unwrap();
+    assert_eq!(reassembled.routes.len(), 2);
+    assert_eq!(second.routes.len(), 1);
+    assert!(second.stops.is_empty());
+
+    reassembled.routes.extend(second.routes);
+    assert_eq!(sorted_routes(reassembled), expected);
+}
+
+#[test]
+fn test_non_zip_feed() {
+    let html = b"<html><body>Service Unavailable</body></html>";
+
+    let err = check_zip_magic(&html[..4], Some("text/html")).unwrap_err();
+    assert!(matches!(err, ScheduleError::ParseError(_)));
+    assert_eq!(
+        err.to_string(),
+        "feed response was not a zip, got text/html"
+    );
+
+    // Shorter than the magic itself
+    assert!(check_zip_magic(b"PK", None).is_err());
+    assert!(check_zip_magic(b"PK\x03\x04", Some("application/zip")).is_ok());
+}
+
+#[test]
+fn test_feed_checksum() {
+    let hash = blake3::hash(b"feed");
+    let other = blake3::hash(b"corrupted feed");
+
+    assert!(check_feed_checksum(&hash.to_hex(), &hash).is_ok());
+    assert!(check_feed_checksum(&format!("{}  gtfs_supplemented.zip\n", hash), &hash).is_ok());
+
+    let err = check_feed_checksum(&other.to_hex(), &hash).unwrap_err();
+    assert!(matches!(err, ScheduleError::ParseError(_)));
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "feed checksum {} doesn't match manifest checksum {}",
+            hash, other
+        )
+    );
+
+    assert!(matches!(
+        check_feed_checksum("not a checksum", &hash),
+        Err(ScheduleError::ParseError(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_service_reads_global_state() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    // The service has no state of its own, it must answer from the same statics the updater writes
+    let addr = spawn_server().await;
+    let resp = connect(addr)
+        .await
+        .get_last_update(LastUpdateRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(
+        resp.timestamp,
+        current_state().full.as_ref().map(|(ts, _)| *ts)
+    );
+    assert_eq!(
+        resp.hash,
+        current_state().feed_hash.map(|h| h.as_bytes().to_vec())
+    );
+}
+
+#[tokio::test]
+async fn test_history_eviction() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    // Requests recorded by earlier tests would hold on to their states
+    publish_state(ServerState::default());
+    SERVER_CONFIG.write().await.max_history_len = 3;
+
+    for i in 0..5 {
+        let schedule = ScheduleIR::builder()
+            .route("A")
+            .trip(trip(&format!("A{}", i), "S1"))
+            .build();
+        update_global_state(schedule, blake3::hash(&[i])).await;
+
+        let mut history_times: Vec<u32> = current_state().history.timestamps().collect();
+        let mut diff_times: Vec<u32> = current_state().diffs.keys().map(|ts| ts.get()).collect();
+        history_times.sort();
+        diff_times.sort();
+
+        assert!(history_times.len() <= 3);
+        assert_eq!(history_times, diff_times);
+        // Updates in the same second still get their own timestamp
+        assert!(history_times.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    assert_eq!(current_state().history.len(), 3);
+}
+
+async fn schedule_health(addr: SocketAddr) -> ServingStatus {
+    let mut client = HealthClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+    let resp = client
+        .check(HealthCheckRequest {
+            service: ScheduleServer::<ScheduleService>::NAME.to_owned(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    resp.status()
+}
+
+#[tokio::test]
+async fn test_health_transitions() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let addr = spawn_server().await;
+
+    HEALTH_REPORTER
+        .set_not_serving::<ScheduleServer<ScheduleService>>()
+        .await;
+    assert_eq!(schedule_health(addr).await, ServingStatus::NotServing);
+
+    let schedule = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .build();
+    update_global_state(schedule, blake3::hash(b"health")).await;
+    assert_eq!(schedule_health(addr).await, ServingStatus::Serving);
+}
+
+#[test]
+fn test_updater_behind() {
+    let now = get_nyc_datetime();
+
+    assert!(is_updater_behind(None, now));
+    assert!(!is_updater_behind(Some(now - Duration::seconds(90)), now));
+    assert!(is_updater_behind(Some(now - Duration::minutes(5)), now));
+}
+
+#[test]
+fn test_nearest_diff() {
+    let diff = |shape_id: &str| ScheduleDiff {
+        removed_shape_ids: vec![shape_id.to_owned()],
+        ..Default::default()
+    };
+    let diff_map = HashMap::from([
+        (UpdateTimestamp(100), diff("100")),
+        (UpdateTimestamp(200), diff("200")),
+    ]);
+    let nearest =
+        |timestamp| nearest_diff(&diff_map, UpdateTimestamp(timestamp)).map(|(_, diff)| diff);
+
+    assert_eq!(nearest(100), Some(&diff("100")));
+    // Between the two stored timestamps
+    assert_eq!(nearest(150), Some(&diff("100")));
+    assert_eq!(nearest(250), Some(&diff("200")));
+    // Nothing old enough, has to get the full schedule
+    assert_eq!(nearest(50), None);
+}
+
+#[test]
+fn test_update_timestamp_overflow() {
+    let at = |secs: i64| {
+        DateTime::from_timestamp(secs, 0)
+            .unwrap()
+            .with_timezone(&New_York)
+    };
+
+    assert_eq!(
+        UpdateTimestamp::from_datetime(&at(u32::MAX as i64)).unwrap(),
+        UpdateTimestamp(u32::MAX)
+    );
+    assert_eq!(
+        UpdateTimestamp::from_datetime(&at(0)).unwrap(),
+        UpdateTimestamp(0)
+    );
+    // Early 2106, and anything before the epoch, would otherwise wrap around
+    assert!(UpdateTimestamp::from_datetime(&at(u32::MAX as i64 + 1)).is_err());
+    assert!(UpdateTimestamp::from_datetime(&at(-1)).is_err());
+}
+
+#[tokio::test]
+async fn test_already_current() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let schedule = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .build();
+    update_global_state(schedule, blake3::hash(b"current")).await;
+    let current = current_state().full.as_ref().map(|(ts, _)| *ts);
+
+    let addr = spawn_server().await;
+    let resp = connect(addr)
+        .await
+        .get_schedule(ScheduleRequest {
+            timestamp: current,
+            page_size: None,
+            page: None,
+            baseline: None,
+            for_date: None,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(resp.timestamp, current);
+    assert!(resp.full_schedule.is_none());
+    assert!(resp.schedule_diff.is_none());
+}
+
+#[test]
+fn test_compute_state_update() {
+    let versions = [
+        vec![("A", "A1"), ("B", "B1")],
+        vec![("A", "A1"), ("B", "B2")],
+        vec![("A", "A2"), ("B", "B2"), ("C", "C1")],
+        vec![("C", "C1")],
+    ];
+    let schedules: Vec<ScheduleIR> = versions
+        .iter()
+        .map(|trips| {
+            // Every route exists in every version, only the trips change
+            let mut builder = ScheduleIR::builder().route("A").route("B").route("C");
+            for (route_id, trip_id) in trips {
+                builder = builder.route(*route_id).trip(trip(trip_id, "S1"));
+            }
+            builder.build()
+        })
+        .collect();
+
+    let mut history = ScheduleHistory::new();
+    for schedule in schedules.iter() {
+        // Same clock reading every time, timestamps must still be distinct
+        let diffs;
+        (history, diffs) = compute_state_update(
+            history,
+            1000,
+            schedule.clone(),
+            &HistoryLimits::new(3),
+            &HashMap::new(),
+        );
+
+        let mut history_times: Vec<u32> = history.timestamps().collect();
+        let mut diff_times: Vec<u32> = diffs.keys().map(|ts| ts.get()).collect();
+        history_times.sort();
+        diff_times.sort();
+        assert_eq!(history_times, diff_times);
+
+        assert!(history.verify());
+        assert_eq!(history.latest().map(|(_, ir)| ir), Some(schedule));
+    }
+
+    assert_eq!(
+        history.timestamps().collect::<Vec<_>>(),
+        vec![1001, 1002, 1003]
+    );
+    for (ts, schedule) in (1001..).zip(schedules[1..].iter()) {
+        assert_eq!(history.reconstruct_at(ts).ok().as_ref(), Some(schedule));
+    }
+}
+
+#[tokio::test]
+async fn test_port_in_use() {
+    let listener = bind_listener("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let err = bind_listener(addr).await.unwrap_err();
+    assert!(matches!(err, ScheduleError::AddrInUse(a) if a == addr));
+    assert_eq!(
+        err.to_string(),
+        format!("port {} already in use", addr.port())
+    );
+}
+
+#[tokio::test]
+async fn test_unavailable_before_first_update() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    // Other tests may have already loaded a schedule, put things back as if none had been
+    STATE.send_replace(Default::default());
+
+    let addr = spawn_server().await;
+    let status = connect(addr)
+        .await
+        .get_schedule(ScheduleRequest {
+            timestamp: None,
+            page_size: None,
+            page: None,
+            baseline: None,
+            for_date: None,
+        })
+        .await
+        .unwrap_err();
+
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+    assert_eq!(status.metadata().get("retry-after").unwrap(), "5");
+}
+
+#[test]
+fn test_access_log_latency() {
+    let timer = RequestTimer::start("abc".to_owned(), 100);
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let log = timer.finish("diff", 42);
+
+    assert!(log.elapsed >= std::time::Duration::from_millis(5));
+
+    let line = log.to_string();
+    assert!(line.starts_with("request_id=abc client_timestamp=100 kind=diff response_bytes=42 "));
+    let elapsed_ms: f64 = line.split("elapsed_ms=").nth(1).unwrap().parse().unwrap();
+    assert!(elapsed_ms >= 5.0);
+}
+
+#[test]
+fn test_next_update_pinned_clock() {
+    let pinned = |rfc3339: &str| {
+        let now = DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc();
+        pin_clock(Some(now));
+        get_next_update(get_nyc_datetime())
+    };
+    let nyc = |rfc3339: &str| {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .with_timezone(&New_York)
+    };
+
+    assert_eq!(
+        pinned("2025-04-01T10:15:30-04:00"),
+        nyc("2025-04-01T10:16:00-04:00")
+    );
+    // Hour boundary
+    assert_eq!(
+        pinned("2025-04-01T10:59:30-04:00"),
+        nyc("2025-04-01T11:00:00-04:00")
+    );
+    // Day boundary, based on the New York day rather than the UTC one
+    assert_eq!(
+        pinned("2025-04-01T23:59:30-04:00"),
+        nyc("2025-04-02T00:00:00-04:00")
+    );
+    assert_eq!(
+        pinned("2025-12-31T23:59:59-05:00"),
+        nyc("2026-01-01T00:00:00-05:00")
+    );
+
+    pin_clock(None);
+}
+
+#[tokio::test]
+async fn test_get_schedules() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let mut timestamps = Vec::new();
+    for trip_id in ["A1", "A2", "A3"] {
+        let schedule = ScheduleIR::builder()
+            .route("A")
+            .trip(trip(trip_id, "S1"))
+            .build();
+        update_global_state(schedule, blake3::hash(trip_id.as_bytes())).await;
+        timestamps.push(current_state().full.as_ref().unwrap().0);
+    }
+    let (first, second, current) = (timestamps[0], timestamps[1], timestamps[2]);
+
+    let addr = spawn_server().await;
+    let resp = connect(addr)
+        .await
+        .get_schedules(SchedulesRequest {
+            // Unknown timestamps repeated and mixed in with known ones
+            timestamps: vec![1, first, 2, second, 1],
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    let state = current_state();
+    let diffs = &state.diffs;
+    assert_eq!(resp.timestamp, Some(current));
+    assert_eq!(resp.responses.len(), 2);
+    for timestamp in [first, second] {
+        let response = resp.responses.get(&timestamp).unwrap();
+        assert_eq!(response.timestamp, Some(current));
+        assert_eq!(
+            response.schedule_diff.as_ref(),
+            diffs.get(&UpdateTimestamp(timestamp))
+        );
+        assert!(response.full_schedule.is_none());
+    }
+
+    assert_eq!(resp.full_timestamps, vec![1, 2]);
+    assert_eq!(
+        resp.full_schedule,
+        current_state().full.as_ref().map(|(_, s)| s.clone())
+    );
+}
+
+#[test]
+fn test_content_stale() {
+    let nyc = |rfc3339: &str| {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .with_timezone(&New_York)
+    };
+    let last_change = Some(nyc("2025-04-01T08:00:00-04:00"));
+
+    assert!(!is_content_stale(
+        last_change,
+        nyc("2025-04-01T13:00:00-04:00"),
+        6
+    ));
+    assert!(is_content_stale(
+        last_change,
+        nyc("2025-04-01T15:00:00-04:00"),
+        6
+    ));
+    // Overnight the feed is expected to be quiet
+    assert!(!is_content_stale(
+        last_change,
+        nyc("2025-04-02T03:00:00-04:00"),
+        6
+    ));
+    assert!(!is_content_stale(None, nyc("2025-04-01T15:00:00-04:00"), 6));
+}
+
+#[tokio::test]
+async fn test_stale_content_health() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let addr = spawn_server().await;
+    let at = |rfc3339: &str| {
+        pin_clock(Some(
+            DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc(),
+        ))
+    };
+
+    at("2025-04-01T08:00:00-04:00");
+    let schedule = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .build();
+    update_global_state(schedule, blake3::hash(b"stale")).await;
+    assert_eq!(schedule_health(addr).await, ServingStatus::Serving);
+
+    // Every fetch after this finds the same feed. Stale contents stay in rotation and are only
+    // flagged by the metric
+    for (time, stale) in [
+        ("2025-04-01T10:00:00-04:00", 0),
+        ("2025-04-01T13:59:00-04:00", 0),
+        ("2025-04-01T14:01:00-04:00", 1),
+        ("2025-04-01T18:00:00-04:00", 1),
+    ] {
+        at(time);
+        record_successful_update().await;
+        assert_eq!(
+            schedule_health(addr).await,
+            ServingStatus::Serving,
+            "at {}",
+            time
+        );
+        assert!(
+            render_metrics()
+                .await
+                .contains(&format!("\ntransit_content_stale {}\n", stale)),
+            "at {}",
+            time
+        );
+    }
+
+    pin_clock(None);
+}
+
+#[tokio::test]
+async fn test_get_route_diff() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let first = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .route("L")
+        .trip(trip("L1", "S2"))
+        .build();
+    update_global_state(first, blake3::hash(b"route diff 1")).await;
+    let first_timestamp = current_state().full.as_ref().unwrap().0;
+
+    let second = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A2", "S1"))
+        .route("L")
+        .trip(trip("L2", "S2"))
+        .build();
+    update_global_state(second, blake3::hash(b"route diff 2")).await;
+
+    let addr = spawn_server().await;
+    let resp = connect(addr)
+        .await
+        .get_route_diff(RouteDiffRequest {
+            route_id: Some("L".to_owned()),
+            timestamp: Some(first_timestamp),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let diff = resp.schedule_diff.unwrap();
+
+    assert_eq!(
+        resp.timestamp,
+        current_state().full.as_ref().map(|(ts, _)| *ts)
+    );
+    assert_eq!(diff.added_trips.len(), 1);
+    assert_eq!(diff.added_trips[0].route_id.as_deref(), Some("L"));
+    assert_eq!(diff.removed_trip_ids.len(), 1);
+    assert_eq!(diff.removed_trip_ids[0].trip_id.as_deref(), Some("L1"));
+    assert!(diff.added_stops.is_empty());
+
+    let status = connect(addr)
+        .await
+        .get_route_diff(RouteDiffRequest {
+            route_id: Some("L".to_owned()),
+            timestamp: Some(1),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+}
+
+#[tokio::test]
+async fn test_get_route_diff_replaced_state() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let limits = HistoryLimits {
+        min_update_gap: 60,
+        ..HistoryLimits::new(10)
+    };
+    // 1110 reverts 1100 and replaces it
+    let mut state = ServerState::default();
+    for (now, trip_id) in [(1000, "L1"), (1100, "L2"), (1110, "L1")] {
+        let schedule = ScheduleIR::builder()
+            .route("L")
+            .trip(trip(trip_id, "S2"))
+            .build();
+        state = next_state(
+            &state,
+            schedule,
+            blake3::hash(trip_id.as_bytes()),
+            now,
+            &limits,
+            DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+        );
+    }
+    publish_state(state);
+
+    let addr = spawn_server().await;
+    let route_diff = |timestamp| async move {
+        connect(addr)
+            .await
+            .get_route_diff(RouteDiffRequest {
+                route_id: Some("L".to_owned()),
+                timestamp: Some(timestamp),
+            })
+            .await
+    };
+
+    // The diff from 1000 doesn't remove L2, so a client on 1100 would keep it
+    let status = route_diff(1100).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    assert!(route_diff(1000).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_next_departures_after_midnight() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    // Trips of the 2025-04-01 service day, the late one leaving at 01:00 the next morning
+    let departing_at = |trip_id: &str, departure_time| {
+        let mut trip = trip(trip_id, "S1");
+        trip.stop_times = HashMap::from([(
+            1,
+            StopTimeIR {
+                departure_time: Some(departure_time),
+                ..trip.stop_times[&1].clone()
+            },
+        )])
+        .into();
+        trip
+    };
+    let schedule = ScheduleIR::builder()
+        .route("A")
+        .trip(departing_at("Gone", 24 * 3600 + 600))
+        .trip(departing_at("Late", 25 * 3600))
+        .build();
+    update_global_state(schedule, blake3::hash(b"after midnight")).await;
+
+    pin_clock(Some(
+        DateTime::parse_from_rfc3339("2025-04-02T00:30:00-04:00")
+            .unwrap()
+            .to_utc(),
+    ));
+    let addr = spawn_server().await;
+    let resp = connect(addr)
+        .await
+        .get_next_departures(NextDeparturesRequest {
+            stop_id: Some("S1".to_owned()),
+            ..Default::default()
+        })
+        .await;
+    pin_clock(None);
+
+    let departures = resp.unwrap().into_inner().departures;
+    let trip_ids: Vec<_> = departures
+        .iter()
+        .map(|d| d.trip_id.as_deref().unwrap())
+        .collect();
+    assert_eq!(trip_ids, vec!["Late"]);
+}
+
+#[test]
+fn test_time_until_update() {
+    let at = |rfc3339: &str| {
+        pin_clock(Some(
+            DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc(),
+        ));
+        get_nyc_datetime()
+    };
+
+    let now = at("2025-04-01T10:15:30-04:00");
+    let next_update = get_next_update(now);
+    assert_eq!(
+        time_until_update(now, next_update),
+        std::time::Duration::from_secs(30)
+    );
+
+    let now = at("2025-04-01T10:15:59.750-04:00");
+    assert_eq!(
+        time_until_update(now, next_update),
+        std::time::Duration::from_millis(250)
+    );
+
+    // Already due, or running late
+    let now = at("2025-04-01T10:16:05-04:00");
+    assert_eq!(
+        time_until_update(now, next_update),
+        std::time::Duration::ZERO
+    );
+
+    // Far off, capped so the loop still wakes up
+    let now = at("2025-04-01T10:00:00-04:00");
+    assert_eq!(
+        time_until_update(now, next_update),
+        std::time::Duration::from_secs(60)
+    );
+
+    pin_clock(None);
+}
+
+#[tokio::test]
+async fn test_reads_during_update() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let schedule = |trip_id: &str| {
+        ScheduleIR::builder()
+            .route("A")
+            .trip(trip(trip_id, "S1"))
+            .build()
+    };
+    update_global_state(schedule("A1"), blake3::hash(b"before slow update")).await;
+    let before = current_state().full.as_ref().map(|(ts, _)| *ts);
+
+    let addr = spawn_server().await;
+    let mut client = connect(addr).await;
+
+    // Stands in for an updater that takes a long time between starting and publishing
+    let updater = tokio::spawn(async move {
+        let prev = current_state();
+        let now = prev.full.as_ref().unwrap().0 + 1;
+        let state = next_state(
+            &prev,
+            schedule("A2"),
+            blake3::hash(b"after slow update"),
+            now,
+            &HistoryLimits::new(10),
+            DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        publish_state(state);
+    });
+
+    for _ in 0..5 {
+        let resp = tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            client.get_last_update(LastUpdateRequest {}),
+        )
+        .await
+        .expect("Read blocked on the update")
+        .unwrap()
+        .into_inner();
+
+        assert_eq!(resp.timestamp, before);
+    }
+
+    updater.await.unwrap();
+    let resp = client
+        .get_last_update(LastUpdateRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(resp.timestamp > before);
+    assert_eq!(
+        resp.hash,
+        Some(blake3::hash(b"after slow update").as_bytes().to_vec())
+    );
+}
+
+// Calls GetSchedule over plain HTTP/2 accepting gzip, returning the compressed flag of the single
+// message in the response
+async fn compressed_flag(addr: SocketAddr, request: ScheduleRequest) -> u8 {
+    let client: Client<HttpConnector, Full<Bytes>> = Client::builder(TokioExecutor::new())
+        .http2_only(true)
+        .build_http();
+
+    let message = request.encode_to_vec();
+    let mut body = vec![0];
+    body.extend((message.len() as u32).to_be_bytes());
+    body.extend(message);
+
+    let req = hyper::Request::builder()
+        .method("POST")
+        .uri(format!("http://{}/db_transit.Schedule/GetSchedule", addr))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .header("grpc-accept-encoding", "gzip")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap();
+    let resp = client.request(req).await.unwrap();
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+
+    body[0]
+}
+
+#[tokio::test]
+async fn test_compression_threshold() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    *SERVER_CONFIG.write().await = ServerConfig {
+        min_compress_bytes: 1024,
+        ..ServerConfig::default()
+    };
+
+    let mut builder = ScheduleIR::builder().route("A");
+    for i in 0..200 {
+        builder = builder.trip(trip(&format!("A{}", i), "S1"));
+    }
+    update_global_state(builder.build(), blake3::hash(b"compression threshold")).await;
+    let current = current_state().full.as_ref().map(|(ts, _)| *ts);
+
+    let addr = spawn_server().await;
+    let request = |timestamp| ScheduleRequest {
+        timestamp,
+        page_size: None,
+        page: None,
+        baseline: None,
+        for_date: None,
+    };
+
+    // Already up to date, only a timestamp to send
+    assert_eq!(compressed_flag(addr, request(current)).await, 0);
+    // Full schedule, well over the threshold
+    assert_eq!(compressed_flag(addr, request(None)).await, 1);
+}
+
+#[tokio::test]
+async fn test_large_message_limit() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let max_message_bytes = 8 * 1024 * 1024;
+    *SERVER_CONFIG.write().await = ServerConfig {
+        max_message_bytes,
+        ..ServerConfig::default()
+    };
+
+    // Well past tonic's 4 MiB default, but under the configured limit
+    let stop_times: Vec<StopTime> = (0..50)
+        .map(|i| StopTime {
+            stop_id: Some(format!("S{}", i)),
+            arrival_time: Some(3600 + i),
+            departure_time: Some(3600 + i),
+            stop_sequence: Some(i),
+        })
+        .collect();
+    let full = FullSchedule {
+        routes: vec![db_transit::Route {
+            route_id: Some("A".to_owned()),
+            trips: (0..10_000)
+                .map(|i| db_transit::Trip {
+                    trip_id: Some(format!("A{}", i)),
+                    stop_times: stop_times.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let encoded_len = full.encoded_len();
+    assert!((6 * 1024 * 1024..max_message_bytes).contains(&encoded_len));
+
+    publish_state(ServerState {
+        full: Some((100, full)),
+        ..Default::default()
+    });
+
+    let addr = spawn_server().await;
+    let request = || ScheduleRequest {
+        timestamp: None,
+        page_size: None,
+        page: None,
+        baseline: None,
+        for_date: None,
+    };
+
+    let resp = connect(addr)
+        .await
+        .max_decoding_message_size(max_message_bytes)
+        .get_schedule(request())
+        .await;
+    // A client left at the default limit can't decode it
+    let default_resp = connect(addr).await.get_schedule(request()).await;
+
+    assert_eq!(
+        resp.unwrap()
+            .into_inner()
+            .full_schedule
+            .unwrap()
+            .encoded_len(),
+        encoded_len
+    );
+    assert!(default_resp.is_err());
+}
+
+// Serves `body` over plain HTTP/1.1 to the first connection, returning its URL
+fn serve_once(body: Vec<u8>) -> String {
+    use std::io::Read;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        // Request is small enough to arrive in one read, its contents don't matter
+        let _ = stream.read(&mut [0; 4096]);
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/zip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(&body).unwrap();
+    });
+
+    format!("http://{}/gtfs_subway.zip", addr)
+}
+
+// Nothing listens on port 1, so fetching from it fails to connect
+const UNREACHABLE_FEED_URL: &str = "http://127.0.0.1:1/gtfs_supplemented.zip";
+
+/// Serves the same in-memory zip on every fetch
+struct FixtureFeedSource(Vec<u8>);
+
+impl FeedSource for FixtureFeedSource {
+    fn fetch<'a>(
+        &'a self,
+        file: &'a mut std::fs::File,
+    ) -> BoxFuture<'a, Result<blake3::Hash, ScheduleError>> {
+        Box::pin(async move {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&self.0)?;
+            file.seek(SeekFrom::Start(0))?;
+
+            Ok(blake3::hash(&self.0))
+        })
+    }
+}
+
+fn fixture_zip() -> Vec<u8> {
+    std::fs::read("gtfs_data/schedule/gtfs_supplemented.zip").unwrap()
+}
+
+#[tokio::test]
+async fn update_falls_back_to_regular_feed() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let fallback = HttpFeedSource::new(serve_once(fixture_zip()));
+    let res = get_update(
+        &HttpFeedSource::new(UNREACHABLE_FEED_URL),
+        Some(&fallback),
+        None,
+        None,
+        None,
+    )
+    .await;
+
+    let (schedule, hash, kind) = res.unwrap();
+    assert_eq!(kind, FeedKind::Regular);
+    assert!(schedule.is_some_and(|s| !s.routes.is_empty()));
+    assert!(hash.is_some());
+}
+
+#[tokio::test]
+async fn update_fails_without_fallback() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let res = get_update(
+        &HttpFeedSource::new(UNREACHABLE_FEED_URL),
+        None,
+        None,
+        None,
+        None,
+    )
+    .await;
+
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn update_cycle_from_fixture() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let zip = fixture_zip();
+    let hash = blake3::hash(&zip);
+    publish_state(ServerState::default());
+
+    let (schedule, new_hash, kind) = get_update(&FixtureFeedSource(zip), None, None, None, None)
+        .await
+        .unwrap();
+    update_global_state(schedule.unwrap(), new_hash.unwrap()).await;
+    let state = current_state();
+
+    assert_eq!(kind, FeedKind::Supplemented);
+    assert_eq!(state.history.len(), 1);
+    assert!(
+        state
+            .history
+            .latest()
+            .is_some_and(|(_, s)| !s.routes.is_empty())
+    );
+    assert_eq!(state.feed_hash, Some(hash));
+    assert!(state.full.is_some());
+}
+
+#[tokio::test]
+async fn update_skips_unchanged_feed() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let zip = fixture_zip();
+    let hash = blake3::hash(&zip);
+
+    // Neither parsed nor checked against the manifest, which can't be reached
+    let (schedule, new_hash, _) = get_update(
+        &FixtureFeedSource(zip),
+        None,
+        Some(UNREACHABLE_FEED_URL),
+        Some(hash),
+        None,
+    )
+    .await
+    .unwrap();
+
+    assert!(schedule.is_none());
+    assert_eq!(new_hash, Some(hash));
+}
+
+// Parses `zip` the same way the updater does, through a file named after `name`
+fn parse_zip(zip: &[u8], name: &str, options: &ScheduleOptions) -> ScheduleIR {
+    let path = std::env::temp_dir().join(format!("transit-server-test-{}.zip", name));
+    std::fs::write(&path, zip).unwrap();
+    let schedule = parse_feed(std::fs::File::open(&path).unwrap(), options);
+    let _ = std::fs::remove_file(&path);
+
+    schedule.unwrap()
+}
+
+#[tokio::test]
+async fn test_park_and_parse_removes_file() {
+    let path = std::env::temp_dir().join(format!("transit-server-park-{}.zip", std::process::id()));
+    let source = FixtureFeedSource(b"not a zip".to_vec());
+    let failing: FeedParser = |_, _| Err("Unable to parse server response".into());
+
+    let failed = park_and_parse(&path, &source, None, None, None, failing).await;
+    assert!(failed.is_err());
+    assert!(!path.exists());
+
+    let unchanged = park_and_parse(
+        &path,
+        &source,
+        None,
+        None,
+        Some(blake3::hash(b"not a zip")),
+        failing,
+    )
+    .await;
+    assert!(unchanged.is_ok_and(|(schedule, _, _)| schedule.is_none()));
+    assert!(!path.exists());
+
+    // Another parse's file is left alone
+    std::fs::write(&path, b"in use").unwrap();
+    let clashing = park_and_parse(&path, &source, None, None, None, parse_feed).await;
+    let contents = std::fs::read(&path);
+    let _ = std::fs::remove_file(&path);
+
+    assert!(clashing.is_err());
+    assert_eq!(contents.unwrap(), b"in use");
+}
+
+#[test]
+fn test_expand_frequencies_from_zip() {
+    let zip = FeedFixture::mta()
+        .rows("routes.txt", "MTA NYCT,GS,S,42 St Shuttle,1,808183\n")
+        .rows("calendar.txt", "Daily,1,1,1,1,1,1,1,20200101,20991231\n")
+        .rows("trips.txt", "GS,GS1,Daily,,,\n")
+        .rows(
+            "stop_times.txt",
+            "GS1,901S,06:00:00,06:00:00,1\n\
+             GS1,902S,06:02:30,06:02:30,2\n",
+        )
+        .file(
+            "frequencies.txt",
+            "trip_id,start_time,end_time,headway_secs,exact_times\n\
+             GS1,06:00:00,06:30:00,900,0\n",
+        )
+        .zip();
+    let options = ScheduleOptions {
+        expand_frequencies: true,
+        ..Default::default()
+    };
+
+    let schedule = parse_zip(&zip, "frequencies", &options);
+
+    let trips = &schedule.routes["GS"].trips;
+    let mut trip_ids: Vec<&str> = trips.keys().map(|id| &**id).collect();
+    trip_ids.sort();
+    assert_eq!(trip_ids, vec!["GS1@06:00:00", "GS1@06:15:00"]);
+    // The template's parsed times are shifted to each departure
+    let generated = &trips["GS1@06:15:00"].stop_times;
+    assert_eq!(generated[&1].departure_time, Some(6 * 3600 + 15 * 60));
+    assert_eq!(generated[&2].arrival_time, Some(6 * 3600 + 17 * 60 + 30));
+}
+
+#[test]
+fn test_fill_stop_times_from_zip() {
+    let zip = FeedFixture::mta()
+        .rows("routes.txt", "MTA NYCT,A,A,8 Av Express,1,0039A6\n")
+        .rows("calendar.txt", "Daily,1,1,1,1,1,1,1,20200101,20991231\n")
+        .rows("trips.txt", "A,A1,Daily,,,\n")
+        .rows(
+            "stop_times.txt",
+            "A1,A02S,08:00:00,08:00:30,1\n\
+             A1,A03S,,,2\n\
+             A1,A05S,,,3\n\
+             A1,A06S,08:03:30,08:04:00,4\n",
+        )
+        .zip();
+    let options = ScheduleOptions {
+        fill_stop_times: true,
+        ..Default::default()
+    };
+
+    let schedule = parse_zip(&zip, "fill-stop-times", &options);
+
+    let stop_times = &schedule.routes["A"].trips["A1"].stop_times;
+    let times: Vec<Option<u32>> = (1..=4)
+        .flat_map(|seq| {
+            [
+                stop_times[&seq].arrival_time,
+                stop_times[&seq].departure_time,
+            ]
+        })
+        .collect();
+    // Evenly spaced from the first departure to the last arrival, in order
+    let minute = |m: u32, s: u32| Some(8 * 3600 + m * 60 + s);
+    assert_eq!(
+        times,
+        vec![
+            minute(0, 0),
+            minute(0, 30),
+            minute(1, 30),
+            minute(1, 30),
+            minute(2, 30),
+            minute(2, 30),
+            minute(3, 30),
+            minute(4, 0)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_geometry_timestamp() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let stop = |stop_id: &str| Stop {
+        stop_id: Some(stop_id.to_owned()),
+        ..Default::default()
+    };
+
+    let first = ScheduleIR::builder()
+        .stop(stop("S1"))
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .build();
+    let state = next_state(
+        &ServerState::default(),
+        first,
+        blake3::hash(b"geometry 1"),
+        100,
+        &HistoryLimits::new(10),
+        DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+    );
+    assert_eq!(state.geometry_timestamp, Some(100));
+
+    // Only the trips change, so the geometry timestamp stays put
+    let second = ScheduleIR::builder()
+        .stop(stop("S1"))
+        .route("A")
+        .trip(trip("A2", "S1"))
+        .build();
+    let state = next_state(
+        &state,
+        second,
+        blake3::hash(b"geometry 2"),
+        200,
+        &HistoryLimits::new(10),
+        DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+    );
+    assert_eq!(state.geometry_timestamp, Some(100));
+
+    let third = ScheduleIR::builder()
+        .stop(stop("S1"))
+        .stop(stop("S2"))
+        .route("A")
+        .trip(trip("A2", "S1"))
+        .build();
+    let state = next_state(
+        &state,
+        third,
+        blake3::hash(b"geometry 3"),
+        300,
+        &HistoryLimits::new(10),
+        DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+    );
+    assert_eq!(state.geometry_timestamp, Some(300));
+
+    publish_state(state);
+
+    let addr = spawn_server().await;
+    let geometry = |timestamp| async move {
+        connect(addr)
+            .await
+            .get_geometry(GeometryRequest { timestamp })
+            .await
+            .unwrap()
+            .into_inner()
+    };
+    let full = geometry(Some(100)).await;
+    let current = geometry(Some(300)).await;
+
+    assert_eq!(full.timestamp, Some(300));
+    assert_eq!(full.stops.len(), 2);
+    assert_eq!(current.timestamp, Some(300));
+    assert!(current.stops.is_empty());
+}
+
+#[tokio::test]
+async fn test_baseline_diff() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let stop = Stop {
+        stop_id: Some("S1".to_owned()),
+        ..Default::default()
+    };
+    let schedule = |trip_ids: &[&str]| {
+        let mut builder = ScheduleIR::builder().stop(stop.clone()).route("A");
+        for trip_id in trip_ids {
+            builder = builder.trip(trip(trip_id, "S1"));
+        }
+        builder.build()
+    };
+    let start = New_York
+        .with_ymd_and_hms(2026, 3, 2, 8, 0, 0)
+        .unwrap()
+        .timestamp() as u32;
+    let cutoff = DEFAULT_SERVICE_DAY_CUTOFF_HOUR;
+
+    let first = schedule(&["A1"]);
+    let mut state = next_state(
+        &ServerState::default(),
+        first.clone(),
+        blake3::hash(b"baseline 0"),
+        start,
+        &HistoryLimits::new(2),
+        cutoff,
+    );
+    let mut chained = first.clone();
+
+    for (i, trip_ids) in [&["A1", "A2"][..], &["A2"], &["A2", "A3"], &["A3"]]
+        .into_iter()
+        .enumerate()
+    {
+        let hash = blake3::hash(format!("baseline {}", i + 1).as_bytes());
+        state = next_state(
+            &state,
+            schedule(trip_ids),
+            hash,
+            start + 3600 * (i as u32 + 1),
+            &HistoryLimits::new(2),
+            cutoff,
+        );
+
+        // The previous newest entry now holds exactly this update
+        let update = &state.history.entries().rev().nth(1).unwrap().to_latest;
+        chained = update.apply_to_schedule(chained).unwrap();
+    }
+
+    let (latest_ts, latest) = state.history.latest().unwrap();
+    let baseline = state.baseline.clone().unwrap();
+    assert_eq!(baseline.timestamp, start);
+    // The first state is long gone from the history, but the baseline still reaches it
+    assert!(state.history.get(start).is_none());
+    assert_eq!(&chained, latest);
+    assert_eq!(
+        baseline
+            .to_latest
+            .apply_to_schedule(ScheduleIR::clone(&baseline.schedule))
+            .unwrap(),
+        chained
+    );
+
+    publish_state(state.clone());
+
+    let addr = spawn_server().await;
+    let request = |baseline| ScheduleRequest {
+        timestamp: Some(start),
+        page_size: None,
+        page: None,
+        baseline,
+    };
+    let from_baseline = connect(addr)
+        .await
+        .get_schedule(request(Some(true)))
+        .await
+        .unwrap()
+        .into_inner();
+    let without_flag = connect(addr)
+        .await
+        .get_schedule(request(None))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(from_baseline.timestamp, Some(latest_ts));
+    assert_eq!(from_baseline.baseline_timestamp, Some(start));
+    assert_eq!(from_baseline.schedule_diff, Some(baseline.diff));
+    assert!(from_baseline.full_schedule.is_none());
+    // Without the flag the evicted timestamp can only be sent the full schedule
+    assert!(without_flag.full_schedule.is_some());
+
+    // The first update of the next service day becomes the new baseline
+    let next_day = start + 24 * 3600;
+    let state = next_state(
+        &state,
+        schedule(&["B1"]),
+        blake3::hash(b"next day"),
+        next_day,
+        &HistoryLimits::new(2),
+        cutoff,
+    );
+    assert_eq!(state.baseline.map(|b| b.timestamp), Some(next_day));
+}
+
+#[tokio::test]
+async fn test_diff_against_uploaded_schedule() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let stop = Stop {
+        stop_id: Some("S1".to_owned()),
+        ..Default::default()
+    };
+    let schedule = |trip_ids: &[&str]| {
+        let mut builder = ScheduleIR::builder().stop(stop.clone()).route("A");
+        for trip_id in trip_ids {
+            builder = builder.trip(trip(trip_id, "S1"));
+        }
+        builder.build()
+    };
+    let stale = schedule(&["A1", "A2", "A4"]);
+    let uploaded = FullSchedule::from(&stale);
+    // Nothing is lost going through the format clients hold
+    assert_eq!(ScheduleIR::try_from(uploaded.clone()).unwrap(), stale);
+
+    publish_state(next_state(
+        &ServerState::default(),
+        schedule(&["A1", "A2", "A3"]),
+        blake3::hash(b"diff against"),
+        100,
+        &HistoryLimits::new(10),
+        DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+    ));
+
+    let addr = spawn_server().await;
+    let request = || DiffAgainstRequest {
+        full_schedule: Some(uploaded.clone()),
+    };
+    let response = connect(addr)
+        .await
+        .diff_against(request())
+        .await
+        .unwrap()
+        .into_inner();
+
+    // The limit is read when the service is built
+    SERVER_CONFIG.write().await.max_diff_against_bytes = 16;
+    let limited = spawn_server().await;
+    let too_large = connect(limited).await.diff_against(request()).await;
+
+    assert_eq!(response.timestamp, Some(100));
+    let diff = response.schedule_diff.unwrap();
+    let added: Vec<_> = diff
+        .added_trips
+        .iter()
+        .map(|t| t.trip.as_ref().unwrap().trip_id.as_deref().unwrap())
+        .collect();
+    assert_eq!(added, vec!["A3"]);
+    assert_eq!(diff.removed_trip_ids.len(), 1);
+    assert_eq!(diff.removed_trip_ids[0].trip_id.as_deref(), Some("A4"));
+    assert!(diff.added_stops.is_empty() && diff.removed_stop_ids.is_empty());
+
+    // Rejected by tonic before the message is decoded
+    assert_eq!(too_large.unwrap_err().code(), tonic::Code::OutOfRange);
+}
+
+#[tokio::test]
+async fn test_archive_on_eviction() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let archive_dir = std::env::temp_dir().join(format!("transit-archive-{}", std::process::id()));
+    {
+        let mut config = SERVER_CONFIG.write().await;
+        config.max_history_len = 1;
+        config.archive_dir = Some(archive_dir.clone());
+    }
+    publish_state(ServerState::default());
+
+    let first = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .build();
+    update_global_state(first.clone(), blake3::hash(b"archive 1")).await;
+    let first_timestamp = current_state().full.as_ref().unwrap().0;
+    let path = archive_path(&archive_dir, first_timestamp);
+    // Nothing is evicted yet
+    let archived_early = path.exists();
+
+    let second = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A2", "S1"))
+        .build();
+    update_global_state(second, blake3::hash(b"archive 2")).await;
+    let second_timestamp = current_state().full.as_ref().unwrap().0;
+    let archived = std::fs::read(&path).map(|b| ArchivedState::decode(b.as_slice()));
+
+    let _ = std::fs::remove_dir_all(&archive_dir);
+
+    assert!(!archived_early);
+    let archived = archived.unwrap().unwrap();
+    assert_eq!(archived.timestamp, Some(first_timestamp));
+    assert_eq!(archived.latest_timestamp, Some(second_timestamp));
+    assert_eq!(archived.schedule, Some(FullSchedule::from(&first)));
+    assert_eq!(archived.to_latest.unwrap().added_trips.len(), 1);
+}
+
+#[test]
+fn test_replay_archive() {
+    let archive_dir = std::env::temp_dir().join(format!("transit-replay-{}", std::process::id()));
+    let schedule = |trip_id: &str| {
+        ScheduleIR::builder()
+            .route("A")
+            .trip(trip(trip_id, "S1"))
+            .build()
+    };
+
+    let mut histories = vec![ScheduleHistory::new()];
+    for (timestamp, trip_id) in [(100, "A1"), (200, "A2"), (300, "A3")] {
+        let mut next = histories.last().unwrap().clone();
+        next.push(timestamp, schedule(trip_id), 1);
+        archive_evicted(&archive_dir, histories.last().unwrap(), &next).unwrap();
+        histories.push(next);
+    }
+
+    let earlier = replay(&archive_dir, 150);
+    let later = replay(&archive_dir, 200);
+    let too_early = replay(&archive_dir, 50);
+    let _ = std::fs::remove_dir_all(&archive_dir);
+
+    let earlier = earlier.unwrap();
+    assert_eq!(earlier.timestamp, Some(100));
+    assert_eq!(earlier.schedule, Some(FullSchedule::from(&schedule("A1"))));
+    assert_eq!(
+        later.unwrap().schedule,
+        Some(FullSchedule::from(&schedule("A2")))
+    );
+    assert!(too_early.is_err());
+}
+
+// Calls GetLastUpdate on the service at `path`, e.g. `/mta.Schedule`, which the generated client
+// can't be pointed at
+async fn last_update_at(
+    addr: SocketAddr,
+    path: &str,
+) -> Result<db_transit::LastUpdateResponse, tonic::Status> {
+    let channel = Channel::from_shared(format!("http://{}", addr))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready().await.unwrap();
+
+    grpc.unary(
+        tonic::Request::new(LastUpdateRequest {}),
+        format!("{}/GetLastUpdate", path).parse().unwrap(),
+        tonic::codec::ProstCodec::default(),
+    )
+    .await
+    .map(tonic::Response::into_inner)
+}
+
+#[tokio::test]
+async fn test_extra_feeds() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let feed = |timestamp: u32, hash: &[u8]| {
+        Arc::new(ServerState {
+            full: Some((timestamp, FullSchedule::default())),
+            feed_hash: Some(blake3::hash(hash)),
+            ..Default::default()
+        })
+    };
+    register_feed("mta").await.send_replace(feed(100, b"mta"));
+    register_feed("path").await.send_replace(feed(200, b"path"));
+
+    publish_state(ServerState {
+        full: Some((300, FullSchedule::default())),
+        ..Default::default()
+    });
+
+    let addr = spawn_server().await;
+    let mta = last_update_at(addr, "/mta.Schedule").await;
+    let path = last_update_at(addr, "/path.Schedule").await;
+    let unknown = last_update_at(addr, "/njt.Schedule").await;
+    let main = last_update_at(addr, "/db_transit.Schedule").await;
+
+    let mta = mta.unwrap();
+    assert_eq!(mta.timestamp, Some(100));
+    assert_eq!(mta.hash, Some(blake3::hash(b"mta").as_bytes().to_vec()));
+    let path = path.unwrap();
+    assert_eq!(path.timestamp, Some(200));
+    assert_eq!(path.hash, Some(blake3::hash(b"path").as_bytes().to_vec()));
+    assert_eq!(unknown.unwrap_err().code(), tonic::Code::NotFound);
+    assert_eq!(main.unwrap().timestamp, Some(300));
+}
+
+#[tokio::test]
+async fn test_last_update_diff_timestamps() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    publish_state(ServerState::default());
+    *SERVER_CONFIG.write().await = ServerConfig {
+        max_history_len: 3,
+        ..Default::default()
+    };
+
+    for (i, trip_id) in ["A1", "A2", "A3", "A4"].into_iter().enumerate() {
+        let schedule = ScheduleIR::builder()
+            .route("A")
+            .trip(trip(trip_id, "S1"))
+            .build();
+        update_global_state(schedule, blake3::hash(&[i as u8])).await;
+    }
+
+    let addr = spawn_server().await;
+    let resp = connect(addr)
+        .await
+        .get_last_update(LastUpdateRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    let history_timestamps: Vec<u32> = current_state().history.timestamps().collect();
+
+    // The oldest update was evicted
+    assert_eq!(history_timestamps.len(), 3);
+    assert_eq!(resp.diff_timestamps, history_timestamps);
+    assert_eq!(resp.timestamp, history_timestamps.last().copied());
+}
+
+#[tokio::test]
+async fn test_trip_churn_suppressed() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    {
+        let mut config = SERVER_CONFIG.write().await;
+        config.max_trip_churn = 0.5;
+        config.suppress_trip_churn = true;
+    }
+    publish_state(ServerState::default());
+
+    let first = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .trip(trip("A2", "S1"))
+        .trip(trip("A3", "S1"))
+        .trip(trip("A4", "S1"))
+        .build();
+    update_global_state(first, blake3::hash(b"churn 1")).await;
+
+    // One of four trips changed, under the limit
+    let second = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .trip(trip("A2", "S1"))
+        .trip(trip("A3", "S1"))
+        .trip(trip("A5", "S1"))
+        .build();
+    update_global_state(second, blake3::hash(b"churn 2")).await;
+    let second_hash = current_state().feed_hash;
+    *LAST_SUCCESS_LOCK.write().await = None;
+
+    // Three of four trips changed, over the limit
+    let third = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .trip(trip("B2", "S1"))
+        .trip(trip("B3", "S1"))
+        .trip(trip("B5", "S1"))
+        .build();
+    update_global_state(third, blake3::hash(b"churn 3")).await;
+    let third_state = current_state();
+    // The suppressed update doesn't count as a success, so staleness still shows
+    let third_success = *LAST_SUCCESS_LOCK.read().await;
+
+    assert_eq!(second_hash, Some(blake3::hash(b"churn 2")));
+    assert_eq!(third_state.feed_hash, second_hash);
+    assert_eq!(third_state.history.len(), 2);
+    assert_eq!(third_success, None);
+}
+
+#[tokio::test]
+async fn test_trip_churn_refetched() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    {
+        let mut config = SERVER_CONFIG.write().await;
+        config.max_trip_churn = 0.5;
+        config.suppress_trip_churn = true;
+    }
+    publish_state(ServerState::default());
+
+    let (schedule, hash, _) = get_update(
+        &FixtureFeedSource(feed_zip(&["A1", "A2"])),
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    let (mut curr_schedule, mut curr_hash) = (schedule.unwrap(), hash.unwrap());
+    update_global_state(curr_schedule.clone(), curr_hash).await;
+    let first_hash = curr_hash;
+
+    // Every trip id regenerated, and the same feed found again on the next fetch
+    let churned = FixtureFeedSource(feed_zip(&["B1", "B2"]));
+    for fetch in 0..2 {
+        *LAST_SUCCESS_LOCK.write().await = None;
+        let update = get_update(&churned, None, None, Some(curr_hash), Some(&curr_schedule))
+            .await
+            .unwrap();
+        // Checked again rather than skipped as unchanged
+        assert!(update.0.is_some(), "fetch {}", fetch);
+
+        handle_update(update, &mut curr_schedule, &mut curr_hash).await;
+        assert_eq!(curr_hash, first_hash, "fetch {}", fetch);
+        assert_eq!(
+            current_state().feed_hash,
+            Some(first_hash),
+            "fetch {}",
+            fetch
+        );
+        assert_eq!(*LAST_SUCCESS_LOCK.read().await, None, "fetch {}", fetch);
+    }
+}
+
+#[tokio::test]
+async fn test_trip_churn_within_burst() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    {
+        let mut config = SERVER_CONFIG.write().await;
+        config.max_trip_churn = 0.5;
+        config.suppress_trip_churn = true;
+        // Every update replaces the newest entry, so no entry holds exactly the second update
+        config.min_update_gap_secs = 3600;
+    }
+    publish_state(ServerState::default());
+
+    let first = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .trip(trip("A2", "S1"))
+        .build();
+    update_global_state(first, blake3::hash(b"burst 1")).await;
+
+    let second = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("B1", "S1"))
+        .trip(trip("B2", "S1"))
+        .build();
+    update_global_state(second, blake3::hash(b"burst 2")).await;
+    let second_state = current_state();
+
+    assert_eq!(second_state.feed_hash, Some(blake3::hash(b"burst 1")));
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_fixture_update_off_runtime() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    // Longest the runtime went without getting to a 1ms tick while a whole update was fetched and
+    // parsed. Tests run on a single threaded runtime, so a parse on it would stall the ticker for
+    // the whole parse
+    let longest_tick_ms = Arc::new(std::sync::atomic::AtomicU64::new(0));
+    let ticker = tokio::spawn({
+        let longest_tick_ms = longest_tick_ms.clone();
+        async move {
+            loop {
+                let before = std::time::Instant::now();
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                longest_tick_ms.fetch_max(
+                    before.elapsed().as_millis() as u64,
+                    std::sync::atomic::Ordering::Relaxed,
+                );
+            }
+        }
+    });
+    let before = std::time::Instant::now();
+    get_update(&FixtureFeedSource(fixture_zip()), None, None, None, None)
+        .await
+        .unwrap();
+    let fetch_ms = before.elapsed().as_millis() as u64;
+    ticker.abort();
+
+    let longest_tick_ms = longest_tick_ms.load(std::sync::atomic::Ordering::Relaxed);
+    assert!(
+        longest_tick_ms < fetch_ms / 4,
+        "Runtime stalled for {}ms of a {}ms update",
+        longest_tick_ms,
+        fetch_ms
+    );
+}
+
+const SLOW_PARSE: std::time::Duration = std::time::Duration::from_millis(500);
+
+fn slow_parser(
+    file: std::fs::File,
+    options: &ScheduleOptions,
+) -> Result<ScheduleIR, ScheduleError> {
+    std::thread::sleep(SLOW_PARSE);
+    parse_feed(file, options)
+}
+
+#[tokio::test]
+async fn test_schedule_served_during_parse() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    publish_state(ServerState::default());
+    let schedule = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .build();
+    update_global_state(schedule, blake3::hash(b"served during parse")).await;
+
+    let addr = spawn_server().await;
+    let mut client = connect(addr).await;
+
+    // Tests run on a single threaded runtime, so a parse blocking it would hold up the request
+    // until the parse is done
+    let source = FixtureFeedSource(fixture_zip());
+    let update = fetch_update(&source, None, None, None, None, slow_parser);
+    let request = async {
+        // Give the parse time to start
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let before = std::time::Instant::now();
+        let resp = client.get_schedule(ScheduleRequest::default()).await;
+        (resp, before.elapsed())
+    };
+    let (update, (resp, elapsed)) = tokio::join!(update, request);
+
+    assert!(update.is_ok());
+    assert!(resp.is_ok());
+    assert!(
+        elapsed < SLOW_PARSE / 2,
+        "Request took {:?} while parsing",
+        elapsed
+    );
+}
+
+#[tokio::test]
+async fn test_coalesce_rapid_updates() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    publish_state(ServerState::default());
+    SERVER_CONFIG.write().await.min_update_gap_secs = 60;
+
+    let first = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A1", "S1"))
+        .build();
+    let second = ScheduleIR::builder()
+        .route("A")
+        .trip(trip("A2", "S1"))
+        .build();
+    update_global_state(first, blake3::hash(b"coalesce 1")).await;
+    let first_ts = current_state().history.latest().unwrap().0;
+    update_global_state(second.clone(), blake3::hash(b"coalesce 2")).await;
+    let state = current_state();
+
+    let (latest_ts, latest) = state.history.latest().unwrap();
+    assert_eq!(state.history.len(), 1);
+    assert_eq!(latest, &second);
+    // Still a new timestamp, so clients on the replaced state notice the change
+    assert!(latest_ts > first_ts);
+    assert!(state.history.get(first_ts).is_none());
+    assert_eq!(
+        state.diffs.keys().collect::<Vec<_>>(),
+        vec![&UpdateTimestamp(latest_ts)]
+    );
+}
+
+#[test]
+fn test_coalesce_measured_from_burst_start() {
+    let schedule = |trip_id: &str| {
+        ScheduleIR::builder()
+            .route("A")
+            .trip(trip(trip_id, "S1"))
+            .build()
+    };
+    let limits = HistoryLimits {
+        min_update_gap: 60,
+        ..HistoryLimits::new(10)
+    };
+
+    let mut history = ScheduleHistory::new();
+    for (now, trip_id) in [(1000, "A1"), (1030, "A2"), (1050, "A3"), (1070, "A4")] {
+        (history, _) =
+            compute_state_update(history, now, schedule(trip_id), &limits, &HashMap::new());
+    }
+
+    // A2 and A3 are within 60s of A1 and replace it, A4 is 70s after A1 and isn't, even though
+    // it's only 20s after A3
+    assert_eq!(history.timestamps().collect::<Vec<_>>(), vec![1050, 1070]);
+    assert_eq!(history.burst_start(), Some(1070));
+}
+
+#[test]
+fn test_replaced_state_gets_full_schedule() {
+    let schedule = |trip_id: &str| {
+        ScheduleIR::builder()
+            .route("A")
+            .trip(trip(trip_id, "S1"))
+            .build()
+    };
+    let limits = HistoryLimits {
+        min_update_gap: 60,
+        ..HistoryLimits::new(10)
+    };
+
+    // R adds A2, N reverts it and replaces R
+    let mut history = ScheduleHistory::new();
+    let mut diffs = HashMap::new();
+    for (now, trips) in [(1000, "A1"), (1100, "A2"), (1110, "A1")] {
+        (history, diffs) =
+            compute_state_update(history, now, schedule(trips), &limits, &HashMap::new());
+    }
+    assert_eq!(history.timestamps().collect::<Vec<_>>(), vec![1000, 1110]);
+
+    let state = ServerState {
+        history,
+        diffs,
+        ..Default::default()
+    };
+    // The diff from 1000 doesn't remove A2, so a client on 1100 would keep it
+    assert!(state.diff_for(UpdateTimestamp(1100)).is_none());
+    assert!(state.diff_for(UpdateTimestamp(1105)).is_none());
+    assert_eq!(
+        state.diff_for(UpdateTimestamp(1000)).map(|(ts, _)| ts),
+        Some(UpdateTimestamp(1000))
+    );
+    assert_eq!(
+        state.diff_for(UpdateTimestamp(1200)).map(|(ts, _)| ts),
+        Some(UpdateTimestamp(1110))
+    );
+}
+
+#[tokio::test]
+async fn test_retain_requested_history() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    publish_state(ServerState::default());
+    {
+        let mut config = SERVER_CONFIG.write().await;
+        config.max_history_len = 2;
+        config.max_retained_history_len = 3;
+        config.retain_requested_secs = 3600;
+    }
+
+    let addr = spawn_server().await;
+    let mut client = connect(addr).await;
+    let mut timestamps = Vec::new();
+    for trip_id in ["A1", "A2", "A3", "A4", "A5"] {
+        let schedule = ScheduleIR::builder()
+            .route("A")
+            .trip(trip(trip_id, "S1"))
+            .build();
+        update_global_state(schedule, blake3::hash(trip_id.as_bytes())).await;
+        timestamps.push(current_state().history.latest().unwrap().0);
+
+        // A slow client still on the first state, and another keeping up from A3 on
+        let requested = match trip_id {
+            "A2" => Some(timestamps[0]),
+            "A3" | "A4" => timestamps.last().copied(),
+            _ => None,
+        };
+        if let Some(timestamp) = requested {
+            client
+                .get_schedule(ScheduleRequest {
+                    timestamp: Some(timestamp),
+                    page_size: None,
+                    page: None,
+                    baseline: None,
+                    for_date: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let mut history_times: Vec<u32> = current_state().history.timestamps().collect();
+        history_times.sort();
+        let expected: Vec<u32> = match trip_id {
+            "A1" => vec![timestamps[0]],
+            "A2" => vec![timestamps[0], timestamps[1]],
+            // A1 was asked for, so the history grows past its length instead of evicting it
+            "A3" => vec![timestamps[0], timestamps[1], timestamps[2]],
+            // At the hard cap the oldest goes regardless, then A2 since nobody asked for it
+            "A4" => vec![timestamps[2], timestamps[3]],
+            // Only ever evicted from the oldest end
+            _ => vec![timestamps[2], timestamps[3], timestamps[4]],
+        };
+        assert_eq!(history_times, expected, "after {}", trip_id);
+    }
+
+    let state = current_state();
+
+    assert!(state.diffs.contains_key(&UpdateTimestamp(timestamps[2])));
+    assert!(!state.diffs.contains_key(&UpdateTimestamp(timestamps[0])));
+    // Only states still in the history are tracked
+    let mut requested: Vec<u32> = state
+        .last_requested
+        .lock()
+        .unwrap()
+        .keys()
+        .map(|ts| ts.get())
+        .collect();
+    requested.sort();
+    assert_eq!(requested, vec![timestamps[2], timestamps[3]]);
+}
+
+// Feed zip with a single daily route A, running a trip for each of `trip_ids` through stop S1
+fn feed_zip(trip_ids: &[&str]) -> Vec<u8> {
+    let trips: String = trip_ids
+        .iter()
+        .map(|id| format!("A,{},Daily,,,\n", id))
+        .collect();
+    let stop_times: String = trip_ids
+        .iter()
+        .map(|id| format!("{},S1,08:00:00,08:00:00,1\n", id))
+        .collect();
+
+    FeedFixture::mta()
+        .rows("stops.txt", "S1,Stop 1,40.0,-74.0,1,\n")
+        .rows("routes.txt", "MTA NYCT,A,A,8 Av Express,1,0039A6\n")
+        .rows("calendar.txt", "Daily,1,1,1,1,1,1,1,20200101,20991231\n")
+        .rows("trips.txt", &trips)
+        .rows("stop_times.txt", &stop_times)
+        .zip()
+}
+
+#[tokio::test]
+async fn test_server_and_cacher_end_to_end() {
+    let _guard = GlobalStateGuard::lock().await;
+    let _cache_guard = CacheStateGuard::lock().await;
+
+    publish_state(ServerState::default());
+    let update = |trip_ids: &'static [&'static str]| async move {
+        let (schedule, hash, _) = get_update(
+            &FixtureFeedSource(feed_zip(trip_ids)),
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        update_global_state(schedule.unwrap(), hash.unwrap()).await;
+
+        current_state().full.as_ref().unwrap().0
+    };
+    let first = update(&["A1"]).await;
+
+    let server_addr = spawn_server().await;
+    CACHER_CONFIG.write().await.upstream_url = format!("http://{}", server_addr).into();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let cacher_addr = listener.local_addr().unwrap();
+    let cacher = tokio::spawn(serve_connections(
+        listener,
+        Arc::new(Semaphore::new(16)),
+        Default::default(),
+    ));
+    let mut client = connect(cacher_addr).await;
+
+    let hits = CACHE_HITS.load(Ordering::Relaxed);
+    let full = client
+        .get_schedule(ScheduleRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    let cached = client
+        .get_schedule(ScheduleRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+    let cache_hits = CACHE_HITS.load(Ordering::Relaxed) - hits;
+
+    // New feed contents, the cacher has to notice and go back to the server
+    let second = update(&["A2"]).await;
+    let diff = client
+        .get_schedule(ScheduleRequest {
+            timestamp: Some(first),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+
+    cacher.abort();
+
+    assert_eq!(cache_hits, 1);
+    assert_eq!(full, cached);
+    assert_eq!(full.timestamp, Some(first));
+    assert!(full.full_schedule.is_some());
+
+    assert_eq!(diff.timestamp, Some(second));
+    assert!(diff.full_schedule.is_none());
+    let diff = diff.schedule_diff.unwrap();
+    let added: Vec<_> = diff
+        .added_trips
+        .iter()
+        .map(|t| t.trip.as_ref().unwrap().trip_id.as_deref().unwrap())
+        .collect();
+    assert_eq!(added, vec!["A2"]);
+    assert_eq!(diff.removed_trip_ids.len(), 1);
+    assert_eq!(diff.removed_trip_ids[0].trip_id.as_deref(), Some("A1"));
+}
+
+#[tokio::test]
+async fn test_diffs_resynced_with_history() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    let schedule = |trip_id: &str| {
+        ScheduleIR::builder()
+            .route("A")
+            .trip(trip(trip_id, "S1"))
+            .build()
+    };
+    let cutoff = DEFAULT_SERVICE_DAY_CUTOFF_HOUR;
+    let state = next_state(
+        &ServerState::default(),
+        schedule("A1"),
+        blake3::hash(b"resync 1"),
+        100,
+        &HistoryLimits::new(10),
+        cutoff,
+    );
+    let state = next_state(
+        &state,
+        schedule("A2"),
+        blake3::hash(b"resync 2"),
+        200,
+        &HistoryLimits::new(10),
+        cutoff,
+    );
+    let expected = state.diffs.clone();
+
+    // One state's diff lost, and one kept for a state the history doesn't have
+    let mut desynced = state.clone();
+    desynced.diffs.remove(&UpdateTimestamp(100));
+    desynced
+        .diffs
+        .insert(UpdateTimestamp(150), ScheduleDiff::default());
+
+    publish_state(desynced);
+    verify_global_state().await;
+    let resynced = current_state();
+
+    assert_eq!(resynced.diffs, expected);
+    assert_eq!(resynced.history, state.history);
+}
+
+#[tokio::test]
+async fn test_full_schedule_for_date() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    // Masks start on 2025-04-01
+    let masked = |trip_id: &str, date_mask| TripIR {
+        date_mask,
+        ..trip(trip_id, "S1")
+    };
+    let schedule = ScheduleIR::builder()
+        .route("A")
+        .trip(masked("A1", 0b01))
+        .trip(masked("A2", 0b10))
+        .trip(masked("A3", 0b11))
+        .route("B")
+        .trip(masked("B1", 0b01))
+        .build();
+
+    publish_state(next_state(
+        &ServerState::default(),
+        schedule,
+        blake3::hash(b"for date"),
+        100,
+        &HistoryLimits::new(10),
+        DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+    ));
+
+    let addr = spawn_server().await;
+    let request = |for_date: &str| ScheduleRequest {
+        for_date: Some(for_date.to_owned()),
+        ..Default::default()
+    };
+    let trip_ids = |resp: ScheduleResponse| {
+        let mut trip_ids: Vec<String> = resp
+            .full_schedule
+            .unwrap()
+            .routes
+            .into_iter()
+            .flat_map(|r| r.trips)
+            .map(|t| t.trip_id.unwrap())
+            .collect();
+        trip_ids.sort();
+        trip_ids
+    };
+
+    let mut client = connect(addr).await;
+    let second_day = client.get_schedule(request("20250402")).await;
+    let paged = client
+        .get_schedule(ScheduleRequest {
+            page_size: Some(1),
+            ..request("20250402")
+        })
+        .await;
+    let outside_window = client.get_schedule(request("20250601")).await;
+    let invalid = client.get_schedule(request("2025-04-02")).await;
+
+    assert_eq!(trip_ids(second_day.unwrap().into_inner()), vec!["A2", "A3"]);
+    assert_eq!(trip_ids(paged.unwrap().into_inner()), vec!["A2", "A3"]);
+    assert!(trip_ids(outside_window.unwrap().into_inner()).is_empty());
+    assert_eq!(invalid.unwrap_err().code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_diff_for_date() {
+    let _guard = GlobalStateGuard::lock().await;
+
+    // Masks start on 2025-04-01, the client only has the trips running on 2025-04-02
+    let masked = |trip_id: &str, date_mask| TripIR {
+        date_mask,
+        ..trip(trip_id, "S1")
+    };
+    let first = ScheduleIR::builder()
+        .route("A")
+        .trip(masked("A1", 0b01))
+        .trip(masked("A2", 0b10))
+        .build();
+    let second = ScheduleIR::builder()
+        .route("A")
+        .trip(masked("A1", 0b11))
+        .trip(masked("A2", 0b01))
+        .trip(masked("A3", 0b01))
+        .trip(masked("A4", 0b10))
+        .build();
+
+    let limits = HistoryLimits::new(10);
+    let first = next_state(
+        &ServerState::default(),
+        first,
+        blake3::hash(b"first"),
+        100,
+        &limits,
+        DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+    );
+    publish_state(next_state(
+        &first,
+        second,
+        blake3::hash(b"second"),
+        200,
+        &limits,
+        DEFAULT_SERVICE_DAY_CUTOFF_HOUR,
+    ));
+
+    let addr = spawn_server().await;
+    let request = |for_date: Option<&str>| ScheduleRequest {
+        timestamp: Some(100),
+        for_date: for_date.map(str::to_owned),
+        ..Default::default()
+    };
+
+    let mut client = connect(addr).await;
+    let narrowed = client.get_schedule(request(Some("20250402"))).await;
+    let unfiltered = client.get_schedule(request(None)).await;
+
+    let trip_ids = |diff: &ScheduleDiff| {
+        let mut added: Vec<String> = diff
+            .added_trips
+            .iter()
+            .map(|t| t.trip.as_ref().unwrap().trip_id().to_owned())
+            .collect();
+        let mut removed: Vec<String> = diff
+            .removed_trip_ids
+            .iter()
+            .map(|t| t.trip_id().to_owned())
+            .collect();
+        added.sort();
+        removed.sort();
+        (added, removed)
+    };
+
+    // A1 now runs on the date and A2 no longer does, the client has neither version of A3
+    let narrowed = narrowed.unwrap().into_inner().schedule_diff.unwrap();
+    assert_eq!(
+        trip_ids(&narrowed),
+        (
+            vec!["A1".into(), "A4".into()],
+            vec!["A2".into(), "A3".into()]
+        )
+    );
+    assert!(narrowed.date_mask_updates.is_empty());
+
+    let unfiltered = unfiltered.unwrap().into_inner().schedule_diff.unwrap();
+    assert_eq!(
+        trip_ids(&unfiltered),
+        (vec!["A3".into(), "A4".into()], vec![])
+    );
+    assert_eq!(unfiltered.date_mask_updates.len(), 2);
+}
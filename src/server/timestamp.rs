@@ -0,0 +1,32 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, TimeZone};
+
+use crate::error::ScheduleError;
+
+/// Time an update was published, in seconds since the epoch. It's what clients report to get the
+/// diff from their state, and is a `u32` to match the proto, so it runs out in 2106
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UpdateTimestamp(pub u32);
+
+impl UpdateTimestamp {
+    /// Timestamp of `time`, failing instead of wrapping around if it's before the epoch or after
+    /// the last second a `u32` holds
+    pub fn from_datetime<Tz: TimeZone>(time: &DateTime<Tz>) -> Result<Self, ScheduleError> {
+        let secs = time.timestamp();
+
+        u32::try_from(secs)
+            .map(Self)
+            .map_err(|_| format!("Time {} is out of range for an update timestamp", secs).into())
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl Display for UpdateTimestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
@@ -0,0 +1,94 @@
+// Counts the allocations of the diff hot path. Swapping the allocator affects the whole binary, so
+// these live in their own test binary instead of with the unit tests
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    collections::HashMap,
+};
+
+use transit_server::diff::ir::{ScheduleIR, StopTimeIR, TripIR};
+
+// Counts allocations made by the current thread, so tests running in parallel don't interfere
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|a| a.get())
+}
+
+fn trip(trip_id: usize) -> TripIR {
+    let stop_times: HashMap<u32, StopTimeIR> = (0..50)
+        .map(|seq| {
+            (
+                seq,
+                StopTimeIR {
+                    stop_id: Some(format!("StopId{}", seq).into()),
+                    arrival_time: Some(seq * 60),
+                    departure_time: Some(seq * 60),
+                    stop_sequence: Some(seq),
+                },
+            )
+        })
+        .collect();
+
+    TripIR {
+        trip_id: format!("TripId{}", trip_id).into(),
+        stop_times: stop_times.into(),
+        headsign: None,
+        shape_id: None,
+        direction: None,
+        mask_start_date: "20250401".parse().unwrap(),
+        date_mask: 1,
+    }
+}
+
+#[test]
+fn test_trip_diff_allocations() {
+    let prev = (0..40)
+        .fold(ScheduleIR::builder().route("RouteId1"), |builder, i| {
+            builder.trip(trip(i))
+        })
+        .build();
+    let schedule = ScheduleIR::builder().route("RouteId1").build();
+
+    let before = allocations();
+    let (added_trips, removed_trip_ids) = schedule.get_trip_diffs(&prev);
+    let allocated = allocations() - before;
+
+    assert_eq!(added_trips.len(), 0);
+    assert_eq!(removed_trip_ids.len(), 40);
+    // Keys share the schedule's ids, so only the set itself allocates. Owned String keys would
+    // need two allocations per removed trip
+    assert!(allocated < removed_trip_ids.len());
+}
+
+#[test]
+fn test_stop_time_clone_allocations() {
+    let trip = trip(0);
+
+    let before = allocations();
+    let cloned = trip.clone();
+    let allocated = allocations() - before;
+
+    assert_eq!(cloned, trip);
+    // Only the stop time map allocates, owned stop ids would need one allocation per stop time
+    assert!(allocated < trip.stop_times.len());
+}